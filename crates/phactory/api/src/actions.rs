@@ -1,4 +1,31 @@
 pub const ACTION_GET_INFO: u8 = 2;
+pub const ACTION_GET_ECDH_KEY_ATTESTATION: u8 = 3;
+pub const ACTION_VERIFY_ECDH_KEY_ATTESTATION: u8 = 4;
+pub const ACTION_RELOCATE_STORAGE: u8 = 5;
+pub const ACTION_GET_CLUSTER_OF_CONTRACT: u8 = 6;
+pub const ACTION_GET_CONTRACT_METADATA: u8 = 7;
+pub const ACTION_GET_KEY_OPERATIONS: u8 = 8;
+pub const ACTION_GET_CLUSTER_STORAGE_USAGE: u8 = 9;
+pub const ACTION_GET_CONTRACT_STORAGE_SIZE: u8 = 10;
+pub const ACTION_SET_CLUSTER_QUERY_CACHE: u8 = 11;
+pub const ACTION_GET_ROLE_TAGS: u8 = 12;
+pub const ACTION_SET_ROLE_TAGS: u8 = 13;
+pub const ACTION_SET_PAUSED: u8 = 14;
+pub const ACTION_LIST_EGRESS_MESSAGES: u8 = 15;
+pub const ACTION_DROP_EGRESS_MESSAGE: u8 = 16;
+pub const ACTION_RESYNC_GK_WORKERS: u8 = 17;
+pub const ACTION_GET_PROTOCOL_VERSION: u8 = 18;
+pub const ACTION_GET_PROCESSING_TIMES: u8 = 19;
+pub const ACTION_GET_SLOW_CONTRACTS: u8 = 20;
+pub const ACTION_GET_CHECKPOINT_STATS: u8 = 21;
+pub const ACTION_START_BENCHMARK: u8 = 22;
+pub const ACTION_GET_GK_WORKER_TOKENOMICS: u8 = 23;
+pub const ACTION_GET_GK_HEARTBEAT_ANOMALIES: u8 = 24;
+pub const ACTION_SIMULATE_HEARTBEAT_CHALLENGE: u8 = 25;
+pub const ACTION_GET_WORKER_STATE: u8 = 26;
+pub const ACTION_GET_SIMULATED_EGRESS: u8 = 27;
+pub const ACTION_EXPORT_PENDING_EGRESS: u8 = 28;
+pub const ACTION_IMPORT_PENDING_EGRESS: u8 = 29;
 
 pub const BIN_ACTION_START: u8 = 128;
 pub const BIN_ACTION_SYNC_PARA_HEADER: u8 = BIN_ACTION_START;
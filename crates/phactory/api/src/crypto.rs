@@ -4,6 +4,7 @@ use parity_scale_codec::{Decode, Encode, Error as CodecError};
 
 use crate::prpc::{Signature, SignatureType};
 pub use phala_crypto::{aead, ecdh, CryptoError};
+use phala_types::{wrap_content_to_sign, SignedContentType};
 
 #[derive(Clone, Encode, Decode, Debug)]
 pub struct EncryptedData {
@@ -95,6 +96,21 @@ impl Signature {
             None => Err(SignatureVerifyError::CertificateMissing),
         }
     }
+
+    /// Verify the signature of a contract query and return the pubkey of the root signer, which is
+    /// the authenticated origin of the query.
+    pub fn verify_query(
+        &self,
+        encoded_encrypted_data: &[u8],
+        current_block: u32,
+    ) -> Result<Vec<u8>, SignatureVerifyError> {
+        // At most two level cert chain supported
+        let key_chain = self.verify(encoded_encrypted_data, current_block, 2)?;
+        key_chain
+            .into_iter()
+            .next()
+            .ok_or(SignatureVerifyError::CertificateMissing)
+    }
 }
 
 fn verify<T>(pubkey: &[u8], sig: &[u8], msg: &[u8]) -> bool
@@ -156,3 +172,154 @@ impl CertificateBody {
         }
     }
 }
+
+/// The message signed by a worker's identity key to attest its ECDH public key.
+pub fn ecdh_key_attestation_message(ecdh_pubkey: &[u8]) -> Vec<u8> {
+    wrap_content_to_sign(ecdh_pubkey, SignedContentType::EcdhKeyAttestation).into_owned()
+}
+
+/// Verify that `ecdh_pubkey` is attested by the worker identity key `identity_pubkey`.
+///
+/// Clients should check this before encrypting anything to a worker's ECDH key, so that a
+/// man-in-the-middle can not substitute its own key.
+pub fn verify_ecdh_key_attestation(
+    identity_pubkey: &[u8],
+    ecdh_pubkey: &[u8],
+    signature: &[u8],
+) -> bool {
+    let message = ecdh_key_attestation_message(ecdh_pubkey);
+    verify::<sp_core::sr25519::Pair>(identity_pubkey, signature, &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prpc::Certificate;
+    use alloc::boxed::Box;
+    use sp_core::{sr25519, Pair as _};
+
+    fn attest(identity: &sp_core::sr25519::Pair, ecdh_pubkey: &[u8]) -> Vec<u8> {
+        identity
+            .sign(&ecdh_key_attestation_message(ecdh_pubkey))
+            .0
+            .to_vec()
+    }
+
+    #[test]
+    fn valid_ecdh_key_attestation() {
+        let identity = sp_core::sr25519::Pair::from_seed(&[1u8; 32]);
+        let ecdh_pubkey = [2u8; 32];
+        let signature = attest(&identity, &ecdh_pubkey);
+        assert!(verify_ecdh_key_attestation(
+            &identity.public().0,
+            &ecdh_pubkey,
+            &signature
+        ));
+    }
+
+    #[test]
+    fn tampered_ecdh_key_attestation() {
+        let identity = sp_core::sr25519::Pair::from_seed(&[1u8; 32]);
+        let mitm = sp_core::sr25519::Pair::from_seed(&[3u8; 32]);
+        let ecdh_pubkey = [2u8; 32];
+        let signature = attest(&identity, &ecdh_pubkey);
+
+        // Substituted ECDH key
+        assert!(!verify_ecdh_key_attestation(
+            &identity.public().0,
+            &[4u8; 32],
+            &signature
+        ));
+        // Signed by someone else
+        let forged = attest(&mitm, &[4u8; 32]);
+        assert!(!verify_ecdh_key_attestation(
+            &identity.public().0,
+            &[4u8; 32],
+            &forged
+        ));
+        // Corrupted signature
+        let mut corrupted = signature;
+        corrupted[0] ^= 1;
+        assert!(!verify_ecdh_key_attestation(
+            &identity.public().0,
+            &ecdh_pubkey,
+            &corrupted
+        ));
+    }
+
+    fn certificate(key: &sr25519::Pair, signer: Option<&sr25519::Pair>, ttl: u32) -> Certificate {
+        let body = CertificateBody {
+            pubkey: key.public().0.to_vec(),
+            ttl,
+            config_bits: 0,
+        }
+        .encode();
+        let signature = signer.map(|signer| {
+            Box::new(Signature {
+                signed_by: Some(Box::new(certificate(signer, None, ttl))),
+                signature_type: SignatureType::Sr25519 as i32,
+                signature: signer.sign(&body).0.to_vec(),
+            })
+        });
+        Certificate {
+            encoded_body: body,
+            signature,
+        }
+    }
+
+    /// A query signed by a session key certified by the `root` account.
+    fn sign_query(root: &sr25519::Pair, session: &sr25519::Pair, query: &[u8]) -> Signature {
+        Signature {
+            signed_by: Some(Box::new(certificate(session, Some(root), 100))),
+            signature_type: SignatureType::Sr25519 as i32,
+            signature: session.sign(query).0.to_vec(),
+        }
+    }
+
+    #[test]
+    fn signed_query_origin_is_root_signer() {
+        let root = sr25519::Pair::from_seed(&[1u8; 32]);
+        let session = sr25519::Pair::from_seed(&[2u8; 32]);
+        let sig = sign_query(&root, &session, b"query");
+        let origin = sig.verify_query(b"query", 10).unwrap();
+        assert_eq!(origin, root.public().0.to_vec());
+    }
+
+    #[test]
+    fn forged_query_signature_is_rejected() {
+        let root = sr25519::Pair::from_seed(&[1u8; 32]);
+        let session = sr25519::Pair::from_seed(&[2u8; 32]);
+        let attacker = sr25519::Pair::from_seed(&[3u8; 32]);
+
+        // Tampered query
+        let sig = sign_query(&root, &session, b"query");
+        assert!(matches!(
+            sig.verify_query(b"tampered", 10),
+            Err(SignatureVerifyError::InvalidSignature)
+        ));
+        // Claims to be certified by root but signed by the attacker
+        let mut sig = sign_query(&attacker, &session, b"query");
+        let cert_sig = sig.signed_by.as_mut().unwrap().signature.as_mut().unwrap();
+        cert_sig.signed_by = Some(Box::new(certificate(&root, None, 100)));
+        assert!(matches!(
+            sig.verify_query(b"query", 10),
+            Err(SignatureVerifyError::InvalidSignature)
+        ));
+        // Expired certificate
+        let sig = sign_query(&root, &session, b"query");
+        assert!(matches!(
+            sig.verify_query(b"query", 101),
+            Err(SignatureVerifyError::CertificateExpired)
+        ));
+        // Unsigned certificate
+        let sig = Signature {
+            signature_type: SignatureType::Sr25519 as i32,
+            signature: session.sign(b"query").0.to_vec(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            sig.verify_query(b"query", 10),
+            Err(SignatureVerifyError::CertificateMissing)
+        ));
+    }
+}
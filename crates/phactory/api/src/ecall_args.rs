@@ -1,4 +1,5 @@
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use parity_scale_codec::{Decode, Encode};
 use serde::{Serialize, Deserialize};
 
@@ -40,8 +41,100 @@ pub struct InitArgs {
 
     /// The public rpc port with acl enabled
     pub public_port: Option<u16>,
+
+    /// Tags of the intended roles of the worker, for fleet management tooling
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub role_tags: Vec<String>,
+
+    /// Start in safe mode, refusing to dispatch blocks until it is resumed explicitly
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub start_paused: bool,
+
+    /// Start in simulation mode, recording the egress messages instead of sending them
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub simulation: bool,
+
+    /// How to handle the conditions indicating the system state is poisoned
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub poisoned_state_policy: PoisonedStatePolicy,
+
+    /// Log the contracts taking longer than this to process a block, 0 to disable
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub slow_contract_threshold_ms: u64,
+
+    /// Defer the checkpoints while the Rust heap in use is above this many MiB, 0 to disable
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub checkpoint_max_memory_mb: u64,
+
+    /// Duration in blocks of the benchmarks started locally without a duration given, 0 for the
+    /// default
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bench_duration: u32,
+
+    /// Benchmark reports above this many iterations are clamped to it, 0 for no limit
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_bench_iterations: u64,
+}
+
+/// Duration in blocks of the benchmarks started locally, the same as the chain default.
+pub const DEFAULT_BENCH_DURATION: u32 = 8;
+
+/// How to handle an invariant violation indicating the system state is poisoned.
+#[derive(Serialize, Deserialize, Debug, Encode, Decode, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PoisonedStatePolicy {
+    /// Abort the process, as moving on with a poisoned state makes no sense.
+    #[default]
+    Abort,
+    /// Drop the offending event with an audit log and keep going in a degraded state, which some
+    /// operators prefer over a crash loop.
+    LogAndContinue,
+}
+
+impl InitArgs {
+    pub fn checkpoint_max_memory(&self) -> Option<usize> {
+        (self.checkpoint_max_memory_mb > 0)
+            .then(|| (self.checkpoint_max_memory_mb as usize).saturating_mul(1024 * 1024))
+    }
+
+    pub fn slow_contract_threshold(&self) -> Option<core::time::Duration> {
+        (self.slow_contract_threshold_ms > 0)
+            .then(|| core::time::Duration::from_millis(self.slow_contract_threshold_ms))
+    }
+
+    pub fn max_bench_iterations(&self) -> Option<u64> {
+        (self.max_bench_iterations > 0).then_some(self.max_bench_iterations)
+    }
+
+    pub fn bench_duration(&self) -> u32 {
+        if self.bench_duration > 0 {
+            self.bench_duration
+        } else {
+            DEFAULT_BENCH_DURATION
+        }
+    }
 }
 
 pub fn git_revision() -> String {
     env!("PHALA_GIT_REVISION").to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_duration_can_be_overridden() {
+        let mut args = InitArgs::default();
+        assert_eq!(args.bench_duration(), DEFAULT_BENCH_DURATION);
+        args.bench_duration = 100;
+        assert_eq!(args.bench_duration(), 100);
+    }
+
+    #[test]
+    fn zero_max_bench_iterations_means_no_limit() {
+        let mut args = InitArgs::default();
+        assert_eq!(args.max_bench_iterations(), None);
+        args.max_bench_iterations = 1000;
+        assert_eq!(args.max_bench_iterations(), Some(1000));
+    }
+}
@@ -0,0 +1,142 @@
+//! Version negotiation between pRuntime and pherry.
+//!
+//! Each side declares the protocol version it speaks and the oldest version of the other side
+//! it can work with. pherry queries the pRuntime's versions before syncing anything and refuses
+//! to go on if either side is too old for the other.
+
+use serde::{Deserialize, Serialize};
+
+/// The protocol version spoken by this build of pRuntime and pherry.
+///
+/// Bump it on any incompatible change of the interface between them.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest pherry protocol version the pRuntime can work with.
+pub const MIN_PHERRY_PROTOCOL_VERSION: u32 = 1;
+/// The oldest pRuntime protocol version pherry can work with.
+pub const MIN_PRUNTIME_PROTOCOL_VERSION: u32 = 1;
+/// The protocol version assumed for pRuntimes predating the handshake.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    /// The protocol version spoken by this side.
+    pub version: u32,
+    /// The oldest protocol version required on the other side.
+    pub min_peer_version: u32,
+}
+
+impl ProtocolVersion {
+    /// The versions declared by pRuntime.
+    pub const fn pruntime() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_peer_version: MIN_PHERRY_PROTOCOL_VERSION,
+        }
+    }
+
+    /// The versions assumed for a pRuntime which does not serve its protocol version yet.
+    pub const fn legacy_pruntime() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            min_peer_version: LEGACY_PROTOCOL_VERSION,
+        }
+    }
+
+    /// The versions declared by pherry.
+    pub const fn pherry() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_peer_version: MIN_PRUNTIME_PROTOCOL_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum HandshakeError {
+    #[display(
+        fmt = "pRuntime speaks protocol v{} but pherry requires at least v{}, please upgrade pRuntime",
+        _0,
+        _1
+    )]
+    PRuntimeTooOld(u32, u32),
+    #[display(
+        fmt = "pherry speaks protocol v{} but pRuntime requires at least v{}, please upgrade pherry",
+        _0,
+        _1
+    )]
+    PherryTooOld(u32, u32),
+}
+
+/// Check whether the given pRuntime and pherry can work together.
+pub fn check_compatible(
+    pruntime: &ProtocolVersion,
+    pherry: &ProtocolVersion,
+) -> Result<(), HandshakeError> {
+    if pruntime.version < pherry.min_peer_version {
+        return Err(HandshakeError::PRuntimeTooOld(
+            pruntime.version,
+            pherry.min_peer_version,
+        ));
+    }
+    if pherry.version < pruntime.min_peer_version {
+        return Err(HandshakeError::PherryTooOld(
+            pherry.version,
+            pruntime.min_peer_version,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: u32, min_peer_version: u32) -> ProtocolVersion {
+        ProtocolVersion {
+            version,
+            min_peer_version,
+        }
+    }
+
+    #[test]
+    fn current_builds_are_compatible() {
+        assert_eq!(
+            check_compatible(&ProtocolVersion::pruntime(), &ProtocolVersion::pherry()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn handshake_rejects_incompatible_versions() {
+        assert_eq!(
+            check_compatible(&version(1, 1), &version(2, 2)),
+            Err(HandshakeError::PRuntimeTooOld(1, 2))
+        );
+        assert_eq!(
+            check_compatible(&version(3, 2), &version(1, 1)),
+            Err(HandshakeError::PherryTooOld(1, 2))
+        );
+        // Newer sides are fine as long as they still accept each other
+        assert_eq!(check_compatible(&version(3, 1), &version(2, 2)), Ok(()));
+    }
+
+    #[test]
+    fn legacy_pruntime_is_accepted() {
+        assert_eq!(
+            check_compatible(
+                &ProtocolVersion::legacy_pruntime(),
+                &ProtocolVersion::pherry()
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn protocol_version_json() {
+        let json = serde_json::to_value(version(2, 1)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"version": 2, "min_peer_version": 1})
+        );
+    }
+}
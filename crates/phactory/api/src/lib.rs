@@ -10,5 +10,6 @@ pub mod storage_sync;
 pub mod pruntime_client;
 pub mod ecall_args;
 pub mod endpoints;
+pub mod handshake;
 
 mod proto_generated;
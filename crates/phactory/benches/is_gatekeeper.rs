@@ -0,0 +1,33 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use parity_scale_codec::Encode;
+use phactory::ChainStorage;
+use phala_types::WorkerPublicKey;
+use sp_core::hashing::twox_128;
+
+fn storage_with_gatekeepers(n: u8) -> (Vec<WorkerPublicKey>, ChainStorage) {
+    let gatekeepers: Vec<_> = (0..n).map(|i| WorkerPublicKey::from_raw([i; 32])).collect();
+    let key = [twox_128(b"PhalaRegistry"), twox_128(b"Gatekeeper")].concat();
+    let storage = ChainStorage::from_pairs(vec![(key, gatekeepers.encode())].into_iter());
+    (gatekeepers, storage)
+}
+
+fn bench_is_gatekeeper(c: &mut Criterion) {
+    let (gatekeepers, storage) = storage_with_gatekeepers(100);
+    let mut group = c.benchmark_group("check 100 gatekeepers");
+    // A new block number on every check forces the list to be decoded each time
+    group.bench_function("decode per check", |b| {
+        b.iter(|| {
+            gatekeepers
+                .iter()
+                .zip(1..)
+                .all(|(gk, block_number)| storage.is_gatekeeper_at(gk, block_number))
+        })
+    });
+    group.bench_function("cached in block", |b| {
+        b.iter(|| gatekeepers.iter().all(|gk| storage.is_gatekeeper_at(gk, 1)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_is_gatekeeper);
+criterion_main!(benches);
@@ -2,6 +2,7 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use log::debug;
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
+use std::time::Instant;
 
 // TODO.kevin: block_box will do best-effort to prevent compiler optimizations, but not guaranteed.
 use core::hint::black_box;
@@ -90,6 +91,32 @@ pub fn iteration_counter() -> u64 {
     ITERATION_COUNTER.load(Ordering::Relaxed)
 }
 
+/// A reading of the iteration counter.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub counter: u64,
+    pub at: Instant,
+}
+
+impl Sample {
+    pub fn now() -> Self {
+        Self {
+            counter: iteration_counter(),
+            at: Instant::now(),
+        }
+    }
+
+    /// Iterations per second from this sample to a later one.
+    pub fn iteration_rate(&self, later: &Sample) -> f64 {
+        let elapsed = later.at.saturating_duration_since(self.at).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        // The counter goes backwards only when reset.
+        later.counter.saturating_sub(self.counter) as f64 / elapsed
+    }
+}
+
 pub fn reset_iteration_counter() {
     ITERATION_COUNTER.store(0, Ordering::Relaxed);
 }
@@ -138,3 +165,29 @@ fn now() -> u64 {
         .expect("Get time failed")
         .as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn iteration_rate_is_computed_from_deltas() {
+        let start = Sample {
+            counter: 1000,
+            at: Instant::now(),
+        };
+        let later = Sample {
+            counter: 1500,
+            at: start.at + Duration::from_millis(2500),
+        };
+        assert_eq!(start.iteration_rate(&later), 200.0);
+        assert_eq!(start.iteration_rate(&start), 0.0);
+
+        let reset = Sample {
+            counter: 10,
+            at: later.at,
+        };
+        assert_eq!(start.iteration_rate(&reset), 0.0);
+    }
+}
@@ -1,7 +1,124 @@
-use phala_types::{wrap_content_to_sign, SignedContentType};
+use phala_types::{messaging::HeartbeatChallenge, wrap_content_to_sign, SignedContentType};
+use sp_core::U256;
 
 use super::*;
 
+/// Number of the contracts listed by the `get_slow_contracts` action and the metrics.
+pub(crate) const TOP_SLOW_CONTRACTS: usize = 20;
+
+#[derive(Deserialize)]
+struct VerifyEcdhKeyAttestationReq {
+    public_key: String,
+    ecdh_public_key: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct RelocateStorageReq {
+    storage_path: String,
+}
+
+#[derive(Deserialize)]
+struct ContractIdReq {
+    contract_id: String,
+}
+
+impl ContractIdReq {
+    fn decode(&self) -> Result<ContractId, Value> {
+        let raw: [u8; 32] = prpc_service::try_decode_hex(&self.contract_id)
+            .ok()
+            .and_then(|raw| raw.try_into().ok())
+            .ok_or_else(|| error_msg("Invalid contract id"))?;
+        Ok(raw.into())
+    }
+}
+
+#[derive(Deserialize)]
+struct SetClusterQueryCacheReq {
+    cluster_id: String,
+    /// Max number of query replies cached per block, 0 to disable.
+    capacity: usize,
+}
+
+#[derive(Deserialize)]
+struct SetRoleTagsReq {
+    role_tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SetPausedReq {
+    paused: bool,
+}
+
+#[derive(Deserialize)]
+struct StartBenchmarkReq {
+    /// Number of blocks the benchmark keeps running, the configured default if not given.
+    #[serde(default)]
+    duration: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct SimulateHeartbeatChallengeReq {
+    /// The worker to check, this worker if not given.
+    #[serde(default)]
+    pubkey: Option<String>,
+    /// The challenge seed, as a decimal number or a 0x-prefixed hex number.
+    seed: String,
+    /// The online target, in the same format as the seed.
+    online_target: String,
+}
+
+fn parse_u256(number: &str) -> Result<U256, Value> {
+    if number.starts_with("0x") {
+        let bytes = prpc_service::try_decode_hex(number)
+            .ok()
+            .filter(|bytes| bytes.len() <= 32)
+            .ok_or_else(|| error_msg("Invalid hex number"))?;
+        Ok(U256::from_big_endian(&bytes))
+    } else {
+        U256::from_dec_str(number).map_err(|_| error_msg("Invalid decimal number"))
+    }
+}
+
+#[derive(Deserialize)]
+struct DropEgressMessageReq {
+    sender: phala_mq::MessageOrigin,
+    sequence: u64,
+    /// Must be true. The messages after the dropped one from the same sender are dropped too.
+    #[serde(default)]
+    confirm: bool,
+}
+
+fn egress_message_json(msg: &phala_mq::SignedMessage) -> Value {
+    json!({
+        "sender": msg.message.sender,
+        "sequence": msg.sequence,
+        "topic": String::from_utf8_lossy(msg.message.destination.path()),
+        "size": msg.message.payload.len(),
+    })
+}
+
+#[derive(Deserialize)]
+struct ImportPendingEgressReq {
+    /// The SCALE encoded messages returned by `export_pending_egress`, in hex.
+    messages: String,
+}
+
+#[derive(Deserialize)]
+struct ResyncGkWorkersReq {
+    /// Must be true. The computing economics of this gatekeeper might diverge from the others.
+    #[serde(default)]
+    confirm: bool,
+}
+
+fn decode_cluster_id(cluster_id: &str) -> Result<phala_mq::ContractClusterId, Value> {
+    let raw: [u8; 32] = prpc_service::try_decode_hex(cluster_id)
+        .ok()
+        .and_then(|raw| raw.try_into().ok())
+        .ok_or_else(|| error_msg("Invalid cluster id"))?;
+    Ok(raw.into())
+}
+
 // For bin_api
 impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform> {
     pub fn sign_http_response(&self, body: &[u8]) -> Option<String> {
@@ -16,6 +133,389 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         Ok(json!(self.get_info()))
     }
 
+    fn get_ecdh_key_attestation_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        Ok(json!({
+            "public_key": hex::encode(system.identity_key.0.public()),
+            "ecdh_public_key": hex::encode(system.ecdh_key.public()),
+            "signature": hex::encode(system.ecdh_key_attestation()),
+        }))
+    }
+
+    fn verify_ecdh_key_attestation_json(
+        &self,
+        input: VerifyEcdhKeyAttestationReq,
+    ) -> Result<Value, Value> {
+        let decode =
+            |s: &str| prpc_service::try_decode_hex(s).map_err(|_| error_msg("Invalid hex input"));
+        let valid = phactory_api::crypto::verify_ecdh_key_attestation(
+            &decode(&input.public_key)?,
+            &decode(&input.ecdh_public_key)?,
+            &decode(&input.signature)?,
+        );
+        Ok(json!({ "valid": valid }))
+    }
+
+    fn relocate_storage_json(&mut self, input: RelocateStorageReq) -> Result<Value, Value> {
+        self.relocate_storage(input.storage_path).map_err(display)?;
+        Ok(json!({ "storage_path": self.args.storage_path }))
+    }
+
+    fn get_protocol_version_json(&self) -> Result<Value, Value> {
+        serde_json::to_value(phactory_api::handshake::ProtocolVersion::pruntime()).map_err(display)
+    }
+
+    fn get_cluster_of_contract_json(&self, input: ContractIdReq) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let cluster = system
+            .cluster_of_contract(&input.decode()?)
+            .map(|cluster| format!("0x{}", hex::encode(cluster)));
+        Ok(json!({ "cluster_id": cluster }))
+    }
+
+    fn get_contract_metadata_json(&self, input: ContractIdReq) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let Some(info) = system.contract_info(&input.decode()?) else {
+            return Ok(json!({ "exists": false }));
+        };
+        Ok(json!({
+            "exists": true,
+            "cluster_id": format!("0x{}", hex::encode(info.cluster_id)),
+            "pubkey": format!("0x{}", hex::encode(info.pubkey)),
+            "sidevm": info.sidevm,
+        }))
+    }
+
+    fn get_key_operations_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let counter = system.key_operations();
+        Ok(json!({
+            "total": counter.total(),
+            "counter": counter,
+        }))
+    }
+
+    fn get_processing_times_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        serde_json::to_value(system.processing_times()).map_err(display)
+    }
+
+    fn get_slow_contracts_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let contracts: Vec<_> = system
+            .processing_times()
+            .top_slow_contracts(TOP_SLOW_CONTRACTS)
+            .into_iter()
+            .map(|(contract_id, slow)| {
+                json!({
+                    "contract_id": format!("0x{}", hex::encode(contract_id)),
+                    "slow_blocks": slow.slow_blocks,
+                    "max_us": slow.max_us,
+                    "last_slow_block": slow.last_slow_block,
+                })
+            })
+            .collect();
+        Ok(json!({ "contracts": contracts }))
+    }
+
+    fn get_checkpoint_stats_json(&self) -> Result<Value, Value> {
+        serde_json::to_value(&self.checkpoint_stats).map_err(display)
+    }
+
+    fn get_gk_worker_tokenomics_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let gatekeeper = system
+            .gatekeeper
+            .as_ref()
+            .ok_or_else(|| error_msg("Not a gatekeeper"))?;
+        let workers: Vec<_> = gatekeeper
+            .worker_tokenomics()
+            .into_iter()
+            .map(|(pubkey, tokenomic)| {
+                json!({
+                    "pubkey": hex::encode(pubkey),
+                    "tokenomic": tokenomic,
+                })
+            })
+            .collect();
+        Ok(json!({ "workers": workers }))
+    }
+
+    fn get_gk_heartbeat_anomalies_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let gatekeeper = system
+            .gatekeeper
+            .as_ref()
+            .ok_or_else(|| error_msg("Not a gatekeeper"))?;
+        let workers: Vec<_> = gatekeeper
+            .heartbeat_anomalies()
+            .into_iter()
+            .map(|(pubkey, anomalies)| {
+                json!({
+                    "pubkey": hex::encode(pubkey),
+                    "stale": anomalies.stale,
+                    "unexpected": anomalies.unexpected,
+                })
+            })
+            .collect();
+        Ok(json!({ "workers": workers }))
+    }
+
+    fn get_cluster_storage_usage_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let clusters: Vec<_> = system
+            .cluster_storage_usage()
+            .into_iter()
+            .map(|usage| {
+                json!({
+                    "cluster_id": format!("0x{}", hex::encode(usage.cluster_id)),
+                    "usage": usage.usage,
+                })
+            })
+            .collect();
+        Ok(json!({ "clusters": clusters }))
+    }
+
+    fn get_contract_storage_size_json(&self, input: ContractIdReq) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let size = system.contract_storage_size(&input.decode()?);
+        Ok(json!({ "size": size }))
+    }
+
+    fn set_cluster_query_cache_json(
+        &mut self,
+        input: SetClusterQueryCacheReq,
+    ) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_mut()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let cluster_id = decode_cluster_id(&input.cluster_id)?;
+        if !system.set_cluster_query_cache_capacity(&cluster_id, input.capacity) {
+            return Err(error_msg("Cluster not found"));
+        }
+        Ok(json!({ "capacity": input.capacity }))
+    }
+
+    fn get_role_tags_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        Ok(json!({ "role_tags": system.role_tags() }))
+    }
+
+    fn set_role_tags_json(&mut self, input: SetRoleTagsReq) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_mut()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        system.set_role_tags(input.role_tags);
+        Ok(json!({ "role_tags": system.role_tags() }))
+    }
+
+    fn set_paused_json(&mut self, input: SetPausedReq) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_mut()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        system.set_paused(input.paused);
+        Ok(json!({ "paused": system.paused() }))
+    }
+
+    fn start_benchmark_json(&mut self, input: StartBenchmarkReq) -> Result<Value, Value> {
+        let duration = input.duration.unwrap_or_else(|| self.args.bench_duration());
+        let system = self
+            .system
+            .as_mut()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        system.start_local_benchmark(duration).map_err(display)?;
+        Ok(json!({ "start_block": system.block_number, "duration": duration }))
+    }
+
+    fn get_worker_state_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let dump = system.worker_state_dump();
+        Ok(json!({
+            "pubkey": hex::encode(dump.pubkey),
+            "hashed_id": format!("{:#x}", dump.hashed_id),
+            "registered": dump.registered,
+            "bench_state": dump.bench_state,
+            "working_state": dump.working_state,
+        }))
+    }
+
+    fn simulate_heartbeat_challenge_json(
+        &self,
+        input: SimulateHeartbeatChallengeReq,
+    ) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let pubkey = match &input.pubkey {
+            Some(pubkey) => {
+                let raw: [u8; 32] = prpc_service::try_decode_hex(pubkey)
+                    .ok()
+                    .and_then(|raw| raw.try_into().ok())
+                    .ok_or_else(|| error_msg("Invalid pubkey"))?;
+                Some(sr25519::Public::from_raw(raw))
+            }
+            None => None,
+        };
+        let challenge = HeartbeatChallenge {
+            seed: parse_u256(&input.seed)?,
+            online_target: parse_u256(&input.online_target)?,
+        };
+        let outcome = system.simulate_heartbeat_challenge(pubkey, &challenge);
+        Ok(json!({
+            "x": outcome.x.to_string(),
+            "hit": outcome.hit,
+            "margin": outcome.margin.to_string(),
+        }))
+    }
+
+    fn list_egress_messages_json(&self) -> Result<Value, Value> {
+        let state = self
+            .runtime_state
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let messages: Vec<_> = state
+            .send_mq
+            .all_messages()
+            .iter()
+            .map(egress_message_json)
+            .collect();
+        Ok(json!({ "messages": messages }))
+    }
+
+    fn simulated_egress_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let messages: Vec<_> = system
+            .simulated_egress()
+            .iter()
+            .map(egress_message_json)
+            .collect();
+        Ok(json!({
+            "simulation": system.is_simulation(),
+            "messages": messages,
+        }))
+    }
+
+    fn export_pending_egress_json(&self) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let messages = system.export_pending_egress();
+        Ok(json!({
+            "count": messages.len(),
+            "messages": hex::encode(messages.encode()),
+        }))
+    }
+
+    fn import_pending_egress_json(
+        &mut self,
+        input: ImportPendingEgressReq,
+    ) -> Result<Value, Value> {
+        let system = self
+            .system
+            .as_mut()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let messages: Vec<phala_mq::SignedMessage> = prpc_service::try_decode_hex(&input.messages)
+            .ok()
+            .and_then(|raw| Decode::decode(&mut &raw[..]).ok())
+            .ok_or_else(|| error_msg("Invalid messages"))?;
+        let count = messages.len();
+        system.import_pending_egress(messages);
+        info!("Imported {count} pending egress messages");
+        Ok(json!({ "imported": count }))
+    }
+
+    fn drop_egress_message_json(&mut self, input: DropEgressMessageReq) -> Result<Value, Value> {
+        if !input.confirm {
+            return Err(error_msg(
+                "Dropping egress messages requires confirm=true, the later messages from the same sender are dropped too",
+            ));
+        }
+        if !matches!(input.sender, phala_mq::MessageOrigin::Worker(_)) {
+            return Err(error_msg(
+                "Only the egress messages of the worker itself can be dropped",
+            ));
+        }
+        let state = self
+            .runtime_state
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let dropped = state
+            .send_mq
+            .drop_messages_from(&input.sender, input.sequence);
+        if dropped == 0 {
+            return Err(error_msg("No such pending message"));
+        }
+        warn!(
+            "Dropped {dropped} egress messages from {} starting at sequence {}",
+            input.sender, input.sequence
+        );
+        Ok(json!({ "dropped": dropped }))
+    }
+
+    fn resync_gk_workers_json(&mut self, input: ResyncGkWorkersReq) -> Result<Value, Value> {
+        if !input.confirm {
+            return Err(error_msg(
+                "Resyncing the GK workers requires confirm=true, the payouts might diverge from other gatekeepers",
+            ));
+        }
+        let state = self
+            .runtime_state
+            .as_ref()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let system = self
+            .system
+            .as_mut()
+            .ok_or_else(|| error_msg("Runtime not initialized"))?;
+        let (added, removed) = system
+            .resync_gatekeeper_from_chain(&state.chain_storage)
+            .map_err(display)?;
+        Ok(json!({ "added": added, "removed": removed }))
+    }
+
     fn bin_sync_header(&mut self, input: blocks::SyncHeaderReq) -> Result<Value, Value> {
         let resp = self
             .sync_header(input.headers, input.authority_set_change)
@@ -57,8 +557,46 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             Decode::decode(&mut scale).map_err(|_| error_msg("Decode input parameter failed"))
         }
 
+        fn load_json<T: DeserializeOwned>(input: &[u8]) -> Result<T, Value> {
+            let mut value: Value = serde_json::from_slice(input).map_err(display)?;
+            serde_json::from_value(value["input"].take()).map_err(display)
+        }
+
         match action {
             ACTION_GET_INFO => self.get_info_json(),
+            ACTION_GET_ECDH_KEY_ATTESTATION => self.get_ecdh_key_attestation_json(),
+            ACTION_VERIFY_ECDH_KEY_ATTESTATION => {
+                self.verify_ecdh_key_attestation_json(load_json(input)?)
+            }
+            ACTION_RELOCATE_STORAGE => self.relocate_storage_json(load_json(input)?),
+            ACTION_GET_CLUSTER_OF_CONTRACT => self.get_cluster_of_contract_json(load_json(input)?),
+            ACTION_GET_CONTRACT_METADATA => self.get_contract_metadata_json(load_json(input)?),
+            ACTION_GET_KEY_OPERATIONS => self.get_key_operations_json(),
+            ACTION_GET_CLUSTER_STORAGE_USAGE => self.get_cluster_storage_usage_json(),
+            ACTION_GET_CONTRACT_STORAGE_SIZE => {
+                self.get_contract_storage_size_json(load_json(input)?)
+            }
+            ACTION_SET_CLUSTER_QUERY_CACHE => self.set_cluster_query_cache_json(load_json(input)?),
+            ACTION_GET_ROLE_TAGS => self.get_role_tags_json(),
+            ACTION_SET_ROLE_TAGS => self.set_role_tags_json(load_json(input)?),
+            ACTION_SET_PAUSED => self.set_paused_json(load_json(input)?),
+            ACTION_LIST_EGRESS_MESSAGES => self.list_egress_messages_json(),
+            ACTION_DROP_EGRESS_MESSAGE => self.drop_egress_message_json(load_json(input)?),
+            ACTION_GET_SIMULATED_EGRESS => self.simulated_egress_json(),
+            ACTION_EXPORT_PENDING_EGRESS => self.export_pending_egress_json(),
+            ACTION_IMPORT_PENDING_EGRESS => self.import_pending_egress_json(load_json(input)?),
+            ACTION_RESYNC_GK_WORKERS => self.resync_gk_workers_json(load_json(input)?),
+            ACTION_GET_PROTOCOL_VERSION => self.get_protocol_version_json(),
+            ACTION_GET_PROCESSING_TIMES => self.get_processing_times_json(),
+            ACTION_GET_SLOW_CONTRACTS => self.get_slow_contracts_json(),
+            ACTION_GET_CHECKPOINT_STATS => self.get_checkpoint_stats_json(),
+            ACTION_START_BENCHMARK => self.start_benchmark_json(load_json(input)?),
+            ACTION_GET_GK_WORKER_TOKENOMICS => self.get_gk_worker_tokenomics_json(),
+            ACTION_GET_GK_HEARTBEAT_ANOMALIES => self.get_gk_heartbeat_anomalies_json(),
+            ACTION_SIMULATE_HEARTBEAT_CHALLENGE => {
+                self.simulate_heartbeat_challenge_json(load_json(input)?)
+            }
+            ACTION_GET_WORKER_STATE => self.get_worker_state_json(),
             BIN_ACTION_SYNC_HEADER => self.bin_sync_header(load_scale(input)?),
             BIN_ACTION_SYNC_PARA_HEADER => self.bin_sync_para_header(load_scale(input)?),
             BIN_ACTION_SYNC_COMBINED_HEADERS => self.bin_sync_combined_headers(load_scale(input)?),
@@ -86,3 +624,64 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         serde_json::to_vec(&output_json).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::tests::{TestNode, TestPlatform};
+    use phactory_api::actions::{ACTION_EXPORT_PENDING_EGRESS, ACTION_IMPORT_PENDING_EGRESS};
+
+    fn phactory_of(node: TestNode) -> (Phactory<TestPlatform>, tempfile::TempDir) {
+        let TestNode {
+            system,
+            _sealing_dir,
+            ..
+        } = node;
+        let mut phactory = Phactory::new(TestPlatform);
+        phactory.system = Some(system);
+        (phactory, _sealing_dir)
+    }
+
+    #[test]
+    fn pending_egress_is_handed_over() {
+        let node = TestNode::new(1);
+        for payload in [b"a", b"b", b"c"] {
+            node.system
+                .egress
+                .push_data(payload.to_vec(), b"phala.network/test".to_vec());
+        }
+        let (mut source, _source_dir) = phactory_of(node);
+        let exported = source
+            .try_handle_scale_api(ACTION_EXPORT_PENDING_EGRESS, b"")
+            .unwrap();
+        assert_eq!(exported["count"], 3);
+
+        let (mut target, _target_dir) = phactory_of(TestNode::new(1));
+        let input = json!({ "input": { "messages": exported["messages"] } });
+        let imported = target
+            .try_handle_scale_api(ACTION_IMPORT_PENDING_EGRESS, input.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(imported["imported"], 3);
+        let target_system = target.system.as_ref().unwrap();
+        assert_eq!(
+            target_system.export_pending_egress(),
+            source.system.as_ref().unwrap().export_pending_egress()
+        );
+
+        // The new messages of the target continue the imported sequences.
+        target_system
+            .egress
+            .push_data(b"d".to_vec(), b"phala.network/test".to_vec());
+        let sequences: Vec<_> = target_system
+            .export_pending_egress()
+            .iter()
+            .map(|msg| msg.sequence)
+            .collect();
+        assert_eq!(sequences, [0, 1, 2, 3]);
+
+        let input = json!({ "input": { "messages": "0x01" } });
+        assert!(target
+            .try_handle_scale_api(ACTION_IMPORT_PENDING_EGRESS, input.to_string().as_bytes())
+            .is_err());
+    }
+}
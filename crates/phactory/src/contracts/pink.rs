@@ -343,6 +343,7 @@ pub mod cluster {
         types::{AccountId, Balance, Hash},
         weights::Weight,
     };
+    use runtime::pallet_fat::ContractRegistryEvent;
     use serde::{Deserialize, Serialize};
     use sp_core::sr25519;
     use sp_runtime::{AccountId32, DispatchError};
@@ -369,6 +370,10 @@ pub mod cluster {
             Some(&mut self.clusters.get_mut(cluster_id)?.storage)
         }
 
+        pub fn get_cluster(&self, cluster_id: &ContractClusterId) -> Option<&Cluster> {
+            self.clusters.get(cluster_id)
+        }
+
         pub fn get_cluster_mut(&mut self, cluster_id: &ContractClusterId) -> Option<&mut Cluster> {
             self.clusters.get_mut(cluster_id)
         }
@@ -384,6 +389,7 @@ pub mod cluster {
                     contracts: Default::default(),
                     key: cluster_key.clone(),
                     config: Default::default(),
+                    registry_events: Default::default(),
                 };
                 let seed_key = cluster_key
                     .derive_sr25519_pair(&[b"ink key derivation seed"])
@@ -401,6 +407,10 @@ pub mod cluster {
         pub fn iter(&self) -> impl Iterator<Item = (&ContractClusterId, &Cluster)> {
             self.clusters.iter()
         }
+
+        pub fn iter_mut(&mut self) -> impl Iterator<Item = (&ContractClusterId, &mut Cluster)> {
+            self.clusters.iter_mut()
+        }
     }
 
     #[derive(Serialize, Deserialize, Default)]
@@ -408,6 +418,9 @@ pub mod cluster {
         pub log_handler: Option<ContractId>,
         // Version used to control the contract API availability.
         pub version: (u16, u16),
+        /// Max number of query replies cached per block, 0 to disable the query cache.
+        #[serde(default)]
+        pub query_cache_capacity: usize,
     }
 
     #[derive(Serialize, Deserialize)]
@@ -417,6 +430,9 @@ pub mod cluster {
         #[serde(with = "more::key_bytes")]
         key: sr25519::Pair,
         pub config: ClusterConfig,
+        /// The registry events emitted in the block being processed, sent at the end of the block.
+        #[serde(skip)]
+        registry_events: Vec<ContractRegistryEvent>,
     }
 
     impl Cluster {
@@ -429,6 +445,23 @@ pub mod cluster {
             &self.key
         }
 
+        pub fn push_registry_event(&mut self, event: ContractRegistryEvent) {
+            self.registry_events.push(event);
+        }
+
+        pub fn take_registry_events(&mut self) -> Vec<ContractRegistryEvent> {
+            std::mem::take(&mut self.registry_events)
+        }
+
+        pub fn storage_usage(&self) -> u64 {
+            self.storage.size() as u64
+        }
+
+        pub fn contract_storage_size(&self, contract_id: &ContractId) -> Option<u64> {
+            self.storage
+                .contract_storage_size(&AccountId32::new(contract_id.0))
+        }
+
         pub fn system_contract(&self) -> Option<AccountId32> {
             self.storage.system_contract()
         }
@@ -531,6 +564,40 @@ pub mod cluster {
             Ok(())
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn keeper_with_cluster() -> (ClusterKeeper, ContractClusterId) {
+            let mut keeper = ClusterKeeper::default();
+            let cluster_id = ContractClusterId::repeat_byte(1);
+            let key = sr25519::Pair::from_seed(&[1; 32]);
+            keeper.get_cluster_or_default_mut(&cluster_id, &key);
+            (keeper, cluster_id)
+        }
+
+        fn upload_code(keeper: &mut ClusterKeeper, cluster_id: &ContractClusterId, size: usize) {
+            keeper
+                .get_cluster_mut(cluster_id)
+                .unwrap()
+                .upload_resource(
+                    &AccountId32::new([2; 32]),
+                    ResourceType::SidevmCode,
+                    vec![size as u8; size],
+                )
+                .unwrap();
+        }
+
+        #[test]
+        fn storage_usage_counts_the_writes() {
+            let (mut keeper, cluster_id) = keeper_with_cluster();
+            let usage = keeper.get_cluster_mut(&cluster_id).unwrap().storage_usage();
+            upload_code(&mut keeper, &cluster_id, 100);
+            let cluster = keeper.get_cluster_mut(&cluster_id).unwrap();
+            assert!(cluster.storage_usage() >= usage + 100);
+        }
+    }
 }
 
 pub(crate) struct ContractEventCallback {
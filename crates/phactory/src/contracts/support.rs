@@ -236,7 +236,7 @@ impl FatContract {
 
     pub(crate) fn start_sidevm(
         &mut self,
-        spawner: &sidevm::service::Spawner,
+        spawner: Option<&sidevm::service::Spawner>,
         code: SidevmCode,
         ensure_waiting_code: bool,
     ) -> Result<()> {
@@ -276,7 +276,13 @@ impl FatContract {
                 ExitReason::WaitingForCode,
             )))
         } else {
-            do_start_sidevm(spawner, &code, self.contract_id.0, self.weight)?
+            do_start_sidevm(
+                spawner,
+                &code,
+                self.contract_id.0,
+                self.cluster_id,
+                self.weight,
+            )?
         };
 
         let start_time = chrono::Utc::now().to_rfc3339();
@@ -292,7 +298,7 @@ impl FatContract {
 
     pub(crate) fn restart_sidevm_if_needed(
         &mut self,
-        spawner: &sidevm::service::Spawner,
+        spawner: Option<&sidevm::service::Spawner>,
     ) -> Result<()> {
         if let Some(sidevm_info) = &mut self.sidevm_info {
             let guard = sidevm_info.handle.lock().unwrap();
@@ -314,7 +320,13 @@ impl FatContract {
                     return Ok(());
                 }
                 sidevm_info.start_time = chrono::Utc::now().to_rfc3339();
-                do_start_sidevm(spawner, &sidevm_info.code, self.contract_id.0, self.weight)?
+                do_start_sidevm(
+                    spawner,
+                    &sidevm_info.code,
+                    self.contract_id.0,
+                    self.cluster_id,
+                    self.weight,
+                )?
             } else {
                 return Ok(());
             };
@@ -366,7 +378,16 @@ impl FatContract {
         }
     }
 
-    pub(crate) fn destroy(self, spawner: &sidevm::service::Spawner) {
+    /// The id of the storage in the local cache for this contract.
+    pub(crate) fn local_cache_id(&self) -> Vec<u8> {
+        ::pink::local_cache::cache_id(self.cluster_id.as_ref(), self.contract_id.as_ref())
+    }
+
+    pub(crate) fn destroy(self, spawner: Option<&sidevm::service::Spawner>) {
+        // No sidevm can be running without the sidevm service.
+        let Some(spawner) = spawner else {
+            return;
+        };
         if let Some(sidevm_info) = &self.sidevm_info {
             match sidevm_info.handle.lock().unwrap().clone() {
                 SidevmHandle::Stopped(_) => {}
@@ -399,35 +420,41 @@ impl FatContract {
             id: hex(self.contract_id),
             weight: self.weight,
             code_hash: self.code_hash.as_ref().map(hex).unwrap_or_default(),
-            sidevm: self.sidevm_info.as_ref().map(|info| {
-                let handle = info.handle.lock().unwrap().clone();
-                let start_time = info.start_time.clone();
-                let code_hash = hex(info.code_hash);
-                match handle {
-                    SidevmHandle::Running(_) => pb::SidevmInfo {
-                        state: "running".into(),
-                        code_hash,
-                        start_time,
-                        ..Default::default()
-                    },
-                    SidevmHandle::Stopped(reason) => pb::SidevmInfo {
-                        state: "stopped".into(),
-                        code_hash,
-                        start_time,
-                        stop_reason: format!("{reason}"),
-                    },
-                }
-            }),
+            sidevm: self.sidevm_info(),
         }
     }
+
+    pub fn sidevm_info(&self) -> Option<pb::SidevmInfo> {
+        self.sidevm_info.as_ref().map(|info| {
+            let handle = info.handle.lock().unwrap().clone();
+            let start_time = info.start_time.clone();
+            let code_hash = hex(info.code_hash);
+            match handle {
+                SidevmHandle::Running(_) => pb::SidevmInfo {
+                    state: "running".into(),
+                    code_hash,
+                    start_time,
+                    ..Default::default()
+                },
+                SidevmHandle::Stopped(reason) => pb::SidevmInfo {
+                    state: "stopped".into(),
+                    code_hash,
+                    start_time,
+                    stop_reason: format!("{reason}"),
+                },
+            }
+        })
+    }
 }
 
 fn do_start_sidevm(
-    spawner: &sidevm::service::Spawner,
+    spawner: Option<&sidevm::service::Spawner>,
     code: &[u8],
     id: VmId,
+    cluster_id: phala_mq::ContractClusterId,
     weight: u32,
 ) -> Result<Arc<Mutex<SidevmHandle>>> {
+    let spawner = spawner.ok_or_else(|| anyhow!("Sidevm is disabled"))?;
     let max_memory_pages: u32 = 1024; // 64MB
     let gas_per_breath = 50_000_000_000_u64; // about 20 ms bench
     let (sender, join_handle) = spawner.start(
@@ -435,7 +462,7 @@ fn do_start_sidevm(
         max_memory_pages,
         id,
         gas_per_breath,
-        local_cache_ops(),
+        local_cache_ops(cluster_id),
         weight,
     )?;
     let handle = Arc::new(Mutex::new(SidevmHandle::Running(sender)));
@@ -452,18 +479,27 @@ fn do_start_sidevm(
     Ok(handle)
 }
 
-fn local_cache_ops() -> sidevm::DynCacheOps {
+fn local_cache_ops(cluster_id: phala_mq::ContractClusterId) -> sidevm::DynCacheOps {
     use ::pink::local_cache as cache;
     type OpResult<T> = Result<T, sidevm::OcallError>;
 
-    struct CacheOps;
+    struct CacheOps {
+        cluster_id: phala_mq::ContractClusterId,
+    }
+
+    impl CacheOps {
+        fn cache_id(&self, contract: &[u8]) -> Vec<u8> {
+            cache::cache_id(self.cluster_id.as_ref(), contract)
+        }
+    }
+
     impl sidevm::CacheOps for CacheOps {
         fn get(&self, contract: &[u8], key: &[u8]) -> OpResult<Option<Vec<u8>>> {
-            Ok(cache::get(contract, key))
+            Ok(cache::get(&self.cache_id(contract), key))
         }
 
         fn set(&self, contract: &[u8], key: &[u8], value: &[u8]) -> OpResult<()> {
-            cache::set(contract, key, value)
+            cache::set(&self.cache_id(contract), key, value)
                 .map_err(|_| sidevm::OcallError::ResourceLimited)
         }
 
@@ -473,15 +509,15 @@ fn local_cache_ops() -> sidevm::DynCacheOps {
             key: &[u8],
             expire_after_secs: u64,
         ) -> OpResult<()> {
-            cache::set_expiration(contract, key, expire_after_secs);
+            cache::set_expiration(&self.cache_id(contract), key, expire_after_secs);
             Ok(())
         }
 
         fn remove(&self, contract: &[u8], key: &[u8]) -> OpResult<Option<Vec<u8>>> {
-            Ok(cache::remove(contract, key))
+            Ok(cache::remove(&self.cache_id(contract), key))
         }
     }
-    &CacheOps
+    Arc::new(CacheOps { cluster_id })
 }
 
 pub use keeper::*;
@@ -9,7 +9,7 @@ use crate::{
     types::{deopaque_query, OpaqueError, OpaqueQuery, OpaqueReply},
 };
 use parity_scale_codec::{Decode, Encode};
-use phala_mq::{ContractId, MessageOrigin};
+use phala_mq::{ContractClusterId, ContractId, MessageOrigin};
 
 use super::QueryContext;
 
@@ -116,7 +116,12 @@ impl ContractsKeeper {
         self.contracts.len()
     }
 
-    pub fn try_restart_sidevms(&mut self, spawner: &Spawner) {
+    /// The cluster the given contract is deployed in, if known to this worker.
+    pub fn cluster_of(&self, id: &ContractId) -> Option<ContractClusterId> {
+        self.contracts.get(id).map(|contract| contract.cluster_id())
+    }
+
+    pub fn try_restart_sidevms(&mut self, spawner: Option<&Spawner>) {
         for contract in self.contracts.values_mut() {
             if let Err(err) = contract.restart_sidevm_if_needed(spawner) {
                 error!("Failed to restart sidevm instance: {:?}", err);
@@ -133,7 +138,12 @@ impl ContractsKeeper {
     }
 
     pub fn apply_local_cache_quotas(&self) {
-        ::pink::local_cache::apply_quotas(calc_cache_quotas(&self.contracts));
+        let contracts: BTreeMap<_, _> = self
+            .contracts
+            .values()
+            .map(|contract| (contract.local_cache_id(), contract))
+            .collect();
+        ::pink::local_cache::apply_quotas(calc_cache_quotas(&contracts));
     }
 }
 
@@ -148,6 +158,12 @@ impl ToWeight for FatContract {
     }
 }
 
+impl<T: ToWeight> ToWeight for &T {
+    fn to_weight(&self) -> u32 {
+        (*self).to_weight()
+    }
+}
+
 pub(super) fn calc_cache_quotas<K: AsRef<[u8]> + Ord, C: ToWeight>(
     contracts: &BTreeMap<K, C>,
 ) -> impl Iterator<Item = (&[u8], usize)> {
@@ -162,6 +178,35 @@ pub(super) fn calc_cache_quotas<K: AsRef<[u8]> + Ord, C: ToWeight>(
     })
 }
 
+/// Creates a bare contract without any sidevm attached.
+#[cfg(test)]
+pub(crate) fn new_test_contract(seed: u8, cluster_id: ContractClusterId) -> FatContract {
+    use phala_crypto::sr25519::KDF;
+    use sp_core::Pair;
+
+    let key = sp_core::sr25519::Pair::from_seed(&[seed; 32]);
+    let ecdh_key = key.derive_ecdh_key().unwrap();
+    let pink = Pink::from_address(sp_core::crypto::AccountId32::new([seed; 32]), cluster_id);
+    let contract_id = pink.id();
+    let send_mq =
+        phala_mq::MessageSendQueue::new().channel(MessageOrigin::Contract(contract_id), key.into());
+    let cmd_mq = crate::secret_channel::SecretReceiver::new_secret(
+        phala_mq::MessageDispatcher::new()
+            .subscribe(phala_types::contract::command_topic(contract_id))
+            .into(),
+        ecdh_key.clone(),
+    );
+    FatContract::new(
+        pink,
+        send_mq,
+        cmd_mq,
+        ecdh_key,
+        cluster_id,
+        contract_id,
+        None,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,4 +281,16 @@ mod tests {
         v.sort();
         v
     }
+
+    #[test]
+    fn cluster_of_contract_works() {
+        let cluster_id = ContractClusterId::repeat_byte(1);
+        let contract = new_test_contract(2, cluster_id);
+        let contract_id = contract.id();
+        let mut contracts = ContractsKeeper::default();
+        contracts.insert(contract);
+
+        assert_eq!(contracts.cluster_of(&contract_id), Some(cluster_id));
+        assert_eq!(contracts.cluster_of(&ContractId::repeat_byte(3)), None);
+    }
 }
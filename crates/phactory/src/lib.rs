@@ -63,6 +63,7 @@ mod bin_api_service;
 mod contracts;
 mod cryptography;
 mod light_validation;
+mod metrics;
 mod prpc_service;
 mod secret_channel;
 mod storage;
@@ -102,6 +103,77 @@ impl RuntimeState {
 const RUNTIME_SEALED_DATA_FILE: &str = "runtime-data.seal";
 const CHECKPOINT_FILE: &str = "checkpoint.seal";
 const CHECKPOINT_VERSION: u32 = 2;
+/// Blocks a checkpoint can be deferred under memory pressure before it is taken anyway.
+const MAX_CHECKPOINT_DEFERRAL_BLOCKS: chain::BlockNumber = 600;
+
+/// Memory usage of taking the checkpoints.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointStats {
+    /// The block of the last checkpoint taken.
+    pub last_block: Option<chain::BlockNumber>,
+    /// Rust heap in use when the last checkpoint started, in bytes.
+    pub used_before: usize,
+    /// Peak Rust heap in use while taking the last checkpoint, in bytes.
+    ///
+    /// The allocator only tracks the peak of the whole process, so this is the larger usage
+    /// before and after the checkpoint if it did not raise the process peak.
+    pub peak_used: usize,
+    /// Number of checkpoints deferred under memory pressure since the last one taken.
+    pub deferred: u64,
+    /// The block at which the pending checkpoint was first deferred.
+    pub deferred_since: Option<chain::BlockNumber>,
+}
+
+impl CheckpointStats {
+    /// Whether to take a checkpoint at `block_number` with `used` bytes of Rust heap in use,
+    /// counting a deferral if it is above `max_memory`.
+    ///
+    /// A checkpoint is never deferred for more than `MAX_CHECKPOINT_DEFERRAL_BLOCKS` blocks.
+    fn admit(
+        &mut self,
+        block_number: chain::BlockNumber,
+        used: usize,
+        max_memory: Option<usize>,
+    ) -> bool {
+        let max_memory = match max_memory {
+            Some(max_memory) if used > max_memory => max_memory,
+            _ => return true,
+        };
+        let since = *self.deferred_since.get_or_insert(block_number);
+        if block_number.saturating_sub(since) >= MAX_CHECKPOINT_DEFERRAL_BLOCKS {
+            warn!(
+                "Checkpoint deferred since block {since}, taking it anyway with {used} bytes in use, limit {max_memory}"
+            );
+            return true;
+        }
+        self.deferred += 1;
+        // Retried on every sync, so only warn on the 1st, 2nd, 4th, 8th... deferral.
+        if self.deferred.is_power_of_two() {
+            warn!(
+                "Checkpoint deferred under memory pressure, {used} bytes in use, limit {max_memory}, {} deferred",
+                self.deferred
+            );
+        }
+        false
+    }
+
+    fn record(
+        &mut self,
+        block_number: chain::BlockNumber,
+        before: &pal::MemoryUsage,
+        after: &pal::MemoryUsage,
+    ) {
+        self.last_block = Some(block_number);
+        self.used_before = before.rust_used;
+        self.peak_used = if after.rust_peak_used > before.rust_peak_used {
+            after.rust_peak_used
+        } else {
+            before.rust_used.max(after.rust_used)
+        };
+        self.deferred = 0;
+        self.deferred_since = None;
+    }
+}
 
 fn checkpoint_filename_for(block_number: chain::BlockNumber, basedir: &str) -> String {
     format!("{basedir}/{CHECKPOINT_FILE}-{block_number:0>9}")
@@ -171,6 +243,44 @@ fn remove_outdated_checkpoints(
     Ok(())
 }
 
+/// Move all checkpoint files from `from` to `to`.
+///
+/// The files are copied first and the originals are only removed once every copy succeeded, so
+/// an interrupted relocation never loses a checkpoint. Relocating a directory onto itself is
+/// refused, since the copies would truncate the files before the originals get removed.
+fn relocate_checkpoints(from: &str, to: &str) -> Result<()> {
+    std::fs::create_dir_all(to).context("Failed to create the new storage directory")?;
+    if let Ok(from_dir) = std::fs::canonicalize(from) {
+        let to_dir = std::fs::canonicalize(to).context("Failed to resolve the new storage path")?;
+        if from_dir == to_dir {
+            anyhow::bail!("The new storage path is the current one");
+        }
+    }
+    let probe = PathBuf::from(to).join(".write-probe");
+    File::create(&probe).context("The new storage directory is not writable")?;
+    std::fs::remove_file(&probe).context("Failed to remove the write probe")?;
+
+    let files = glob_checkpoint_files_sorted(from).context("Glob checkpoint files failed")?;
+    let mut copied = vec![];
+    for (_block, src) in files.iter() {
+        let filename = src.file_name().context("Invalid checkpoint filename")?;
+        let dst = PathBuf::from(to).join(filename);
+        if let Err(err) = std::fs::copy(src, &dst) {
+            for dst in copied {
+                let _ = std::fs::remove_file(dst);
+            }
+            return Err(err).context(format!("Failed to copy {}", src.display()));
+        }
+        copied.push(dst);
+    }
+    for (_block, src) in files {
+        if let Err(e) = std::fs::remove_file(&src) {
+            error!("Failed to remove {}: {}", src.display(), e);
+        }
+    }
+    Ok(())
+}
+
 #[derive(Encode, Decode, Clone, Debug)]
 struct PersistentRuntimeData {
     genesis_block_hash: H256,
@@ -226,6 +336,8 @@ pub struct Phactory<Platform> {
     #[serde(default = "Instant::now")]
     last_checkpoint: Instant,
     #[serde(skip)]
+    checkpoint_stats: CheckpointStats,
+    #[serde(skip)]
     #[serde(default = "default_query_scheduler")]
     query_scheduler: RequestScheduler<ContractId>,
 
@@ -259,6 +371,7 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             signed_endpoints: None,
             handover_ecdh_key: None,
             last_checkpoint: Instant::now(),
+            checkpoint_stats: Default::default(),
             query_scheduler: default_query_scheduler(),
             netconfig: Default::default(),
             can_load_chain_state: false,
@@ -287,9 +400,35 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         if let Some(system) = &mut self.system {
             system.sealing_path = self.args.sealing_path.clone();
             system.storage_path = self.args.storage_path.clone();
+            if !self.args.role_tags.is_empty() {
+                system.set_role_tags(self.args.role_tags.clone());
+            }
+            if self.args.start_paused {
+                system.set_paused(true);
+            }
+            if self.args.simulation {
+                system.set_simulation(true);
+            }
+            system.set_slow_contract_threshold(self.args.slow_contract_threshold());
+            system.set_max_bench_iterations(self.args.max_bench_iterations());
+            system.set_poisoned_state_policy(self.args.poisoned_state_policy);
         }
     }
 
+    /// Move the checkpoints to `storage_path` and take new checkpoints there from now on.
+    pub fn relocate_storage(&mut self, storage_path: String) -> Result<()> {
+        info!(
+            "Relocating storage from {} to {}",
+            self.args.storage_path, storage_path
+        );
+        relocate_checkpoints(&self.args.storage_path, &storage_path)?;
+        if let Some(system) = &mut self.system {
+            system.storage_path = storage_path.clone();
+        }
+        self.args.storage_path = storage_path;
+        Ok(())
+    }
+
     fn init_runtime_data(
         &self,
         genesis_block_hash: H256,
@@ -470,10 +609,14 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         info!("Taking checkpoint...");
         let checkpoint_file = checkpoint_filename_for(current_block, &self.args.storage_path);
         let file = File::create(&checkpoint_file).context("Failed to create checkpoint file")?;
+        let memory_before = self.platform.memory_usage();
         self.take_checkpoint_to_writer(&key, file)
             .context("Take checkpoint to writer failed")?;
+        let memory_after = self.platform.memory_usage();
         info!("Checkpoint saved to {}", checkpoint_file);
         self.last_checkpoint = Instant::now();
+        self.checkpoint_stats
+            .record(current_block, &memory_before, &memory_after);
         remove_outdated_checkpoints(
             &self.args.storage_path,
             self.args.max_checkpoint_files,
@@ -694,3 +837,109 @@ fn derive_key_for_checkpoint(identity_key: &[u8]) -> [u8; 16] {
 fn hex(data: impl AsRef<[u8]>) -> String {
     format!("0x{}", hex_fmt::HexFmt(data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_is_deferred_under_memory_pressure() {
+        let usage = |rust_used, rust_peak_used| pal::MemoryUsage {
+            total_peak_used: 0,
+            rust_used,
+            rust_peak_used,
+        };
+        let mut stats = CheckpointStats::default();
+        assert!(stats.admit(1, usize::MAX, None));
+        assert!(!stats.admit(2, 200, Some(100)));
+        assert!(!stats.admit(3, 150, Some(100)));
+        assert_eq!(stats.deferred, 2);
+        assert_eq!(stats.deferred_since, Some(2));
+        assert_eq!(stats.last_block, None);
+
+        assert!(stats.admit(10, 100, Some(100)));
+        stats.record(10, &usage(100, 300), &usage(120, 500));
+        assert_eq!(
+            stats,
+            CheckpointStats {
+                last_block: Some(10),
+                used_before: 100,
+                peak_used: 500,
+                deferred: 0,
+                deferred_since: None,
+            }
+        );
+        // The process peak was not raised by the checkpoint
+        stats.record(20, &usage(100, 500), &usage(120, 500));
+        assert_eq!(stats.peak_used, 120);
+    }
+
+    #[test]
+    fn checkpoint_deferral_is_bounded() {
+        let mut stats = CheckpointStats::default();
+        assert!(!stats.admit(100, 200, Some(100)));
+        assert!(!stats.admit(100 + MAX_CHECKPOINT_DEFERRAL_BLOCKS - 1, 200, Some(100)));
+        // Still under pressure, but deferred for too long
+        assert!(stats.admit(100 + MAX_CHECKPOINT_DEFERRAL_BLOCKS, 200, Some(100)));
+        assert_eq!(stats.deferred, 2);
+
+        let usage = pal::MemoryUsage {
+            total_peak_used: 0,
+            rust_used: 200,
+            rust_peak_used: 200,
+        };
+        stats.record(100 + MAX_CHECKPOINT_DEFERRAL_BLOCKS, &usage, &usage);
+        assert_eq!(stats.deferred_since, None);
+        assert!(!stats.admit(101 + MAX_CHECKPOINT_DEFERRAL_BLOCKS, 200, Some(100)));
+    }
+
+    #[test]
+    fn relocate_checkpoints_moves_all_files() {
+        let from = tempfile::tempdir().unwrap();
+        let to = tempfile::tempdir().unwrap();
+        let from_path = from.path().to_str().unwrap();
+        let to_path = to.path().join("nested").to_str().unwrap().to_string();
+
+        for block in [10, 20, 30] {
+            std::fs::write(checkpoint_filename_for(block, from_path), [block as u8]).unwrap();
+        }
+        relocate_checkpoints(from_path, &to_path).unwrap();
+
+        assert!(glob_checkpoint_files_sorted(from_path).unwrap().is_empty());
+        let moved = glob_checkpoint_files_sorted(&to_path).unwrap();
+        let blocks: Vec<_> = moved.iter().map(|(block, _)| *block).collect();
+        assert_eq!(blocks, vec![30, 20, 10]);
+        for (block, path) in moved {
+            assert_eq!(std::fs::read(path).unwrap(), vec![block as u8]);
+        }
+    }
+
+    #[test]
+    fn relocate_checkpoints_rejects_unwritable_target() {
+        let from = tempfile::tempdir().unwrap();
+        let from_path = from.path().to_str().unwrap();
+        std::fs::write(checkpoint_filename_for(1, from_path), b"ckpt").unwrap();
+
+        // A regular file can not be used as the storage directory.
+        let target = from.path().join("not-a-dir");
+        std::fs::write(&target, b"").unwrap();
+        assert!(relocate_checkpoints(from_path, target.to_str().unwrap()).is_err());
+        assert_eq!(glob_checkpoint_files_sorted(from_path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn relocate_checkpoints_rejects_the_same_directory() {
+        let from = tempfile::tempdir().unwrap();
+        let from_path = from.path().to_str().unwrap();
+        std::fs::write(checkpoint_filename_for(1, from_path), b"ckpt").unwrap();
+
+        let same = from.path().join("nested").join("..");
+        std::fs::create_dir(from.path().join("nested")).unwrap();
+        for to in [from_path, same.to_str().unwrap()] {
+            assert!(relocate_checkpoints(from_path, to).is_err());
+        }
+        let files = glob_checkpoint_files_sorted(from_path).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(std::fs::read(&files[0].1).unwrap(), b"ckpt");
+    }
+}
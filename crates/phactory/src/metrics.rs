@@ -0,0 +1,450 @@
+//! Exports the worker counters in the Prometheus text exposition format.
+//!
+//! See https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::fmt::Write as _;
+
+use phactory_api::prpc as pb;
+use phala_types::WorkerPublicKey;
+
+use crate::bin_api_service::TOP_SLOW_CONTRACTS;
+use crate::system::{
+    gk::HeartbeatAnomalies, ClusterStorageUsage, Histogram, KeyOperationCounter, ProcessingTimes,
+};
+
+use super::*;
+
+#[derive(Debug, Clone, Copy)]
+enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+struct Sample {
+    /// Appended to the metric name, for the series of a histogram.
+    suffix: &'static str,
+    labels: Vec<(&'static str, String)>,
+    value: u64,
+}
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    metric_type: MetricType,
+    samples: Vec<Sample>,
+}
+
+impl Metric {
+    fn new(name: &'static str, metric_type: MetricType, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            metric_type,
+            samples: vec![],
+        }
+    }
+
+    fn counter(name: &'static str, help: &'static str) -> Self {
+        Self::new(name, MetricType::Counter, help)
+    }
+
+    fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self::new(name, MetricType::Gauge, help)
+    }
+
+    fn histogram(name: &'static str, help: &'static str) -> Self {
+        Self::new(name, MetricType::Histogram, help)
+    }
+
+    fn value(self, value: impl Into<u64>) -> Self {
+        self.sample(vec![], value)
+    }
+
+    fn sample(self, labels: Vec<(&'static str, String)>, value: impl Into<u64>) -> Self {
+        self.suffixed_sample("", labels, value)
+    }
+
+    fn suffixed_sample(
+        mut self,
+        suffix: &'static str,
+        labels: Vec<(&'static str, String)>,
+        value: impl Into<u64>,
+    ) -> Self {
+        self.samples.push(Sample {
+            suffix,
+            labels,
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Add the bucket, sum and count series of `histogram`.
+    fn observations(mut self, labels: Vec<(&'static str, String)>, histogram: &Histogram) -> Self {
+        let with_le = |le: String| {
+            let mut labels = labels.clone();
+            labels.push(("le", le));
+            labels
+        };
+        for (bound, count) in &histogram.buckets {
+            self = self.suffixed_sample("_bucket", with_le(bound.to_string()), *count);
+        }
+        self.suffixed_sample("_bucket", with_le("+Inf".into()), histogram.count)
+            .suffixed_sample("_sum", labels.clone(), histogram.sum_us)
+            .suffixed_sample("_count", labels, histogram.count)
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn encode(metrics: &[Metric]) -> String {
+    let mut out = String::new();
+    for metric in metrics {
+        if metric.samples.is_empty() {
+            continue;
+        }
+        _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        _ = writeln!(
+            out,
+            "# TYPE {} {}",
+            metric.name,
+            metric.metric_type.as_str()
+        );
+        for sample in &metric.samples {
+            out.push_str(metric.name);
+            out.push_str(sample.suffix);
+            if !sample.labels.is_empty() {
+                let labels: Vec<_> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(value)))
+                    .collect();
+                _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            _ = writeln!(out, " {}", sample.value);
+        }
+    }
+    out
+}
+
+fn collect(
+    info: &pb::PhactoryInfo,
+    key_operations: Option<KeyOperationCounter>,
+    master_pubkey_mismatch_blocks: Option<chain::BlockNumber>,
+    cluster_storage: &[ClusterStorageUsage],
+    processing_times: Option<&ProcessingTimes>,
+    heartbeat_anomalies: &[(WorkerPublicKey, HeartbeatAnomalies)],
+) -> Vec<Metric> {
+    let kind = |kind: &str| vec![("kind", kind.to_string())];
+    let cluster = |usage: &ClusterStorageUsage| {
+        vec![("cluster", format!("0x{}", hex::encode(usage.cluster_id)))]
+    };
+
+    let mut metrics = vec![
+        Metric::gauge(
+            "phactory_initialized",
+            "Whether the runtime is initialized.",
+        )
+        .value(info.initialized),
+        Metric::gauge(
+            "phactory_registered",
+            "Whether the worker is registered on chain.",
+        )
+        .value(info.registered),
+        Metric::gauge(
+            "phactory_next_header_number",
+            "The next relay chain header number to sync.",
+        )
+        .value(info.headernum),
+        Metric::gauge(
+            "phactory_next_para_header_number",
+            "The next parachain header number to sync.",
+        )
+        .value(info.para_headernum),
+        Metric::gauge(
+            "phactory_next_block_number",
+            "The next block number to dispatch.",
+        )
+        .value(info.blocknum),
+        Metric::gauge(
+            "phactory_pending_messages",
+            "Egress messages waiting to be sent.",
+        )
+        .value(info.pending_messages),
+        Metric::gauge("phactory_benchmark_score", "The benchmark score.").value(info.score),
+    ];
+
+    let mut memory = Metric::gauge("phactory_memory_bytes", "Memory usage of the runtime.");
+    if let Some(usage) = &info.memory_usage {
+        memory = memory
+            .sample(kind("rust_used"), usage.rust_used)
+            .sample(kind("rust_peak_used"), usage.rust_peak_used)
+            .sample(kind("total_peak_used"), usage.total_peak_used);
+    }
+    metrics.push(memory);
+
+    let mut clusters = Metric::gauge("phactory_clusters", "Contract clusters on the worker.");
+    let mut contracts = Metric::gauge("phactory_contracts", "Contracts on the worker.");
+    if let Some(system) = &info.system {
+        clusters = clusters.value(system.number_of_clusters);
+        contracts = contracts.value(system.number_of_contracts);
+    }
+    metrics.push(clusters);
+    metrics.push(contracts);
+
+    let mut key_ops = Metric::counter(
+        "phactory_key_operations_total",
+        "Key operations handled by the worker.",
+    );
+    if let Some(counter) = key_operations {
+        key_ops = key_ops
+            .sample(kind("master_key"), counter.master_key)
+            .sample(kind("cluster_key"), counter.cluster_key)
+            .sample(kind("contract_key"), counter.contract_key);
+    }
+    metrics.push(key_ops);
+
+    let mut master_pubkey_mismatch = Metric::gauge(
+        "phactory_master_pubkey_mismatch_blocks",
+        "Blocks since the local master key started to differ from the on-chain master pubkey.",
+    );
+    if let Some(blocks) = master_pubkey_mismatch_blocks {
+        master_pubkey_mismatch = master_pubkey_mismatch.value(blocks);
+    }
+    metrics.push(master_pubkey_mismatch);
+
+    let mut storage_usage = Metric::gauge(
+        "phactory_cluster_storage_bytes",
+        "Storage usage of a cluster.",
+    );
+    for usage in cluster_storage {
+        storage_usage = storage_usage.sample(cluster(usage), usage.usage);
+    }
+    metrics.push(storage_usage);
+
+    let mut block_times = Metric::histogram(
+        "phactory_block_processing_microseconds",
+        "Time spent on processing a block.",
+    );
+    let mut phase_times = Metric::histogram(
+        "phactory_block_phase_processing_microseconds",
+        "Time spent on a phase of processing a block.",
+    );
+    let mut slow_blocks = Metric::counter(
+        "phactory_slow_contract_blocks_total",
+        "Blocks a contract was slow to process, for the slowest contracts.",
+    );
+    let mut slow_max = Metric::gauge(
+        "phactory_slow_contract_max_microseconds",
+        "The longest time a contract took to process a block, for the slowest contracts.",
+    );
+    if let Some(times) = processing_times {
+        block_times = block_times.observations(vec![], times.blocks());
+        for (phase, histogram) in times.phases() {
+            phase_times =
+                phase_times.observations(vec![("phase", phase.as_str().to_string())], histogram);
+        }
+        for (contract_id, slow) in times.top_slow_contracts(TOP_SLOW_CONTRACTS) {
+            let contract = || vec![("contract", format!("0x{}", hex::encode(contract_id)))];
+            slow_blocks = slow_blocks.sample(contract(), slow.slow_blocks);
+            slow_max = slow_max.sample(contract(), slow.max_us);
+        }
+    }
+    metrics.push(block_times);
+    metrics.push(phase_times);
+    metrics.push(slow_blocks);
+    metrics.push(slow_max);
+
+    let mut gk_heartbeats = Metric::counter(
+        "phactory_gk_heartbeat_anomalies_total",
+        "Heartbeats the gatekeeper did not expect, summed over the workers.",
+    );
+    if !heartbeat_anomalies.is_empty() {
+        let (stale, unexpected) = heartbeat_anomalies.iter().fold(
+            (0_u64, 0_u64),
+            |(stale, unexpected), (_, anomalies)| {
+                (
+                    stale + anomalies.stale as u64,
+                    unexpected + anomalies.unexpected as u64,
+                )
+            },
+        );
+        gk_heartbeats = gk_heartbeats
+            .sample(kind("stale"), stale)
+            .sample(kind("unexpected"), unexpected);
+    }
+    metrics.push(gk_heartbeats);
+    metrics
+}
+
+impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform> {
+    /// The worker counters in the Prometheus text exposition format.
+    pub fn prometheus_metrics(&self) -> String {
+        let info = self.get_info();
+        let system = self.system.as_ref();
+        let key_operations = system.map(|system| system.key_operations());
+        let master_pubkey_mismatch_blocks =
+            system.and_then(|system| system.master_pubkey_mismatch_blocks());
+        let cluster_storage = system
+            .map(|system| system.cluster_storage_usage())
+            .unwrap_or_default();
+        let processing_times = system.map(|system| system.processing_times());
+        let heartbeat_anomalies = system
+            .and_then(|system| system.gatekeeper.as_ref())
+            .map(|gatekeeper| gatekeeper.heartbeat_anomalies())
+            .unwrap_or_default();
+        encode(&collect(
+            &info,
+            key_operations,
+            master_pubkey_mismatch_blocks,
+            &cluster_storage,
+            processing_times,
+            &heartbeat_anomalies,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::Phase;
+
+    #[test]
+    fn exposition_format() {
+        let info = pb::PhactoryInfo {
+            initialized: true,
+            registered: true,
+            headernum: 100,
+            para_headernum: 50,
+            blocknum: 40,
+            pending_messages: 2,
+            score: 1000,
+            memory_usage: Some(pb::MemoryUsage {
+                rust_used: 1024,
+                rust_peak_used: 2048,
+                total_peak_used: 4096,
+            }),
+            system: Some(pb::SystemInfo {
+                number_of_clusters: 1,
+                number_of_contracts: 3,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let key_operations = KeyOperationCounter {
+            master_key: 1,
+            cluster_key: 2,
+            contract_key: 3,
+        };
+        let cluster_storage = [
+            ClusterStorageUsage {
+                cluster_id: phala_mq::ContractClusterId::repeat_byte(0xab),
+                usage: 4096,
+            },
+            ClusterStorageUsage {
+                cluster_id: phala_mq::ContractClusterId::repeat_byte(0xcd),
+                usage: 512,
+            },
+        ];
+        insta::assert_display_snapshot!(encode(&collect(
+            &info,
+            Some(key_operations),
+            None,
+            &cluster_storage,
+            None,
+            &[],
+        )));
+    }
+
+    #[test]
+    fn metrics_without_samples_are_omitted() {
+        let info = pb::PhactoryInfo::default();
+        let exposition = encode(&collect(&info, None, None, &[], None, &[]));
+        assert!(exposition.contains("phactory_initialized 0\n"));
+        assert!(!exposition.contains("phactory_key_operations_total"));
+        assert!(!exposition.contains("phactory_master_pubkey_mismatch_blocks"));
+        assert!(!exposition.contains("phactory_cluster_storage_bytes"));
+    }
+
+    #[test]
+    fn master_pubkey_mismatch_is_exported() {
+        let info = pb::PhactoryInfo::default();
+        let exposition = encode(&collect(&info, None, Some(120), &[], None, &[]));
+        assert!(exposition.contains("phactory_master_pubkey_mismatch_blocks 120\n"));
+    }
+
+    #[test]
+    fn processing_times_are_exported() {
+        let mut times = ProcessingTimes::default();
+        times.set_slow_contract_threshold(Some(std::time::Duration::from_millis(1)));
+        times.record(Phase::Contracts, std::time::Duration::from_millis(5));
+        times.record_contract(
+            ContractId::repeat_byte(1),
+            std::time::Duration::from_millis(5),
+        );
+        times.finish_block(1);
+        let info = pb::PhactoryInfo::default();
+        let exposition = encode(&collect(&info, None, None, &[], Some(&times), &[]));
+        assert!(exposition.contains("# TYPE phactory_block_processing_microseconds histogram\n"));
+        assert!(
+            exposition.contains("phactory_block_processing_microseconds_bucket{le=\"1000\"} 0\n")
+        );
+        assert!(
+            exposition.contains("phactory_block_processing_microseconds_bucket{le=\"+Inf\"} 1\n")
+        );
+        assert!(exposition.contains("phactory_block_processing_microseconds_sum 5000\n"));
+        assert!(exposition.contains(
+            "phactory_block_phase_processing_microseconds_count{phase=\"contracts\"} 1\n"
+        ));
+        assert!(exposition.contains(&format!(
+            "phactory_slow_contract_blocks_total{{contract=\"0x{}\"}} 1\n",
+            hex::encode([1; 32])
+        )));
+    }
+
+    #[test]
+    fn heartbeat_anomalies_are_summed() {
+        let info = pb::PhactoryInfo::default();
+        let anomalies = [
+            (
+                WorkerPublicKey::from_raw([1; 32]),
+                HeartbeatAnomalies {
+                    stale: 1,
+                    unexpected: 2,
+                },
+            ),
+            (
+                WorkerPublicKey::from_raw([2; 32]),
+                HeartbeatAnomalies {
+                    stale: 3,
+                    unexpected: 0,
+                },
+            ),
+        ];
+        let exposition = encode(&collect(&info, None, None, &[], None, &anomalies));
+        assert!(exposition.contains("phactory_gk_heartbeat_anomalies_total{kind=\"stale\"} 4\n"));
+        assert!(
+            exposition.contains("phactory_gk_heartbeat_anomalies_total{kind=\"unexpected\"} 2\n")
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -221,6 +221,11 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             blocks.first().map(|h| h.block_header.number),
             blocks.last().map(|h| h.block_header.number)
         );
+        if self.system.as_ref().map_or(false, |system| system.paused()) {
+            // Hold the blocks back rather than dropping their messages, so the state never
+            // diverges from the chain and syncing picks up where it stopped once resumed.
+            return Err(from_display("Runtime paused"));
+        }
         let counters = self.runtime_state()?.storage_synchronizer.counters();
         blocks.retain(|b| b.block_header.number >= counters.next_block_number);
 
@@ -255,6 +260,14 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         if self.last_checkpoint.elapsed().as_secs() < self.args.checkpoint_interval {
             return Ok(());
         }
+        // Retried on the next sync, as the last checkpoint time is left untouched.
+        let used = self.platform.memory_usage().rust_used;
+        if !self
+            .checkpoint_stats
+            .admit(current_block, used, self.args.checkpoint_max_memory())
+        {
+            return Ok(());
+        }
         self.take_checkpoint(current_block)
     }
 
@@ -375,7 +388,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             return Err(from_display("state root mismatch"));
         }
 
-        let system = system::System::new(
+        let mut system = system::System::new(
             self.platform.clone(),
             self.dev_mode,
             self.args.sealing_path.clone(),
@@ -387,7 +400,18 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             &mut runtime_state.recv_mq,
             contracts,
             self.args.cores as _,
-        );
+            self.args.role_tags.clone(),
+        )
+        .map_err(from_debug)?;
+        if self.args.start_paused {
+            system.set_paused(true);
+        }
+        if self.args.simulation {
+            system.set_simulation(true);
+        }
+        system.set_slow_contract_threshold(self.args.slow_contract_threshold());
+        system.set_max_bench_iterations(self.args.max_bench_iterations());
+        system.set_poisoned_state_policy(self.args.poisoned_state_policy);
 
         // Build WorkerRegistrationInfoV2
         let runtime_info = WorkerRegistrationInfoV2::<chain::AccountId> {
@@ -489,7 +513,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         let messages: Vec<_> = self
             .runtime_state
             .as_ref()
-            .map(|state| state.send_mq.all_messages_grouped().into_iter().collect())
+            .map(|state| state.send_mq.all_messages_grouped())
             .unwrap_or_default();
         Ok(messages)
     }
@@ -514,21 +538,10 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         // Validate signature
         let origin = if let Some(sig) = &request.signature {
             let current_block = self.get_info().blocknum - 1;
-            // At most two level cert chain supported
-            match sig.verify(&request.encoded_encrypted_data, current_block, 2) {
-                Ok(key_chain) => match &key_chain[..] {
-                    [root_pubkey, ..] => Some(root_pubkey.clone()),
-                    _ => {
-                        return Err(from_display("BUG: verify ok but no key?"));
-                    }
-                },
-                Err(err) => {
-                    return Err(from_display(format!(
-                        "Verifying signature failed: {:?}",
-                        err
-                    )));
-                }
-            }
+            let origin = sig
+                .verify_query(&request.encoded_encrypted_data, current_block)
+                .map_err(|err| from_display(format!("Verifying signature failed: {:?}", err)))?;
+            Some(origin)
         } else {
             info!("No query signature");
             None
@@ -606,7 +619,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         let messages = state
             .chain_storage
             .mq_messages()
-            .map_err(|_| from_display("Can not get mq messages from storage"))?;
+            .map_err(|err| from_display(format!("Can not get mq messages from storage: {err}")))?;
 
         state.recv_mq.reset_local_index();
 
@@ -1509,6 +1522,6 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi for Rpc
     }
 }
 
-fn try_decode_hex(hex_str: &str) -> Result<Vec<u8>, hex::FromHexError> {
+pub(crate) fn try_decode_hex(hex_str: &str) -> Result<Vec<u8>, hex::FromHexError> {
     hex::decode(hex_str.strip_prefix("0x").unwrap_or(hex_str))
 }
@@ -2,7 +2,7 @@ use crate::light_validation::{storage_proof::StorageProof, LightValidation};
 use phactory_api::storage_sync::{BlockValidator, Error as SyncError, Result};
 use std::string::ToString;
 
-pub use storage_ext::ChainStorage;
+pub use storage_ext::{ChainStorage, StorageDecodeError};
 
 impl BlockValidator for LightValidation<chain::Runtime> {
     fn submit_finalized_headers(
@@ -36,23 +36,37 @@ impl BlockValidator for LightValidation<chain::Runtime> {
 
 mod storage_ext {
     use crate::{chain, light_validation::utils::storage_prefix};
-    use chain::{pallet_fat, pallet_mq, pallet_registry};
+    use chain::{pallet_computation, pallet_fat, pallet_mq, pallet_registry};
     use log::error;
     use parity_scale_codec::{Decode, Error};
     use phala_mq::{Message, MessageOrigin};
     use phala_trie_storage::TrieStorage;
     use serde::{Deserialize, Serialize};
     use sp_state_machine::{Ext, OverlayedChanges, StorageTransactionCache};
+    use std::sync::Mutex;
+
+    /// A storage value failed to decode, usually due to a schema change in a runtime upgrade.
+    #[derive(Debug, thiserror::Error)]
+    #[error("Failed to decode the storage value at 0x{}: {source}", hex::encode(.key))]
+    pub struct StorageDecodeError {
+        pub key: Vec<u8>,
+        #[source]
+        pub source: Error,
+    }
 
     #[derive(Serialize, Deserialize, Default)]
     pub struct ChainStorage {
         trie_storage: TrieStorage<crate::RuntimeHasher>,
+        /// The gatekeeper list decoded at the given block.
+        #[serde(skip)]
+        gatekeepers_cache: Mutex<Option<(chain::BlockNumber, Vec<phala_types::WorkerPublicKey>)>>,
     }
 
     impl From<TrieStorage<crate::RuntimeHasher>> for ChainStorage {
         fn from(value: TrieStorage<crate::RuntimeHasher>) -> Self {
             Self {
                 trie_storage: value,
+                gatekeepers_cache: Default::default(),
             }
         }
     }
@@ -61,17 +75,46 @@ mod storage_ext {
         fn get_raw(&self, key: impl AsRef<[u8]>) -> Option<Vec<u8>> {
             self.trie_storage.get(key)
         }
-        fn get_decoded_result<T: Decode>(&self, key: impl AsRef<[u8]>) -> Result<Option<T>, Error> {
+        fn get_decoded_result<T: Decode>(
+            &self,
+            key: impl AsRef<[u8]>,
+        ) -> Result<Option<T>, StorageDecodeError> {
+            let key = key.as_ref();
             self.get_raw(key)
-                .map(|v| match Decode::decode(&mut &v[..]) {
-                    Ok(decoded) => Ok(decoded),
-                    Err(e) => {
-                        error!("Decode storage value failed: {}", e);
-                        Err(e)
-                    }
+                .map(|v| {
+                    Decode::decode(&mut &v[..]).map_err(|source| {
+                        let err = StorageDecodeError {
+                            key: key.to_vec(),
+                            source,
+                        };
+                        error!("{}", err);
+                        err
+                    })
                 })
                 .transpose()
         }
+
+        /// Read and decode the values of the keys in one pass, in the order of the keys.
+        pub fn get_many_decoded<T: Decode, K: AsRef<[u8]>>(
+            &self,
+            keys: &[K],
+        ) -> Result<Vec<Option<T>>, StorageDecodeError> {
+            self.trie_storage
+                .get_many(keys)
+                .into_iter()
+                .zip(keys)
+                .map(|(value, key)| {
+                    value
+                        .map(|v| {
+                            Decode::decode(&mut &v[..]).map_err(|source| StorageDecodeError {
+                                key: key.as_ref().to_vec(),
+                                source,
+                            })
+                        })
+                        .transpose()
+                })
+                .collect()
+        }
     }
 
     impl ChainStorage {
@@ -84,6 +127,7 @@ mod storage_ext {
         }
 
         pub fn load(&mut self, pairs: impl Iterator<Item = (impl AsRef<[u8]>, impl AsRef<[u8]>)>) {
+            self.invalidate_cache();
             self.trie_storage.load(pairs);
         }
 
@@ -96,9 +140,14 @@ mod storage_ext {
         }
 
         pub fn inner_mut(&mut self) -> &mut TrieStorage<crate::RuntimeHasher> {
+            self.invalidate_cache();
             &mut self.trie_storage
         }
 
+        fn invalidate_cache(&mut self) {
+            *self.gatekeepers_cache.get_mut().unwrap() = None;
+        }
+
         pub fn execute_with<R>(&self, f: impl FnOnce() -> R) -> R {
             let backend = self.trie_storage.as_trie_backend();
             let mut overlay = OverlayedChanges::default();
@@ -111,7 +160,7 @@ mod storage_ext {
             self.execute_with(chain::ParachainInfo::parachain_id).0
         }
 
-        pub fn mq_messages(&self) -> Result<Vec<Message>, Error> {
+        pub fn mq_messages(&self) -> Result<Vec<Message>, StorageDecodeError> {
             for key in ["OutboundMessagesV2", "OutboundMessages"] {
                 let messages: Vec<Message> = self
                     .get_decoded_result(storage_prefix("PhalaMq", key))
@@ -152,6 +201,55 @@ mod storage_ext {
             self.execute_with(pallet_registry::Gatekeeper::<chain::Runtime>::get)
         }
 
+        /// Whether `pubkey` is a gatekeeper at `block_number`.
+        ///
+        /// The gatekeeper list is decoded once per block and reused by the following checks
+        /// in the same block.
+        pub fn is_gatekeeper_at(
+            &self,
+            pubkey: &phala_types::WorkerPublicKey,
+            block_number: chain::BlockNumber,
+        ) -> bool {
+            let mut cache = self.gatekeepers_cache.lock().unwrap();
+            match &*cache {
+                Some((cached_at, gatekeepers)) if *cached_at == block_number => {
+                    gatekeepers.contains(pubkey)
+                }
+                _ => {
+                    let gatekeepers = self.gatekeepers();
+                    let is_gatekeeper = gatekeepers.contains(pubkey);
+                    *cache = Some((block_number, gatekeepers));
+                    is_gatekeeper
+                }
+            }
+        }
+
+        pub(crate) fn gatekeeper_master_pubkey(&self) -> Option<phala_types::MasterPublicKey> {
+            self.execute_with(pallet_registry::GatekeeperMasterPubkey::<chain::Runtime>::get)
+        }
+
+        pub(crate) fn tokenomic_parameters(
+            &self,
+        ) -> Option<phala_types::messaging::TokenomicParameters> {
+            self.execute_with(pallet_computation::TokenomicParameters::<chain::Runtime>::get)
+        }
+
+        pub(crate) fn worker_ecdh_pubkey(
+            &self,
+            worker: &phala_types::WorkerPublicKey,
+        ) -> Option<phala_types::EcdhPublicKey> {
+            self.execute_with(|| pallet_registry::Workers::<chain::Runtime>::get(worker))
+                .map(|info| info.ecdh_pubkey)
+        }
+
+        pub(crate) fn registered_workers(
+            &self,
+        ) -> Vec<pallet_registry::WorkerInfoV2<chain::AccountId>> {
+            self.execute_with(|| {
+                pallet_registry::Workers::<chain::Runtime>::iter_values().collect()
+            })
+        }
+
         pub(crate) fn is_worker_registered(&self, worker: &phala_types::WorkerPublicKey) -> bool {
             self.execute_with(|| pallet_registry::Workers::<chain::Runtime>::get(worker))
                 .is_some()
@@ -175,4 +273,114 @@ mod storage_ext {
             false
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::light_validation::utils::storage_map_prefix_twox_64_concat;
+        use parity_scale_codec::Encode;
+
+        #[test]
+        fn mismatched_value_type_is_reported_with_key() {
+            let key = storage_map_prefix_twox_64_concat(b"PhalaRegistry", b"Workers", &42u32);
+            let storage = ChainStorage::from_pairs(vec![(key.clone(), vec![1u8, 2])].into_iter());
+
+            assert_eq!(
+                storage.get_decoded_result::<u16>(&key).unwrap(),
+                Some(0x0201)
+            );
+            let err = storage.get_decoded_result::<u64>(&key).unwrap_err();
+            assert_eq!(err.key, key);
+            assert!(err.to_string().contains(&hex::encode(&key)));
+        }
+
+        #[test]
+        fn missing_value_is_not_an_error() {
+            let key = storage_map_prefix_twox_64_concat(b"PhalaRegistry", b"Workers", &42u32);
+            let storage = ChainStorage::default();
+            assert_eq!(storage.get_decoded_result::<u64>(&key).unwrap(), None);
+        }
+
+        #[test]
+        fn batch_read_matches_individual_reads() {
+            let keys: Vec<_> = (0u32..4)
+                .map(|i| storage_map_prefix_twox_64_concat(b"PhalaRegistry", b"Workers", &i))
+                .collect();
+            let storage = ChainStorage::from_pairs(
+                vec![
+                    (keys[0].clone(), 7u64.encode()),
+                    (keys[2].clone(), 9u64.encode()),
+                ]
+                .into_iter(),
+            );
+
+            let batch = storage.get_many_decoded::<u64, _>(&keys).unwrap();
+            let individual: Vec<_> = keys
+                .iter()
+                .map(|key| storage.get_decoded_result::<u64>(key).unwrap())
+                .collect();
+            assert_eq!(batch, individual);
+            assert_eq!(batch, vec![Some(7), None, Some(9), None]);
+
+            let err = storage.get_many_decoded::<u128, _>(&keys).unwrap_err();
+            assert_eq!(err.key, keys[0]);
+        }
+
+        #[test]
+        fn gatekeeper_cache_follows_the_storage() {
+            let gk1 = phala_types::WorkerPublicKey::from_raw([1u8; 32]);
+            let gk2 = phala_types::WorkerPublicKey::from_raw([2u8; 32]);
+            let key = storage_prefix("PhalaRegistry", "Gatekeeper");
+            let mut storage =
+                ChainStorage::from_pairs(vec![(key.clone(), vec![gk1].encode())].into_iter());
+
+            assert!(storage.is_gatekeeper_at(&gk1, 1));
+            assert!(!storage.is_gatekeeper_at(&gk2, 1));
+
+            // Updating the storage drops the cached list even in the same block
+            storage.load(vec![(key.clone(), vec![gk1, gk2].encode())].into_iter());
+            assert!(storage.is_gatekeeper_at(&gk2, 1));
+
+            storage.load(vec![(key, vec![gk2].encode())].into_iter());
+            assert!(!storage.is_gatekeeper_at(&gk1, 2));
+            assert!(storage.is_gatekeeper_at(&gk2, 2));
+        }
+
+        #[test]
+        fn registry_accessors_read_the_pallet_storage() {
+            let gatekeeper = phala_types::WorkerPublicKey::from_raw([1u8; 32]);
+            let runtime_hash = vec![0xaa_u8; 32];
+            let storage = ChainStorage::from_pairs(
+                vec![
+                    (
+                        storage_prefix("PhalaRegistry", "Gatekeeper"),
+                        vec![gatekeeper].encode(),
+                    ),
+                    (
+                        storage_map_prefix_twox_64_concat(
+                            b"PhalaRegistry",
+                            b"PRuntimeAddedAt",
+                            &runtime_hash,
+                        ),
+                        (100 as chain::BlockNumber).encode(),
+                    ),
+                ]
+                .into_iter(),
+            );
+
+            assert_eq!(storage.gatekeepers(), vec![gatekeeper]);
+            assert!(storage.is_gatekeeper_at(&gatekeeper, 1));
+            assert_eq!(storage.get_pruntime_added_at(&runtime_hash), Some(100));
+            assert_eq!(storage.get_pruntime_added_at(&[0xbb; 32]), None);
+            assert!(!storage.is_worker_registered(&gatekeeper));
+        }
+
+        #[test]
+        fn undecodable_mq_messages_are_reported() {
+            let key = storage_prefix("PhalaMq", "OutboundMessagesV2");
+            let storage = ChainStorage::from_pairs(vec![(key.clone(), vec![0xffu8])].into_iter());
+            let err = storage.mq_messages().unwrap_err();
+            assert_eq!(err.key, key);
+        }
+    }
 }
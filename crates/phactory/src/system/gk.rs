@@ -1,4 +1,7 @@
-use super::{RotatedMasterKey, TransactionError, TypedReceiver, WorkerState};
+use super::{
+    master_key::{self, MasterKeyAuditEvent},
+    PoisonedStatePolicy, RotatedMasterKey, TransactionError, TypedReceiver, WorkerState,
+};
 use chain::pallet_fat::ClusterRegistryEvent;
 use chain::pallet_registry::GatekeeperRegistryEvent;
 use phala_crypto::{
@@ -21,7 +24,7 @@ use phala_types::{
     wrap_content_to_sign, EcdhPublicKey, SignedContentType, WorkerPublicKey,
 };
 use serde::{Deserialize, Serialize};
-use sp_core::{hashing, sr25519, Pair};
+use sp_core::{hashing, sr25519, Pair, U256};
 
 use crate::types::BlockInfo;
 
@@ -31,7 +34,7 @@ use std::{
 };
 
 use fixed_macro::types::U64F64 as fp;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use phactory_api::prpc as pb;
 pub use tokenomic::{FixedPoint, TokenomicInfo};
 
@@ -42,6 +45,13 @@ const VRF_INTERVAL: u32 = 5;
 
 const MASTER_KEY_SHARING_SALT: &[u8] = b"master_key_sharing";
 
+/// Block interval to compare the local master key with the on-chain master pubkey
+const MASTER_PUBKEY_CHECK_INTERVAL: chain::BlockNumber = 10;
+
+/// A master pubkey mismatch lasting longer than this, e.g. a rotation which failed to land on
+/// chain, is reported as an error
+const MASTER_PUBKEY_MISMATCH_TOLERANCE: chain::BlockNumber = 100;
+
 // pesudo_random_number = blake2_256(last_random_number, block_number, derived_master_key)
 //
 // NOTICE: we abandon the random number involving master key signature, since the malleability of sr25519 signature
@@ -84,6 +94,14 @@ pub struct WorkerInfo {
     unresponsive: bool,
     tokenomic: TokenomicInfo,
     heartbeat_flag: bool,
+    /// Heartbeats answering the challenges of an ended computing session. Many of them suggest the
+    /// worker lags behind the chain.
+    #[serde(default)]
+    stale_heartbeats: u32,
+    /// Heartbeats not answering the oldest challenge waited for, dropped when the poisoned state
+    /// policy allows to continue.
+    #[serde(default)]
+    unexpected_heartbeats: u32,
     #[cfg(feature = "gk-stat")]
     stat: WorkerStat,
 }
@@ -96,6 +114,8 @@ impl WorkerInfo {
             unresponsive: false,
             tokenomic: Default::default(),
             heartbeat_flag: false,
+            stale_heartbeats: 0,
+            unexpected_heartbeats: 0,
             #[cfg(feature = "gk-stat")]
             stat: Default::default(),
         }
@@ -110,6 +130,42 @@ impl WorkerInfo {
     }
 }
 
+/// Tracks how long the local master key has differed from the on-chain master pubkey.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub(crate) struct MasterPubkeyCheck {
+    mismatch_since: Option<chain::BlockNumber>,
+}
+
+impl MasterPubkeyCheck {
+    /// Update with the current pubkeys and return the number of blocks the mismatch has lasted.
+    ///
+    /// No on-chain pubkey, before the first gatekeeper uploads it, is not a mismatch.
+    pub fn update(
+        &mut self,
+        block_number: chain::BlockNumber,
+        local: &sr25519::Public,
+        on_chain: Option<&sr25519::Public>,
+    ) -> chain::BlockNumber {
+        match on_chain {
+            Some(on_chain) if on_chain != local => {
+                let since = *self.mismatch_since.get_or_insert(block_number);
+                block_number - since
+            }
+            _ => {
+                if let Some(since) = self.mismatch_since.take() {
+                    info!("Gatekeeper: master pubkey mismatch since block {since} resolved");
+                }
+                0
+            }
+        }
+    }
+
+    pub fn mismatch_blocks(&self, block_number: chain::BlockNumber) -> chain::BlockNumber {
+        self.mismatch_since
+            .map_or(0, |since| block_number.saturating_sub(since))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Gatekeeper<MsgChan> {
     /// The current master key in use
@@ -121,13 +177,21 @@ pub(crate) struct Gatekeeper<MsgChan> {
     registered_on_chain: bool,
     #[serde(with = "more::scale_bytes")]
     master_key_history: Vec<RotatedMasterKey>,
-    egress: MsgChan, // TODO.kevin: syncing the egress state while migrating.
+    egress: MsgChan,
+    /// The next egress sequence as of the end of the last block.
+    ///
+    /// The send queue is checkpointed as well, but it is restored into the queue in case the
+    /// queue lost track of the gatekeeper, so that the pallet never sees a sequence reused.
+    #[serde(default)]
+    egress_sequence: u64,
     gatekeeper_events: TypedReceiver<GatekeeperEvent>,
     cluster_events: TypedReceiver<ClusterEvent>,
     // Randomness
     last_random_number: RandomNumber,
     iv_seq: u64,
     pub(crate) computing_economics: ComputingEconomics<MsgChan>,
+    #[serde(default)]
+    master_pubkey_check: MasterPubkeyCheck,
 }
 
 impl<MsgChan> Gatekeeper<MsgChan>
@@ -153,11 +217,13 @@ where
             registered_on_chain: false,
             master_key_history,
             egress: egress.clone(),
+            egress_sequence: 0,
             gatekeeper_events: recv_mq.subscribe_bound(),
             cluster_events: recv_mq.subscribe_bound(),
             last_random_number: [0_u8; 32],
             iv_seq: 0,
             computing_economics: ComputingEconomics::new(recv_mq, egress),
+            master_pubkey_check: Default::default(),
         }
     }
 
@@ -209,6 +275,53 @@ where
         self.master_key.public()
     }
 
+    pub fn worker_tokenomics(&self) -> Vec<(WorkerPublicKey, TokenomicSnapshot)> {
+        self.computing_economics.worker_tokenomics()
+    }
+
+    pub fn heartbeat_anomalies(&self) -> Vec<(WorkerPublicKey, HeartbeatAnomalies)> {
+        self.computing_economics.heartbeat_anomalies()
+    }
+
+    pub fn master_pubkey_mismatch_blocks(
+        &self,
+        block_number: chain::BlockNumber,
+    ) -> chain::BlockNumber {
+        self.master_pubkey_check.mismatch_blocks(block_number)
+    }
+
+    fn check_master_pubkey(&mut self, block: &BlockInfo<'_>) {
+        // The shadow gatekeeper runs with its own master key.
+        if cfg!(feature = "shadow-gk") || block.block_number % MASTER_PUBKEY_CHECK_INTERVAL != 0 {
+            return;
+        }
+        let local = self.master_key.public();
+        let on_chain = super::chain_state::master_pubkey(block.storage);
+        let blocks = self
+            .master_pubkey_check
+            .update(block.block_number, &local, on_chain.as_ref());
+        if blocks > MASTER_PUBKEY_MISMATCH_TOLERANCE {
+            error!(
+                "Gatekeeper: local master pubkey {} mismatches the on-chain one {:?} for {blocks} blocks",
+                hex::encode(local),
+                on_chain.map(hex::encode),
+            );
+        }
+    }
+
+    /// Set how to handle the conditions indicating the state is poisoned.
+    pub fn set_poisoned_state_policy(&mut self, policy: PoisonedStatePolicy) {
+        self.computing_economics.set_poisoned_state_policy(policy);
+    }
+
+    /// Reconcile the tracked workers with the worker registrations on chain.
+    ///
+    /// Returns the numbers of the added and removed workers, or None if the tracked workers
+    /// already match the chain.
+    pub fn resync_from_chain(&mut self, storage: &crate::ChainStorage) -> Option<(usize, usize)> {
+        self.computing_economics.resync_from_chain(storage)
+    }
+
     pub fn master_key_history(&self) -> &Vec<RotatedMasterKey> {
         &self.master_key_history
     }
@@ -261,6 +374,12 @@ where
                 master_pubkey,
             });
 
+        master_key::audit(MasterKeyAuditEvent::Rotated {
+            block_number: block_height,
+            rotation_id,
+            master_pubkey,
+        });
+
         self.master_key = new_master_key;
         self.egress.set_signer(self.master_key.clone().into());
         true
@@ -272,11 +391,18 @@ where
         ecdh_pubkey: &EcdhPublicKey,
         block_number: chain::BlockNumber,
     ) {
-        if self.master_key_history.len() > 1 {
+        let n_keys = if self.master_key_history.len() > 1 {
             self.share_master_key_history(pubkey, ecdh_pubkey, block_number);
+            self.master_key_history.len()
         } else {
             self.share_latest_master_key(pubkey, ecdh_pubkey, block_number);
-        }
+            1
+        };
+        master_key::audit(MasterKeyAuditEvent::Shared {
+            block_number,
+            dest: *pubkey,
+            n_keys,
+        });
     }
 
     pub fn share_latest_master_key(
@@ -341,7 +467,13 @@ where
         event: RotateMasterKeyEvent,
         identity_key: sr25519::Pair,
     ) {
-        let new_master_key = crate::new_sr25519_key();
+        let new_master_key = master_key::generate(&mut rand::thread_rng())
+            .unwrap_or_else(|err| panic!("Refused to generate the master key: {err:?}"));
+        master_key::audit(MasterKeyAuditEvent::RotationGenerated {
+            block_number: block.block_number,
+            rotation_id: event.rotation_id,
+            master_pubkey: new_master_key.public(),
+        });
         let secret_key = new_master_key.dump_secret_key();
         let secret_keys: BTreeMap<_, _> = event
             .gk_identities
@@ -413,6 +545,13 @@ where
             self.computing_economics.did_process_block(block, &mut ());
         }
         self.emit_random_number(block.block_number);
+        self.check_master_pubkey(block);
+        self.egress_sequence = self.egress.next_sequence();
+    }
+
+    /// Make sure the egress does not reuse a sequence taken before the last checkpoint.
+    pub fn restore_egress_sequence(&self) {
+        self.egress.ensure_next_sequence(self.egress_sequence);
     }
 
     fn process_gatekeeper_event(&mut self, origin: MessageOrigin, event: GatekeeperEvent) {
@@ -559,7 +698,10 @@ where
         if expect_random != event.random_number {
             error!("Fatal error: Expect random number {:?}", expect_random);
             #[cfg(not(feature = "shadow-gk"))]
-            panic!("GK state poisoned");
+            super::state_poisoned(
+                self.computing_economics.poisoned_state_policy,
+                "GK state poisoned",
+            );
         }
     }
 
@@ -639,7 +781,19 @@ pub struct ComputingEconomics<MsgChan> {
     system_events: TypedReceiver<SystemEvent>,
     gatekeeper_events: TypedReceiver<GatekeeperEvent>,
     workers: BTreeMap<WorkerPublicKey, WorkerInfo>,
+    /// The tracked workers by their hashed ids, to detect the collisions.
+    ///
+    /// Not saved in the checkpoints, but rebuilt from `workers` by `rebuild_hashed_ids` on restore.
+    #[serde(skip)]
+    hashed_ids: BTreeMap<U256, WorkerPublicKey>,
     tokenomic_params: tokenomic::Params,
+    /// Whether the tokenomic parameters have been loaded from the chain storage.
+    ///
+    /// They are loaded once at the first block, in case they were set on chain before the
+    /// gatekeeper started listening to `TokenomicParametersChanged`. A state restored from before
+    /// this flag was added has been following the messages all along, so it counts as loaded.
+    #[serde(default = "restored_tokenomic_params_loaded")]
+    tokenomic_params_loaded: bool,
     /// Indicates a set of update is enabled on-chain
     /// - Remove payout delta V limitation
     ///   (https://github.com/Phala-Network/phala-blockchain/issues/693)
@@ -651,6 +805,12 @@ pub struct ComputingEconomics<MsgChan> {
     unresp_fix: bool,
     #[serde(skip, default)]
     eco_cache: EconomicCalcCache,
+    #[serde(skip)]
+    poisoned_state_policy: PoisonedStatePolicy,
+}
+
+fn restored_tokenomic_params_loaded() -> bool {
+    true
 }
 
 #[derive(Default)]
@@ -682,6 +842,29 @@ fn test_restore_phala_launched() {
     assert!(!state.phala_launched);
 }
 
+#[test]
+fn test_restore_tokenomic_params_loaded() {
+    #[derive(Serialize, Deserialize)]
+    struct ComputingEconomics0 {
+        tokenomic_params: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ComputingEconomics1 {
+        tokenomic_params: u32,
+        #[serde(default = "restored_tokenomic_params_loaded")]
+        tokenomic_params_loaded: bool,
+    }
+
+    let checkpoint = serde_cbor::to_vec(&ComputingEconomics0 {
+        tokenomic_params: 1,
+    })
+    .unwrap();
+
+    let state: ComputingEconomics1 = serde_cbor::from_slice(&checkpoint).unwrap();
+    assert!(state.tokenomic_params_loaded);
+}
+
 #[cfg(feature = "gk-stat")]
 impl From<&WorkerStat> for pb::WorkerStat {
     fn from(stat: &WorkerStat) -> Self {
@@ -694,8 +877,53 @@ impl From<&WorkerStat> for pb::WorkerStat {
     }
 }
 
-impl From<&WorkerInfo> for pb::WorkerState {
+/// The tokenomic state the gatekeeper tracks for a worker, for debugging the payouts.
+///
+/// The fixed point numbers are given by their bits, serialized as decimal strings since u128 is
+/// out of the range of most JSON parsers.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenomicSnapshot {
+    #[serde(serialize_with = "serialize_bits")]
+    pub v: u128,
+    #[serde(serialize_with = "serialize_bits")]
+    pub v_init: u128,
+    #[serde(serialize_with = "serialize_bits")]
+    pub p_bench: u128,
+    #[serde(serialize_with = "serialize_bits")]
+    pub p_instant: u128,
+    pub confidence_level: u8,
+    pub unresponsive: bool,
+}
+
+fn serialize_bits<S: serde::Serializer>(bits: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(bits)
+}
+
+impl From<&WorkerInfo> for TokenomicSnapshot {
     fn from(info: &WorkerInfo) -> Self {
+        Self {
+            v: info.tokenomic.v.to_bits(),
+            v_init: info.tokenomic.v_init.to_bits(),
+            p_bench: info.tokenomic.p_bench.to_bits(),
+            p_instant: info.tokenomic.p_instant.to_bits(),
+            confidence_level: info.tokenomic.confidence_level,
+            unresponsive: info.unresponsive,
+        }
+    }
+}
+
+/// Counts of the heartbeats of a worker that the gatekeeper did not expect.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatAnomalies {
+    /// Heartbeats for ended computing sessions.
+    pub stale: u32,
+    /// Heartbeats for a challenge other than the oldest one waited for.
+    pub unexpected: u32,
+}
+
+impl WorkerInfo {
+    fn to_pb(&self, params: &tokenomic::Params) -> pb::WorkerState {
+        let info = self;
         pb::WorkerState {
             registered: info.state.registered,
             unresponsive: info.unresponsive,
@@ -718,7 +946,7 @@ impl From<&WorkerInfo> for pb::WorkerState {
             stat: Some((&info.stat).into()),
             #[cfg(not(feature = "gk-stat"))]
             stat: None,
-            tokenomic_info: Some(info.tokenomic.into()),
+            tokenomic_info: Some(info.tokenomic.to_pb(params)),
         }
     }
 }
@@ -731,25 +959,180 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
             system_events: recv_mq.subscribe_bound(),
             gatekeeper_events: recv_mq.subscribe_bound(),
             workers: Default::default(),
+            hashed_ids: Default::default(),
             tokenomic_params: tokenomic::test_params(),
+            tokenomic_params_loaded: false,
             phala_launched: false,
             unresp_fix: false,
             eco_cache: Default::default(),
+            poisoned_state_policy: Default::default(),
         }
     }
 
+    /// Set how to handle the conditions indicating the state is poisoned.
+    pub fn set_poisoned_state_policy(&mut self, policy: PoisonedStatePolicy) {
+        self.poisoned_state_policy = policy;
+    }
+
     pub fn dump_workers_state(&self) -> Vec<(WorkerPublicKey, pb::WorkerState)> {
+        self.workers
+            .values()
+            .map(|info| (info.state.pubkey, info.to_pb(&self.tokenomic_params)))
+            .collect()
+    }
+
+    pub fn worker_tokenomics(&self) -> Vec<(WorkerPublicKey, TokenomicSnapshot)> {
         self.workers
             .values()
             .map(|info| (info.state.pubkey, info.into()))
             .collect()
     }
 
+    /// The workers which sent stale or unexpected heartbeats, with the counts.
+    pub fn heartbeat_anomalies(&self) -> Vec<(WorkerPublicKey, HeartbeatAnomalies)> {
+        self.workers
+            .values()
+            .filter(|info| info.stale_heartbeats > 0 || info.unexpected_heartbeats > 0)
+            .map(|info| {
+                let anomalies = HeartbeatAnomalies {
+                    stale: info.stale_heartbeats,
+                    unexpected: info.unexpected_heartbeats,
+                };
+                (info.state.pubkey, anomalies)
+            })
+            .collect()
+    }
+
     pub fn worker_state(&self, pubkey: &WorkerPublicKey) -> Option<pb::WorkerState> {
-        self.workers.get(pubkey).map(Into::into)
+        self.workers
+            .get(pubkey)
+            .map(|info| info.to_pb(&self.tokenomic_params))
+    }
+
+    /// The tracked worker with the same hashed id as `worker`, if any.
+    fn hashed_id_collision(&self, worker: &WorkerInfo) -> Option<WorkerPublicKey> {
+        self.hashed_ids
+            .get(&worker.state.hashed_id)
+            .filter(|other| **other != worker.state.pubkey)
+            .copied()
+    }
+
+    /// Start tracking a newly registered worker.
+    ///
+    /// Workers with the same hashed id respond to the same heartbeat challenges, which takes a
+    /// blake2 collision. Such a worker is tracked as usual, as all the gatekeepers must agree on
+    /// it, but the collision is reported loudly.
+    fn add_worker(&mut self, worker: WorkerInfo) {
+        if let Some(other) = self.hashed_id_collision(&worker) {
+            error!(
+                target: "gk_computing",
+                "[{}] hashed id collides with worker {}, they hit the same heartbeat challenges",
+                hex::encode(worker.state.pubkey),
+                hex::encode(other)
+            );
+        } else {
+            self.hashed_ids
+                .insert(worker.state.hashed_id, worker.state.pubkey);
+        }
+        self.workers.insert(worker.state.pubkey, worker);
+    }
+
+    /// Stop tracking a worker.
+    ///
+    /// If another tracked worker has the same hashed id, the index moves to it.
+    fn remove_worker(&mut self, pubkey: &WorkerPublicKey) {
+        let hashed_id = match self.workers.remove(pubkey) {
+            Some(worker) => worker.state.hashed_id,
+            None => return,
+        };
+        if self.hashed_ids.get(&hashed_id) != Some(pubkey) {
+            return;
+        }
+        match self
+            .workers
+            .values()
+            .find(|info| info.state.hashed_id == hashed_id)
+        {
+            Some(other) => {
+                self.hashed_ids.insert(hashed_id, other.state.pubkey);
+            }
+            None => {
+                self.hashed_ids.remove(&hashed_id);
+            }
+        }
+    }
+
+    /// Rebuild the hashed id index, which is not saved in the checkpoints.
+    ///
+    /// Of the workers with the same hashed id, the first one in `workers` is indexed.
+    pub(crate) fn rebuild_hashed_ids(&mut self) {
+        self.hashed_ids.clear();
+        for info in self.workers.values() {
+            self.hashed_ids
+                .entry(info.state.hashed_id)
+                .or_insert(info.state.pubkey);
+        }
+    }
+
+    /// Compare the tracked workers with the worker registrations on chain.
+    ///
+    /// Returns the registered workers which are not tracked, along with their confidence levels,
+    /// and the tracked workers which are not registered.
+    fn registration_mismatch(
+        &self,
+        storage: &crate::ChainStorage,
+    ) -> (Vec<(WorkerPublicKey, u8)>, Vec<WorkerPublicKey>) {
+        let registered: BTreeMap<_, _> = storage
+            .registered_workers()
+            .into_iter()
+            .map(|info| (info.pubkey, info.confidence_level))
+            .collect();
+        let unregistered = self
+            .workers
+            .keys()
+            .filter(|pubkey| !registered.contains_key(pubkey))
+            .cloned()
+            .collect();
+        let missing = registered
+            .into_iter()
+            .filter(|(pubkey, _)| !self.workers.contains_key(pubkey))
+            .collect();
+        (missing, unregistered)
+    }
+
+    /// Drop the workers not registered on chain, and add the registered ones which are missing.
+    ///
+    /// The added workers start from the registered state, as if the Registered event was just
+    /// received. Their computing sessions are restored by the upcoming events, if any.
+    ///
+    /// Returns the numbers of the added and removed workers, or None if no mismatch is detected,
+    /// in which case the tracked workers are left untouched.
+    pub fn resync_from_chain(&mut self, storage: &crate::ChainStorage) -> Option<(usize, usize)> {
+        let (missing, unregistered) = self.registration_mismatch(storage);
+        if missing.is_empty() && unregistered.is_empty() {
+            return None;
+        }
+        for pubkey in &unregistered {
+            self.remove_worker(pubkey);
+        }
+        for (pubkey, confidence_level) in &missing {
+            let mut worker = WorkerInfo::new(*pubkey);
+            worker.state.registered = true;
+            worker.tokenomic.confidence_level = *confidence_level;
+            self.add_worker(worker);
+        }
+        let (added, removed) = (missing.len(), unregistered.len());
+        warn!("Resynced GK workers from chain, added {added}, removed {removed}");
+        Some((added, removed))
     }
 
     pub fn will_process_block(&mut self, block: &BlockInfo<'_>) {
+        if !self.tokenomic_params_loaded
+            && block.storage.pruntime_consensus_version()
+                >= super::TOKENOMIC_PARAMS_FROM_STORAGE_SINCE
+        {
+            self.load_tokenomic_params(block.storage);
+        }
         let sum_share = self.sum_share();
         let report = WorkingInfoUpdateEvent::new(block.block_number, block.now_ms);
         self.eco_cache = EconomicCalcCache { sum_share, report };
@@ -891,6 +1274,24 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
         }
     }
 
+    /// Replace the default tokenomic parameters with the ones on chain, if any.
+    fn load_tokenomic_params(&mut self, storage: &crate::ChainStorage) {
+        self.tokenomic_params_loaded = true;
+        match super::chain_state::tokenomic_parameters(storage) {
+            Some(params) => {
+                self.tokenomic_params = params.into();
+                info!(
+                    target: "gk_computing",
+                    "Tokenomic parameter loaded from chain: {:#?}",
+                    &self.tokenomic_params
+                );
+            }
+            None => {
+                info!(target: "gk_computing", "No tokenomic parameter on chain, using the default");
+            }
+        }
+    }
+
     #[cfg(test)]
     pub fn test_process_messages(&mut self, block: &BlockInfo<'_>) {
         self.will_process_block(block);
@@ -952,11 +1353,17 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
                 }
 
                 if Some(&challenge_block) != worker_info.waiting_heartbeats.get(0) {
+                    worker_info.unexpected_heartbeats =
+                        worker_info.unexpected_heartbeats.saturating_add(1);
                     error!(target: "gk_computing", "Fatal error: Unexpected heartbeat {:?}", event);
                     error!(target: "gk_computing", "Sent from worker {}", hex::encode(worker_pubkey));
                     error!(target: "gk_computing", "Waiting heartbeats {:#?}", worker_info.waiting_heartbeats);
                     // The state has been poisoned. Make no sence to keep moving on.
-                    panic!("GK or Worker state poisoned");
+                    super::state_poisoned(
+                        self.poisoned_state_policy,
+                        "GK or Worker state poisoned",
+                    );
+                    return;
                 }
 
                 // The oldest one comfirmed.
@@ -965,20 +1372,25 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
                 let working_state = if let Some(state) = &worker_info.state.working_state {
                     state
                 } else {
-                    trace!(
+                    worker_info.stale_heartbeats = worker_info.stale_heartbeats.saturating_add(1);
+                    debug!(
                         target: "gk_computing",
-                        "[{}] Computing already stopped, ignore the heartbeat.",
-                        hex::encode(worker_info.state.pubkey)
+                        "[{}] Computing already stopped, ignore the heartbeat of session {}.",
+                        hex::encode(worker_info.state.pubkey),
+                        session_id
                     );
                     return;
                 };
 
                 if session_id != working_state.session_id {
-                    trace!(
+                    debug!(
                         target: "gk_computing",
-                        "[{}] Heartbeat response to previous computing sessions, ignore it.",
-                        hex::encode(worker_info.state.pubkey)
+                        "[{}] Heartbeat response to previous computing session {}, current {}, ignore it.",
+                        hex::encode(worker_info.state.pubkey),
+                        session_id,
+                        working_state.session_id
                     );
+                    worker_info.stale_heartbeats = worker_info.stale_heartbeats.saturating_add(1);
                     return;
                 }
 
@@ -1047,10 +1459,19 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
             event: WorkerEvent::Registered(_),
         }) = &event
         {
-            let _ = self
-                .workers
-                .entry(*pubkey)
-                .or_insert_with(|| WorkerInfo::new(*pubkey));
+            // Don't let a forged registration event create a phantom worker
+            if block.storage.pruntime_consensus_version() >= super::WORKER_REGISTRATION_CHECK_SINCE
+                && !block.storage.is_worker_registered(pubkey)
+            {
+                error!(
+                    "Rejected the registration of worker {:?} which is not registered on chain",
+                    pubkey
+                );
+                return;
+            }
+            if !self.workers.contains_key(pubkey) {
+                self.add_worker(WorkerInfo::new(*pubkey));
+            }
         }
 
         let log_on = log::log_enabled!(log::Level::Debug);
@@ -1081,6 +1502,18 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
                             init_p,
                         } => {
                             let v = FixedPoint::from_bits(*init_v);
+                            let init_p = if *init_p > tokenomic::MAX_P_BENCH {
+                                warn!(
+                                    target: "gk_computing",
+                                    "[{}] implausible benchmark score {}, clamped to {}",
+                                    hex::encode(worker.state.pubkey),
+                                    init_p,
+                                    tokenomic::MAX_P_BENCH
+                                );
+                                tokenomic::MAX_P_BENCH
+                            } else {
+                                *init_p
+                            };
                             let prev = worker.tokenomic;
                             // NOTE.kevin: To track the heartbeats by global timeline, don't clear the waiting_heartbeats.
                             // worker.waiting_heartbeats.clear();
@@ -1093,8 +1526,8 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
                                 v_update_block: block.block_number,
                                 iteration_last: 0,
                                 challenge_time_last: block.now_ms,
-                                p_bench: FixedPoint::from_num(*init_p),
-                                p_instant: FixedPoint::from_num(*init_p),
+                                p_bench: FixedPoint::from_num(init_p),
+                                p_instant: FixedPoint::from_num(init_p),
                                 confidence_level: prev.confidence_level,
                                 contract_running: false,
 
@@ -1146,6 +1579,7 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
             GatekeeperEvent::TokenomicParametersChanged(params) => {
                 if origin.is_pallet() {
                     self.tokenomic_params = params.into();
+                    self.tokenomic_params_loaded = true;
                     info!(
                         target: "gk_computing",
                         "Tokenomic parameter updated: {:#?}",
@@ -1198,7 +1632,7 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
                     !info.unresponsive
                 }
             })
-            .map(|info| info.tokenomic.share())
+            .map(|info| info.tokenomic.share(&self.tokenomic_params))
             .sum()
     }
 }
@@ -1239,18 +1673,18 @@ mod tokenomic {
     use phala_types::messaging::TokenomicParameters;
     use serde::{Deserialize, Serialize};
 
+    /// The highest benchmark score the registry pallet ever assigns.
+    ///
+    /// A higher score can only come from a broken benchmark, so it is clamped rather than paid for.
+    /// This is not configurable per gatekeeper since all of them must agree on the V of workers.
+    pub const MAX_P_BENCH: u32 = 6000;
+
     fn square(v: FixedPoint) -> FixedPoint {
         v * v
     }
 
-    fn conf_score(level: u8) -> FixedPoint {
-        match level {
-            1 | 2 | 3 | 128 => fp!(1),
-            4 => fp!(0.8),
-            5 => fp!(0.7),
-            _ => fp!(0),
-        }
-    }
+    /// The share multipliers of the confidence levels 1 to 5, the same as the pallet uses.
+    const DEFAULT_CONF_SCORES: [FixedPoint; 5] = [fp!(1), fp!(1), fp!(1), fp!(0.8), fp!(0.7)];
 
     #[cfg(feature = "gk-stat")]
     #[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
@@ -1308,13 +1742,14 @@ mod tokenomic {
         }
     }
 
-    impl From<TokenomicInfo> for super::pb::TokenomicInfo {
-        fn from(info: TokenomicInfo) -> Self {
-            Self {
+    impl TokenomicInfo {
+        pub fn to_pb(&self, params: &Params) -> super::pb::TokenomicInfo {
+            let info = self;
+            super::pb::TokenomicInfo {
                 v: info.v.to_string(),
                 v_init: info.v_init.to_string(),
                 v_deductible: info.v_deductible.to_string(),
-                share: info.share().to_string(),
+                share: info.share(params).to_string(),
                 v_update_at: info.v_update_at,
                 v_update_block: info.v_update_block,
                 iteration_last: info.iteration_last,
@@ -1334,6 +1769,10 @@ mod tokenomic {
     pub struct Params {
         #[serde(with = "serde_fp")]
         rho: FixedPoint,
+        /// The share of V slashed per block, as `update_v_slash` runs once per block.
+        ///
+        /// The pallet converts the hourly rate of 0.1% with the block time before sending it, so
+        /// no conversion is needed here.
         #[serde(with = "serde_fp")]
         slash_rate: FixedPoint,
         #[serde(with = "serde_fp")]
@@ -1349,6 +1788,49 @@ mod tokenomic {
         #[serde(with = "serde_fp")]
         payout_ration: FixedPoint,
         pub heartbeat_window: u32,
+        /// The share multipliers of the confidence levels 1 to 5.
+        #[serde(with = "serde_conf_scores", default = "default_conf_scores")]
+        pub conf_scores: [FixedPoint; 5],
+    }
+
+    fn default_conf_scores() -> [FixedPoint; 5] {
+        DEFAULT_CONF_SCORES
+    }
+
+    mod serde_conf_scores {
+        use super::FixedPoint;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S>(scores: &[FixedPoint; 5], serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            // The same encoding as serde_fp, for each of the scores
+            scores
+                .map(|score| score.to_bits().to_be_bytes())
+                .serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<[FixedPoint; 5], D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let scores: [[u8; 16]; 5] = Deserialize::deserialize(deserializer)?;
+            Ok(scores.map(|bytes| FixedPoint::from_bits(u128::from_be_bytes(bytes))))
+        }
+    }
+
+    impl Params {
+        /// The share multiplier of a confidence level.
+        ///
+        /// Level 128 counts as level 1 and the unknown levels earn no share.
+        fn conf_score(&self, level: u8) -> FixedPoint {
+            match level {
+                1..=5 => self.conf_scores[level as usize - 1],
+                128 => self.conf_scores[0],
+                _ => fp!(0),
+            }
+        }
     }
 
     impl From<TokenomicParameters> for Params {
@@ -1365,6 +1847,8 @@ mod tokenomic {
                 treasury_ration,
                 payout_ration,
                 heartbeat_window: params.heartbeat_window,
+                // Not in the on-chain parameters yet
+                conf_scores: DEFAULT_CONF_SCORES,
             }
         }
     }
@@ -1380,6 +1864,7 @@ mod tokenomic {
             treasury_ration: fp!(0.2),
             payout_ration: fp!(0.8),
             heartbeat_window: 10,
+            conf_scores: DEFAULT_CONF_SCORES,
         }
     }
 
@@ -1427,7 +1912,7 @@ mod tokenomic {
                 // May receive more than one heartbeat for a single worker in a single block.
                 return NO_UPDATE;
             }
-            let share = self.share();
+            let share = self.share(params);
             if share == fp!(0) {
                 return NO_UPDATE;
             }
@@ -1498,8 +1983,9 @@ mod tokenomic {
             }
         }
 
-        pub fn share(&self) -> FixedPoint {
-            (square(self.v) + square(fp!(2) * self.p() * conf_score(self.confidence_level))).sqrt()
+        pub fn share(&self, params: &Params) -> FixedPoint {
+            let conf_score = params.conf_score(self.confidence_level);
+            (square(self.v) + square(fp!(2) * self.p() * conf_score)).sqrt()
         }
 
         pub fn update_p_instant(&mut self, now: u64, iterations: u64, contract_running: bool) {
@@ -1617,21 +2103,25 @@ pub mod tests {
     struct Roles {
         mq: MessageDispatcher,
         gk: ComputingEconomics<CollectChannel>,
-        workers: [WorkerPublicKey; 2],
+        workers: Vec<WorkerPublicKey>,
     }
 
     impl Roles {
         fn test_roles() -> Roles {
+            Self::with_workers(2)
+        }
+
+        /// The workers are seeded with 0x01, 0x02, ... up to `n`.
+        fn with_workers(n: u8) -> Roles {
             let mut mq = MessageDispatcher::new();
             let egress = CollectChannel::default();
             let gk = ComputingEconomics::new(&mut mq, egress);
             Roles {
                 mq,
                 gk,
-                workers: [
-                    WorkerPublicKey::from_raw([0x01u8; 32]),
-                    WorkerPublicKey::from_raw([0x02u8; 32]),
-                ],
+                workers: (1..=n)
+                    .map(|seed| WorkerPublicKey::from_raw([seed; 32]))
+                    .collect(),
             }
         }
 
@@ -1695,15 +2185,60 @@ pub mod tests {
         }
     }
 
+    /// The chain storage with the workers of `Roles::test_roles` registered.
+    fn registered_workers_storage() -> crate::ChainStorage {
+        registered_workers_storage_of(2)
+    }
+
+    fn registered_workers_storage_of(n: u8) -> crate::ChainStorage {
+        use crate::light_validation::utils::storage_map_prefix_twox_64_concat;
+
+        let pairs = (1..=n).map(|seed| {
+            let pubkey = WorkerPublicKey::from_raw([seed; 32]);
+            let info = chain::pallet_registry::WorkerInfoV2::<chain::AccountId> {
+                pubkey,
+                ecdh_pubkey: phala_types::EcdhPublicKey::from_raw([seed; 32]),
+                runtime_version: 0,
+                last_updated: 0,
+                operator: None,
+                attestation_provider: None,
+                confidence_level: 2,
+                initial_score: None,
+                features: vec![],
+            };
+            (
+                storage_map_prefix_twox_64_concat(b"PhalaRegistry", b"Workers", &pubkey),
+                info.encode(),
+            )
+        });
+        crate::ChainStorage::from_pairs(pairs)
+    }
+
     fn with_block(block_number: chain::BlockNumber, call: impl FnOnce(&BlockInfo)) {
-        // GK never use the storage ATM.
-        let storage = crate::ChainStorage::default();
+        // GK only checks the worker registrations in the storage ATM.
+        with_block_storage(block_number, &registered_workers_storage(), call)
+    }
+
+    fn with_block_storage(
+        block_number: chain::BlockNumber,
+        storage: &crate::ChainStorage,
+        call: impl FnOnce(&BlockInfo),
+    ) {
+        with_block_at(block_number, block_ts(block_number), storage, call)
+    }
+
+    fn with_block_at(
+        block_number: chain::BlockNumber,
+        now_ms: u64,
+        storage: &crate::ChainStorage,
+        call: impl FnOnce(&BlockInfo),
+    ) {
         let mut recv_mq = phala_mq::MessageDispatcher::new();
         let mut send_mq = phala_mq::MessageSendQueue::new();
         let block = BlockInfo {
             block_number,
-            now_ms: block_ts(block_number),
-            storage: &storage,
+            now_ms,
+            storage,
             recv_mq: &mut recv_mq,
             send_mq: &mut send_mq,
         };
@@ -1714,6 +2249,229 @@ pub mod tests {
         block_number as u64 * 12000
     }
 
+    #[test]
+    fn gk_should_use_the_tokenomic_params_on_chain() {
+        use crate::light_validation::utils::storage_prefix;
+
+        let on_chain = msg::TokenomicParameters {
+            pha_rate: fp!(1).to_bits(),
+            // Far from the default, so that the V updates tell which one is in use
+            rho: fp!(1.001).to_bits(),
+            budget_per_block: fp!(100).to_bits(),
+            v_max: fp!(30000).to_bits(),
+            cost_k: fp!(0.000000015815258751856933056).to_bits(),
+            cost_b: fp!(0.000033711472602739674283).to_bits(),
+            slash_rate: fp!(0.0000033333333333333240063).to_bits(),
+            treasury_ratio: fp!(0.2).to_bits(),
+            heartbeat_window: 10,
+            rig_k: fp!(0.3).to_bits(),
+            rig_b: fp!(0).to_bits(),
+            re: fp!(1.5).to_bits(),
+            k: fp!(50).to_bits(),
+            kappa: fp!(1).to_bits(),
+        };
+        let mut storage = registered_workers_storage();
+        storage.load(
+            [(
+                storage_prefix("PhalaComputation", "TokenomicParameters"),
+                on_chain.encode(),
+            )]
+            .into_iter(),
+        );
+
+        // Not loaded until the consensus version switches
+        let mut r = Roles::test_roles();
+        with_block_storage(1, &storage, |block| {
+            r.gk.test_process_messages(block);
+        });
+        assert!(!r.gk.tokenomic_params_loaded);
+
+        storage.load(
+            [(
+                storage_prefix("PhalaRegistry", "PRuntimeConsensusVersion"),
+                super::super::TOKENOMIC_PARAMS_FROM_STORAGE_SINCE.encode(),
+            )]
+            .into_iter(),
+        );
+        let mut r = Roles::test_roles();
+        with_block_storage(1, &storage, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        with_block_storage(2, &storage, |block| {
+            r.for_worker(0).pallet_say(msg::WorkerEvent::Started {
+                session_id: 1,
+                init_v: fp!(1).to_bits(),
+                init_p: 100,
+            });
+            r.gk.test_process_messages(block);
+        });
+
+        let mut expected = r.get_worker(0).tokenomic;
+        expected.update_v_idle(&on_chain.into());
+        let mut with_default_params = r.get_worker(0).tokenomic;
+        with_default_params.update_v_idle(&super::tokenomic::test_params());
+        assert_ne!(expected.v, with_default_params.v);
+
+        // Idle, no event
+        with_block_storage(3, &storage, |block| {
+            r.gk.test_process_messages(block);
+        });
+        assert_eq!(r.get_worker(0).tokenomic.v, expected.v);
+    }
+
+    #[test]
+    fn gk_should_reject_spoofed_worker_registration() {
+        use crate::light_validation::utils::storage_prefix;
+
+        let stranger = WorkerPublicKey::from_raw([0x03u8; 32]);
+        let spoof_registration = |consensus_version: u32| {
+            let mut r = Roles::test_roles();
+            let mut storage = registered_workers_storage();
+            storage.load(
+                [(
+                    storage_prefix("PhalaRegistry", "PRuntimeConsensusVersion"),
+                    consensus_version.encode(),
+                )]
+                .into_iter(),
+            );
+            with_block_storage(1, &storage, |block| {
+                let sender = MessageOrigin::Pallet(b"Pallet".to_vec());
+                let message = msg::SystemEvent::new_worker_event(
+                    stranger,
+                    msg::WorkerEvent::Registered(msg::WorkerInfo {
+                        attestation_provider: None,
+                        confidence_level: 2,
+                    }),
+                );
+                r.mq.dispatch_bound(&sender, message);
+                r.for_worker(0)
+                    .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                        attestation_provider: None,
+                        confidence_level: 2,
+                    }));
+                r.gk.test_process_messages(block);
+            });
+            r
+        };
+
+        // The registrations are not checked until the consensus version switches.
+        let r = spoof_registration(super::super::WORKER_REGISTRATION_CHECK_SINCE - 1);
+        assert_eq!(r.gk.workers.len(), 2);
+        assert!(r.gk.workers.contains_key(&stranger));
+
+        let r = spoof_registration(super::super::WORKER_REGISTRATION_CHECK_SINCE);
+        assert_eq!(r.gk.workers.len(), 1);
+        assert!(!r.gk.workers.contains_key(&stranger));
+        assert!(r.get_worker(0).state.registered);
+    }
+
+    #[test]
+    fn gk_should_detect_hashed_id_collisions() {
+        let mut r = Roles::test_roles();
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+
+        let worker1 = super::WorkerInfo::new(r.workers[1]);
+        assert_eq!(r.gk.hashed_id_collision(&worker1), None);
+        // A tracked worker doesn't collide with itself.
+        let worker0 = super::WorkerInfo::new(r.workers[0]);
+        assert_eq!(r.gk.hashed_id_collision(&worker0), None);
+
+        // Fake a blake2 collision.
+        let mut colliding = super::WorkerInfo::new(r.workers[1]);
+        colliding.state.hashed_id = r.get_worker(0).state.hashed_id;
+        assert_eq!(r.gk.hashed_id_collision(&colliding), Some(r.workers[0]));
+
+        // The colliding worker is still tracked.
+        r.gk.add_worker(colliding);
+        assert_eq!(r.gk.workers.len(), 2);
+
+        // Untracking the colliding worker keeps the index of the original one.
+        r.gk.remove_worker(&r.workers[1]);
+        assert_eq!(r.gk.hashed_ids.len(), 1);
+        let mut colliding = super::WorkerInfo::new(r.workers[1]);
+        colliding.state.hashed_id = r.get_worker(0).state.hashed_id;
+        assert_eq!(r.gk.hashed_id_collision(&colliding), Some(r.workers[0]));
+
+        // Untracking the original one moves the index to the colliding one.
+        r.gk.add_worker(colliding);
+        r.gk.remove_worker(&r.workers[0]);
+        assert_eq!(r.gk.hashed_ids.len(), 1);
+        let mut another = super::WorkerInfo::new(WorkerPublicKey::from_raw([0x03u8; 32]));
+        another.state.hashed_id = r.get_worker(1).state.hashed_id;
+        assert_eq!(r.gk.hashed_id_collision(&another), Some(r.workers[1]));
+
+        r.gk.remove_worker(&r.workers[1]);
+        assert!(r.gk.hashed_ids.is_empty());
+    }
+
+    #[test]
+    fn gk_should_rebuild_the_hashed_id_index_once_lost() {
+        let mut r = Roles::test_roles();
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        // As if restored from a checkpoint, where the index is not saved.
+        r.gk.hashed_ids.clear();
+        r.gk.rebuild_hashed_ids();
+
+        let mut colliding = super::WorkerInfo::new(r.workers[1]);
+        colliding.state.hashed_id = r.get_worker(0).state.hashed_id;
+        assert_eq!(r.gk.hashed_id_collision(&colliding), Some(r.workers[0]));
+        r.gk.add_worker(colliding);
+        assert_eq!(r.gk.hashed_ids.len(), 1);
+        assert_eq!(
+            r.gk.hashed_ids.get(&r.get_worker(0).state.hashed_id),
+            Some(&r.workers[0])
+        );
+    }
+
+    #[test]
+    fn gk_workers_can_be_resynced_from_chain() {
+        let mut r = Roles::test_roles();
+        let phantom = WorkerPublicKey::from_raw([0x03u8; 32]);
+
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 1,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        // Diverge from the chain: a phantom worker, and worker 1 missing.
+        r.gk.workers
+            .insert(phantom, super::WorkerInfo::new(phantom));
+        assert_eq!(r.gk.workers.len(), 2);
+
+        let storage = registered_workers_storage();
+        assert_eq!(r.gk.resync_from_chain(&storage), Some((1, 1)));
+        assert!(!r.gk.workers.contains_key(&phantom));
+        // The tracked worker is kept as is.
+        assert_eq!(r.get_worker(0).tokenomic.confidence_level, 1);
+        assert!(r.get_worker(1).state.registered);
+        assert_eq!(r.get_worker(1).tokenomic.confidence_level, 2);
+
+        // Refused once reconciled.
+        assert_eq!(r.gk.resync_from_chain(&storage), None);
+    }
+
     #[test]
     fn gk_should_be_able_to_observe_worker_states() {
         let mut r = Roles::test_roles();
@@ -1965,9 +2723,163 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn gk_should_ignore_heartbeats_of_ended_sessions() {
+        let mut r = Roles::test_roles();
+
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        with_block(2, |block| {
+            let mut worker0 = r.for_worker(0);
+            worker0.pallet_say(msg::WorkerEvent::Started {
+                session_id: 1,
+                init_v: fp!(1).to_bits(),
+                init_p: 100,
+            });
+            worker0.challenge();
+            r.gk.test_process_messages(block);
+        });
+
+        // A new session starts before the worker answers the challenge of the previous one.
+        r.gk.egress.clear();
+        with_block(3, |block| {
+            let mut worker0 = r.for_worker(0);
+            worker0.pallet_say(msg::WorkerEvent::Stopped);
+            worker0.pallet_say(msg::WorkerEvent::Started {
+                session_id: 2,
+                init_v: fp!(2).to_bits(),
+                init_p: 100,
+            });
+            worker0.challenge();
+            r.gk.test_process_messages(block);
+        });
+        {
+            // The final V of the first session is settled on stop.
+            let messages = r.gk.egress.drain_working_info_update_event();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].settle.len(), 1);
+            assert_eq!(messages[0].settle[0].payout, 0);
+        }
+        assert_eq!(r.get_worker(0).waiting_heartbeats, vec![2, 3]);
+
+        // The late heartbeat is consumed without poisoning the state, and settles nothing since
+        // its session has been settled already.
+        with_block(4, |block| {
+            r.for_worker(0).heartbeat(1, 2, 10000000);
+            r.gk.test_process_messages(block);
+        });
+        assert!(r.gk.egress.drain_working_info_update_event().is_empty());
+        assert_eq!(r.get_worker(0).waiting_heartbeats, vec![3]);
+        let anomalies = super::HeartbeatAnomalies {
+            stale: 1,
+            unexpected: 0,
+        };
+        assert_eq!(r.gk.heartbeat_anomalies(), vec![(r.workers[0], anomalies)]);
+
+        with_block(5, |block| {
+            r.for_worker(0).heartbeat(2, 3, 10000000);
+            r.gk.test_process_messages(block);
+        });
+        let messages = r.gk.egress.drain_working_info_update_event();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].settle.len(), 1);
+        assert!(r.get_worker(0).waiting_heartbeats.is_empty());
+        assert_eq!(r.get_worker(0).stale_heartbeats, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "GK or Worker state poisoned")]
+    fn gk_should_abort_on_unexpected_heartbeats_by_default() {
+        let mut r = Roles::test_roles();
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        with_block(2, |block| {
+            let mut worker = r.for_worker(0);
+            worker.pallet_say(msg::WorkerEvent::Started {
+                session_id: 1,
+                init_v: fp!(1).to_bits(),
+                init_p: 100,
+            });
+            worker.challenge();
+            r.gk.test_process_messages(block);
+        });
+        with_block(3, |block| {
+            // Answers a challenge never sent
+            r.for_worker(0).heartbeat(1, 1, 10000000);
+            r.gk.test_process_messages(block);
+        });
+    }
+
+    #[test]
+    fn gk_should_drop_unexpected_heartbeats_if_allowed_to_continue() {
+        let mut r = Roles::test_roles();
+        r.gk.set_poisoned_state_policy(PoisonedStatePolicy::LogAndContinue);
+        with_block(1, |block| {
+            for n in 0..2 {
+                r.for_worker(n)
+                    .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                        attestation_provider: None,
+                        confidence_level: 2,
+                    }));
+            }
+            r.gk.test_process_messages(block);
+        });
+        with_block(2, |block| {
+            for n in 0..2 {
+                let mut worker = r.for_worker(n);
+                worker.pallet_say(msg::WorkerEvent::Started {
+                    session_id: 1,
+                    init_v: fp!(1).to_bits(),
+                    init_p: 100,
+                });
+                worker.challenge();
+            }
+            r.gk.test_process_messages(block);
+        });
+
+        r.gk.egress.clear();
+        with_block(3, |block| {
+            // Answers a challenge never sent
+            r.for_worker(0).heartbeat(1, 1, 10000000);
+            r.for_worker(1).heartbeat(1, 2, 10000000);
+            r.gk.test_process_messages(block);
+        });
+
+        assert_eq!(r.get_worker(0).unexpected_heartbeats, 1);
+        assert_eq!(r.get_worker(0).waiting_heartbeats, vec![2]);
+        assert!(r.get_worker(1).waiting_heartbeats.is_empty());
+        let messages = r.gk.egress.drain_working_info_update_event();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].settle.len(), 1);
+        assert_eq!(messages[0].settle[0].pubkey, r.workers[1]);
+
+        // The worker can still answer the challenge it was sent
+        with_block(4, |block| {
+            r.for_worker(0).heartbeat(1, 2, 10000000);
+            r.gk.test_process_messages(block);
+        });
+        assert!(r.get_worker(0).waiting_heartbeats.is_empty());
+    }
+
+    /// Governance can change the heartbeat window, so the timeouts are not tested with the default.
+    const NON_DEFAULT_HEARTBEAT_WINDOW: u32 = 25;
+
     #[test]
     fn gk_should_slash_and_report_offline_workers_case3() {
         let mut r = Roles::test_roles();
+        r.gk.tokenomic_params.heartbeat_window = NON_DEFAULT_HEARTBEAT_WINDOW;
         let mut block_number = 1;
 
         // Register worker
@@ -2050,6 +2962,7 @@ pub mod tests {
     #[test]
     fn gk_should_slash_offline_workers_sliently_case4() {
         let mut r = Roles::test_roles();
+        r.gk.tokenomic_params.heartbeat_window = NON_DEFAULT_HEARTBEAT_WINDOW;
         let mut block_number = 1;
 
         // Register worker
@@ -2124,6 +3037,7 @@ pub mod tests {
     #[test]
     fn gk_should_report_recovered_workers_case5() {
         let mut r = Roles::test_roles();
+        r.gk.tokenomic_params.heartbeat_window = NON_DEFAULT_HEARTBEAT_WINDOW;
         let mut block_number = 1;
 
         // Register worker
@@ -2192,6 +3106,248 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn gk_should_time_out_heartbeats_by_block_count_on_irregular_chains() {
+        let mut r = Roles::test_roles();
+        let storage = registered_workers_storage();
+        let window = r.gk.tokenomic_params.heartbeat_window;
+
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        with_block(2, |block| {
+            let mut worker0 = r.for_worker(0);
+            worker0.pallet_say(msg::WorkerEvent::Started {
+                session_id: 1,
+                init_v: fp!(1).to_bits(),
+                init_p: 100,
+            });
+            worker0.challenge();
+            r.gk.test_process_messages(block);
+        });
+
+        // A stalled chain: the last block of the window comes an hour later.
+        let last_in_window = 2 + window;
+        let stalled_ms = block_ts(last_in_window) + 3_600_000;
+        with_block_at(last_in_window, stalled_ms, &storage, |block| {
+            r.gk.test_process_messages(block);
+        });
+        assert!(!r.get_worker(0).unresponsive);
+
+        // A fast chain: the timeout block comes right after.
+        with_block_at(last_in_window + 1, stalled_ms + 1, &storage, |block| {
+            r.gk.test_process_messages(block);
+        });
+        assert!(r.get_worker(0).unresponsive);
+    }
+
+    /// Renders a report with the pubkeys shortened and the settled amounts reduced to whether
+    /// they are paid, since the exact amounts are covered by the tokenomic tests.
+    fn describe_report(report: &WorkingInfoUpdateEvent) -> String {
+        use std::fmt::Write as _;
+
+        let short = |pubkey: &WorkerPublicKey| hex::encode(&pubkey.as_ref()[..2]);
+        let paid = |bits: u128| if bits == 0 { "zero" } else { "nonzero" };
+        let mut out = String::new();
+        writeln!(out, "block_number: {}", report.block_number).unwrap();
+        writeln!(out, "timestamp_ms: {}", report.timestamp_ms).unwrap();
+        let offline: Vec<_> = report.offline.iter().map(short).collect();
+        writeln!(out, "offline: {offline:?}").unwrap();
+        let recovered: Vec<_> = report.recovered_to_online.iter().map(short).collect();
+        writeln!(out, "recovered_to_online: {recovered:?}").unwrap();
+        writeln!(out, "settle:").unwrap();
+        for info in &report.settle {
+            writeln!(
+                out,
+                "  {}: payout {}, treasury {}",
+                short(&info.pubkey),
+                paid(info.payout),
+                paid(info.treasury)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn gk_should_report_a_mixed_block_in_one_event() {
+        // worker0 recovers, worker1 goes offline, worker2 and worker3 settle in the same block.
+        let mut r = Roles::with_workers(4);
+        r.gk.tokenomic_params.heartbeat_window = NON_DEFAULT_HEARTBEAT_WINDOW;
+        let storage = registered_workers_storage_of(4);
+        let mut block_number = 1;
+
+        // Register in the reverse order to make sure the report doesn't follow the events.
+        with_block_storage(block_number, &storage, |block| {
+            for n in (0..4).rev() {
+                r.for_worker(n)
+                    .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                        attestation_provider: None,
+                        confidence_level: 2,
+                    }));
+            }
+            r.gk.test_process_messages(block);
+        });
+
+        block_number += 1;
+        with_block_storage(block_number, &storage, |block| {
+            for n in (0..4).rev() {
+                let mut worker = r.for_worker(n);
+                worker.pallet_say(msg::WorkerEvent::Started {
+                    session_id: 1,
+                    init_v: fp!(1).to_bits(),
+                    init_p: 100,
+                });
+            }
+            r.for_worker(0).challenge();
+            r.gk.test_process_messages(block);
+        });
+        let challenge_block0 = block_number;
+
+        block_number += 1;
+        with_block_storage(block_number, &storage, |block| {
+            r.for_worker(1).challenge();
+            r.gk.test_process_messages(block);
+        });
+
+        block_number += 1;
+        with_block_storage(block_number, &storage, |block| {
+            r.for_worker(3).challenge();
+            r.for_worker(2).challenge();
+            r.gk.test_process_messages(block);
+        });
+        let challenge_block23 = block_number;
+
+        // worker0 times out.
+        block_number = challenge_block0 + r.gk.tokenomic_params.heartbeat_window + 1;
+        with_block_storage(block_number, &storage, |block| {
+            r.gk.test_process_messages(block);
+        });
+        assert!(r.get_worker(0).unresponsive);
+        assert!(!r.get_worker(1).unresponsive);
+        r.gk.egress.clear();
+
+        // worker1 times out while the others respond.
+        block_number += 1;
+        with_block_storage(block_number, &storage, |block| {
+            r.for_worker(3).heartbeat(1, challenge_block23, 10000000);
+            r.for_worker(2).heartbeat(1, challenge_block23, 10000000);
+            r.for_worker(0).heartbeat(1, challenge_block0, 10000000);
+            r.gk.test_process_messages(block);
+        });
+
+        let messages = r.gk.egress.drain_working_info_update_event();
+        assert_eq!(messages.len(), 1, "Should report the block in one event");
+        let report = &messages[0];
+        for info in &report.settle {
+            let worker = &r.gk.workers[&info.pubkey];
+            assert_eq!(info.v, worker.tokenomic.v.to_bits());
+        }
+        insta::assert_display_snapshot!(describe_report(report));
+    }
+
+    #[test]
+    fn gk_should_export_worker_tokenomics() {
+        let mut r = Roles::test_roles();
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 4,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        with_block(2, |block| {
+            r.for_worker(0).pallet_say(msg::WorkerEvent::Started {
+                session_id: 1,
+                init_v: fp!(3000).to_bits(),
+                init_p: 100,
+            });
+            r.gk.test_process_messages(block);
+        });
+
+        let tokenomics = r.gk.worker_tokenomics();
+        assert_eq!(tokenomics.len(), 1);
+        let (pubkey, snapshot) = &tokenomics[0];
+        assert_eq!(*pubkey, r.workers[0]);
+        assert_eq!(snapshot.v, r.get_worker(0).tokenomic.v.to_bits());
+        assert_eq!(snapshot.v_init, fp!(3000).to_bits());
+        assert_eq!(snapshot.p_bench, fp!(100).to_bits());
+        assert_eq!(snapshot.p_instant, fp!(100).to_bits());
+        assert_eq!(snapshot.confidence_level, 4);
+        assert!(!snapshot.unresponsive);
+
+        let json = serde_json::to_value(snapshot).unwrap();
+        assert_eq!(json["v_init"], fp!(3000).to_bits().to_string());
+    }
+
+    #[test]
+    fn gk_should_clamp_implausible_bench_scores() {
+        let mut r = Roles::test_roles();
+        with_block(1, |block| {
+            r.for_worker(0)
+                .pallet_say(msg::WorkerEvent::Registered(msg::WorkerInfo {
+                    attestation_provider: None,
+                    confidence_level: 2,
+                }));
+            r.gk.test_process_messages(block);
+        });
+        with_block(2, |block| {
+            let mut worker0 = r.for_worker(0);
+            worker0.pallet_say(msg::WorkerEvent::BenchScore(u32::MAX));
+            worker0.pallet_say(msg::WorkerEvent::Started {
+                session_id: 1,
+                init_v: fp!(3000).to_bits(),
+                init_p: u32::MAX,
+            });
+            r.gk.test_process_messages(block);
+        });
+        let tokenomic = r.get_worker(0).tokenomic;
+        assert_eq!(tokenomic.p_bench, fp!(6000));
+        assert_eq!(tokenomic.p_instant, fp!(6000));
+    }
+
+    #[test]
+    fn conf_scores_weight_the_share() {
+        let mut params = super::tokenomic::test_params();
+        let mut tokenomic = super::TokenomicInfo::default();
+        tokenomic.v = fp!(3000);
+        tokenomic.p_bench = fp!(1000);
+        tokenomic.p_instant = fp!(1000);
+        tokenomic.confidence_level = 5;
+        let share = tokenomic.share(&params);
+        params.conf_scores[4] = fp!(1);
+        assert!(tokenomic.share(&params) > share);
+
+        // Level 128 follows level 1
+        tokenomic.confidence_level = 128;
+        let share = tokenomic.share(&params);
+        params.conf_scores[0] = fp!(0.5);
+        assert!(tokenomic.share(&params) < share);
+    }
+
+    #[test]
+    fn slash_rate_is_per_block() {
+        const BLOCKS_PER_HOUR: chain::BlockNumber = 3600 / 12;
+        let params = super::tokenomic::test_params();
+        let mut tokenomic = super::TokenomicInfo::default();
+        tokenomic.v = fp!(1000);
+        for block_number in 0..BLOCKS_PER_HOUR {
+            tokenomic.update_v_slash(&params, block_number);
+        }
+        // About 0.1% in an hour
+        let slashed = fp!(1000) - tokenomic.v;
+        assert!(
+            slashed > fp!(0.99) && slashed < fp!(1),
+            "Slashed {slashed} in an hour"
+        );
+    }
+
     #[test]
     fn check_tokenomic_numerics() {
         let mut r = Roles::test_roles();
@@ -2437,4 +3593,101 @@ pub mod tests {
         let fp_de: Wrapper = ciborium::de::from_reader(&*buf).unwrap();
         assert_eq!(fp.0, fp_de.0);
     }
+
+    #[test]
+    fn master_key_operations_are_audited() {
+        use super::{master_key, Gatekeeper, MasterKeyAuditEvent, RotatedMasterKey};
+        use phala_crypto::sr25519::{Persistence, KDF};
+        use phala_types::{EcdhPublicKey, WorkerIdentity};
+        use sp_core::{sr25519, Pair};
+
+        let master_key = sr25519::Pair::from_seed(&[1; 32]);
+        let history = vec![RotatedMasterKey {
+            rotation_id: 0,
+            block_height: 0,
+            secret: master_key.dump_secret_key(),
+        }];
+        let mut recv_mq = MessageDispatcher::new();
+        let send_mq = phala_mq::MessageSendQueue::new();
+        let egress = send_mq.channel(MessageOrigin::Gatekeeper, master_key.clone().into());
+        let mut gk = Gatekeeper::new(history, &mut recv_mq, egress);
+
+        let new_gk = sr25519::Pair::from_seed(&[2; 32]);
+        let new_gk = WorkerIdentity {
+            pubkey: new_gk.public(),
+            ecdh_pubkey: EcdhPublicKey(new_gk.derive_ecdh_key().unwrap().public()),
+        };
+        let _ = master_key::take_recorded_audit_events();
+
+        gk.share_master_key(&new_gk.pubkey, &new_gk.ecdh_pubkey, 10);
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Shared {
+                block_number: 10,
+                dest: new_gk.pubkey,
+                n_keys: 1,
+            }]
+        );
+
+        with_block(11, |block| {
+            gk.process_master_key_rotation_request(
+                block,
+                msg::RotateMasterKeyEvent {
+                    rotation_id: 1,
+                    gk_identities: vec![new_gk.clone()],
+                },
+                master_key.clone(),
+            )
+        });
+        let events = master_key::take_recorded_audit_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            MasterKeyAuditEvent::RotationGenerated {
+                block_number: 11,
+                rotation_id: 1,
+                ..
+            }
+        ));
+
+        let rotated_key = sr25519::Pair::from_seed(&[3; 32]);
+        assert!(gk.append_master_key(RotatedMasterKey {
+            rotation_id: 1,
+            block_height: 12,
+            secret: rotated_key.dump_secret_key(),
+        }));
+        assert!(gk.switch_master_key(1, 12));
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Rotated {
+                block_number: 12,
+                rotation_id: 1,
+                master_pubkey: rotated_key.public(),
+            }]
+        );
+    }
+
+    #[test]
+    fn stuck_master_pubkey_mismatch_is_tracked() {
+        use super::MasterPubkeyCheck;
+        use sp_core::{sr25519, Pair};
+
+        let local = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let rotated = sr25519::Pair::from_seed(&[2u8; 32]).public();
+        let mut check = MasterPubkeyCheck::default();
+
+        // Nothing uploaded on chain yet, or the keys match.
+        assert_eq!(check.update(10, &local, None), 0);
+        assert_eq!(check.update(20, &local, Some(&local)), 0);
+
+        // The local key rotated but the new pubkey never lands on chain.
+        assert_eq!(check.update(30, &rotated, Some(&local)), 0);
+        assert_eq!(check.update(40, &rotated, Some(&local)), 10);
+        assert_eq!(check.update(230, &rotated, Some(&local)), 200);
+        assert_eq!(check.mismatch_blocks(235), 205);
+
+        // Resolved once the chain catches up.
+        assert_eq!(check.update(240, &rotated, Some(&rotated)), 0);
+        assert_eq!(check.mismatch_blocks(250), 0);
+    }
 }
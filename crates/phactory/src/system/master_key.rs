@@ -1,16 +1,28 @@
 use std::path::PathBuf;
+use std::sync::Mutex;
 use std::vec::Vec;
 
+use anyhow::{bail, Context as _, Result};
 use parity_scale_codec::{Decode, Encode};
 use phala_types::{wrap_content_to_sign, SignedContentType};
-use sp_core::sr25519;
+use rand::RngCore;
+use sp_core::{sr25519, Pair};
 
-use phala_crypto::sr25519::{Signature, Signing, Sr25519SecretKey};
+use phala_crypto::sr25519::{Signature, Signing, Sr25519SecretKey, SEED_BYTES};
 
 use crate::pal::Sealing;
 
 /// Master key filepath
 pub const MASTER_KEY_FILE: &str = "master_key.seal";
+/// File written and removed to check the sealing path is writable.
+const WRITE_PROBE_FILE: &str = ".write_probe";
+
+/// Bytes drawn from the RNG to check its health before generating a master key.
+const ENTROPY_SAMPLE_BYTES: usize = 256;
+/// A run of identical bytes this long in the sample indicates a stuck RNG.
+const REPETITION_CUTOFF: usize = 6;
+/// A byte value occurring more than this many times in the sample indicates a biased RNG.
+const PROPORTION_CUTOFF: usize = 16;
 
 #[derive(Debug, Encode, Decode, Clone)]
 struct PersistentMasterKey {
@@ -25,6 +37,82 @@ pub struct RotatedMasterKey {
     pub secret: Sr25519SecretKey,
 }
 
+/// A master key lifecycle operation, recorded for security auditing.
+///
+/// It only carries public information and never any secret key material.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MasterKeyAuditEvent {
+    /// The first gatekeeper generated the genesis master key.
+    Generated {
+        block_number: chain::BlockNumber,
+        master_pubkey: sr25519::Public,
+    },
+    /// The master key (or its full history) was shared to a newly registered gatekeeper.
+    Shared {
+        block_number: chain::BlockNumber,
+        dest: sr25519::Public,
+        n_keys: usize,
+    },
+    /// This worker received the master key from a gatekeeper.
+    Received {
+        block_number: chain::BlockNumber,
+        master_pubkey: sr25519::Public,
+    },
+    /// This worker received the master key history from a gatekeeper.
+    HistoryReceived {
+        block_number: chain::BlockNumber,
+        n_keys: usize,
+    },
+    /// The local sealed master key history was updated.
+    HistoryUpdated {
+        block_number: chain::BlockNumber,
+        n_keys: usize,
+    },
+    /// A new master key was generated in response to a rotation request.
+    RotationGenerated {
+        block_number: chain::BlockNumber,
+        rotation_id: u64,
+        master_pubkey: sr25519::Public,
+    },
+    /// The master key was switched to a rotated one.
+    Rotated {
+        block_number: chain::BlockNumber,
+        rotation_id: u64,
+        master_pubkey: sr25519::Public,
+    },
+}
+
+type AuditSink = Box<dyn Fn(&MasterKeyAuditEvent) + Send>;
+
+static AUDIT_SINK: Mutex<Option<AuditSink>> = Mutex::new(None);
+
+#[cfg(test)]
+thread_local! {
+    static RECORDED_AUDIT_EVENTS: std::cell::RefCell<Vec<MasterKeyAuditEvent>> = Default::default();
+}
+
+/// Set an extra destination for the master key audit events.
+///
+/// The events are always logged under the `master_key_audit` target regardless of the sink.
+pub fn set_audit_sink(sink: impl Fn(&MasterKeyAuditEvent) + Send + 'static) {
+    *AUDIT_SINK.lock().unwrap() = Some(Box::new(sink));
+}
+
+pub(crate) fn audit(event: MasterKeyAuditEvent) {
+    info!(target: "master_key_audit", "{:?}", event);
+    if let Some(sink) = AUDIT_SINK.lock().unwrap().as_ref() {
+        sink(&event);
+    }
+    #[cfg(test)]
+    RECORDED_AUDIT_EVENTS.with(|events| events.borrow_mut().push(event));
+}
+
+/// Take the audit events emitted by the current thread so far.
+#[cfg(test)]
+pub(crate) fn take_recorded_audit_events() -> Vec<MasterKeyAuditEvent> {
+    RECORDED_AUDIT_EVENTS.with(|events| events.take())
+}
+
 #[derive(Debug, Encode, Decode, Clone)]
 struct MasterKeyHistory {
     rotations: Vec<RotatedMasterKey>,
@@ -43,6 +131,49 @@ enum MasterKeySeal {
     V2(PersistentMasterKeyHistory),
 }
 
+/// Check the health of the entropy source.
+///
+/// These are simplified versions of the repetition count and adaptive proportion tests from NIST
+/// SP 800-90B. The cutoffs are chosen so that a healthy RNG practically never fails them.
+fn check_entropy(rng: &mut impl RngCore) -> Result<()> {
+    let mut sample = [0_u8; ENTROPY_SAMPLE_BYTES];
+    rng.try_fill_bytes(&mut sample)
+        .context("Failed to read from the entropy source")?;
+
+    let mut run = 1;
+    for pair in sample.windows(2) {
+        run = if pair[0] == pair[1] { run + 1 } else { 1 };
+        if run >= REPETITION_CUTOFF {
+            let byte = pair[0];
+            bail!("Degraded entropy source: byte 0x{byte:02x} repeated {run} times");
+        }
+    }
+
+    let mut counts = [0_usize; 256];
+    for byte in sample {
+        counts[byte as usize] += 1;
+    }
+    if let Some((byte, count)) = counts
+        .iter()
+        .enumerate()
+        .find(|(_, count)| **count > PROPORTION_CUTOFF)
+    {
+        bail!(
+            "Degraded entropy source: byte 0x{byte:02x} occurred {count} times in {ENTROPY_SAMPLE_BYTES} bytes"
+        );
+    }
+    Ok(())
+}
+
+/// Generate a new master key from `rng`, failing if the entropy source looks degraded.
+pub(crate) fn generate(rng: &mut impl RngCore) -> Result<sr25519::Pair> {
+    check_entropy(rng)?;
+    let mut seed = [0_u8; SEED_BYTES];
+    rng.try_fill_bytes(&mut seed)
+        .context("Failed to read from the entropy source")?;
+    Ok(sr25519::Pair::from_seed(&seed))
+}
+
 fn master_key_file_path(sealing_path: &str) -> PathBuf {
     PathBuf::from(sealing_path).join(MASTER_KEY_FILE)
 }
@@ -70,6 +201,17 @@ pub fn seal(
         .expect("Seal master key failed");
 }
 
+/// Check that files can be sealed to `sealing_path`.
+///
+/// A master key generated by the first gatekeeper is lost forever if it can't be sealed, so this
+/// should be checked before any key generation can happen.
+pub(crate) fn ensure_sealing_path_writable(sealing_path: &str) -> Result<()> {
+    let probe = PathBuf::from(sealing_path).join(WRITE_PROBE_FILE);
+    std::fs::write(&probe, b"")
+        .and_then(|()| std::fs::remove_file(&probe))
+        .with_context(|| format!("The sealing path {sealing_path:?} is not writable"))
+}
+
 pub fn gk_master_key_exists(sealing_path: &str) -> bool {
     master_key_file_path(sealing_path).exists()
 }
@@ -1,5 +1,7 @@
 pub mod gk;
 mod master_key;
+mod processing_time;
+mod query_cache;
 
 use crate::{
     benchmark,
@@ -22,8 +24,9 @@ use crate::contracts;
 use crate::pal;
 use chain::pallet_fat::ContractRegistryEvent;
 use chain::pallet_registry::RegistryEvent;
-pub use master_key::{gk_master_key_exists, RotatedMasterKey};
+pub use master_key::{gk_master_key_exists, set_audit_sink, MasterKeyAuditEvent, RotatedMasterKey};
 use parity_scale_codec::{Decode, Encode};
+pub use phactory_api::ecall_args::PoisonedStatePolicy;
 pub use phactory_api::prpc::{GatekeeperRole, GatekeeperStatus, SystemInfo};
 use phala_crypto::{
     ecdh::EcdhKey,
@@ -31,8 +34,8 @@ use phala_crypto::{
     sr25519::{Persistence, KDF},
 };
 use phala_mq::{
-    traits::MessageChannel, BadOrigin, ContractId, MessageDispatcher, MessageOrigin,
-    MessageSendQueue, SignedMessageChannel, TypedReceiver,
+    traits::MessageChannel, BadOrigin, BindTopic, ContractId, MessageDispatcher, MessageOrigin,
+    MessageSendQueue, SignedMessage, SignedMessageChannel, TypedReceiver,
 };
 use phala_serde_more as more;
 use phala_types::{
@@ -47,23 +50,49 @@ use phala_types::{
     messaging::{
         AeadIV, BatchRotateMasterKeyEvent, DispatchMasterKeyEvent, DispatchMasterKeyHistoryEvent,
         GatekeeperChange, GatekeeperLaunch, HeartbeatChallenge, KeyDistribution,
-        NewGatekeeperEvent, RemoveGatekeeperEvent, RotateMasterKeyEvent, SystemEvent, WorkerEvent,
-        WorkingReportEvent,
+        MasterKeyReshareRequest, NewGatekeeperEvent, RemoveGatekeeperEvent, RotateMasterKeyEvent,
+        SystemEvent, WorkerEvent, WorkingReportEvent,
     },
     wrap_content_to_sign, EcdhPublicKey, HandoverChallenge, SignedContentType, WorkerPublicKey,
 };
+pub use processing_time::{Histogram, Phase, ProcessingTimes, SlowContract};
 use serde::{Deserialize, Serialize};
 use sidevm::service::{Command as SidevmCommand, CommandSender, Report, Spawner, SystemMessage};
-use sp_core::{hashing::blake2_256, sr25519, Pair, U256};
+use sp_core::{hashing::blake2_256, sr25519, Pair, H256, U256};
 
 use pink::runtime::{HookPoint, PinkEvent};
+use query_cache::{QueryCache, QueryKey};
 use std::cell::Cell;
 use std::convert::TryFrom;
 use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub type TransactionResult = Result<pink::runtime::ExecSideEffects, TransactionError>;
 
-pub(crate) const MAX_SUPPORTED_CONSENSUS_VERSION: u32 = 0;
+pub(crate) const MAX_SUPPORTED_CONSENSUS_VERSION: u32 = 5;
+
+/// The on-chain consensus version from which the contract registry events of a cluster in a block
+/// are sent in one `ContractRegistryEvent::Batch`.
+const BATCHED_REGISTRY_EVENTS_SINCE: u32 = 1;
+
+/// The on-chain consensus version from which the gatekeepers answer the `MasterKeyReshareRequest`s.
+const MASTER_KEY_RESHARE_SINCE: u32 = 2;
+
+/// The on-chain consensus version from which the workers report the deployed clusters with the
+/// cluster key fingerprint in `WorkerClusterReport::ClusterDeployedV2`.
+const CLUSTER_KEY_FINGERPRINT_SINCE: u32 = 3;
+
+/// The on-chain consensus version from which the gatekeepers only track the workers which are
+/// registered on chain.
+const WORKER_REGISTRATION_CHECK_SINCE: u32 = 4;
+
+/// The on-chain consensus version from which the gatekeepers load the tokenomic parameters from
+/// the chain storage at their first block.
+const TOKENOMIC_PARAMS_FROM_STORAGE_SINCE: u32 = 5;
+
+/// Blocks between two master key reshare requests of a gatekeeper which lost its master key.
+const MASTER_KEY_RESHARE_INTERVAL: chain::BlockNumber = 50;
 
 #[derive(Encode, Decode, Debug, Clone, thiserror::Error)]
 #[error("TransactionError: {:?}", self)]
@@ -110,6 +139,12 @@ pub enum TransactionError {
     NoClusterOnGatekeeper,
     NoPinkSystemCode,
     BadPinkSystemVersion,
+    ClusterKeyMismatch,
+    UnexpectedMasterKeyRecipient,
+    KeyDecryptionFailed,
+    InvalidMasterKeyHistory,
+    EcdhPubkeyMismatch,
+    MasterPubkeyMismatch,
 }
 
 impl From<BadOrigin> for TransactionError {
@@ -118,12 +153,19 @@ impl From<BadOrigin> for TransactionError {
     }
 }
 
+/// The longest benchmark accepted, about two hours. A longer one would keep the worker busy
+/// benchmarking rather than serving.
+const MAX_BENCH_DURATION: u32 = 600;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct BenchState {
     start_block: chain::BlockNumber,
     start_time: u64,
     start_iter: u64,
     duration: u32,
+    /// Started by the operator rather than requested by the chain.
+    #[serde(default)]
+    local: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -140,6 +182,46 @@ struct WorkingInfo {
     start_iter: u64,
 }
 
+/// A snapshot of the worker state machine, see `WorkerState::dump_state`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorkerStateDump {
+    #[serde(with = "more::pubkey_bytes")]
+    pub pubkey: WorkerPublicKey,
+    pub hashed_id: U256,
+    pub registered: bool,
+    pub bench_state: Option<BenchStateDump>,
+    pub working_state: Option<WorkingStateDump>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BenchStateDump {
+    pub start_block: chain::BlockNumber,
+    pub start_time: u64,
+    pub start_iter: u64,
+    pub duration: u32,
+    pub local: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorkingStateDump {
+    pub session_id: u32,
+    /// Paused while the worker is considered unresponsive.
+    pub paused: bool,
+    pub start_time: u64,
+    pub start_iter: u64,
+}
+
+/// Whether a worker responds to a heartbeat challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChallengeOutcome {
+    /// The hashed worker id xor the challenge seed.
+    pub x: U256,
+    /// Whether `x` is within the online target, so that the worker sends a heartbeat.
+    pub hit: bool,
+    /// The distance between `x` and the online target.
+    pub margin: U256,
+}
+
 // Minimum worker state machine can be reused to replay in GK.
 #[derive(Debug, Serialize, Deserialize)]
 struct WorkerState {
@@ -188,11 +270,20 @@ impl WorkerState {
                         self.registered = true;
                     }
                     BenchStart { duration } => {
+                        if duration > MAX_BENCH_DURATION {
+                            if log_on {
+                                error!(
+                                    "Benchmark duration {duration} exceeds {MAX_BENCH_DURATION}, ignored"
+                                );
+                            }
+                            return;
+                        }
                         self.bench_state = Some(BenchState {
                             start_block: block.block_number,
                             start_time: block.now_ms,
                             start_iter: callback.bench_iterations(),
                             duration,
+                            local: false,
                         });
                         callback.bench_resume();
                     }
@@ -202,6 +293,13 @@ impl WorkerState {
                         }
                     }
                     Started { session_id, .. } => {
+                        if matches!(self.bench_state, Some(BenchState { local: true, .. })) {
+                            // The score would change in the middle of the session otherwise.
+                            if log_on {
+                                warn!("Local benchmark cancelled by the computing session");
+                            }
+                            self.bench_state = None;
+                        }
                         self.working_state = Some(WorkingInfo {
                             session_id,
                             state: Computing,
@@ -286,11 +384,8 @@ impl WorkerState {
             return;
         }
 
-        let x = self.hashed_id ^ seed_info.seed;
-        let online_hit = x <= seed_info.online_target;
-
         // Push queue when necessary
-        if online_hit {
+        if self.challenge_outcome(seed_info).hit {
             let iterations = callback.bench_iterations() - working_state.start_iter;
             callback.heartbeat(
                 working_state.session_id,
@@ -301,6 +396,71 @@ impl WorkerState {
         }
     }
 
+    pub fn dump_state(&self) -> WorkerStateDump {
+        WorkerStateDump {
+            pubkey: self.pubkey,
+            hashed_id: self.hashed_id,
+            registered: self.registered,
+            bench_state: self.bench_state.as_ref().map(|bench| BenchStateDump {
+                start_block: bench.start_block,
+                start_time: bench.start_time,
+                start_iter: bench.start_iter,
+                duration: bench.duration,
+                local: bench.local,
+            }),
+            working_state: self.working_state.as_ref().map(|info| WorkingStateDump {
+                session_id: info.session_id,
+                paused: matches!(info.state, WorkingState::Paused),
+                start_time: info.start_time,
+                start_iter: info.start_iter,
+            }),
+        }
+    }
+
+    fn challenge_outcome(&self, seed_info: &HeartbeatChallenge) -> ChallengeOutcome {
+        let x = self.hashed_id ^ seed_info.seed;
+        let hit = x <= seed_info.online_target;
+        let margin = if hit {
+            seed_info.online_target - x
+        } else {
+            x - seed_info.online_target
+        };
+        ChallengeOutcome { x, hit, margin }
+    }
+
+    /// Start a benchmark without waiting for the chain to request it.
+    ///
+    /// The result is reported to the chain the same way as the benchmarks started by `BenchStart`.
+    fn start_local_bench(
+        &mut self,
+        block_number: chain::BlockNumber,
+        now_ms: u64,
+        duration: u32,
+        callback: &mut impl WorkerStateMachineCallback,
+    ) -> Result<(), &'static str> {
+        if !self.registered {
+            return Err("Worker not registered");
+        }
+        if self.working_state.is_some() {
+            return Err("Worker is computing");
+        }
+        if self.bench_state.is_some() {
+            return Err("Benchmark already running");
+        }
+        if duration == 0 || duration > MAX_BENCH_DURATION {
+            return Err("Benchmark duration out of range");
+        }
+        self.bench_state = Some(BenchState {
+            start_block: block_number,
+            start_time: now_ms,
+            start_iter: callback.bench_iterations(),
+            duration,
+            local: true,
+        });
+        callback.bench_resume();
+        Ok(())
+    }
+
     fn need_pause(&self) -> bool {
         self.bench_state.is_none() && self.working_state.is_none()
     }
@@ -316,11 +476,16 @@ impl WorkerState {
             start_time,
             start_iter,
             duration,
+            ..
         }) = self.bench_state
         {
             if block.block_number - start_block >= duration {
                 self.bench_state = None;
-                let iterations = callback.bench_iterations() - start_iter;
+                let iterations = bench_iterations_since(
+                    start_iter,
+                    callback.bench_iterations(),
+                    callback.max_bench_iterations(),
+                );
                 callback.bench_report(start_time, iterations);
                 if self.need_pause() {
                     callback.bench_pause();
@@ -330,10 +495,31 @@ impl WorkerState {
     }
 }
 
+/// The iterations of a benchmark started with the counter at `start_iter`.
+///
+/// A counter going backwards was reset during the benchmark, e.g. restored from an older
+/// checkpoint, so only the iterations since then are counted. The result is clamped to `max`.
+fn bench_iterations_since(start_iter: u64, counter: u64, max: Option<u64>) -> u64 {
+    let iterations = counter.checked_sub(start_iter).unwrap_or_else(|| {
+        warn!("Benchmark iteration counter went back from {start_iter} to {counter}");
+        counter
+    });
+    match max {
+        Some(max) if iterations > max => {
+            warn!("Implausible benchmark iterations {iterations}, clamped to {max}");
+            max
+        }
+        _ => iterations,
+    }
+}
+
 trait WorkerStateMachineCallback {
     fn bench_iterations(&self) -> u64 {
         0
     }
+    fn max_bench_iterations(&self) -> Option<u64> {
+        None
+    }
     fn bench_resume(&mut self) {}
     fn bench_pause(&mut self) {}
     fn bench_report(&mut self, _start_time: u64, _iterations: u64) {}
@@ -351,12 +537,16 @@ struct WorkerSMDelegate<'a> {
     egress: &'a SignedMessageChannel,
     n_clusters: u32,
     n_contracts: u32,
+    max_bench_iterations: Option<u64>,
 }
 
 impl WorkerStateMachineCallback for WorkerSMDelegate<'_> {
     fn bench_iterations(&self) -> u64 {
         benchmark::iteration_counter()
     }
+    fn max_bench_iterations(&self) -> Option<u64> {
+        self.max_bench_iterations
+    }
     fn bench_resume(&mut self) {
         benchmark::resume();
     }
@@ -397,15 +587,33 @@ impl WorkerStateMachineCallback for WorkerSMDelegate<'_> {
 #[serde(transparent)]
 pub(crate) struct WorkerIdentityKey(#[serde(with = "more::key_bytes")] sr25519::Pair);
 
-// By mocking the public key of the identity key pair, we can pretend to be the first Gatekeeper on Khala
-// for "shadow-gk" simulation.
+// By mocking the public key of the identity key pair, we can pretend to be an existing Gatekeeper
+// for "shadow-gk" simulation. The first Gatekeeper on Khala is impersonated by default, set the
+// env `SHADOW_GK_PUBKEY` to simulate another one.
 #[cfg(feature = "shadow-gk")]
 impl WorkerIdentityKey {
     pub(crate) fn public(&self) -> sr25519::Public {
+        static PUBKEY: once_cell::sync::Lazy<sr25519::Public> = once_cell::sync::Lazy::new(|| {
+            let configured = std::env::var("SHADOW_GK_PUBKEY").ok();
+            shadow_gk_pubkey(configured.as_deref())
+        });
+        *PUBKEY
+    }
+}
+
+#[cfg(feature = "shadow-gk")]
+fn shadow_gk_pubkey(configured: Option<&str>) -> sr25519::Public {
+    match configured {
+        Some(hex_str) => {
+            let raw = hex::decode(hex_str.trim_start_matches("0x"))
+                .expect("SHADOW_GK_PUBKEY should be a hex string");
+            let raw = <[u8; 32]>::try_from(raw).expect("SHADOW_GK_PUBKEY should be 32 bytes long");
+            sr25519::Public(raw)
+        }
         // The pubkey of the first GK on khala
-        sr25519::Public(hex_literal::hex!(
+        None => sr25519::Public(hex_literal::hex!(
             "60067697c486c809737e50d30a67480c5f0cede44be181b96f7d59bc2116a850"
-        ))
+        )),
     }
 }
 
@@ -422,6 +630,244 @@ fn get_contract_key(cluster_key: &sr25519::Pair, contract_id: &ContractId) -> sr
         .expect("should not fail with valid info")
 }
 
+/// What a client needs to know about a contract before sending commands to it.
+#[derive(Debug, Clone)]
+pub struct ContractInfoResponse {
+    pub cluster_id: phala_mq::ContractClusterId,
+    pub pubkey: sr25519::Public,
+    pub sidevm: Option<phactory_api::prpc::SidevmInfo>,
+}
+
+/// Find the cluster of a contract to query.
+///
+/// A missing cluster means the contract has outlived its cluster due to a bug, which only fails
+/// the query rather than panicking.
+fn cluster_for_query<'a>(
+    clusters: &'a mut ClusterKeeper,
+    contract: &contracts::FatContract,
+) -> Result<&'a mut Cluster, OpaqueError> {
+    let cluster_id = contract.cluster_id();
+    clusters.get_cluster_mut(&cluster_id).ok_or_else(|| {
+        error!(
+            "BUG: cluster {cluster_id:?} of contract {:?} not found",
+            contract.id()
+        );
+        OpaqueError::ClusterNotFound
+    })
+}
+
+fn remove_orphaned_contracts(
+    contracts: &mut ContractsKeeper,
+    clusters: &ClusterKeeper,
+    spawner: Option<&Spawner>,
+) -> Vec<ContractId> {
+    let orphaned: Vec<_> = contracts
+        .iter()
+        .filter(|(_, contract)| clusters.get_cluster(&contract.cluster_id()).is_none())
+        .map(|(id, _)| *id)
+        .collect();
+    for id in &orphaned {
+        if let Some(contract) = contracts.remove(id) {
+            error!(
+                "Removing contract {id:?} of missing cluster {:?}",
+                contract.cluster_id()
+            );
+            contract.destroy(spawner);
+        }
+    }
+    orphaned
+}
+
+/// Storage usage of a cluster in bytes.
+#[derive(Debug, Clone)]
+pub struct ClusterStorageUsage {
+    pub cluster_id: phala_mq::ContractClusterId,
+    pub usage: u64,
+}
+
+fn contract_info_response(
+    contract: &contracts::FatContract,
+    cluster_key: &sr25519::Pair,
+) -> ContractInfoResponse {
+    ContractInfoResponse {
+        cluster_id: contract.cluster_id(),
+        pubkey: get_contract_key(cluster_key, &contract.id()).public(),
+        sidevm: contract.sidevm_info(),
+    }
+}
+
+/// Monotonic counters of the key operations handled by this worker.
+///
+/// Monitoring can alert on unexpected jumps of the total.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyOperationCounter {
+    /// Master keys generated, shared, received or switched.
+    pub master_key: u64,
+    /// Cluster keys received.
+    pub cluster_key: u64,
+    /// Contract keys derived for newly instantiated contracts.
+    pub contract_key: u64,
+}
+
+impl KeyOperationCounter {
+    pub fn total(&self) -> u64 {
+        self.master_key
+            .saturating_add(self.cluster_key)
+            .saturating_add(self.contract_key)
+    }
+
+    fn record_master_key(&mut self) {
+        self.master_key = self.master_key.saturating_add(1);
+    }
+
+    fn record_cluster_key(&mut self) {
+        self.cluster_key = self.cluster_key.saturating_add(1);
+    }
+
+    fn record_contract_keys(&mut self, n: usize) {
+        self.contract_key = self.contract_key.saturating_add(n as u64);
+    }
+}
+
+/// A commitment to the full key material of a cluster.
+///
+/// Workers can publish it to let others check that they were deployed with the same cluster key,
+/// without revealing anything about the key.
+fn cluster_key_fingerprint(
+    cluster_id: &phala_mq::ContractClusterId,
+    cluster_key: &sr25519::Pair,
+) -> H256 {
+    let secret = cluster_key.dump_secret_key();
+    blake2_256(&(b"phala/cluster_key_fingerprint", cluster_id, &secret[..]).encode()).into()
+}
+
+fn check_master_key_recipient(
+    my_pubkey: &WorkerPublicKey,
+    is_local_gatekeeper: bool,
+    is_gatekeeper_on_chain: bool,
+) -> Result<(), TransactionError> {
+    if is_local_gatekeeper || is_gatekeeper_on_chain {
+        return Ok(());
+    }
+    error!(
+        "!!!! Received a master key as a normal worker {:?}, possible attack, rejected !!!!",
+        my_pubkey
+    );
+    Err(TransactionError::UnexpectedMasterKeyRecipient)
+}
+
+/// Only a registered gatekeeper may ask for the master key, and only to its registered ECDH key.
+fn check_master_key_reshare_request(
+    requester: &WorkerPublicKey,
+    is_gatekeeper_on_chain: bool,
+    registered_ecdh_pubkey: Option<&EcdhPublicKey>,
+    request: &MasterKeyReshareRequest,
+) -> Result<(), TransactionError> {
+    if !is_gatekeeper_on_chain {
+        error!(
+            "!!!! Worker {:?} requested the master key while not a gatekeeper, rejected !!!!",
+            requester
+        );
+        return Err(TransactionError::NotGatekeeper);
+    }
+    if registered_ecdh_pubkey != Some(&request.ecdh_pubkey) {
+        error!(
+            "!!!! Gatekeeper {:?} requested the master key to an unregistered ECDH key, rejected !!!!",
+            requester
+        );
+        return Err(TransactionError::EcdhPubkeyMismatch);
+    }
+    Ok(())
+}
+
+/// The latest key of a reshared master key history must be the master key on chain.
+fn check_reshared_master_key_history(
+    master_key_history: &[RotatedMasterKey],
+    block: &BlockInfo,
+) -> Result<(), TransactionError> {
+    let latest = master_key_history
+        .last()
+        .map(|key| sr25519::Pair::restore_from_secret_key(&key.secret).public());
+    let on_chain = block.storage.gatekeeper_master_pubkey();
+    if latest.is_none() || latest != on_chain {
+        error!(
+            "!!!! Reshared master key {:?} differs from the one on chain {:?}, rejected !!!!",
+            latest.map(hex::encode),
+            on_chain.map(hex::encode)
+        );
+        return Err(TransactionError::MasterPubkeyMismatch);
+    }
+    Ok(())
+}
+
+/// Tells a benign duplicated cluster deployment apart from one carrying a different key.
+fn cluster_redeploy_error(
+    cluster_id: &phala_mq::ContractClusterId,
+    existing_key: &sr25519::Pair,
+    incoming_key: &sr25519::Pair,
+) -> TransactionError {
+    if existing_key.dump_secret_key() == incoming_key.dump_secret_key() {
+        error!("Cluster {:?} is already deployed", cluster_id);
+        TransactionError::DuplicatedClusterDeploy
+    } else {
+        error!(
+            "!!!! Received a different key for the deployed cluster {:?}, possible key confusion attack !!!!",
+            cluster_id
+        );
+        TransactionError::ClusterKeyMismatch
+    }
+}
+
+/// Order a received master key history by the rotation ids rather than the transmission order.
+///
+/// The history must hold every rotation exactly once, starting from the genesis key.
+fn ordered_master_key_history(
+    mut history: Vec<RotatedMasterKey>,
+) -> Result<Vec<RotatedMasterKey>, TransactionError> {
+    history.sort_by_key(|key| key.rotation_id);
+    let complete = history
+        .iter()
+        .enumerate()
+        .all(|(index, key)| key.rotation_id == index as u64);
+    if history.is_empty() || !complete {
+        let rotation_ids: Vec<_> = history.iter().map(|key| key.rotation_id).collect();
+        error!("Invalid master key history, rotation ids: {rotation_ids:?}");
+        return Err(TransactionError::InvalidMasterKeyHistory);
+    }
+    Ok(history)
+}
+
+/// Decrypt a key dispatched to this worker, failing instead of panicking on a bad payload.
+fn decrypt_dispatched_key(
+    my_ecdh_key: &EcdhKey,
+    ecdh_pubkey: &EcdhPublicKey,
+    encrypted_key: &[u8],
+    iv: &AeadIV,
+) -> Result<sr25519::Pair, TransactionError> {
+    let secret = key_share::decrypt_secret_from(my_ecdh_key, &ecdh_pubkey.0, encrypted_key, iv)
+        .map_err(|err| {
+            error!("Failed to decrypt dispatched key: {:?}", err);
+            TransactionError::KeyDecryptionFailed
+        })?;
+    sr25519::Pair::from_seed_slice(&secret).map_err(|err| {
+        error!("Dispatched key is not a valid sr25519 secret: {:?}", err);
+        TransactionError::KeyDecryptionFailed
+    })
+}
+
+/// The report to the pallet about a failed cluster deployment.
+///
+/// A duplicated deployment leaves the cluster deployed, so it is not reported as a failure.
+fn cluster_deployment_failure_report(
+    cluster: phala_mq::ContractClusterId,
+    err: &anyhow::Error,
+) -> Option<WorkerClusterReport> {
+    match err.downcast_ref::<TransactionError>() {
+        Some(TransactionError::DuplicatedClusterDeploy) => None,
+        _ => Some(WorkerClusterReport::ClusterDeploymentFailed { id: cluster }),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct System<Platform> {
     platform: Platform,
@@ -429,6 +875,9 @@ pub struct System<Platform> {
     dev_mode: bool,
     pub(crate) sealing_path: String,
     pub(crate) storage_path: String,
+    /// In simulation mode, egress messages are recorded rather than sent.
+    #[serde(default)]
+    simulation: bool,
     // Messageing
     egress: SignedMessageChannel,
     system_events: TypedReceiver<SystemEvent>,
@@ -437,6 +886,8 @@ pub struct System<Platform> {
     key_distribution_events: TypedReceiver<KeyDistribution<chain::BlockNumber>>,
     cluster_key_distribution_events: TypedReceiver<ClusterOperation<chain::AccountId>>,
     contract_operation_events: TypedReceiver<ContractOperation<chain::Hash, chain::AccountId>>,
+    #[serde(default = "subscribe_master_key_reshare_requests")]
+    master_key_reshare_requests: TypedReceiver<MasterKeyReshareRequest>,
     // Worker
     pub(crate) identity_key: WorkerIdentityKey,
     #[serde(with = "ecdh_serde")]
@@ -447,12 +898,34 @@ pub struct System<Platform> {
     worker_state: WorkerState,
     // Gatekeeper
     pub(crate) gatekeeper: Option<gk::Gatekeeper<SignedMessageChannel>>,
+    /// Set when the local master key is found lost, until a gatekeeper shares it back.
+    #[serde(default)]
+    awaiting_master_key_reshare: bool,
 
     pub(crate) contracts: ContractsKeeper,
     pub(crate) contract_clusters: ClusterKeeper,
+    #[serde(default)]
+    key_operations: KeyOperationCounter,
+    #[serde(skip)]
+    query_cache: Arc<Mutex<QueryCache>>,
+    #[serde(skip)]
+    processing_times: ProcessingTimes,
+    /// Benchmark reports are clamped to this many iterations.
+    #[serde(skip)]
+    max_bench_iterations: Option<u64>,
+    /// Intended roles of the worker. Only metadata for fleet management tooling.
+    #[serde(default)]
+    role_tags: Vec<String>,
+    /// Safe mode. No block is dispatched and no message is processed.
+    #[serde(skip)]
+    paused: bool,
+    /// How to handle the conditions indicating the state is poisoned.
+    #[serde(skip)]
+    poisoned_state_policy: PoisonedStatePolicy,
+    /// None if the sidevm service failed to start, in which case sidevm is disabled.
     #[serde(skip)]
     #[serde(default = "create_sidevm_service_default")]
-    sidevm_spawner: Spawner,
+    sidevm_spawner: Option<Spawner>,
 
     // Cached for query
     pub(crate) block_number: BlockNumber,
@@ -471,19 +944,63 @@ pub fn sidevm_config(n_workers: usize) {
     N_WORKERS.with(|v| v.set(n_workers))
 }
 
-fn create_sidevm_service_default() -> Spawner {
-    create_sidevm_service(N_WORKERS.with(|n| n.get()))
+fn create_sidevm_service_default() -> Option<Spawner> {
+    sidevm_service_or_disabled(|| create_sidevm_service(N_WORKERS.with(|n| n.get())))
+}
+
+// Checkpoints taken before the reshare request was introduced have no such receiver.
+fn subscribe_master_key_reshare_requests() -> TypedReceiver<MasterKeyReshareRequest> {
+    phala_mq::checkpoint_helper::subscribe_default(MasterKeyReshareRequest::topic()).into()
+}
+
+/// Trim the tags and drop the empty or duplicated ones.
+fn normalize_role_tags(tags: Vec<String>) -> Vec<String> {
+    let mut tags: Vec<_> = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Degrade to running with sidevm disabled rather than failing when the service can not be created.
+fn sidevm_service_or_disabled(create: impl FnOnce() -> Result<Spawner>) -> Option<Spawner> {
+    match create() {
+        Ok(spawner) => Some(spawner),
+        Err(err) => {
+            error!("Failed to create the sidevm service, sidevm is disabled: {err:?}");
+            None
+        }
+    }
+}
+
+/// Handle an invariant violation indicating the state is poisoned according to `policy`.
+///
+/// Returns only if the policy is to continue, in which case the caller must drop the offending
+/// event.
+pub(crate) fn state_poisoned(policy: PoisonedStatePolicy, reason: &str) {
+    match policy {
+        PoisonedStatePolicy::Abort => panic!("{reason}"),
+        PoisonedStatePolicy::LogAndContinue => {
+            error!(
+                target: "phactory::poisoned_state_audit",
+                "{reason}! The offending event is dropped, the state might be inconsistent from now on"
+            );
+        }
+    }
 }
 
-fn create_sidevm_service(worker_threads: usize) -> Spawner {
-    let (service, spawner) = sidevm::service::service(worker_threads);
+fn create_sidevm_service(worker_threads: usize) -> Result<Spawner> {
+    let (service, spawner) = sidevm::service::try_service(worker_threads)?;
     spawner.spawn(service.run(|report| match report {
         Report::VmTerminated { id, reason } => {
             let id = hex_fmt::HexFmt(&id[..4]);
             info!("Sidevm {id} terminated with reason: {reason:?}");
         }
     }));
-    spawner
+    Ok(spawner)
 }
 
 impl<Platform: pal::Platform> System<Platform> {
@@ -500,19 +1017,25 @@ impl<Platform: pal::Platform> System<Platform> {
         recv_mq: &mut MessageDispatcher,
         contracts: ContractsKeeper,
         worker_threads: usize,
-    ) -> Self {
+        role_tags: Vec<String>,
+    ) -> Result<Self> {
         // Trigger panic early if platform is not properly implemented.
         let _ = Platform::app_version();
+        // Fail before any master key could be generated and then lost for being unable to seal it.
+        master_key::ensure_sealing_path_writable(&sealing_path)?;
 
         let identity_key = WorkerIdentityKey(identity_key);
         let pubkey = identity_key.public();
         let sender = MessageOrigin::Worker(pubkey);
+        let sidevm_spawner =
+            create_sidevm_service(worker_threads).context("Failed to create the sidevm service")?;
 
-        System {
+        Ok(System {
             platform,
             dev_mode,
             sealing_path,
             storage_path,
+            simulation: false,
             egress: send_mq.channel(sender, identity_key.clone().0.into()),
             system_events: recv_mq.subscribe_bound(),
             gatekeeper_launch_events: recv_mq.subscribe_bound(),
@@ -520,21 +1043,232 @@ impl<Platform: pal::Platform> System<Platform> {
             key_distribution_events: recv_mq.subscribe_bound(),
             cluster_key_distribution_events: recv_mq.subscribe_bound(),
             contract_operation_events: recv_mq.subscribe_bound(),
+            master_key_reshare_requests: recv_mq.subscribe_bound(),
             identity_key,
             ecdh_key,
             trusted_identity_key,
             last_challenge: None,
             worker_state: WorkerState::new(pubkey),
             gatekeeper: None,
+            awaiting_master_key_reshare: false,
             contracts,
             contract_clusters: Default::default(),
+            key_operations: Default::default(),
+            query_cache: Default::default(),
+            processing_times: Default::default(),
+            max_bench_iterations: None,
+            role_tags: normalize_role_tags(role_tags),
+            paused: false,
+            poisoned_state_policy: Default::default(),
             block_number: 0,
             now_ms: 0,
-            sidevm_spawner: create_sidevm_service(worker_threads),
+            sidevm_spawner: Some(sidevm_spawner),
             genesis_block: 0,
+        })
+    }
+
+    /// Enable or disable the simulation mode.
+    ///
+    /// All egress paths share the same send queue, so the worker, the gatekeeper and the contracts
+    /// are all silenced. The messages they push can be inspected via `simulated_egress`.
+    pub fn set_simulation(&mut self, simulation: bool) {
+        info!("System: set simulation mode to {simulation}");
+        self.simulation = simulation;
+        self.egress.send_queue().set_simulation(simulation);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause or resume the message processing.
+    ///
+    /// While paused, `dispatch_block` refuses the blocks, so the worker stays at the last block
+    /// processed and carries on from there once resumed.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused {
+            warn!("System: message processing paused");
+        } else {
+            info!("System: message processing resumed");
+        }
+        self.paused = paused;
+    }
+
+    pub fn worker_state_dump(&self) -> WorkerStateDump {
+        self.worker_state.dump_state()
+    }
+
+    /// Check whether a worker would respond to the given heartbeat challenge, this worker if
+    /// `pubkey` is not given.
+    ///
+    /// The registration and the computing state of the worker are not taken into account.
+    pub fn simulate_heartbeat_challenge(
+        &self,
+        pubkey: Option<WorkerPublicKey>,
+        challenge: &HeartbeatChallenge,
+    ) -> ChallengeOutcome {
+        match pubkey {
+            Some(pubkey) => WorkerState::new(pubkey).challenge_outcome(challenge),
+            None => self.worker_state.challenge_outcome(challenge),
+        }
+    }
+
+    /// Benchmark the worker again without the chain requesting it, e.g. after a hardware change.
+    ///
+    /// The benchmark lasts `duration` blocks from the last synced block, so it should be started
+    /// once the worker has caught up with the chain. Refused while computing. The report takes a
+    /// worker egress sequence which a replay of the blocks follows from the chain state.
+    pub fn start_local_benchmark(&mut self, duration: u32) -> Result<()> {
+        self.worker_state
+            .start_local_bench(
+                self.block_number,
+                self.now_ms,
+                duration,
+                &mut WorkerSMDelegate {
+                    egress: &self.egress,
+                    n_clusters: self.contract_clusters.len() as _,
+                    n_contracts: self.contracts.len() as _,
+                    max_bench_iterations: self.max_bench_iterations,
+                },
+            )
+            .map_err(|err| anyhow!(err))?;
+        info!(
+            "System: local benchmark started at block {} for {duration} blocks",
+            self.block_number
+        );
+        Ok(())
+    }
+
+    pub fn role_tags(&self) -> &[String] {
+        &self.role_tags
+    }
+
+    pub fn set_role_tags(&mut self, role_tags: Vec<String>) {
+        self.role_tags = normalize_role_tags(role_tags);
+        info!("System: set role tags to {:?}", self.role_tags);
+    }
+
+    pub fn key_operations(&self) -> KeyOperationCounter {
+        self.key_operations
+    }
+
+    pub fn processing_times(&self) -> &ProcessingTimes {
+        &self.processing_times
+    }
+
+    /// Flag the contracts taking longer than `threshold` to process a block, none if not given.
+    pub fn set_slow_contract_threshold(&mut self, threshold: Option<Duration>) {
+        self.processing_times.set_slow_contract_threshold(threshold);
+    }
+
+    pub fn set_max_bench_iterations(&mut self, max: Option<u64>) {
+        self.max_bench_iterations = max;
+    }
+
+    /// Set how to handle the conditions indicating the system or the gatekeeper state is poisoned.
+    pub fn set_poisoned_state_policy(&mut self, policy: PoisonedStatePolicy) {
+        self.poisoned_state_policy = policy;
+        if let Some(gatekeeper) = &mut self.gatekeeper {
+            gatekeeper.set_poisoned_state_policy(policy);
         }
     }
 
+    /// Reconcile the workers tracked by the gatekeeper with the registrations on chain.
+    ///
+    /// Only applied when the tracked workers are detected to mismatch the chain. Returns the
+    /// numbers of the added and removed workers.
+    pub fn resync_gatekeeper_from_chain(
+        &mut self,
+        chain_storage: &crate::ChainStorage,
+    ) -> Result<(usize, usize)> {
+        let gk = self
+            .gatekeeper
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not a gatekeeper"))?;
+        gk.resync_from_chain(chain_storage)
+            .ok_or_else(|| anyhow!("No mismatch between the GK workers and the chain detected"))
+    }
+
+    /// Blocks since the local master key started to differ from the on-chain master pubkey, None
+    /// if the worker is not a gatekeeper.
+    pub fn master_pubkey_mismatch_blocks(&self) -> Option<BlockNumber> {
+        self.gatekeeper
+            .as_ref()
+            .map(|gk| gk.master_pubkey_mismatch_blocks(self.block_number))
+    }
+
+    pub fn cluster_of_contract(
+        &self,
+        contract_id: &ContractId,
+    ) -> Option<phala_mq::ContractClusterId> {
+        self.contracts.cluster_of(contract_id)
+    }
+
+    /// Returns `None` if the contract is not deployed on this worker.
+    pub fn contract_info(&self, contract_id: &ContractId) -> Option<ContractInfoResponse> {
+        let contract = self.contracts.get(contract_id)?;
+        let cluster = self.contract_clusters.get_cluster(&contract.cluster_id())?;
+        Some(contract_info_response(contract, cluster.key()))
+    }
+
+    /// Approximate storage size of a contract in bytes.
+    ///
+    /// Returns `None` if the contract is not deployed on this worker.
+    pub fn contract_storage_size(&self, contract_id: &ContractId) -> Option<u64> {
+        let cluster_id = self.contracts.cluster_of(contract_id)?;
+        self.contract_clusters
+            .get_cluster(&cluster_id)?
+            .contract_storage_size(contract_id)
+    }
+
+    pub fn cluster_storage_usage(&self) -> Vec<ClusterStorageUsage> {
+        self.contract_clusters
+            .iter()
+            .map(|(cluster_id, cluster)| ClusterStorageUsage {
+                cluster_id: *cluster_id,
+                usage: cluster.storage_usage(),
+            })
+            .collect()
+    }
+
+    /// Enable caching the replies of identical queries to the contracts of a cluster within a
+    /// block. A capacity of 0 disables the cache.
+    ///
+    /// Returns false if the cluster is not deployed on this worker.
+    pub fn set_cluster_query_cache_capacity(
+        &mut self,
+        cluster_id: &phala_mq::ContractClusterId,
+        capacity: usize,
+    ) -> bool {
+        let Some(cluster) = self.contract_clusters.get_cluster_mut(cluster_id) else {
+            return false;
+        };
+        info!("Set query cache capacity of cluster {cluster_id:?} to {capacity}");
+        cluster.config.query_cache_capacity = capacity;
+        true
+    }
+
+    pub fn is_simulation(&self) -> bool {
+        self.simulation
+    }
+
+    /// The egress messages recorded in simulation mode.
+    pub fn simulated_egress(&self) -> Vec<SignedMessage> {
+        self.egress.send_queue().simulated_messages()
+    }
+
+    /// All the pending egress messages of the worker, ordered by sender and sequence.
+    ///
+    /// Used to carry the unsent messages over to the new worker on handover.
+    pub fn export_pending_egress(&self) -> Vec<SignedMessage> {
+        self.egress.send_queue().all_messages()
+    }
+
+    /// Re-enqueue the pending egress exported from the previous worker.
+    pub fn import_pending_egress(&self, messages: Vec<SignedMessage>) {
+        self.egress.send_queue().import_messages(messages);
+    }
+
     pub fn get_system_message_handler(&mut self, cluster_id: &ContractId) -> Option<CommandSender> {
         let handler_contract_id = self
             .contract_clusters
@@ -612,6 +1346,7 @@ impl<Platform: pal::Platform> System<Platform> {
         >,
         OpaqueError,
     > {
+        use futures::future::{ready, Either};
         use pink::storage::Snapshot as _;
 
         let contract = self
@@ -619,12 +1354,29 @@ impl<Platform: pal::Platform> System<Platform> {
             .get_mut(contract_id)
             .ok_or(OpaqueError::ContractNotFound)?;
         let cluster_id = contract.cluster_id();
-        let storage = self
-            .contract_clusters
-            .get_cluster_mut(&cluster_id)
-            .expect("BUG: contract cluster should always exists")
-            .storage
-            .snapshot();
+        let cluster = cluster_for_query(&mut self.contract_clusters, contract)?;
+        let cache_capacity = cluster.config.query_cache_capacity;
+        let cache_key = QueryKey {
+            contract_id: *contract_id,
+            origin: origin.cloned(),
+            query,
+        };
+        let block_number = self.block_number;
+        if cache_capacity > 0 {
+            let cached =
+                self.query_cache
+                    .lock()
+                    .unwrap()
+                    .get(block_number, &cluster_id, &cache_key);
+            if let Some(reply) = cached {
+                return Ok(Either::Left(ready(Ok((
+                    reply,
+                    cluster_id,
+                    ExecSideEffects::default(),
+                )))));
+            }
+        }
+        let storage = cluster.storage.snapshot();
         let sidevm_handle = contract.sidevm_handle();
         let weight = contract.weight();
         let contract = contract.snapshot_for_query();
@@ -637,13 +1389,27 @@ impl<Platform: pal::Platform> System<Platform> {
             query_scheduler,
             weight,
         };
-        let origin = origin.cloned();
-        Ok(async move {
-            contract
-                .handle_query(origin.as_ref(), query, &mut context)
-                .await
-                .map(|(reply, effects)| (reply, cluster_id, effects))
-        })
+        let query_cache = self.query_cache.clone();
+        Ok(Either::Right(async move {
+            let (reply, effects) = contract
+                .handle_query(
+                    cache_key.origin.as_ref(),
+                    cache_key.query.clone(),
+                    &mut context,
+                )
+                .await?;
+            // Queries with side effects are not cacheable.
+            if cache_capacity > 0 && effects.is_empty() {
+                query_cache.lock().unwrap().insert(
+                    block_number,
+                    cluster_id,
+                    cache_capacity,
+                    cache_key,
+                    reply.clone(),
+                );
+            }
+            Ok((reply, cluster_id, effects))
+        }))
     }
 
     pub fn process_next_message(&mut self, block: &mut BlockInfo) -> anyhow::Result<bool> {
@@ -669,6 +1435,11 @@ impl<Platform: pal::Platform> System<Platform> {
             (event, origin) = self.contract_operation_events => {
                 self.process_contract_operation_event(block, origin, event)?
             },
+            (event, origin) = self.master_key_reshare_requests => {
+                if let Err(err) = self.process_master_key_reshare_request(block, origin, event) {
+                    error!("Failed to process master key reshare request: {:?}", err);
+                }
+            },
         };
         Ok(ok.is_none())
     }
@@ -677,12 +1448,31 @@ impl<Platform: pal::Platform> System<Platform> {
         self.block_number = block.block_number;
         self.now_ms = block.now_ms;
 
+        if self.paused {
+            return;
+        }
+        self.sync_worker_egress_sequence(block);
         if let Some(gatekeeper) = &mut self.gatekeeper {
             gatekeeper.will_process_block(block);
         }
     }
 
+    /// Move the worker egress sequence forward to the one expected by the chain.
+    ///
+    /// Some worker messages are not driven by the blocks, e.g. the local benchmark reports, so
+    /// replaying the blocks doesn't reproduce them. Without this, the messages sent after them
+    /// would reuse the sequences already taken on chain and get dropped.
+    fn sync_worker_egress_sequence(&self, block: &BlockInfo) {
+        let sender = MessageOrigin::Worker(self.identity_key.public());
+        self.egress
+            .ensure_next_sequence(block.storage.mq_sequence(&sender));
+    }
+
     pub fn process_messages(&mut self, block: &mut BlockInfo) {
+        if self.paused {
+            return;
+        }
+        let start = Instant::now();
         loop {
             match self.process_next_message(block) {
                 Err(err) => {
@@ -695,9 +1485,19 @@ impl<Platform: pal::Platform> System<Platform> {
                 }
             }
         }
+        self.processing_times
+            .record(Phase::SystemEvents, start.elapsed());
+
+        let start = Instant::now();
         self.process_contract_messages(block);
+        self.processing_times
+            .record(Phase::Contracts, start.elapsed());
+
         if let Some(gatekeeper) = &mut self.gatekeeper {
+            let start = Instant::now();
             gatekeeper.process_messages(block);
+            self.processing_times
+                .record(Phase::Gatekeeper, start.elapsed());
         }
     }
 
@@ -709,6 +1509,7 @@ impl<Platform: pal::Platform> System<Platform> {
         // in the scope of entire `for loop` body.
         let contract_ids: Vec<_> = self.contracts.keys().cloned().collect();
         'outer: for key in contract_ids {
+            let start = Instant::now();
             // Inner loop to handle commands. One command per iteration and apply the command side-effects to make it
             // availabe for next command.
             loop {
@@ -727,24 +1528,41 @@ impl<Platform: pal::Platform> System<Platform> {
                     Some(result) => result,
                     None => break,
                 };
-                handle_contract_command_result(
+                let installed = handle_contract_command_result(
                     result,
                     cluster_id,
                     &mut self.contracts,
                     &mut self.contract_clusters,
                     block,
                     &self.egress,
-                    &self.sidevm_spawner,
+                    self.sidevm_spawner.as_ref(),
                     log_handler,
                     block.storage,
                 );
+                self.key_operations.record_contract_keys(installed);
             }
+            self.processing_times.record_contract(key, start.elapsed());
         }
     }
 
     pub fn did_process_block(&mut self, block: &mut BlockInfo) {
+        if self.paused {
+            return;
+        }
         if let Some(gatekeeper) = &mut self.gatekeeper {
+            let start = Instant::now();
             gatekeeper.did_process_block(block);
+            self.processing_times
+                .record(Phase::Gatekeeper, start.elapsed());
+        }
+        // The request takes the next egress sequence of the worker, so it is only sent once the
+        // chain has accepted all the earlier messages of the worker, and resent until any
+        // gatekeeper responds.
+        if self.awaiting_master_key_reshare
+            && block.block_number % MASTER_KEY_RESHARE_INTERVAL == 0
+            && self.worker_egress_accepted(block)
+        {
+            self.request_master_key_reshare();
         }
 
         self.worker_state.on_block_processed(
@@ -753,10 +1571,13 @@ impl<Platform: pal::Platform> System<Platform> {
                 egress: &self.egress,
                 n_clusters: self.contract_clusters.len() as _,
                 n_contracts: self.contracts.len() as _,
+                max_bench_iterations: self.max_bench_iterations,
             },
         );
+        let start = Instant::now();
         let contract_ids: Vec<_> = self.contracts.keys().cloned().collect();
         'outer: for key in contract_ids {
+            let contract_start = Instant::now();
             let log_handler = self.get_system_message_handler_for_contract_id(&key);
             let contract = match self.contracts.get_mut(&key) {
                 None => continue 'outer,
@@ -769,23 +1590,35 @@ impl<Platform: pal::Platform> System<Platform> {
             };
             let result = contract.on_block_end(&mut env);
             let cluster_id = contract.cluster_id();
-            handle_contract_command_result(
+            let installed = handle_contract_command_result(
                 result,
                 cluster_id,
                 &mut self.contracts,
                 &mut self.contract_clusters,
                 block,
                 &self.egress,
-                &self.sidevm_spawner,
+                self.sidevm_spawner.as_ref(),
                 log_handler,
                 block.storage,
             );
+            self.key_operations.record_contract_keys(installed);
+            self.processing_times
+                .record_contract(key, contract_start.elapsed());
         }
         if self.contracts.weight_changed {
             self.contracts.weight_changed = false;
             self.contracts.apply_local_cache_quotas();
         }
-        self.contracts.try_restart_sidevms(&self.sidevm_spawner);
+        send_registry_events(&mut self.contract_clusters, block);
+        self.processing_times
+            .record(Phase::Contracts, start.elapsed());
+
+        let start = Instant::now();
+        self.contracts
+            .try_restart_sidevms(self.sidevm_spawner.as_ref());
+        self.processing_times
+            .record(Phase::SidevmRestarts, start.elapsed());
+        self.processing_times.finish_block(block.block_number);
 
         let contract_running = !self.contract_clusters.is_empty();
         benchmark::set_flag(benchmark::Flags::CONTRACT_RUNNING, contract_running);
@@ -799,6 +1632,7 @@ impl<Platform: pal::Platform> System<Platform> {
                 egress: &self.egress,
                 n_clusters: self.contract_clusters.len() as _,
                 n_contracts: self.contracts.len() as _,
+                max_bench_iterations: self.max_bench_iterations,
             },
             true,
         );
@@ -825,6 +1659,10 @@ impl<Platform: pal::Platform> System<Platform> {
             .expect("checked; qed.")
             .set_master_key_history(&master_key_history)
         {
+            master_key::audit(MasterKeyAuditEvent::HistoryUpdated {
+                block_number: self.block_number,
+                n_keys: master_key_history.len(),
+            });
             master_key::seal(
                 self.sealing_path.clone(),
                 &master_key_history,
@@ -858,13 +1696,14 @@ impl<Platform: pal::Platform> System<Platform> {
                 .expect("empty master key history")
                 .secret,
         );
-        let gatekeeper = gk::Gatekeeper::new(
+        let mut gatekeeper = gk::Gatekeeper::new(
             master_key_history,
             block.recv_mq,
             block
                 .send_mq
                 .channel(MessageOrigin::Gatekeeper, master_key.into()),
         );
+        gatekeeper.set_poisoned_state_policy(self.poisoned_state_policy);
         self.gatekeeper = Some(gatekeeper);
 
         // TODO: clear up existing clusters
@@ -892,7 +1731,22 @@ impl<Platform: pal::Platform> System<Platform> {
                     block.block_number
                 );
                 if let Some(gatekeeper) = &mut self.gatekeeper {
-                    gatekeeper.master_pubkey_uploaded(event.master_pubkey);
+                    if !cfg!(feature = "shadow-gk")
+                        && gatekeeper.master_pubkey() != event.master_pubkey
+                    {
+                        // Only the first gatekeeper ends up here, with a master key it generated
+                        // afresh because its original master_key.seal was lost.
+                        error!(
+                            "Local master pubkey {} differs from the on-chain one {}, the sealed master key is lost",
+                            hex::encode(gatekeeper.master_pubkey()),
+                            hex::encode(event.master_pubkey)
+                        );
+                        self.gatekeeper = None;
+                        // Requested in `did_process_block`.
+                        self.awaiting_master_key_reshare = true;
+                    } else {
+                        gatekeeper.master_pubkey_uploaded(event.master_pubkey);
+                    }
                 }
             }
             GatekeeperLaunch::RotateMasterKey(event) => {
@@ -919,19 +1773,22 @@ impl<Platform: pal::Platform> System<Platform> {
         _origin: MessageOrigin,
         event: NewGatekeeperEvent,
     ) {
-        // ATTENTION: the first gk cannot resume if its original master_key.seal is lost,
-        // since there is no tx recorded on-chain that shares the key to itself
+        // ATTENTION: the first gk cannot resume from the chain alone if its original
+        // master_key.seal is lost, since there is no tx recorded on-chain that shares the key
+        // to itself. It would generate a different master key here instead.
         //
-        // Solution: always unregister the first gk after the second gk receives the key,
-        // thank god we only need to do this once for each blockchain
+        // Solution: the mismatch is detected once the master pubkey on chain is replayed, then
+        // the gk drops the generated key and asks the other gatekeepers to share the master key
+        // history back with a `MasterKeyReshareRequest`.
 
         // double check the first gatekeeper is valid on chain
-        if !chain_state::is_gatekeeper(&event.pubkey, block.storage) {
+        if !chain_state::is_gatekeeper(&event.pubkey, block) {
             error!(
                 "Fatal error: Invalid first gatekeeper registration {:?}",
                 event
             );
-            panic!("System state poisoned");
+            state_poisoned(self.poisoned_state_policy, "System state poisoned");
+            return;
         }
 
         let mut master_key_history = master_key::try_unseal(
@@ -945,12 +1802,18 @@ impl<Platform: pal::Platform> System<Platform> {
             if master_key_history.is_empty() {
                 info!("Gatekeeper: generate master key as the first gatekeeper");
                 // generate master key as the first gatekeeper, no need to restart
-                let master_key = crate::new_sr25519_key();
+                let master_key = master_key::generate(&mut rand::thread_rng())
+                    .unwrap_or_else(|err| panic!("Refused to generate the master key: {err:?}"));
                 master_key_history.push(RotatedMasterKey {
                     rotation_id: 0,
                     block_height: 0,
                     secret: master_key.dump_secret_key(),
                 });
+                master_key::audit(MasterKeyAuditEvent::Generated {
+                    block_number: block.block_number,
+                    master_pubkey: master_key.public(),
+                });
+                self.key_operations.record_master_key();
                 // manually seal the first master key for the first gk
                 master_key::seal(
                     self.sealing_path.clone(),
@@ -1008,6 +1871,7 @@ impl<Platform: pal::Platform> System<Platform> {
                 event,
                 self.identity_key.0.clone(),
             );
+            self.key_operations.record_master_key();
         }
     }
 
@@ -1042,16 +1906,18 @@ impl<Platform: pal::Platform> System<Platform> {
         }
 
         // double check the registered gatekeeper is valid on chain
-        if !chain_state::is_gatekeeper(&event.pubkey, block.storage) {
+        if !chain_state::is_gatekeeper(&event.pubkey, block) {
             error!(
                 "Fatal error: Invalid first gatekeeper registration {:?}",
                 event
             );
-            panic!("System state poisoned");
+            state_poisoned(self.poisoned_state_policy, "System state poisoned");
+            return;
         }
 
         if let Some(gatekeeper) = &mut self.gatekeeper {
             gatekeeper.share_master_key(&event.pubkey, &event.ecdh_pubkey, block.block_number);
+            self.key_operations.record_master_key();
 
             let my_pubkey = self.identity_key.public();
             if my_pubkey == event.pubkey {
@@ -1092,7 +1958,7 @@ impl<Platform: pal::Platform> System<Platform> {
     ) {
         match event {
             KeyDistribution::MasterKeyDistribution(event) => {
-                if let Err(err) = self.process_master_key_distribution(origin, event) {
+                if let Err(err) = self.process_master_key_distribution(block, origin, event) {
                     error!("Failed to process master key distribution event: {:?}", err);
                 };
             }
@@ -1105,7 +1971,7 @@ impl<Platform: pal::Platform> System<Platform> {
                 };
             }
             KeyDistribution::MasterKeyHistory(event) => {
-                if let Err(err) = self.process_master_key_history(origin, event) {
+                if let Err(err) = self.process_master_key_history(block, origin, event) {
                     error!("Failed to process master key history event: {:?}", err);
                 };
             }
@@ -1127,8 +1993,9 @@ impl<Platform: pal::Platform> System<Platform> {
                         "Failed to process cluster key distribution event: {:?}",
                         err
                     );
-                    let message = WorkerClusterReport::ClusterDeploymentFailed { id: cluster };
-                    self.egress.push_message(&message);
+                    if let Some(message) = cluster_deployment_failure_report(cluster, &err) {
+                        self.egress.push_message(&message);
+                    }
                 }
             }
             ClusterOperation::DestroyCluster(cluster_id) => {
@@ -1144,7 +2011,7 @@ impl<Platform: pal::Platform> System<Platform> {
                 info!("Destroying cluster {}", hex_fmt::HexFmt(&cluster_id));
                 for contract in cluster.iter_contracts() {
                     if let Some(contract) = self.contracts.remove(contract) {
-                        contract.destroy(&self.sidevm_spawner);
+                        contract.destroy(self.sidevm_spawner.as_ref());
                     }
                 }
             }
@@ -1334,17 +2201,18 @@ impl<Platform: pal::Platform> System<Platform> {
                             .contract_clusters
                             .get_cluster_mut(&cluster_id)
                             .expect("Cluster must exist");
-                        apply_pink_side_effects(
+                        let installed = apply_pink_side_effects(
                             effects,
                             cluster_id,
                             &mut self.contracts,
                             cluster,
                             block,
                             &self.egress,
-                            &self.sidevm_spawner,
+                            self.sidevm_spawner.as_ref(),
                             log_handler,
                             block.storage,
                         );
+                        self.key_operations.record_contract_keys(installed);
                     }
                 }
             }
@@ -1354,26 +2222,25 @@ impl<Platform: pal::Platform> System<Platform> {
 
     /// Decrypt the key encrypted by `encrypt_key_to()`
     ///
-    /// This function could panic a lot, thus should only handle data from other pRuntimes.
+    /// A forged or corrupted ciphertext, or one encrypted to another worker, fails with
+    /// `KeyDecryptionFailed`.
     fn decrypt_key_from(
         &self,
         ecdh_pubkey: &EcdhPublicKey,
         encrypted_key: &[u8],
         iv: &AeadIV,
-    ) -> sr25519::Pair {
+    ) -> Result<sr25519::Pair, TransactionError> {
         let my_ecdh_key = self
             .identity_key
             .derive_ecdh_key()
             .expect("Should never failed with valid identity key; qed.");
-        let secret =
-            key_share::decrypt_secret_from(&my_ecdh_key, &ecdh_pubkey.0, encrypted_key, iv)
-                .expect("Failed to decrypt dispatched key");
-        sr25519::Pair::restore_from_secret_key(&secret)
+        decrypt_dispatched_key(&my_ecdh_key, ecdh_pubkey, encrypted_key, iv)
     }
 
     /// Process encrypted master key from mq
     fn process_master_key_distribution(
         &mut self,
+        block: &mut BlockInfo,
         origin: MessageOrigin,
         event: DispatchMasterKeyEvent,
     ) -> Result<(), TransactionError> {
@@ -1384,20 +2251,32 @@ impl<Platform: pal::Platform> System<Platform> {
 
         let my_pubkey = self.identity_key.public();
         if my_pubkey == event.dest {
+            self.ensure_master_key_recipient(block)?;
             let master_pair =
-                self.decrypt_key_from(&event.ecdh_pubkey, &event.encrypted_master_key, &event.iv);
+                self.decrypt_key_from(&event.ecdh_pubkey, &event.encrypted_master_key, &event.iv)?;
             info!("Gatekeeper: successfully decrypt received master key");
-            self.set_master_key_history(vec![RotatedMasterKey {
+            let master_key_history = vec![RotatedMasterKey {
                 rotation_id: 0,
                 block_height: 0,
                 secret: master_pair.dump_secret_key(),
-            }]);
+            }];
+            if self.awaiting_master_key_reshare {
+                check_reshared_master_key_history(&master_key_history, block)?;
+            }
+            master_key::audit(MasterKeyAuditEvent::Received {
+                block_number: self.block_number,
+                master_pubkey: master_pair.public(),
+            });
+            self.key_operations.record_master_key();
+            self.awaiting_master_key_reshare = false;
+            self.set_master_key_history(master_key_history);
         }
         Ok(())
     }
 
     fn process_master_key_history(
         &mut self,
+        block: &mut BlockInfo,
         origin: MessageOrigin,
         event: DispatchMasterKeyHistoryEvent<chain::BlockNumber>,
     ) -> Result<(), TransactionError> {
@@ -1408,32 +2287,111 @@ impl<Platform: pal::Platform> System<Platform> {
 
         let my_pubkey = self.identity_key.public();
         if my_pubkey == event.dest {
-            let master_key_history: Vec<RotatedMasterKey> = event
+            self.ensure_master_key_recipient(block)?;
+            let master_key_history = event
                 .encrypted_master_key_history
                 .iter()
-                .map(|(rotation_id, block_height, key)| RotatedMasterKey {
-                    rotation_id: *rotation_id,
-                    block_height: *block_height,
-                    secret: self
-                        .decrypt_key_from(&key.ecdh_pubkey, &key.encrypted_key, &key.iv)
-                        .dump_secret_key(),
+                .map(|(rotation_id, block_height, key)| {
+                    let key =
+                        self.decrypt_key_from(&key.ecdh_pubkey, &key.encrypted_key, &key.iv)?;
+                    Ok(RotatedMasterKey {
+                        rotation_id: *rotation_id,
+                        block_height: *block_height,
+                        secret: key.dump_secret_key(),
+                    })
                 })
-                .collect();
+                .collect::<Result<Vec<_>, TransactionError>>()?;
+            let master_key_history = ordered_master_key_history(master_key_history)?;
+            if self.awaiting_master_key_reshare {
+                // The reshared history replaces the lost seal, so it is only sealed if it holds the
+                // master key on chain.
+                check_reshared_master_key_history(&master_key_history, block)?;
+            }
+            master_key::audit(MasterKeyAuditEvent::HistoryReceived {
+                block_number: self.block_number,
+                n_keys: master_key_history.len(),
+            });
+            self.key_operations.record_master_key();
+            self.awaiting_master_key_reshare = false;
             self.set_master_key_history(master_key_history);
         }
         Ok(())
     }
 
-    /// Decrypt the rotated master key
-    ///
-    /// The new master key takes effect immediately after the GatekeeperRegistryEvent::RotatedMasterPubkey is sent
-    fn process_batch_rotate_master_key(
+    /// Whether the chain has accepted all the egress messages of the worker.
+    fn worker_egress_accepted(&self, block: &BlockInfo) -> bool {
+        let sender = MessageOrigin::Worker(self.identity_key.public());
+        block.storage.mq_sequence(&sender) == self.egress.next_sequence()
+    }
+
+    fn request_master_key_reshare(&self) {
+        info!("Gatekeeper: request the other gatekeepers to share the master key");
+        self.egress.push_message(&MasterKeyReshareRequest {
+            ecdh_pubkey: EcdhPublicKey(self.ecdh_key.public()),
+        });
+    }
+
+    /// Share the master key history to a registered gatekeeper which lost its sealed master key.
+    fn process_master_key_reshare_request(
         &mut self,
         block: &mut BlockInfo,
         origin: MessageOrigin,
-        event: BatchRotateMasterKeyEvent,
+        event: MasterKeyReshareRequest,
     ) -> Result<(), TransactionError> {
-        // ATTENTION.shelven: There would be a mismatch between on-chain and off-chain master key until the on-chain pubkey
+        // The reshare changes the gatekeeper egress, so all the gatekeepers must start to answer
+        // the requests at the same block.
+        if block.storage.pruntime_consensus_version() < MASTER_KEY_RESHARE_SINCE {
+            info!("Ignored the master key reshare request before it is enabled on chain");
+            return Ok(());
+        }
+        let requester = match origin {
+            MessageOrigin::Worker(pubkey) => pubkey,
+            _ => {
+                error!("Invalid origin {:?} sent a {:?}", origin, event);
+                return Err(TransactionError::BadOrigin);
+            }
+        };
+        let gatekeeper = match &mut self.gatekeeper {
+            Some(gatekeeper) => gatekeeper,
+            None => return Ok(()),
+        };
+        if requester == self.identity_key.public() {
+            return Ok(());
+        }
+        check_master_key_reshare_request(
+            &requester,
+            chain_state::is_gatekeeper(&requester, block),
+            block.storage.worker_ecdh_pubkey(&requester).as_ref(),
+            &event,
+        )?;
+        info!(
+            "Gatekeeper: reshare the master key to {}",
+            hex::encode(requester)
+        );
+        gatekeeper.share_master_key(&requester, &event.ecdh_pubkey, block.block_number);
+        self.key_operations.record_master_key();
+        Ok(())
+    }
+
+    /// Only gatekeepers, either running locally or registered on chain, are expected to receive master keys.
+    fn ensure_master_key_recipient(&self, block: &BlockInfo) -> Result<(), TransactionError> {
+        check_master_key_recipient(
+            &self.identity_key.public(),
+            self.gatekeeper.is_some(),
+            chain_state::is_gatekeeper(&self.identity_key.public(), block),
+        )
+    }
+
+    /// Decrypt the rotated master key
+    ///
+    /// The new master key takes effect immediately after the GatekeeperRegistryEvent::RotatedMasterPubkey is sent
+    fn process_batch_rotate_master_key(
+        &mut self,
+        block: &mut BlockInfo,
+        origin: MessageOrigin,
+        event: BatchRotateMasterKeyEvent,
+    ) -> Result<(), TransactionError> {
+        // ATTENTION.shelven: There would be a mismatch between on-chain and off-chain master key until the on-chain pubkey
         // is updated, which may cause problem in the future.
         if !origin.is_gatekeeper() {
             error!("Invalid origin {:?} sent a {:?}", origin, event);
@@ -1450,20 +2408,17 @@ impl<Platform: pal::Platform> System<Platform> {
             return Err(TransactionError::BadSenderSignature);
         }
         // valid master key but from a non-gk
-        if !chain_state::is_gatekeeper(&event.sender, block.storage) {
+        if !chain_state::is_gatekeeper(&event.sender, block) {
             error!("Fatal error: Forged batch master key rotation {:?}", event);
             return Err(TransactionError::MasterKeyLeakage);
         }
 
         let my_pubkey = self.identity_key.public();
-        // for normal worker
+        if event.secret_keys.contains_key(&my_pubkey) {
+            self.ensure_master_key_recipient(block)?;
+        }
+        // for normal worker, or a gatekeeper that has not received the master key yet
         if self.gatekeeper.is_none() {
-            if event.secret_keys.contains_key(&my_pubkey) {
-                panic!(
-                    "Batch rotate master key to a normal worker {:?}",
-                    &my_pubkey
-                );
-            }
             return Ok(());
         }
 
@@ -1474,8 +2429,9 @@ impl<Platform: pal::Platform> System<Platform> {
                 &encrypted_key.ecdh_pubkey,
                 &encrypted_key.encrypted_key,
                 &encrypted_key.iv,
-            );
+            )?;
             info!("Worker: successfully decrypt received rotated master key");
+            self.key_operations.record_master_key();
             let gatekeeper = self.gatekeeper.as_mut().expect("checked; qed.");
             if gatekeeper.append_master_key(RotatedMasterKey {
                 rotation_id: event.rotation_id,
@@ -1500,6 +2456,7 @@ impl<Platform: pal::Platform> System<Platform> {
             // This is a valid GK in syncing, the needed master key should already be dispatched before the restart this
             // pRuntime.
             info!("Worker: rotate master key with received master key history");
+            self.key_operations.record_master_key();
         } else {
             // This is an unregistered GK whose master key is not outdated yet, it 's still sliently syncing. It cannot
             // do silent syncing anymore since it does not know the rotated key.
@@ -1541,14 +2498,14 @@ impl<Platform: pal::Platform> System<Platform> {
                 &encrypted_key.ecdh_pubkey,
                 &encrypted_key.encrypted_key,
                 &encrypted_key.iv,
-            );
+            )?;
             info!("Worker: successfully decrypt received cluster key");
+            self.key_operations.record_cluster_key();
 
             // TODO(shelven): forget cluster key after expiration time
-            let cluster = self.contract_clusters.get_cluster_mut(&cluster_id);
-            if cluster.is_some() {
-                error!("Cluster {:?} is already deployed", &cluster_id);
-                return Err(TransactionError::DuplicatedClusterDeploy.into());
+            if let Some(cluster) = self.contract_clusters.get_cluster_mut(&cluster_id) {
+                let err = cluster_redeploy_error(&cluster_id, cluster.key(), &cluster_key);
+                return Err(err.into());
             }
             let system_code = block.storage.pink_system_code().1;
             if system_code.is_empty() {
@@ -1600,23 +2557,33 @@ impl<Platform: pal::Platform> System<Platform> {
                 pink.id(),
                 cluster.config.version
             );
-            apply_pink_side_effects(
+            let installed = apply_pink_side_effects(
                 effects,
                 event.cluster,
                 &mut self.contracts,
                 cluster,
                 block,
                 &self.egress,
-                &self.sidevm_spawner,
+                self.sidevm_spawner.as_ref(),
                 None,
                 block.storage,
             );
+            self.key_operations.record_contract_keys(installed);
 
-            let message = WorkerClusterReport::ClusterDeployed {
-                id: event.cluster,
-                pubkey: cluster_key.public(),
-            };
-            self.egress.push_message(&message);
+            if block.storage.pruntime_consensus_version() >= CLUSTER_KEY_FINGERPRINT_SINCE {
+                let message = WorkerClusterReport::ClusterDeployedV2 {
+                    id: event.cluster,
+                    pubkey: cluster_key.public(),
+                    fingerprint: cluster_key_fingerprint(&event.cluster, &cluster_key),
+                };
+                self.egress.push_message(&message);
+            } else {
+                let message = WorkerClusterReport::ClusterDeployed {
+                    id: event.cluster,
+                    pubkey: cluster_key.public(),
+                };
+                self.egress.push_message(&message);
+            }
         }
         Ok(())
     }
@@ -1659,16 +2626,45 @@ impl<Platform: pal::Platform> System<Platform> {
             genesis_block: self.genesis_block,
         }
     }
+
+    /// Sign the ECDH public key with the identity key, binding the two keys together.
+    ///
+    /// The signature can be checked with `phactory_api::crypto::verify_ecdh_key_attestation`.
+    pub fn ecdh_key_attestation(&self) -> sr25519::Signature {
+        let message = phactory_api::crypto::ecdh_key_attestation_message(&self.ecdh_key.public());
+        self.identity_key.sign(&message)
+    }
 }
 
 impl<P: pal::Platform> System<P> {
     pub fn on_restored(&mut self) -> Result<()> {
+        self.egress.send_queue().set_simulation(self.simulation);
+        if let Some(gatekeeper) = &mut self.gatekeeper {
+            gatekeeper.restore_egress_sequence();
+            gatekeeper.computing_economics.rebuild_hashed_ids();
+        }
         ::pink::runtime::set_worker_pubkey(self.ecdh_key.public());
-        self.contracts.try_restart_sidevms(&self.sidevm_spawner);
+        self.reconcile_contracts();
+        self.contracts
+            .try_restart_sidevms(self.sidevm_spawner.as_ref());
         self.contracts.apply_local_cache_quotas();
         Ok(())
     }
 
+    /// Remove the contracts whose cluster no longer exists, which only happens after a bug or a
+    /// partially failed teardown. Returns the ids of the removed contracts.
+    pub fn reconcile_contracts(&mut self) -> Vec<ContractId> {
+        let removed = remove_orphaned_contracts(
+            &mut self.contracts,
+            &self.contract_clusters,
+            self.sidevm_spawner.as_ref(),
+        );
+        if !removed.is_empty() {
+            self.contracts.weight_changed = true;
+        }
+        removed
+    }
+
     pub(crate) fn apply_side_effects(
         &mut self,
         cluster_id: phala_mq::ContractClusterId,
@@ -1690,7 +2686,7 @@ impl<P: pal::Platform> System<P> {
             cluster_id,
             &mut self.contracts,
             cluster,
-            &self.sidevm_spawner,
+            self.sidevm_spawner.as_ref(),
             chain_storage,
         );
     }
@@ -1704,10 +2700,11 @@ impl<P: pal::Platform> System<P> {
             .contracts
             .get_mut(&contract_id)
             .ok_or_else(|| anyhow!("Contract not found"))?;
-        contract.start_sidevm(&self.sidevm_spawner, SidevmCode::Code(code), true)
+        contract.start_sidevm(self.sidevm_spawner.as_ref(), SidevmCode::Code(code), true)
     }
 }
 
+/// Returns the number of contracts instantiated by the command.
 #[allow(clippy::too_many_arguments)]
 pub fn handle_contract_command_result(
     result: TransactionResult,
@@ -1716,14 +2713,14 @@ pub fn handle_contract_command_result(
     clusters: &mut ClusterKeeper,
     block: &mut BlockInfo,
     egress: &SignedMessageChannel,
-    spawner: &Spawner,
+    spawner: Option<&Spawner>,
     log_handler: Option<CommandSender>,
     chain_storage: &crate::ChainStorage,
-) {
+) -> usize {
     let effects = match result {
         Err(err) => {
             error!("Run contract command failed: {:?}", err);
-            return;
+            return 0;
         }
         Ok(effects) => effects,
     };
@@ -1733,7 +2730,7 @@ pub fn handle_contract_command_result(
                 "BUG: contract cluster not found, it should always exsists, cluster_id={:?}",
                 cluster_id
             );
-            return;
+            return 0;
         }
         Some(cluster) => cluster,
     };
@@ -1747,9 +2744,10 @@ pub fn handle_contract_command_result(
         spawner,
         log_handler,
         chain_storage,
-    );
+    )
 }
 
+/// Returns the number of newly installed contracts.
 #[allow(clippy::too_many_arguments)]
 pub fn apply_pink_side_effects(
     effects: ExecSideEffects,
@@ -1758,11 +2756,11 @@ pub fn apply_pink_side_effects(
     cluster: &mut Cluster,
     block: &mut BlockInfo,
     egress: &SignedMessageChannel,
-    spawner: &Spawner,
+    spawner: Option<&Spawner>,
     log_handler: Option<CommandSender>,
     chain_storage: &crate::ChainStorage,
-) {
-    apply_instantiating_events(
+) -> usize {
+    let installed = apply_instantiating_events(
         effects.instantiated,
         cluster_id,
         contracts,
@@ -1779,6 +2777,7 @@ pub fn apply_pink_side_effects(
         chain_storage,
     );
     apply_ink_side_effects(effects.ink_events, cluster_id, block, log_handler);
+    installed
 }
 
 fn apply_instantiating_events(
@@ -1788,7 +2787,8 @@ fn apply_instantiating_events(
     cluster: &mut Cluster,
     block: &mut BlockInfo,
     _egress: &SignedMessageChannel,
-) {
+) -> usize {
+    let mut installed = 0;
     for (deployer, address) in instantiated_events {
         let pink = Pink::from_address(address.clone(), cluster_id);
         let contract_id = ContractId::from(address.as_ref());
@@ -1818,26 +2818,56 @@ fn apply_instantiating_events(
         };
 
         cluster.add_contract(id);
+        installed += 1;
 
         let message = ContractRegistryEvent::PubkeyAvailable {
             contract: contract_id,
             pubkey: contract_key.public(),
             deployer: phala_types::messaging::AccountId(deployer.into()),
         };
-        let sender = MessageOrigin::Cluster(cluster_id);
+        info!("Pink instantiated: cluster={cluster_id} {message:?}");
+        cluster.push_registry_event(message);
+    }
+    installed
+}
+
+/// Sends the registry events emitted by each cluster in the block.
+fn send_registry_events(clusters: &mut ClusterKeeper, block: &BlockInfo) {
+    let batched = block.storage.pruntime_consensus_version() >= BATCHED_REGISTRY_EVENTS_SINCE;
+    for (cluster_id, cluster) in clusters.iter_mut() {
+        let events = cluster.take_registry_events();
+        if events.is_empty() {
+            continue;
+        }
+        let sender = MessageOrigin::Cluster(*cluster_id);
         let cluster_mq: SignedMessageChannel =
             block.send_mq.channel(sender, cluster.key().clone().into());
-        cluster_mq.push_message(&message);
-        info!("Pink instantiated: cluster={cluster_id} {message:?}");
+        for message in batch_registry_events(events, batched) {
+            cluster_mq.push_message(&message);
+        }
     }
 }
 
+/// Merges the registry events of a cluster in a block into one egress message if `batched`.
+///
+/// Older runtimes can not decode `ContractRegistryEvent::Batch`, so it is only sent once the chain
+/// has switched to `BATCHED_REGISTRY_EVENTS_SINCE`.
+fn batch_registry_events(
+    events: Vec<ContractRegistryEvent>,
+    batched: bool,
+) -> Vec<ContractRegistryEvent> {
+    if !batched || events.len() < 2 {
+        return events;
+    }
+    vec![ContractRegistryEvent::Batch(events)]
+}
+
 pub(crate) fn apply_pink_events(
     pink_events: Vec<(AccountId, PinkEvent)>,
     cluster_id: phala_mq::ContractClusterId,
     contracts: &mut ContractsKeeper,
     cluster: &mut Cluster,
-    spawner: &Spawner,
+    spawner: Option<&Spawner>,
     chain_storage: &crate::ChainStorage,
 ) {
     for (origin, event) in pink_events {
@@ -1921,7 +2951,7 @@ pub(crate) fn apply_pink_events(
                 }
             }
             PinkEvent::CacheOp(op) => {
-                pink::local_cache::apply_cache_op(&origin, op);
+                pink::local_cache::apply_cache_op(cluster_id.as_ref(), &origin, op);
             }
             PinkEvent::StopSidevm => {
                 let vmid = sidevm::ShortId(origin.as_ref());
@@ -2063,7 +3093,1559 @@ pub mod chain_state {
     use super::*;
     use crate::storage::ChainStorage;
 
-    pub fn is_gatekeeper(pubkey: &WorkerPublicKey, chain_storage: &ChainStorage) -> bool {
-        chain_storage.gatekeepers().contains(pubkey)
+    pub fn is_gatekeeper(pubkey: &WorkerPublicKey, block: &BlockInfo) -> bool {
+        block.storage.is_gatekeeper_at(pubkey, block.block_number)
+    }
+
+    pub fn master_pubkey(chain_storage: &ChainStorage) -> Option<phala_types::MasterPublicKey> {
+        chain_storage.gatekeeper_master_pubkey()
+    }
+
+    pub fn tokenomic_parameters(
+        chain_storage: &ChainStorage,
+    ) -> Option<phala_types::messaging::TokenomicParameters> {
+        chain_storage.tokenomic_parameters()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn role_tags_are_normalized() {
+        let tags = ["query", " gatekeeper ", "", "query"]
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect();
+        assert_eq!(normalize_role_tags(tags), vec!["gatekeeper", "query"]);
+    }
+
+    #[test]
+    fn sidevm_is_disabled_when_service_creation_fails() {
+        let spawner =
+            sidevm_service_or_disabled(|| Err(anyhow!("Resource temporarily unavailable")));
+        assert!(spawner.is_none());
+    }
+
+    #[test]
+    fn cluster_key_fingerprint_is_stable() {
+        let cluster_key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(2);
+        let fingerprint = cluster_key_fingerprint(&cluster_id, &cluster_key);
+
+        // The same key restored elsewhere gives the same fingerprint.
+        let restored = sr25519::Pair::restore_from_secret_key(&cluster_key.dump_secret_key());
+        assert_eq!(cluster_key_fingerprint(&cluster_id, &restored), fingerprint);
+
+        let other_key = sr25519::Pair::from_seed(&[3u8; 32]);
+        assert_ne!(
+            cluster_key_fingerprint(&cluster_id, &other_key),
+            fingerprint
+        );
+        let other_cluster = phala_mq::ContractClusterId::repeat_byte(4);
+        assert_ne!(
+            cluster_key_fingerprint(&other_cluster, &cluster_key),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn registry_events_are_merged_into_a_batch() {
+        let event = |n: u8| ContractRegistryEvent::PubkeyAvailable {
+            contract: ContractId::repeat_byte(n),
+            pubkey: sr25519::Pair::from_seed(&[n; 32]).public(),
+            deployer: phala_types::messaging::AccountId([0; 32]),
+        };
+
+        assert!(batch_registry_events(vec![], true).is_empty());
+        assert!(matches!(
+            &batch_registry_events(vec![event(1)], true)[..],
+            [ContractRegistryEvent::PubkeyAvailable { .. }]
+        ));
+        match &batch_registry_events(vec![event(1), event(2), event(3)], true)[..] {
+            [ContractRegistryEvent::Batch(events)] => assert_eq!(events.len(), 3),
+            other => panic!("Unexpected messages: {other:?}"),
+        }
+        // Not batched before the chain switches to the consensus version.
+        let messages = batch_registry_events(vec![event(1), event(2), event(3)], false);
+        assert_eq!(messages.len(), 3);
+        assert!(messages
+            .iter()
+            .all(|message| matches!(message, ContractRegistryEvent::PubkeyAvailable { .. })));
+    }
+
+    #[test]
+    fn querying_orphaned_contract_fails_gracefully() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let cluster_key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let contract = contracts::new_test_contract(2, cluster_id);
+
+        let mut clusters = ClusterKeeper::default();
+        assert!(matches!(
+            cluster_for_query(&mut clusters, &contract),
+            Err(OpaqueError::ClusterNotFound)
+        ));
+        clusters.get_cluster_or_default_mut(&cluster_id, &cluster_key);
+        assert!(cluster_for_query(&mut clusters, &contract).is_ok());
+    }
+
+    #[test]
+    fn orphaned_contracts_are_removed() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let missing_cluster_id = phala_mq::ContractClusterId::repeat_byte(2);
+        let cluster_key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let contract = contracts::new_test_contract(2, cluster_id);
+        let orphan = contracts::new_test_contract(3, missing_cluster_id);
+        let contract_id = contract.id();
+        let orphan_id = orphan.id();
+
+        let mut clusters = ClusterKeeper::default();
+        clusters.get_cluster_or_default_mut(&cluster_id, &cluster_key);
+        let mut keeper = ContractsKeeper::default();
+        keeper.insert(contract);
+        keeper.insert(orphan);
+
+        let removed = remove_orphaned_contracts(&mut keeper, &clusters, None);
+        assert_eq!(removed, vec![orphan_id]);
+        assert!(keeper.get(&orphan_id).is_none());
+        assert!(keeper.get(&contract_id).is_some());
+        assert!(remove_orphaned_contracts(&mut keeper, &clusters, None).is_empty());
+    }
+
+    #[test]
+    fn normal_worker_rejects_master_key() {
+        let pubkey = WorkerPublicKey::from_raw([1; 32]);
+        assert!(matches!(
+            check_master_key_recipient(&pubkey, false, false),
+            Err(TransactionError::UnexpectedMasterKeyRecipient)
+        ));
+    }
+
+    #[test]
+    fn gatekeeper_accepts_master_key() {
+        let pubkey = WorkerPublicKey::from_raw([1; 32]);
+        // Running gatekeeper receiving a rotated key.
+        assert!(check_master_key_recipient(&pubkey, true, true).is_ok());
+        // Unregistered gatekeeper still syncing silently.
+        assert!(check_master_key_recipient(&pubkey, true, false).is_ok());
+        // Newly registered gatekeeper receiving its first key.
+        assert!(check_master_key_recipient(&pubkey, false, true).is_ok());
+    }
+
+    #[test]
+    fn redeploying_cluster_with_same_key_is_duplicate() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let incoming = sr25519::Pair::restore_from_secret_key(&key.dump_secret_key());
+        assert!(matches!(
+            cluster_redeploy_error(&cluster_id, &key, &incoming),
+            TransactionError::DuplicatedClusterDeploy
+        ));
+    }
+
+    #[test]
+    fn redeploying_cluster_with_different_key_is_mismatch() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let incoming = sr25519::Pair::from_seed(&[2u8; 32]);
+        assert!(matches!(
+            cluster_redeploy_error(&cluster_id, &key, &incoming),
+            TransactionError::ClusterKeyMismatch
+        ));
+    }
+
+    fn rotated_master_key(rotation_id: u64) -> RotatedMasterKey {
+        RotatedMasterKey {
+            rotation_id,
+            block_height: rotation_id as chain::BlockNumber * 100,
+            secret: sr25519::Pair::from_seed(&[rotation_id as u8; 32]).dump_secret_key(),
+        }
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub(crate) struct TestPlatform;
+
+    impl pal::Sealing for TestPlatform {
+        type SealError = std::io::Error;
+        type UnsealError = std::io::Error;
+
+        fn seal_data(
+            &self,
+            path: impl AsRef<std::path::Path>,
+            data: &[u8],
+        ) -> Result<(), Self::SealError> {
+            std::fs::write(path, data)
+        }
+
+        fn unseal_data(
+            &self,
+            path: impl AsRef<std::path::Path>,
+        ) -> Result<Option<Vec<u8>>, Self::UnsealError> {
+            match std::fs::read(path) {
+                Ok(data) => Ok(Some(data)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    impl pal::RA for TestPlatform {
+        type Error = anyhow::Error;
+
+        fn create_attestation_report(
+            &self,
+            _provider: Option<phala_types::AttestationProvider>,
+            _data: &[u8],
+        ) -> Result<Vec<u8>, Self::Error> {
+            Ok(vec![])
+        }
+
+        fn quote_test(
+            &self,
+            _provider: Option<phala_types::AttestationProvider>,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn measurement(&self) -> Option<Vec<u8>> {
+            None
+        }
+    }
+
+    impl pal::Machine for TestPlatform {
+        fn machine_id(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn cpu_core_num(&self) -> u32 {
+            1
+        }
+
+        fn cpu_feature_level(&self) -> u32 {
+            0
+        }
+    }
+
+    impl pal::MemoryStats for TestPlatform {
+        fn memory_usage(&self) -> pal::MemoryUsage {
+            pal::MemoryUsage {
+                total_peak_used: 0,
+                rust_used: 0,
+                rust_peak_used: 0,
+            }
+        }
+    }
+
+    impl pal::AppInfo for TestPlatform {
+        fn app_version() -> pal::AppVersion {
+            pal::AppVersion {
+                major: 0,
+                minor: 0,
+                patch: 0,
+            }
+        }
+    }
+
+    /// A worker processing the blocks fed by the test like `dispatch_block` does.
+    pub(crate) struct TestNode {
+        pub(crate) system: System<TestPlatform>,
+        send_mq: MessageSendQueue,
+        recv_mq: MessageDispatcher,
+        pub(crate) _sealing_dir: tempfile::TempDir,
+    }
+
+    impl TestNode {
+        pub(crate) fn new(seed: u8) -> Self {
+            let sealing_dir = tempfile::tempdir().unwrap();
+            let sealing_path = sealing_dir.path().to_str().unwrap().to_string();
+            let identity_key = sr25519::Pair::from_seed(&[seed; 32]);
+            let ecdh_key = identity_key.derive_ecdh_key().unwrap();
+            let send_mq = MessageSendQueue::new();
+            let mut recv_mq = MessageDispatcher::new();
+            let system = System::new(
+                TestPlatform,
+                true,
+                sealing_path.clone(),
+                sealing_path,
+                identity_key,
+                ecdh_key,
+                false,
+                &send_mq,
+                &mut recv_mq,
+                ContractsKeeper::default(),
+                1,
+                vec![],
+            )
+            .unwrap();
+            TestNode {
+                system,
+                send_mq,
+                recv_mq,
+                _sealing_dir: sealing_dir,
+            }
+        }
+
+        fn pubkey(&self) -> WorkerPublicKey {
+            self.system.identity_key.public()
+        }
+
+        fn ecdh_pubkey(&self) -> EcdhPublicKey {
+            EcdhPublicKey(self.system.ecdh_key.public())
+        }
+
+        fn run_block(
+            &mut self,
+            block_number: chain::BlockNumber,
+            storage: &crate::ChainStorage,
+            messages: Vec<phala_mq::Message>,
+        ) {
+            self.recv_mq.reset_local_index();
+            let mut block = BlockInfo {
+                block_number,
+                now_ms: block_number as u64 * 12000,
+                storage,
+                send_mq: &self.send_mq,
+                recv_mq: &mut self.recv_mq,
+            };
+            self.system.will_process_block(&mut block);
+            for message in messages {
+                block.recv_mq.dispatch(message);
+                self.system.process_messages(&mut block);
+            }
+            self.system.did_process_block(&mut block);
+            block.recv_mq.clear();
+        }
+
+        /// Call `f` with the system in the middle of processing block `block_number`.
+        fn in_block<R>(
+            &mut self,
+            block_number: chain::BlockNumber,
+            storage: &crate::ChainStorage,
+            f: impl FnOnce(&mut System<TestPlatform>, &mut BlockInfo) -> R,
+        ) -> R {
+            let mut block = BlockInfo {
+                block_number,
+                now_ms: block_number as u64 * 12000,
+                storage,
+                send_mq: &self.send_mq,
+                recv_mq: &mut self.recv_mq,
+            };
+            f(&mut self.system, &mut block)
+        }
+
+        /// The pending egress of `sender`, as to be dispatched to the other workers.
+        fn egress_of(&self, sender: &MessageOrigin) -> Vec<phala_mq::Message> {
+            self.send_mq
+                .messages(sender)
+                .into_iter()
+                .map(|signed| signed.message)
+                .collect()
+        }
+    }
+
+    fn pallet_message<T: Encode + BindTopic>(event: &T) -> phala_mq::Message {
+        phala_mq::Message::new(
+            MessageOrigin::Pallet(b"PhalaRegistry".to_vec()),
+            T::topic(),
+            event.encode(),
+        )
+    }
+
+    /// The chain storage with the `gatekeepers` registered as workers and gatekeepers.
+    fn gatekeepers_storage(gatekeepers: &[&TestNode]) -> crate::ChainStorage {
+        gatekeepers_storage_at(gatekeepers, 0)
+    }
+
+    /// Same as `gatekeepers_storage`, with the on-chain consensus version set.
+    fn gatekeepers_storage_at(
+        gatekeepers: &[&TestNode],
+        consensus_version: u32,
+    ) -> crate::ChainStorage {
+        gatekeepers_storage_with(gatekeepers, consensus_version, vec![])
+    }
+
+    /// Same as `gatekeepers_storage_at`, with the `extra` storage items.
+    fn gatekeepers_storage_with(
+        gatekeepers: &[&TestNode],
+        consensus_version: u32,
+        extra: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> crate::ChainStorage {
+        use crate::light_validation::utils::{storage_map_prefix_twox_64_concat, storage_prefix};
+
+        let mut pairs: Vec<_> = gatekeepers
+            .iter()
+            .map(|node| {
+                let info = chain::pallet_registry::WorkerInfoV2::<chain::AccountId> {
+                    pubkey: node.pubkey(),
+                    ecdh_pubkey: node.ecdh_pubkey(),
+                    runtime_version: 0,
+                    last_updated: 0,
+                    operator: None,
+                    attestation_provider: None,
+                    confidence_level: 2,
+                    initial_score: None,
+                    features: vec![],
+                };
+                (
+                    storage_map_prefix_twox_64_concat(b"PhalaRegistry", b"Workers", &node.pubkey()),
+                    info.encode(),
+                )
+            })
+            .collect();
+        let pubkeys: Vec<_> = gatekeepers.iter().map(|node| node.pubkey()).collect();
+        pairs.push((
+            storage_prefix("PhalaRegistry", "Gatekeeper"),
+            pubkeys.encode(),
+        ));
+        pairs.push((
+            storage_prefix("PhalaRegistry", "PRuntimeConsensusVersion"),
+            consensus_version.encode(),
+        ));
+        pairs.extend(extra);
+        crate::ChainStorage::from_pairs(pairs.into_iter())
+    }
+
+    #[test]
+    fn master_key_lifecycle_is_audited() {
+        let mut first = TestNode::new(1);
+        let mut second = TestNode::new(2);
+        let storage = gatekeepers_storage(&[&first, &second]);
+        let _ = master_key::take_recorded_audit_events();
+
+        // The first gatekeeper generates the master key.
+        let event = GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+            pubkey: first.pubkey(),
+            ecdh_pubkey: first.ecdh_pubkey(),
+        });
+        first.run_block(1, &storage, vec![pallet_message(&event)]);
+        let master_pubkey = first.system.gatekeeper.as_ref().unwrap().master_pubkey();
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Generated {
+                block_number: 1,
+                master_pubkey,
+            }]
+        );
+
+        // Then shares it to the second one, which asks for a restart once it is received.
+        let event = GatekeeperChange::Registered(NewGatekeeperEvent {
+            pubkey: second.pubkey(),
+            ecdh_pubkey: second.ecdh_pubkey(),
+        });
+        first.run_block(2, &storage, vec![pallet_message(&event)]);
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Shared {
+                block_number: 2,
+                dest: second.pubkey(),
+                n_keys: 1,
+            }]
+        );
+        let shared = first.egress_of(&MessageOrigin::Gatekeeper);
+        let restart = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            second.run_block(3, &storage, shared)
+        }));
+        assert!(restart.is_err());
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Received {
+                block_number: 3,
+                master_pubkey,
+            }]
+        );
+
+        // A longer history replaces the one of the running gatekeeper.
+        let history: Vec<_> = (0..2).map(rotated_master_key).collect();
+        let mut recv_mq = MessageDispatcher::new();
+        let send_mq = MessageSendQueue::new();
+        let egress = send_mq.channel(
+            MessageOrigin::Gatekeeper,
+            sr25519::Pair::restore_from_secret_key(&history[1].secret).into(),
+        );
+        let mut other_gk = gk::Gatekeeper::new(history, &mut recv_mq, egress);
+        other_gk.register_on_chain();
+        other_gk.share_master_key(&first.pubkey(), &first.ecdh_pubkey(), 4);
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Shared {
+                block_number: 4,
+                dest: first.pubkey(),
+                n_keys: 2,
+            }]
+        );
+        let shared = send_mq
+            .all_messages()
+            .into_iter()
+            .map(|signed| signed.message)
+            .collect();
+        first.run_block(5, &storage, shared);
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![
+                MasterKeyAuditEvent::HistoryReceived {
+                    block_number: 5,
+                    n_keys: 2,
+                },
+                MasterKeyAuditEvent::HistoryUpdated {
+                    block_number: 5,
+                    n_keys: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lost_master_key_is_reshared() {
+        use crate::light_validation::utils::{storage_map_prefix_twox_64_concat, storage_prefix};
+        use phala_types::messaging::MasterPubkeyEvent;
+
+        let mut keeper = TestNode::new(1);
+        let mut lost = TestNode::new(2);
+        let lost_worker = MessageOrigin::Worker(lost.pubkey());
+        let storage = gatekeepers_storage(&[&keeper, &lost]);
+        let launch = |node: &TestNode| {
+            pallet_message(&GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+                pubkey: node.pubkey(),
+                ecdh_pubkey: node.ecdh_pubkey(),
+            }))
+        };
+        let reshare_requests = |node: &TestNode| -> Vec<_> {
+            node.egress_of(&MessageOrigin::Worker(node.pubkey()))
+                .into_iter()
+                .filter(|message| *message.destination.path() == MasterKeyReshareRequest::topic())
+                .collect()
+        };
+
+        keeper.run_block(1, &storage, vec![launch(&keeper)]);
+        let master_pubkey = keeper.system.gatekeeper.as_ref().unwrap().master_pubkey();
+
+        // Without the seal, a fresh master key is generated when replaying the launch and then
+        // dropped once it turns out to differ from the one on chain.
+        lost.run_block(1, &storage, vec![launch(&lost)]);
+        let event = GatekeeperLaunch::MasterPubkeyOnChain(MasterPubkeyEvent { master_pubkey });
+        lost.run_block(2, &storage, vec![pallet_message(&event)]);
+        assert!(lost.system.gatekeeper.is_none());
+        assert!(lost.system.awaiting_master_key_reshare);
+
+        // Not requested while the chain has not accepted the master pubkey upload of the worker.
+        lost.run_block(MASTER_KEY_RESHARE_INTERVAL, &storage, vec![]);
+        assert!(reshare_requests(&lost).is_empty());
+        let ingress = (
+            storage_map_prefix_twox_64_concat(b"PhalaMq", b"OffchainIngress", &lost_worker),
+            1u64.encode(),
+        );
+        let accepted = gatekeepers_storage_with(&[&keeper, &lost], 0, vec![ingress.clone()]);
+        lost.run_block(2 * MASTER_KEY_RESHARE_INTERVAL, &accepted, vec![]);
+        let requests = reshare_requests(&lost);
+        assert_eq!(requests.len(), 1);
+
+        // Only answered once enabled on chain.
+        let reshare_enabled = gatekeepers_storage_at(&[&keeper, &lost], MASTER_KEY_RESHARE_SINCE);
+        let _ = master_key::take_recorded_audit_events();
+        keeper.run_block(101, &storage, requests.clone());
+        assert!(master_key::take_recorded_audit_events().is_empty());
+        keeper.run_block(102, &reshare_enabled, requests);
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Shared {
+                block_number: 102,
+                dest: lost.pubkey(),
+                n_keys: 1,
+            }]
+        );
+
+        // Not sealed until the master key is confirmed on chain.
+        let sealed_pubkey = |node: &TestNode| {
+            let history = master_key::try_unseal(
+                node.system.sealing_path.clone(),
+                &node.system.identity_key.0,
+                &node.system.platform,
+            );
+            sr25519::Pair::restore_from_secret_key(&history.last().unwrap().secret).public()
+        };
+        let shared = keeper.egress_of(&MessageOrigin::Gatekeeper);
+        lost.run_block(103, &reshare_enabled, shared.clone());
+        assert!(lost.system.awaiting_master_key_reshare);
+        assert!(master_key::take_recorded_audit_events().is_empty());
+        assert_ne!(sealed_pubkey(&lost), master_pubkey);
+
+        let confirmed = gatekeepers_storage_with(
+            &[&keeper, &lost],
+            MASTER_KEY_RESHARE_SINCE,
+            vec![
+                ingress,
+                (
+                    storage_prefix("PhalaRegistry", "GatekeeperMasterPubkey"),
+                    master_pubkey.encode(),
+                ),
+            ],
+        );
+        let restart = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lost.run_block(104, &confirmed, shared)
+        }));
+        assert!(restart.is_err());
+        assert!(!lost.system.awaiting_master_key_reshare);
+        assert_eq!(sealed_pubkey(&lost), master_pubkey);
+        assert_eq!(
+            master_key::take_recorded_audit_events(),
+            vec![MasterKeyAuditEvent::Received {
+                block_number: 104,
+                master_pubkey,
+            }]
+        );
+    }
+
+    #[test]
+    fn no_egress_is_produced_while_paused() {
+        let mut node = TestNode::new(1);
+        let storage = gatekeepers_storage(&[&node]);
+        let event = GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+            pubkey: node.pubkey(),
+            ecdh_pubkey: node.ecdh_pubkey(),
+        });
+        let worker = MessageOrigin::Worker(node.pubkey());
+
+        node.system.set_paused(true);
+        node.run_block(1, &storage, vec![pallet_message(&event)]);
+        assert!(node.send_mq.all_messages().is_empty());
+        assert!(node.system.gatekeeper.is_none());
+
+        node.system.set_paused(false);
+        node.run_block(2, &storage, vec![pallet_message(&event)]);
+        assert!(!node.egress_of(&worker).is_empty());
+        assert!(node.system.gatekeeper.is_some());
+    }
+
+    /// Runs a block in which each of `commands` instantiates the given contracts in `cluster_id`.
+    fn run_instantiating_block(
+        node: &mut TestNode,
+        storage: &crate::ChainStorage,
+        cluster_id: phala_mq::ContractClusterId,
+        commands: &[&[u8]],
+    ) {
+        let mut block = BlockInfo {
+            block_number: 1,
+            now_ms: 12000,
+            storage,
+            send_mq: &node.send_mq,
+            recv_mq: &mut node.recv_mq,
+        };
+        node.system.will_process_block(&mut block);
+        for contracts in commands {
+            let effects = ExecSideEffects {
+                instantiated: contracts
+                    .iter()
+                    .map(|n| (AccountId::new([0; 32]), AccountId::new([*n; 32])))
+                    .collect(),
+                ..Default::default()
+            };
+            let cluster = node
+                .system
+                .contract_clusters
+                .get_cluster_mut(&cluster_id)
+                .unwrap();
+            apply_pink_side_effects(
+                effects,
+                cluster_id,
+                &mut node.system.contracts,
+                cluster,
+                &mut block,
+                &node.system.egress,
+                None,
+                None,
+                storage,
+            );
+        }
+        node.system.did_process_block(&mut block);
+    }
+
+    #[test]
+    fn registry_events_are_batched_per_block() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let cluster_key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let sender = MessageOrigin::Cluster(cluster_id);
+        let decode = |message: &phala_mq::Message| {
+            ContractRegistryEvent::decode(&mut &message.payload[..]).unwrap()
+        };
+
+        let mut node = TestNode::new(1);
+        let storage = gatekeepers_storage_at(&[], BATCHED_REGISTRY_EVENTS_SINCE);
+        node.system
+            .contract_clusters
+            .get_cluster_or_default_mut(&cluster_id, &cluster_key);
+        run_instantiating_block(&mut node, &storage, cluster_id, &[&[2, 3], &[4]]);
+        let egress = node.egress_of(&sender);
+        assert_eq!(egress.len(), 1);
+        match decode(&egress[0]) {
+            ContractRegistryEvent::Batch(events) => assert_eq!(events.len(), 3),
+            other => panic!("Unexpected message: {other:?}"),
+        }
+
+        // A single event is sent alone.
+        let mut node = TestNode::new(1);
+        node.system
+            .contract_clusters
+            .get_cluster_or_default_mut(&cluster_id, &cluster_key);
+        run_instantiating_block(&mut node, &storage, cluster_id, &[&[2]]);
+        let egress = node.egress_of(&sender);
+        assert_eq!(egress.len(), 1);
+        assert!(matches!(
+            decode(&egress[0]),
+            ContractRegistryEvent::PubkeyAvailable { .. }
+        ));
+
+        // Not batched before the chain switches to the consensus version.
+        let mut node = TestNode::new(1);
+        let storage = gatekeepers_storage_at(&[], 0);
+        node.system
+            .contract_clusters
+            .get_cluster_or_default_mut(&cluster_id, &cluster_key);
+        run_instantiating_block(&mut node, &storage, cluster_id, &[&[2, 3], &[4]]);
+        let egress = node.egress_of(&sender);
+        assert_eq!(egress.len(), 3);
+        assert!(egress.iter().all(|message| matches!(
+            decode(message),
+            ContractRegistryEvent::PubkeyAvailable { .. }
+        )));
+    }
+
+    #[test]
+    fn contract_info_of_installed_contract() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let cluster_key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let contract_id = ContractId::repeat_byte(2);
+
+        let mut node = TestNode::new(1);
+        let storage = gatekeepers_storage(&[]);
+        assert!(node.system.contract_info(&contract_id).is_none());
+        node.system
+            .contract_clusters
+            .get_cluster_or_default_mut(&cluster_id, &cluster_key);
+        run_instantiating_block(&mut node, &storage, cluster_id, &[&[2]]);
+
+        let info = node.system.contract_info(&contract_id).unwrap();
+        assert_eq!(info.cluster_id, cluster_id);
+        assert!(info.sidevm.is_none());
+        // The same pubkey as registered on chain.
+        let egress = node.egress_of(&MessageOrigin::Cluster(cluster_id));
+        match ContractRegistryEvent::decode(&mut &egress[0].payload[..]).unwrap() {
+            ContractRegistryEvent::PubkeyAvailable {
+                contract, pubkey, ..
+            } => {
+                assert_eq!(contract, contract_id);
+                assert_eq!(info.pubkey, pubkey);
+            }
+            other => panic!("Unexpected message: {other:?}"),
+        }
+        assert!(node
+            .system
+            .contract_info(&ContractId::repeat_byte(3))
+            .is_none());
+    }
+
+    #[test]
+    fn key_operations_are_counted_per_operation() {
+        let mut node = TestNode::new(1);
+        let second = TestNode::new(2);
+        let storage = gatekeepers_storage(&[&node, &second]);
+        assert_eq!(node.system.key_operations().total(), 0);
+
+        // Generating the master key.
+        let event = GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+            pubkey: node.pubkey(),
+            ecdh_pubkey: node.ecdh_pubkey(),
+        });
+        node.run_block(1, &storage, vec![pallet_message(&event)]);
+        assert_eq!(node.system.key_operations().master_key, 1);
+        assert_eq!(node.system.key_operations().total(), 1);
+
+        // A block without key operation.
+        node.run_block(2, &storage, vec![]);
+        assert_eq!(node.system.key_operations().total(), 1);
+
+        // Sharing the master key.
+        let event = GatekeeperChange::Registered(NewGatekeeperEvent {
+            pubkey: second.pubkey(),
+            ecdh_pubkey: second.ecdh_pubkey(),
+        });
+        node.run_block(3, &storage, vec![pallet_message(&event)]);
+        assert_eq!(
+            node.system.key_operations(),
+            KeyOperationCounter {
+                master_key: 2,
+                cluster_key: 0,
+                contract_key: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn no_egress_is_sent_in_simulation() {
+        let mut node = TestNode::new(1);
+        let storage = gatekeepers_storage(&[&node]);
+        let event = GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+            pubkey: node.pubkey(),
+            ecdh_pubkey: node.ecdh_pubkey(),
+        });
+        let worker = MessageOrigin::Worker(node.pubkey());
+
+        node.system.set_simulation(true);
+        node.run_block(1, &storage, vec![pallet_message(&event)]);
+        assert!(node.system.gatekeeper.is_some());
+        assert!(node.send_mq.all_messages().is_empty());
+        let simulated = node.system.simulated_egress();
+        assert!(!simulated.is_empty());
+        assert!(simulated.iter().any(|msg| msg.message.sender == worker));
+        // The simulated messages take no real sequence.
+        assert_eq!(node.send_mq.next_sequence(&worker), 0);
+
+        node.system.set_simulation(false);
+        assert!(node.system.simulated_egress().is_empty());
+        node.system
+            .egress
+            .push_data(b"real".to_vec(), b"phala.network/test".to_vec());
+        let sent = node.send_mq.messages(&worker);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].sequence, 0);
+    }
+
+    #[test]
+    fn role_tags_persist_across_checkpoints() {
+        let mut node = TestNode::new(1);
+        node.system
+            .set_role_tags(vec!["query".into(), " gatekeeper ".into()]);
+        let checkpoint = serde_cbor::to_vec(&node.system).unwrap();
+
+        let mut recv_mq = MessageDispatcher::new();
+        let mut send_mq = MessageSendQueue::new();
+        let restored: System<TestPlatform> =
+            phala_mq::checkpoint_helper::using_dispatcher(&mut recv_mq, || {
+                phala_mq::checkpoint_helper::using_send_mq(&mut send_mq, || {
+                    serde_cbor::from_slice(&checkpoint).unwrap()
+                })
+            });
+        assert_eq!(restored.role_tags(), ["gatekeeper", "query"]);
+    }
+
+    #[test]
+    fn shuffled_master_key_history_is_reordered() {
+        let history = vec![
+            rotated_master_key(2),
+            rotated_master_key(0),
+            rotated_master_key(3),
+            rotated_master_key(1),
+        ];
+        let ordered = ordered_master_key_history(history).unwrap();
+        let rotation_ids: Vec<_> = ordered.iter().map(|key| key.rotation_id).collect();
+        assert_eq!(rotation_ids, vec![0, 1, 2, 3]);
+        assert_eq!(ordered[0], rotated_master_key(0));
+    }
+
+    #[test]
+    fn incomplete_master_key_history_is_rejected() {
+        for rotation_ids in [vec![], vec![1, 2], vec![0, 2], vec![1, 0, 1]] {
+            let history = rotation_ids.into_iter().map(rotated_master_key).collect();
+            assert!(matches!(
+                ordered_master_key_history(history),
+                Err(TransactionError::InvalidMasterKeyHistory)
+            ));
+        }
+    }
+
+    #[test]
+    fn duplicated_cluster_deploy_is_not_reported_as_failure() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let err = TransactionError::DuplicatedClusterDeploy.into();
+        assert!(cluster_deployment_failure_report(cluster_id, &err).is_none());
+    }
+
+    #[test]
+    fn undecryptable_master_keys_are_rejected() {
+        use phala_types::messaging::EncryptedKey;
+
+        let mut first = TestNode::new(1);
+        let second = TestNode::new(2);
+        let storage = gatekeepers_storage(&[&first, &second]);
+        let event = GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+            pubkey: first.pubkey(),
+            ecdh_pubkey: first.ecdh_pubkey(),
+        });
+        first.run_block(1, &storage, vec![pallet_message(&event)]);
+        let master_pubkey = first.system.gatekeeper.as_ref().unwrap().master_pubkey();
+        let _ = master_key::take_recorded_audit_events();
+
+        // Sent by the second gatekeeper, but encrypted to itself rather than to the first one.
+        let sender_key = sr25519::Pair::from_seed(&[2u8; 32]);
+        let iv = [1u8; 12];
+        let (ecdh_pubkey, encrypted_key) = key_share::encrypt_secret_to(
+            &sender_key,
+            &[b"test"],
+            &second.ecdh_pubkey().0,
+            &rotated_master_key(1).secret,
+            &iv,
+        )
+        .unwrap();
+        let ecdh_pubkey = EcdhPublicKey(ecdh_pubkey);
+        let misdirected = EncryptedKey {
+            ecdh_pubkey: ecdh_pubkey.clone(),
+            encrypted_key: encrypted_key.clone(),
+            iv,
+        };
+        let (_, good_key) = key_share::encrypt_secret_to(
+            &sender_key,
+            &[b"test"],
+            &first.ecdh_pubkey().0,
+            &rotated_master_key(0).secret,
+            &iv,
+        )
+        .unwrap();
+        let good = EncryptedKey {
+            ecdh_pubkey: ecdh_pubkey.clone(),
+            encrypted_key: good_key,
+            iv,
+        };
+        // Too short to even hold the AEAD tag.
+        let truncated = EncryptedKey {
+            ecdh_pubkey: ecdh_pubkey.clone(),
+            encrypted_key: encrypted_key[..3].to_vec(),
+            iv,
+        };
+
+        for key in [&misdirected, &truncated] {
+            let event = DispatchMasterKeyEvent {
+                dest: first.pubkey(),
+                ecdh_pubkey: key.ecdh_pubkey.clone(),
+                encrypted_master_key: key.encrypted_key.clone(),
+                iv: key.iv,
+            };
+            let result = first.in_block(2, &storage, |system, block| {
+                system.process_master_key_distribution(block, MessageOrigin::Gatekeeper, event)
+            });
+            assert!(matches!(result, Err(TransactionError::KeyDecryptionFailed)));
+
+            // One undecryptable key fails the whole history.
+            let event = DispatchMasterKeyHistoryEvent {
+                dest: first.pubkey(),
+                encrypted_master_key_history: vec![(0, 0, good.clone()), (1, 100, key.clone())],
+            };
+            let result = first.in_block(2, &storage, |system, block| {
+                system.process_master_key_history(block, MessageOrigin::Gatekeeper, event)
+            });
+            assert!(matches!(result, Err(TransactionError::KeyDecryptionFailed)));
+
+            let mut event = BatchRotateMasterKeyEvent {
+                rotation_id: 1,
+                secret_keys: [(first.pubkey(), key.clone())].into_iter().collect(),
+                sender: second.pubkey(),
+                sig: vec![],
+            };
+            let data = wrap_content_to_sign(
+                &event.data_be_signed(),
+                SignedContentType::MasterKeyRotation,
+            );
+            event.sig = sender_key.sign(&data).0.to_vec();
+            let result = first.in_block(2, &storage, |system, block| {
+                system.process_batch_rotate_master_key(block, MessageOrigin::Gatekeeper, event)
+            });
+            assert!(matches!(result, Err(TransactionError::KeyDecryptionFailed)));
+        }
+
+        let gatekeeper = first.system.gatekeeper.as_ref().unwrap();
+        assert_eq!(gatekeeper.master_pubkey(), master_pubkey);
+        assert_eq!(gatekeeper.master_key_history().len(), 1);
+        assert!(master_key::take_recorded_audit_events().is_empty());
+    }
+
+    #[test]
+    fn undecryptable_cluster_key_is_reported_as_failure() {
+        let cluster_id = phala_mq::ContractClusterId::repeat_byte(1);
+        let my_ecdh_key = sr25519::Pair::from_seed(&[1u8; 32])
+            .derive_ecdh_key()
+            .unwrap();
+        let sender_ecdh_key = sr25519::Pair::from_seed(&[2u8; 32])
+            .derive_ecdh_key()
+            .unwrap();
+        let err = decrypt_dispatched_key(
+            &my_ecdh_key,
+            &EcdhPublicKey(sender_ecdh_key.public()),
+            &[0u8; 80],
+            &[0u8; 12],
+        )
+        .unwrap_err();
+        assert!(matches!(err, TransactionError::KeyDecryptionFailed));
+        assert!(matches!(
+            cluster_deployment_failure_report(cluster_id, &err.into()),
+            Some(WorkerClusterReport::ClusterDeploymentFailed { id }) if id == cluster_id
+        ));
+    }
+
+    #[test]
+    fn master_key_reshare_request_is_validated() {
+        let requester = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let ecdh_pubkey = |seed| {
+            let key = sr25519::Pair::from_seed(&[seed; 32])
+                .derive_ecdh_key()
+                .unwrap();
+            EcdhPublicKey(key.public())
+        };
+        let request = MasterKeyReshareRequest {
+            ecdh_pubkey: ecdh_pubkey(1),
+        };
+
+        assert!(check_master_key_reshare_request(
+            &requester,
+            true,
+            Some(&ecdh_pubkey(1)),
+            &request
+        )
+        .is_ok());
+        assert!(matches!(
+            check_master_key_reshare_request(&requester, false, Some(&ecdh_pubkey(1)), &request),
+            Err(TransactionError::NotGatekeeper)
+        ));
+        assert!(matches!(
+            check_master_key_reshare_request(&requester, true, Some(&ecdh_pubkey(2)), &request),
+            Err(TransactionError::EcdhPubkeyMismatch)
+        ));
+        assert!(matches!(
+            check_master_key_reshare_request(&requester, true, None, &request),
+            Err(TransactionError::EcdhPubkeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn first_gatekeeper_recovers_lost_master_key_by_reshare() {
+        // The request has to reach the other gatekeepers through the off-chain egress.
+        assert!(!MasterKeyReshareRequest::topic().starts_with(b"^"));
+
+        let history: Vec<_> = (0..3).map(rotated_master_key).collect();
+        let master_key = sr25519::Pair::restore_from_secret_key(&history[2].secret);
+        let mut recv_mq = MessageDispatcher::new();
+        let send_mq = MessageSendQueue::new();
+        let egress = send_mq.channel(MessageOrigin::Gatekeeper, master_key.into());
+        let mut second_gk = gk::Gatekeeper::new(history.clone(), &mut recv_mq, egress);
+        second_gk.register_on_chain();
+
+        // The first gatekeeper restarted without its seal.
+        let first_gk = sr25519::Pair::from_seed(&[1u8; 32]);
+        let first_gk_ecdh = first_gk.derive_ecdh_key().unwrap();
+        let request = MasterKeyReshareRequest {
+            ecdh_pubkey: EcdhPublicKey(first_gk_ecdh.public()),
+        };
+        check_master_key_reshare_request(
+            &first_gk.public(),
+            true,
+            Some(&request.ecdh_pubkey),
+            &request,
+        )
+        .unwrap();
+        second_gk.share_master_key(&first_gk.public(), &request.ecdh_pubkey, 1000);
+
+        let messages = send_mq.all_messages();
+        assert_eq!(messages.len(), 1);
+        let event = match KeyDistribution::<chain::BlockNumber>::decode(
+            &mut &messages[0].message.payload[..],
+        ) {
+            Ok(KeyDistribution::MasterKeyHistory(event)) => event,
+            other => panic!("Unexpected key distribution: {other:?}"),
+        };
+        assert_eq!(event.dest, first_gk.public());
+        let received = event
+            .encrypted_master_key_history
+            .iter()
+            .map(|(rotation_id, block_height, key)| RotatedMasterKey {
+                rotation_id: *rotation_id,
+                block_height: *block_height,
+                secret: decrypt_dispatched_key(
+                    &first_gk_ecdh,
+                    &key.ecdh_pubkey,
+                    &key.encrypted_key,
+                    &key.iv,
+                )
+                .unwrap()
+                .dump_secret_key(),
+            })
+            .collect();
+        assert_eq!(ordered_master_key_history(received).unwrap(), history);
+    }
+
+    #[test]
+    fn processing_times_are_recorded_per_block() {
+        let mut node = TestNode::new(1);
+        let storage = gatekeepers_storage(&[&node]);
+
+        let event = GatekeeperLaunch::FirstGatekeeper(NewGatekeeperEvent {
+            pubkey: node.pubkey(),
+            ecdh_pubkey: node.ecdh_pubkey(),
+        });
+        node.run_block(1, &storage, vec![pallet_message(&event)]);
+        // The messages are processed one round each, so the phases get recorded several times.
+        let registrations = (0x10..0x13u8)
+            .map(|seed| {
+                let event = SystemEvent::new_worker_event(
+                    WorkerPublicKey::from_raw([seed; 32]),
+                    WorkerEvent::Registered(phala_types::messaging::WorkerInfo {
+                        attestation_provider: None,
+                        confidence_level: 2,
+                    }),
+                );
+                pallet_message(&event)
+            })
+            .collect();
+        node.run_block(2, &storage, registrations);
+        node.run_block(3, &storage, vec![]);
+
+        let times = node.system.processing_times();
+        assert_eq!(times.blocks().count, 3);
+        // No message to process in block 3
+        assert_eq!(times.phase(Phase::SystemEvents).unwrap().count, 2);
+        assert_eq!(times.phase(Phase::Contracts).unwrap().count, 3);
+        assert_eq!(times.phase(Phase::Gatekeeper).unwrap().count, 3);
+        assert_eq!(times.phase(Phase::SidevmRestarts).unwrap().count, 3);
+        // The blocks are the sums of their phases.
+        let phases_sum: u64 = [
+            Phase::SystemEvents,
+            Phase::Contracts,
+            Phase::Gatekeeper,
+            Phase::SidevmRestarts,
+        ]
+        .iter()
+        .map(|&phase| times.phase(phase).unwrap().sum_us)
+        .sum();
+        assert!(times.blocks().sum_us >= phases_sum);
+    }
+
+    #[test]
+    fn gatekeeper_egress_sequence_survives_restart() {
+        let history = vec![rotated_master_key(0)];
+        let master_key = sr25519::Pair::restore_from_secret_key(&history[0].secret);
+        let mut recv_mq = MessageDispatcher::new();
+        let send_mq = MessageSendQueue::new();
+        let egress = send_mq.channel(MessageOrigin::Gatekeeper, master_key.into());
+        let mut gatekeeper = gk::Gatekeeper::new(history, &mut recv_mq, egress);
+        gatekeeper.register_on_chain();
+
+        let worker = sr25519::Pair::from_seed(&[1u8; 32]);
+        let ecdh_pubkey = EcdhPublicKey(worker.derive_ecdh_key().unwrap().public());
+        let storage = crate::ChainStorage::default();
+        for block_number in 1..=2 {
+            gatekeeper.share_master_key(&worker.public(), &ecdh_pubkey, block_number);
+            let block = BlockInfo {
+                block_number,
+                now_ms: 0,
+                storage: &storage,
+                send_mq: &send_mq,
+                recv_mq: &mut recv_mq,
+            };
+            gatekeeper.did_process_block(&block);
+        }
+        let last_sequence = send_mq.all_messages().last().unwrap().sequence;
+        let checkpoint = serde_cbor::to_vec(&gatekeeper).unwrap();
+
+        // Restored along with a send queue which lost track of the gatekeeper.
+        let mut recv_mq = MessageDispatcher::new();
+        let mut send_mq = MessageSendQueue::new();
+        let mut restored: gk::Gatekeeper<SignedMessageChannel> =
+            phala_mq::checkpoint_helper::using_dispatcher(&mut recv_mq, || {
+                phala_mq::checkpoint_helper::using_send_mq(&mut send_mq, || {
+                    serde_cbor::from_slice(&checkpoint).unwrap()
+                })
+            });
+        restored.restore_egress_sequence();
+        restored.share_master_key(&worker.public(), &ecdh_pubkey, 3);
+
+        let messages = send_mq.all_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sequence, last_sequence + 1);
+    }
+
+    #[derive(Default)]
+    struct BenchRecorder {
+        iterations: u64,
+        max_iterations: Option<u64>,
+        running: bool,
+        reports: Vec<(u64, u64)>,
+        heartbeats: Vec<chain::BlockNumber>,
+    }
+
+    impl WorkerStateMachineCallback for BenchRecorder {
+        fn bench_iterations(&self) -> u64 {
+            self.iterations
+        }
+        fn max_bench_iterations(&self) -> Option<u64> {
+            self.max_iterations
+        }
+        fn bench_resume(&mut self) {
+            self.running = true;
+        }
+        fn bench_pause(&mut self) {
+            self.running = false;
+        }
+        fn bench_report(&mut self, start_time: u64, iterations: u64) {
+            self.reports.push((start_time, iterations));
+        }
+        fn heartbeat(
+            &mut self,
+            _session_id: u32,
+            block_num: chain::BlockNumber,
+            _block_time: u64,
+            _iterations: u64,
+        ) {
+            self.heartbeats.push(block_num);
+        }
+    }
+
+    fn run_worker_block(
+        state: &mut WorkerState,
+        callback: &mut BenchRecorder,
+        block_number: chain::BlockNumber,
+        event: Option<WorkerEvent>,
+    ) {
+        let storage = crate::ChainStorage::default();
+        let send_mq = MessageSendQueue::new();
+        let mut recv_mq = MessageDispatcher::new();
+        let block = BlockInfo {
+            block_number,
+            now_ms: block_number as u64 * 12000,
+            storage: &storage,
+            send_mq: &send_mq,
+            recv_mq: &mut recv_mq,
+        };
+        if let Some(event) = event {
+            let event = SystemEvent::new_worker_event(state.pubkey, event);
+            state.process_event(&block, &event, callback, false);
+        }
+        state.on_block_processed(&block, callback);
+    }
+
+    #[test]
+    fn worker_egress_sequence_follows_the_chain() {
+        use crate::light_validation::utils::storage_map_prefix_twox_64_concat;
+
+        let mut node = TestNode::new(1);
+        // E.g. a local benchmark report accepted on chain, which the replay didn't send.
+        let storage = crate::ChainStorage::from_pairs(
+            vec![(
+                storage_map_prefix_twox_64_concat(
+                    b"PhalaMq",
+                    b"OffchainIngress",
+                    &MessageOrigin::Worker(node.pubkey()),
+                ),
+                3u64.encode(),
+            )]
+            .into_iter(),
+        );
+        node.run_block(1, &storage, vec![]);
+        assert_eq!(node.system.egress.next_sequence(), 3);
+
+        // Pending messages not accepted yet keep their sequences.
+        let report = RegistryEvent::BenchReport {
+            start_time: 0,
+            iterations: 0,
+        };
+        node.system.egress.push_message(&report);
+        node.run_block(2, &storage, vec![]);
+        assert_eq!(node.system.egress.next_sequence(), 4);
+    }
+
+    #[test]
+    fn local_benchmark_is_reported() {
+        let pubkey = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let mut state = WorkerState::new(pubkey);
+        let mut callback = BenchRecorder::default();
+
+        assert!(state.start_local_bench(0, 0, 2, &mut callback).is_err());
+        let registered = WorkerEvent::Registered(phala_types::messaging::WorkerInfo {
+            attestation_provider: None,
+            confidence_level: 2,
+        });
+        run_worker_block(&mut state, &mut callback, 1, Some(registered));
+        assert!(state.start_local_bench(1, 12000, 0, &mut callback).is_err());
+        state.start_local_bench(1, 12000, 2, &mut callback).unwrap();
+        assert!(callback.running);
+        assert!(state.start_local_bench(1, 12000, 2, &mut callback).is_err());
+
+        callback.iterations = 100;
+        run_worker_block(&mut state, &mut callback, 2, None);
+        assert!(callback.reports.is_empty());
+        callback.iterations = 250;
+        run_worker_block(&mut state, &mut callback, 3, None);
+        assert_eq!(callback.reports, vec![(12000, 250)]);
+        assert!(!callback.running);
+
+        // A computing session cancels the local benchmark and forbids new ones.
+        state.start_local_bench(3, 36000, 5, &mut callback).unwrap();
+        let started = WorkerEvent::Started {
+            session_id: 1,
+            init_v: 0,
+            init_p: 100,
+        };
+        run_worker_block(&mut state, &mut callback, 4, Some(started));
+        assert!(state.bench_state.is_none());
+        assert!(state.start_local_bench(4, 48000, 2, &mut callback).is_err());
+        for block_number in 5..10 {
+            run_worker_block(&mut state, &mut callback, block_number, None);
+        }
+        assert_eq!(callback.reports.len(), 1);
+    }
+
+    #[test]
+    fn state_dump_follows_the_transitions() {
+        let pubkey = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let mut state = WorkerState::new(pubkey);
+        let mut callback = BenchRecorder::default();
+        let dump = state.dump_state();
+        assert_eq!(dump.pubkey, pubkey);
+        assert_eq!(dump.hashed_id, U256::from(blake2_256(pubkey.as_ref())));
+        assert!(!dump.registered);
+
+        let registered = WorkerEvent::Registered(phala_types::messaging::WorkerInfo {
+            attestation_provider: None,
+            confidence_level: 2,
+        });
+        run_worker_block(&mut state, &mut callback, 1, Some(registered));
+        callback.iterations = 10;
+        let bench_start = WorkerEvent::BenchStart { duration: 5 };
+        run_worker_block(&mut state, &mut callback, 2, Some(bench_start));
+        let dump = state.dump_state();
+        assert!(dump.registered);
+        assert_eq!(
+            dump.bench_state,
+            Some(BenchStateDump {
+                start_block: 2,
+                start_time: 24000,
+                start_iter: 10,
+                duration: 5,
+                local: false,
+            })
+        );
+        assert_eq!(dump.working_state, None);
+
+        let started = WorkerEvent::Started {
+            session_id: 1,
+            init_v: 0,
+            init_p: 100,
+        };
+        run_worker_block(&mut state, &mut callback, 3, Some(started));
+        let session = WorkingStateDump {
+            session_id: 1,
+            paused: false,
+            start_time: 36000,
+            start_iter: 10,
+        };
+        assert_eq!(state.dump_state().working_state, Some(session.clone()));
+
+        let unresponsive = WorkerEvent::EnterUnresponsive;
+        run_worker_block(&mut state, &mut callback, 4, Some(unresponsive));
+        assert_eq!(
+            state.dump_state().working_state,
+            Some(WorkingStateDump {
+                paused: true,
+                ..session.clone()
+            })
+        );
+        let responsive = WorkerEvent::ExitUnresponsive;
+        run_worker_block(&mut state, &mut callback, 5, Some(responsive));
+        assert_eq!(state.dump_state().working_state, Some(session));
+
+        run_worker_block(&mut state, &mut callback, 6, Some(WorkerEvent::Stopped));
+        assert_eq!(state.dump_state().working_state, None);
+    }
+
+    #[test]
+    fn benchmark_survives_a_reset_iteration_counter() {
+        let pubkey = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let mut state = WorkerState::new(pubkey);
+        let mut callback = BenchRecorder::default();
+        let bench_start = || WorkerEvent::BenchStart { duration: 2 };
+
+        callback.iterations = 1000;
+        run_worker_block(&mut state, &mut callback, 1, Some(bench_start()));
+        // Reset in the middle of the benchmark.
+        callback.iterations = 30;
+        run_worker_block(&mut state, &mut callback, 2, None);
+        callback.iterations = 80;
+        run_worker_block(&mut state, &mut callback, 3, None);
+        assert_eq!(callback.reports, vec![(12000, 80)]);
+
+        callback.max_iterations = Some(500);
+        run_worker_block(&mut state, &mut callback, 4, Some(bench_start()));
+        callback.iterations = 10_000;
+        run_worker_block(&mut state, &mut callback, 6, None);
+        assert_eq!(callback.reports[1], (48000, 500));
+    }
+
+    #[test]
+    fn benchmark_iterations_are_clamped() {
+        assert_eq!(bench_iterations_since(100, 250, None), 150);
+        assert_eq!(bench_iterations_since(100, 250, Some(150)), 150);
+        assert_eq!(bench_iterations_since(100, 250, Some(149)), 149);
+        assert_eq!(bench_iterations_since(100, 40, None), 40);
+        assert_eq!(bench_iterations_since(100, 40, Some(10)), 10);
+    }
+
+    #[test]
+    fn overlong_chain_benchmark_is_ignored() {
+        let pubkey = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let mut state = WorkerState::new(pubkey);
+        let mut callback = BenchRecorder::default();
+
+        let too_long = WorkerEvent::BenchStart {
+            duration: MAX_BENCH_DURATION + 1,
+        };
+        run_worker_block(&mut state, &mut callback, 1, Some(too_long));
+        assert!(state.bench_state.is_none());
+        assert!(!callback.running);
+
+        let longest = WorkerEvent::BenchStart {
+            duration: MAX_BENCH_DURATION,
+        };
+        run_worker_block(&mut state, &mut callback, 2, Some(longest));
+        assert!(state.bench_state.is_some());
+        assert!(callback.running);
+    }
+
+    #[test]
+    fn simulated_challenge_matches_the_heartbeats() {
+        let pubkey = sr25519::Pair::from_seed(&[1u8; 32]).public();
+        let mut state = WorkerState::new(pubkey);
+        let mut callback = BenchRecorder::default();
+        let registered = WorkerEvent::Registered(phala_types::messaging::WorkerInfo {
+            attestation_provider: None,
+            confidence_level: 2,
+        });
+        run_worker_block(&mut state, &mut callback, 1, Some(registered));
+        let started = WorkerEvent::Started {
+            session_id: 1,
+            init_v: 0,
+            init_p: 100,
+        };
+        run_worker_block(&mut state, &mut callback, 2, Some(started));
+
+        let hashed_id = state.hashed_id;
+        let seed = U256::from(0x1234u64);
+        let x = hashed_id ^ seed;
+        let targets = [U256::zero(), x - 1, x, x + 1, U256::MAX];
+        for (n, &online_target) in targets.iter().enumerate() {
+            let block_number = 3 + n as chain::BlockNumber;
+            let challenge = HeartbeatChallenge {
+                seed,
+                online_target,
+            };
+            let outcome = state.challenge_outcome(&challenge);
+            assert_eq!(outcome.x, x);
+
+            let storage = crate::ChainStorage::default();
+            let send_mq = MessageSendQueue::new();
+            let mut recv_mq = MessageDispatcher::new();
+            let block = BlockInfo {
+                block_number,
+                now_ms: block_number as u64 * 12000,
+                storage: &storage,
+                send_mq: &send_mq,
+                recv_mq: &mut recv_mq,
+            };
+            let event = SystemEvent::HeartbeatChallenge(challenge);
+            state.process_event(&block, &event, &mut callback, false);
+            let responded = callback.heartbeats.last() == Some(&block_number);
+            assert_eq!(outcome.hit, responded, "online_target = {online_target}");
+        }
+        assert_eq!(callback.heartbeats, vec![5, 6, 7]);
+
+        let challenge = HeartbeatChallenge {
+            seed,
+            online_target: x - 1,
+        };
+        let outcome = state.challenge_outcome(&challenge);
+        assert!(!outcome.hit);
+        assert_eq!(outcome.margin, U256::one());
+    }
+
+    /// Yields bytes from a fixed pattern, standing in for a degraded entropy source.
+    struct PatternRng(&'static [u8], usize);
+
+    impl rand::RngCore for PatternRng {
+        fn next_u32(&mut self) -> u32 {
+            let mut buf = [0; 4];
+            self.fill_bytes(&mut buf);
+            u32::from_le_bytes(buf)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            let mut buf = [0; 8];
+            self.fill_bytes(&mut buf);
+            u64::from_le_bytes(buf)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.0[self.1 % self.0.len()];
+                self.1 += 1;
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "System state poisoned")]
+    fn poisoned_state_aborts_by_default() {
+        assert_eq!(PoisonedStatePolicy::default(), PoisonedStatePolicy::Abort);
+        state_poisoned(PoisonedStatePolicy::default(), "System state poisoned");
+    }
+
+    #[test]
+    fn poisoned_state_can_be_logged_and_skipped() {
+        state_poisoned(PoisonedStatePolicy::LogAndContinue, "System state poisoned");
+    }
+
+    #[test]
+    fn master_key_generation_rejects_degraded_rng() {
+        // Stuck at a single value.
+        assert!(master_key::generate(&mut PatternRng(&[0], 0)).is_err());
+        // No long runs, but heavily biased to a few values.
+        assert!(master_key::generate(&mut PatternRng(&[1, 2, 3, 4], 0)).is_err());
+    }
+
+    #[test]
+    fn unwritable_sealing_path_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |path: std::path::PathBuf| path.to_str().unwrap().to_string();
+
+        assert!(master_key::ensure_sealing_path_writable(&path(dir.path().into())).is_ok());
+        // The probe file is cleaned up
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let missing = dir.path().join("missing");
+        assert!(master_key::ensure_sealing_path_writable(&path(missing)).is_err());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(master_key::ensure_sealing_path_writable(&path(file)).is_err());
+    }
+
+    #[test]
+    fn master_key_generation_accepts_healthy_rng() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        assert!(master_key::generate(&mut rng).is_ok());
+        assert!(master_key::generate(&mut rand::thread_rng()).is_ok());
+    }
+
+    #[cfg(feature = "shadow-gk")]
+    #[test]
+    fn shadow_gk_pubkey_is_configurable() {
+        let khala_gk =
+            hex_literal::hex!("60067697c486c809737e50d30a67480c5f0cede44be181b96f7d59bc2116a850");
+        assert_eq!(shadow_gk_pubkey(None).0, khala_gk);
+
+        let configured = [0x42u8; 32];
+        let hex_str = format!("0x{}", hex::encode(configured));
+        assert_eq!(shadow_gk_pubkey(Some(&hex_str)).0, configured);
+        assert_eq!(shadow_gk_pubkey(Some(&hex_str[2..])).0, configured);
     }
 }
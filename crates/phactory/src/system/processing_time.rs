@@ -0,0 +1,277 @@
+//! Wall-clock time spent on processing the blocks.
+//!
+//! The time of each block is broken down by phase, so that operators can see which phase
+//! dominates and spot the slow blocks. The contracts taking too long in a block are tracked too.
+//! These are local statistics, never part of the state.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use log::warn;
+use phala_mq::ContractId;
+use serde::Serialize;
+
+/// Upper bounds of the histogram buckets in microseconds.
+const BUCKET_BOUNDS_US: [u64; 7] = [
+    100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000, 60_000_000,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Processing the system, gatekeeper launch and key distribution events.
+    SystemEvents,
+    /// The gatekeeper processing its messages and finishing the block.
+    Gatekeeper,
+    /// The contracts processing their commands and finishing the block.
+    Contracts,
+    /// Restarting the sidevm instances which have stopped.
+    SidevmRestarts,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::SystemEvents => "system_events",
+            Phase::Gatekeeper => "gatekeeper",
+            Phase::Contracts => "contracts",
+            Phase::SidevmRestarts => "sidevm_restarts",
+        }
+    }
+}
+
+/// A cumulative histogram of durations, in the same shape as a Prometheus histogram.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Histogram {
+    /// Pairs of the upper bound in microseconds and the number of durations within the bound.
+    pub buckets: Vec<(u64, u64)>,
+    /// Number of all durations observed.
+    pub count: u64,
+    /// Sum of all durations observed in microseconds.
+    pub sum_us: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: BUCKET_BOUNDS_US.iter().map(|&bound| (bound, 0)).collect(),
+            count: 0,
+            sum_us: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let us = as_micros(duration);
+        for (bound, count) in self.buckets.iter_mut() {
+            if us <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+/// A contract which has taken longer than the threshold to process a block.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SlowContract {
+    /// Number of the blocks it was slow in.
+    pub slow_blocks: u64,
+    /// The longest time it took to process a block in microseconds.
+    pub max_us: u64,
+    /// The last block it was slow in.
+    pub last_slow_block: chain::BlockNumber,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProcessingTimes {
+    /// The whole time of each block.
+    blocks: Histogram,
+    phases: BTreeMap<Phase, Histogram>,
+    #[serde(skip)]
+    current_block: Duration,
+    #[serde(skip)]
+    current_phases: BTreeMap<Phase, Duration>,
+    /// Contracts taking longer than this to process a block are flagged, none if not given.
+    #[serde(skip)]
+    slow_contract_threshold: Option<Duration>,
+    #[serde(skip)]
+    current_contracts: BTreeMap<ContractId, Duration>,
+    #[serde(skip)]
+    slow_contracts: BTreeMap<ContractId, SlowContract>,
+}
+
+impl ProcessingTimes {
+    pub fn set_slow_contract_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_contract_threshold = threshold;
+    }
+
+    /// Record the time spent on `contract` in the current block, on top of the time recorded
+    /// for its phase.
+    pub fn record_contract(&mut self, contract: ContractId, elapsed: Duration) {
+        *self.current_contracts.entry(contract).or_default() += elapsed;
+    }
+
+    /// Record the time spent on `phase` in the current block. A phase may be recorded several
+    /// times in a block, its times add up.
+    pub fn record(&mut self, phase: Phase, elapsed: Duration) {
+        *self.current_phases.entry(phase).or_default() += elapsed;
+        self.current_block += elapsed;
+    }
+
+    /// Record the whole time of the current block, which is the sum of its phases, along with the
+    /// time of each phase, and flag the contracts which were slow in it.
+    pub fn finish_block(&mut self, block_number: chain::BlockNumber) {
+        let elapsed = std::mem::take(&mut self.current_block);
+        self.blocks.observe(elapsed);
+        for (phase, elapsed) in std::mem::take(&mut self.current_phases) {
+            self.phases.entry(phase).or_default().observe(elapsed);
+        }
+
+        let contracts = std::mem::take(&mut self.current_contracts);
+        let threshold = match self.slow_contract_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        for (contract, elapsed) in contracts {
+            if elapsed <= threshold {
+                continue;
+            }
+            warn!(
+                "Contract 0x{} took {:?} to process block {block_number}",
+                hex::encode(contract),
+                elapsed
+            );
+            let slow = self.slow_contracts.entry(contract).or_default();
+            slow.slow_blocks += 1;
+            slow.max_us = slow.max_us.max(as_micros(elapsed));
+            slow.last_slow_block = block_number;
+        }
+    }
+
+    /// The `limit` slowest contracts by the longest time they took to process a block.
+    pub fn top_slow_contracts(&self, limit: usize) -> Vec<(ContractId, SlowContract)> {
+        let mut contracts: Vec<_> = self
+            .slow_contracts
+            .iter()
+            .map(|(contract, slow)| (*contract, slow.clone()))
+            .collect();
+        contracts.sort_by(|a, b| b.1.max_us.cmp(&a.1.max_us));
+        contracts.truncate(limit);
+        contracts
+    }
+
+    pub fn blocks(&self) -> &Histogram {
+        &self.blocks
+    }
+
+    pub fn phase(&self, phase: Phase) -> Option<&Histogram> {
+        self.phases.get(&phase)
+    }
+
+    pub fn phases(&self) -> impl Iterator<Item = (Phase, &Histogram)> {
+        self.phases
+            .iter()
+            .map(|(phase, histogram)| (*phase, histogram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_bucketed() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_secs(3600));
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.sum_us, 50 + 5_000 + 3_600_000_000);
+        assert_eq!(
+            histogram.buckets,
+            vec![
+                (100, 1),
+                (1_000, 1),
+                (10_000, 2),
+                (100_000, 2),
+                (1_000_000, 2),
+                (10_000_000, 2),
+                (60_000_000, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn blocks_sum_up_their_phases() {
+        let mut times = ProcessingTimes::default();
+        times.record(Phase::SystemEvents, Duration::from_micros(10));
+        // Observed once per block, however many times recorded
+        times.record(Phase::Contracts, Duration::from_micros(15));
+        times.record(Phase::Contracts, Duration::from_micros(5));
+        times.finish_block(1);
+        times.record(Phase::SystemEvents, Duration::from_micros(1));
+        times.finish_block(2);
+
+        assert_eq!(times.blocks().count, 2);
+        assert_eq!(times.blocks().sum_us, 31);
+        assert_eq!(times.phase(Phase::SystemEvents).unwrap().count, 2);
+        assert_eq!(times.phase(Phase::Contracts).unwrap().count, 1);
+        assert_eq!(times.phase(Phase::Contracts).unwrap().sum_us, 20);
+        assert_eq!(times.phase(Phase::Contracts).unwrap().buckets[0], (100, 1));
+        assert_eq!(times.phase(Phase::Gatekeeper), None);
+    }
+
+    #[test]
+    fn slow_contracts_are_flagged() {
+        let fast = ContractId::repeat_byte(1);
+        let slow = ContractId::repeat_byte(2);
+        let slower = ContractId::repeat_byte(3);
+
+        let mut times = ProcessingTimes::default();
+        times.record_contract(slow, Duration::from_secs(2));
+        times.finish_block(1);
+        // Nothing is flagged without a threshold
+        assert!(times.top_slow_contracts(10).is_empty());
+
+        times.set_slow_contract_threshold(Some(Duration::from_secs(1)));
+        times.record_contract(fast, Duration::from_millis(10));
+        // The time of a contract adds up within a block
+        times.record_contract(slow, Duration::from_millis(600));
+        times.record_contract(slow, Duration::from_millis(600));
+        times.record_contract(slower, Duration::from_secs(5));
+        times.finish_block(2);
+        times.record_contract(slow, Duration::from_secs(3));
+        times.finish_block(3);
+
+        assert_eq!(
+            times.top_slow_contracts(10),
+            vec![
+                (
+                    slower,
+                    SlowContract {
+                        slow_blocks: 1,
+                        max_us: 5_000_000,
+                        last_slow_block: 2,
+                    }
+                ),
+                (
+                    slow,
+                    SlowContract {
+                        slow_blocks: 2,
+                        max_us: 3_000_000,
+                        last_slow_block: 3,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(times.top_slow_contracts(1)[0].0, slower);
+    }
+}
@@ -0,0 +1,108 @@
+//! Caches the replies of contract queries within a block.
+//!
+//! Dashboards tend to poll the same query over and over, while a contract's state only changes
+//! when a block is dispatched. Caching is opt-in per cluster, since a query may also depend on
+//! things outside of the chain state, such as http requests or the local cache.
+
+use std::collections::BTreeMap;
+
+use phala_mq::{ContractClusterId, ContractId};
+
+use crate::types::{OpaqueQuery, OpaqueReply};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct QueryKey {
+    pub contract_id: ContractId,
+    pub origin: Option<chain::AccountId>,
+    pub query: OpaqueQuery,
+}
+
+#[derive(Default)]
+pub(crate) struct QueryCache {
+    block_number: chain::BlockNumber,
+    clusters: BTreeMap<ContractClusterId, BTreeMap<QueryKey, OpaqueReply>>,
+}
+
+impl QueryCache {
+    /// Drop all cached replies if the block has advanced.
+    fn advance_to(&mut self, block_number: chain::BlockNumber) {
+        if block_number > self.block_number {
+            self.block_number = block_number;
+            self.clusters.clear();
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        block_number: chain::BlockNumber,
+        cluster_id: &ContractClusterId,
+        key: &QueryKey,
+    ) -> Option<OpaqueReply> {
+        self.advance_to(block_number);
+        self.clusters.get(cluster_id)?.get(key).cloned()
+    }
+
+    /// Cache a reply made at `block_number`. At most `capacity` replies are cached per cluster in
+    /// a block.
+    pub fn insert(
+        &mut self,
+        block_number: chain::BlockNumber,
+        cluster_id: ContractClusterId,
+        capacity: usize,
+        key: QueryKey,
+        reply: OpaqueReply,
+    ) {
+        self.advance_to(block_number);
+        if block_number < self.block_number {
+            // The reply is outdated
+            return;
+        }
+        let entries = self.clusters.entry(cluster_id).or_default();
+        if entries.len() >= capacity {
+            return;
+        }
+        entries.insert(key, reply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(query: &[u8]) -> QueryKey {
+        QueryKey {
+            contract_id: ContractId::repeat_byte(1),
+            origin: None,
+            query: query.to_vec(),
+        }
+    }
+
+    #[test]
+    fn cache_hit_within_block_and_miss_after_block_advances() {
+        let cluster = ContractClusterId::repeat_byte(0);
+        let mut cache = QueryCache::default();
+        assert_eq!(cache.get(1, &cluster, &key(b"q")), None);
+        cache.insert(1, cluster, 8, key(b"q"), b"r".to_vec());
+        assert_eq!(cache.get(1, &cluster, &key(b"q")), Some(b"r".to_vec()));
+        assert_eq!(cache.get(1, &cluster, &key(b"other")), None);
+        assert_eq!(cache.get(2, &cluster, &key(b"q")), None);
+
+        // Replies made at an earlier block are not cached
+        cache.insert(1, cluster, 8, key(b"q"), b"r".to_vec());
+        assert_eq!(cache.get(2, &cluster, &key(b"q")), None);
+    }
+
+    #[test]
+    fn cache_is_bounded_per_cluster() {
+        let cluster = ContractClusterId::repeat_byte(0);
+        let mut cache = QueryCache::default();
+        cache.insert(1, cluster, 1, key(b"a"), b"a".to_vec());
+        cache.insert(1, cluster, 1, key(b"b"), b"b".to_vec());
+        assert_eq!(cache.get(1, &cluster, &key(b"a")), Some(b"a".to_vec()));
+        assert_eq!(cache.get(1, &cluster, &key(b"b")), None);
+        // Disabled
+        let other = ContractClusterId::repeat_byte(1);
+        cache.insert(1, other, 0, key(b"a"), b"a".to_vec());
+        assert_eq!(cache.get(1, &other, &key(b"a")), None);
+    }
+}
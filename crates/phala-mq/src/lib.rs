@@ -21,7 +21,7 @@ pub mod checkpoint_helper;
 #[cfg(feature = "dispatcher")]
 pub use dispatcher::{MessageDispatcher, TypedReceiveError, TypedReceiver};
 #[cfg(feature = "queue")]
-pub use send_queue::{MessageChannel, MessageSendQueue};
+pub use send_queue::{MessageChannel, MessagePriority, MessageSendQueue, MAX_SIMULATED_MESSAGES};
 #[cfg(any(feature = "queue", feature = "dispatcher"))]
 pub use simple_mpsc::{ReceiveError, Receiver};
 
@@ -67,6 +67,12 @@ pub mod traits {
         fn set_dummy(&self, _dummy: bool) {}
         /// Set signer for the channel.
         fn set_signer(&mut self, _signer: Self::Signer) {}
+        /// The sequence of the next message pushed into the channel.
+        fn next_sequence(&self) -> u64 {
+            0
+        }
+        /// Make sure the next message pushed into the channel takes at least the given sequence.
+        fn ensure_next_sequence(&self, _sequence: u64) {}
     }
 
     /// A MessagePrepareChannel is used prepare messages which later can be pushed into the message queue.
@@ -1,7 +1,11 @@
 use crate::{
     Message, MessageOrigin, MessageSigner, Mutex, SenderId, SignedMessage, SigningMessage,
 };
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Default, Serialize, Deserialize)]
@@ -11,9 +15,48 @@ struct Channel {
     dummy: bool,
 }
 
+/// The pending egress of high priority senders is drained before the normal ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessagePriority {
+    High,
+    Normal,
+}
+
+impl MessagePriority {
+    /// Workers and gatekeepers send heartbeats and key operations, which should not be delayed
+    /// behind the bulk reports of contracts and clusters.
+    pub fn of(sender: &SenderId) -> Self {
+        match sender {
+            MessageOrigin::Worker(_) | MessageOrigin::Gatekeeper => MessagePriority::High,
+            _ => MessagePriority::Normal,
+        }
+    }
+}
+
+/// The channels in the order to be drained, by priority and then by sender.
+fn drain_order(inner: &BTreeMap<SenderId, Channel>) -> Vec<(&SenderId, &Channel)> {
+    let mut channels: Vec<_> = inner.iter().collect();
+    channels.sort_by_key(|(sender, _)| MessagePriority::of(sender));
+    channels
+}
+
+/// The number of latest messages kept in simulation mode.
+pub const MAX_SIMULATED_MESSAGES: usize = 1024;
+
+#[derive(Default)]
+struct Simulation {
+    /// The latest `MAX_SIMULATED_MESSAGES` messages recorded.
+    messages: VecDeque<SignedMessage>,
+    /// The next simulated sequence of each sender, starting from its real one.
+    sequences: BTreeMap<SenderId, u64>,
+}
+
 #[derive(Clone, Default)]
 pub struct MessageSendQueue {
     inner: Arc<Mutex<BTreeMap<SenderId, Channel>>>,
+    /// When set, the queue is in simulation mode and messages are recorded here rather than
+    /// being queued for sending.
+    simulated: Arc<Mutex<Option<Simulation>>>,
 }
 
 impl Serialize for MessageSendQueue {
@@ -35,6 +78,7 @@ impl<'de> Deserialize<'de> for MessageSendQueue {
         let inner = BTreeMap::<SenderId, Channel>::deserialize(deserializer)?;
         Ok(MessageSendQueue {
             inner: Arc::new(Mutex::new(inner)),
+            simulated: Default::default(),
         })
     }
 }
@@ -43,6 +87,7 @@ impl MessageSendQueue {
     pub fn new() -> Self {
         MessageSendQueue {
             inner: Default::default(),
+            simulated: Default::default(),
         }
     }
 
@@ -56,16 +101,29 @@ impl MessageSendQueue {
         constructor: impl FnOnce(u64) -> SignedMessage,
     ) {
         let mut inner = self.inner.lock();
-        let entry = inner.entry(sender).or_default();
-        if !entry.dummy {
-            let message = constructor(entry.sequence);
+        let entry = inner.entry(sender.clone()).or_default();
+        let mut simulated = self.simulated.lock();
+        // Simulated messages take sequences of their own, so that the real sequences carry on
+        // without gaps once the simulation ends.
+        let sequence = match &*simulated {
+            Some(simulation) => simulation
+                .sequences
+                .get(&sender)
+                .copied()
+                .unwrap_or(entry.sequence),
+            None => entry.sequence,
+        };
+        let message = if entry.dummy {
+            None
+        } else {
+            let message = constructor(sequence);
 
             if log::log_enabled!(target: "phala_mq", log::Level::Debug) {
                 log::debug!(target: "phala_mq",
                     "Sending message, from={}, to={:?}, seq={}, payload_hash={}",
                     message.message.sender,
                     message.message.destination,
-                    entry.sequence,
+                    sequence,
                     hex::encode(sp_core::blake2_256(&message.message.payload)),
                 );
             } else {
@@ -73,12 +131,71 @@ impl MessageSendQueue {
                     "Sending message, from={}, to={:?}, seq={}",
                     message.message.sender,
                     message.message.destination,
-                    entry.sequence,
+                    sequence,
                 );
             }
-            entry.messages.push(message);
+            Some(message)
+        };
+        match &mut *simulated {
+            Some(simulation) => {
+                simulation.sequences.insert(sender, sequence + 1);
+                if let Some(message) = message {
+                    if simulation.messages.len() >= MAX_SIMULATED_MESSAGES {
+                        simulation.messages.pop_front();
+                    }
+                    simulation.messages.push_back(message);
+                }
+            }
+            None => {
+                entry.sequence = sequence + 1;
+                if let Some(message) = message {
+                    entry.messages.push(message);
+                }
+            }
+        }
+    }
+
+    /// Enable or disable the simulation mode.
+    ///
+    /// In simulation mode, all messages pushed to the queue are recorded in a separate buffer
+    /// instead of being sent, dropping the oldest ones beyond `MAX_SIMULATED_MESSAGES`. They
+    /// don't consume the sequences of the senders.
+    /// Disabling the simulation mode discards the recorded messages.
+    pub fn set_simulation(&self, enabled: bool) {
+        let mut simulated = self.simulated.lock();
+        match (enabled, simulated.is_some()) {
+            (true, false) => *simulated = Some(Simulation::default()),
+            (false, true) => *simulated = None,
+            _ => {}
         }
-        entry.sequence += 1;
+    }
+
+    pub fn is_simulation(&self) -> bool {
+        self.simulated.lock().is_some()
+    }
+
+    /// The latest messages recorded in simulation mode.
+    pub fn simulated_messages(&self) -> Vec<SignedMessage> {
+        self.simulated
+            .lock()
+            .as_ref()
+            .map(|simulation| simulation.messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The sequence of the next message from `sender`.
+    pub fn next_sequence(&self, sender: &SenderId) -> u64 {
+        self.inner
+            .lock()
+            .get(sender)
+            .map_or(0, |channel| channel.sequence)
+    }
+
+    /// Move the next sequence of `sender` forward to `sequence`, never backward.
+    pub fn ensure_next_sequence(&self, sender: SenderId, sequence: u64) {
+        let mut inner = self.inner.lock();
+        let entry = inner.entry(sender).or_default();
+        entry.sequence = entry.sequence.max(sequence);
     }
 
     pub fn set_dummy_mode(&self, sender: SenderId, dummy: bool) {
@@ -87,18 +204,20 @@ impl MessageSendQueue {
         entry.dummy = dummy;
     }
 
+    /// All pending messages, in the order of `MessagePriority`.
     pub fn all_messages(&self) -> Vec<SignedMessage> {
         let inner = self.inner.lock();
-        inner
-            .iter()
+        drain_order(&inner)
+            .into_iter()
             .flat_map(|(_k, v)| v.messages.iter().cloned())
             .collect()
     }
 
-    pub fn all_messages_grouped(&self) -> BTreeMap<MessageOrigin, Vec<SignedMessage>> {
+    /// All pending messages grouped by sender, in the order of `MessagePriority`.
+    pub fn all_messages_grouped(&self) -> Vec<(MessageOrigin, Vec<SignedMessage>)> {
         let inner = self.inner.lock();
-        inner
-            .iter()
+        drain_order(&inner)
+            .into_iter()
             .map(|(k, v)| (k.clone(), v.messages.clone()))
             .collect()
     }
@@ -119,6 +238,54 @@ impl MessageSendQueue {
             .sum()
     }
 
+    /// Re-enqueue the messages exported by `all_messages` from another queue, e.g. to hand over
+    /// the pending egress to a new worker.
+    ///
+    /// The messages keep their sequences and signatures. Messages already in the queue are
+    /// skipped, and the next sequence of each sender is moved past the imported messages.
+    pub fn import_messages(&self, messages: impl IntoIterator<Item = SignedMessage>) {
+        let mut inner = self.inner.lock();
+        for message in messages {
+            let entry = inner.entry(message.message.sender.clone()).or_default();
+            entry.sequence = entry.sequence.max(message.sequence + 1);
+            if let Err(pos) = entry
+                .messages
+                .binary_search_by_key(&message.sequence, |msg| msg.sequence)
+            {
+                entry.messages.insert(pos, message);
+            }
+        }
+    }
+
+    /// Drop the pending message of `sender` at `sequence`, e.g. a poison message which the chain
+    /// keeps rejecting.
+    ///
+    /// The chain only accepts the messages of a sender in consecutive sequences, so the messages
+    /// after the dropped one are dropped as well, and the next message of the sender takes over
+    /// the sequence of the dropped one. Returns the number of dropped messages, which is zero if
+    /// there is no pending message at `sequence`.
+    ///
+    /// Only the messages of a `MessageOrigin::Worker` can be dropped. The egress sequences of the
+    /// gatekeeper, clusters and contracts are part of the state replicated by the other workers,
+    /// so rewinding them on a single worker would make it diverge.
+    pub fn drop_messages_from(&self, sender: &SenderId, sequence: u64) -> usize {
+        if !matches!(sender, MessageOrigin::Worker(_)) {
+            return 0;
+        }
+        let mut inner = self.inner.lock();
+        let entry = match inner.get_mut(sender) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+        if !entry.messages.iter().any(|msg| msg.sequence == sequence) {
+            return 0;
+        }
+        let n_before = entry.messages.len();
+        entry.messages.retain(|msg| msg.sequence < sequence);
+        entry.sequence = sequence;
+        n_before - entry.messages.len()
+    }
+
     /// Purge the messages which are aready accepted on chain.
     pub fn purge(&self, next_sequence_for: impl Fn(&SenderId) -> u64) {
         let mut inner = self.inner.lock();
@@ -151,6 +318,11 @@ mod msg_channel {
                 signer,
             }
         }
+
+        /// The queue this channel pushes messages into.
+        pub fn send_queue(&self) -> &MessageSendQueue {
+            &self.queue
+        }
     }
 
     impl<Si: MessageSigner + Clone> MessageChannel<Si> {
@@ -187,6 +359,15 @@ mod msg_channel {
         fn set_signer(&mut self, signer: Self::Signer) {
             self.signer = signer;
         }
+
+        fn next_sequence(&self) -> u64 {
+            self.queue.next_sequence(&self.sender)
+        }
+
+        fn ensure_next_sequence(&self, sequence: u64) {
+            self.queue
+                .ensure_next_sequence(self.sender.clone(), sequence);
+        }
     }
 
     impl<T: MessageSigner + Clone> crate::traits::MessagePrepareChannel for MessageChannel<T> {
@@ -99,6 +99,19 @@ impl MessageOrigin {
         matches!(self, Self::Gatekeeper)
     }
 
+    /// Returns if the origin is from a user
+    pub fn is_user(&self) -> bool {
+        matches!(self, Self::AccountId(_))
+    }
+
+    /// Returns the account id if the origin is from a user
+    pub fn as_account_id(&self) -> Option<&AccountId> {
+        match self {
+            Self::AccountId(account_id) => Some(account_id),
+            _ => None,
+        }
+    }
+
     /// Returns the account id if the origin is from a user, or `Err(BadOrigin)` otherwise
     pub fn account(&self) -> Result<AccountId32, BadOrigin> {
         match self {
@@ -254,6 +267,60 @@ impl Message {
     }
 }
 
+/// Default max length of the topics accepted by `MessageBuilder`.
+pub const DEFAULT_MAX_TOPIC_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum BuildError {
+    #[display(fmt = "Empty topic")]
+    EmptyTopic,
+    #[display(fmt = "Topic too long, {} > {}", len, max)]
+    TopicTooLong { len: usize, max: usize },
+}
+
+/// Builds messages from a sender, validating the destination topics.
+///
+/// Prefer it over `Message::new` when the topic is not a well-known constant.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    sender: SenderId,
+    max_topic_len: usize,
+}
+
+impl MessageBuilder {
+    pub fn new(sender: impl Into<SenderId>) -> Self {
+        Self {
+            sender: sender.into(),
+            max_topic_len: DEFAULT_MAX_TOPIC_LEN,
+        }
+    }
+
+    /// Set the max length of the topics accepted.
+    pub fn max_topic_len(mut self, max_topic_len: usize) -> Self {
+        self.max_topic_len = max_topic_len;
+        self
+    }
+
+    /// Build a message to `destination`, which must be non-empty and no longer than the max.
+    pub fn build(
+        &self,
+        destination: impl Into<Path>,
+        payload: Vec<u8>,
+    ) -> Result<Message, BuildError> {
+        let destination = destination.into();
+        if destination.is_empty() {
+            return Err(BuildError::EmptyTopic);
+        }
+        if destination.len() > self.max_topic_len {
+            return Err(BuildError::TopicTooLong {
+                len: destination.len(),
+                max: self.max_topic_len,
+            });
+        }
+        Ok(Message::new(self.sender.clone(), destination, payload))
+    }
+}
+
 pub struct DecodedMessage<T> {
     pub sender: SenderId,
     pub destination: Topic,
@@ -275,6 +342,18 @@ impl SignedMessage {
         }
         .raw_data()
     }
+
+    /// Returns if the message is signed by `pubkey`
+    #[cfg(feature = "signers")]
+    pub fn verify(&self, pubkey: &sp_core::sr25519::Public) -> bool {
+        use core::convert::TryFrom;
+        use sp_core::{crypto::Pair as _, sr25519};
+
+        match sr25519::Signature::try_from(&self.signature[..]) {
+            Ok(signature) => sr25519::Pair::verify(&signature, self.data_be_signed(), pubkey),
+            Err(_) => false,
+        }
+    }
 }
 
 #[derive(Encode)]
@@ -309,3 +388,62 @@ impl<Signer: MessageSigner> SigningMessage<Signer> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn all_origins() -> Vec<MessageOrigin> {
+        vec![
+            MessageOrigin::Pallet(b"Pallet".to_vec()),
+            MessageOrigin::Contract(ContractId::repeat_byte(1)),
+            MessageOrigin::Worker(sp_core::sr25519::Public([2; 32])),
+            MessageOrigin::AccountId(AccountId::repeat_byte(3)),
+            MessageOrigin::MultiLocation(b"location".to_vec()),
+            MessageOrigin::Gatekeeper,
+            MessageOrigin::Cluster(ContractClusterId::repeat_byte(4)),
+            MessageOrigin::Reserved,
+        ]
+    }
+
+    #[test]
+    fn message_builder_rejects_bad_topics() {
+        let builder = MessageBuilder::new(MessageOrigin::Gatekeeper).max_topic_len(8);
+        assert_eq!(
+            builder.build(Vec::new(), b"payload".to_vec()),
+            Err(BuildError::EmptyTopic)
+        );
+        assert_eq!(
+            builder.build(*b"123456789", b"payload".to_vec()),
+            Err(BuildError::TopicTooLong { len: 9, max: 8 })
+        );
+        assert_eq!(
+            builder.build(*b"12345678", b"payload".to_vec()),
+            Ok(Message::new(
+                MessageOrigin::Gatekeeper,
+                *b"12345678",
+                b"payload".to_vec()
+            ))
+        );
+        assert_eq!(
+            MessageBuilder::new(MessageOrigin::Gatekeeper).build(Vec::new(), vec![]),
+            Err(BuildError::EmptyTopic)
+        );
+    }
+
+    #[test]
+    fn origin_kind_helpers() {
+        for origin in all_origins() {
+            let is_pallet = matches!(origin, MessageOrigin::Pallet(_));
+            let is_user = matches!(origin, MessageOrigin::AccountId(_));
+            assert_eq!(origin.is_pallet(), is_pallet, "{origin}");
+            assert_eq!(origin.is_user(), is_user, "{origin}");
+            assert_eq!(origin.as_account_id().is_some(), is_user, "{origin}");
+        }
+        assert_eq!(
+            MessageOrigin::AccountId(AccountId::repeat_byte(3)).as_account_id(),
+            Some(&AccountId::repeat_byte(3))
+        );
+    }
+}
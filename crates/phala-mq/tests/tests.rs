@@ -2,18 +2,20 @@ use phala_mq::MessageOrigin;
 use phala_mq::traits::MessageChannel;
 
 #[cfg(feature = "queue")]
-#[test]
-fn test_send_message() {
-    #[derive(Clone)]
-    struct TestSigner(Vec<u8>);
+#[derive(Clone)]
+struct TestSigner(Vec<u8>);
 
-    impl MessageSigner for TestSigner {
-        fn sign(&self, _data: &[u8]) -> Vec<u8> {
-            self.0.clone()
-        }
+#[cfg(feature = "queue")]
+impl phala_mq::MessageSigner for TestSigner {
+    fn sign(&self, _data: &[u8]) -> Vec<u8> {
+        self.0.clone()
     }
+}
 
-    use phala_mq::{MessageSendQueue, MessageSigner};
+#[cfg(feature = "queue")]
+#[test]
+fn test_send_message() {
+    use phala_mq::MessageSendQueue;
     let queue = MessageSendQueue::new();
     let runtime = MessageOrigin::Pallet(b"p0".to_vec());
     let worker0 = MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([0u8; 32]));
@@ -74,6 +76,196 @@ fn test_send_message() {
     }
 }
 
+#[cfg(feature = "queue")]
+#[test]
+fn test_import_messages() {
+    use phala_mq::MessageSendQueue;
+
+    let runtime = MessageOrigin::Pallet(b"p0".to_vec());
+    let worker0 = MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([0u8; 32]));
+
+    let source = MessageSendQueue::new();
+    let handle0 = source.channel(runtime.clone(), TestSigner(b"key0".to_vec()));
+    let handle1 = source.channel(worker0.clone(), TestSigner(b"key1".to_vec()));
+    handle0.push_data(b"payload00".to_vec(), b"phala.network/test0".to_vec());
+    handle1.push_data(b"payload10".to_vec(), b"phala.network/test1".to_vec());
+    handle0.push_data(b"payload01".to_vec(), b"phala.network/test0".to_vec());
+    source.purge(|sender| match &sender {
+        MessageOrigin::Pallet(_) => 1,
+        _ => 0,
+    });
+
+    let exported = source.all_messages();
+    let target = MessageSendQueue::new();
+    target.import_messages(exported.clone());
+    assert_eq!(target.all_messages(), exported);
+    // Importing twice doesn't duplicate messages
+    target.import_messages(exported.clone());
+    assert_eq!(target.all_messages(), exported);
+
+    // New messages continue the imported sequences
+    let handle0 = target.channel(runtime.clone(), TestSigner(b"key0".to_vec()));
+    handle0.push_data(b"payload02".to_vec(), b"phala.network/test0".to_vec());
+    let messages = target.messages(&runtime);
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].sequence, 1);
+    assert_eq!(messages[0].signature, b"key0");
+    assert_eq!(messages[1].sequence, 2);
+    assert_eq!(target.messages(&worker0).len(), 1);
+}
+
+#[cfg(feature = "queue")]
+#[test]
+fn test_high_priority_messages_drained_first() {
+    use phala_mq::{MessagePriority, MessageSendQueue};
+
+    let contract = MessageOrigin::Contract([1u8; 32].into());
+    let cluster = MessageOrigin::Cluster([2u8; 32].into());
+    let worker0 = MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([0u8; 32]));
+    assert_eq!(MessagePriority::of(&worker0), MessagePriority::High);
+    assert_eq!(
+        MessagePriority::of(&MessageOrigin::Gatekeeper),
+        MessagePriority::High
+    );
+    assert_eq!(MessagePriority::of(&contract), MessagePriority::Normal);
+
+    let queue = MessageSendQueue::new();
+    let contract_handle = queue.channel(contract.clone(), TestSigner(b"key0".to_vec()));
+    let cluster_handle = queue.channel(cluster.clone(), TestSigner(b"key1".to_vec()));
+    for _ in 0..3 {
+        contract_handle.push_data(b"report".to_vec(), b"phala.network/report".to_vec());
+        cluster_handle.push_data(b"report".to_vec(), b"phala.network/report".to_vec());
+    }
+    // The heartbeat is queued last but drained first.
+    let worker_handle = queue.channel(worker0.clone(), TestSigner(b"key2".to_vec()));
+    worker_handle.push_data(b"heartbeat".to_vec(), b"phala.network/heartbeat".to_vec());
+
+    let senders: Vec<_> = queue
+        .all_messages_grouped()
+        .into_iter()
+        .map(|(sender, _)| sender)
+        .collect();
+    assert_eq!(senders, vec![worker0.clone(), contract, cluster]);
+
+    let messages = queue.all_messages();
+    assert_eq!(messages.len(), 7);
+    assert_eq!(messages[0].message.sender, worker0);
+    assert_eq!(messages[0].message.payload, b"heartbeat");
+}
+
+#[cfg(feature = "queue")]
+#[test]
+fn test_drop_messages_from() {
+    use phala_mq::MessageSendQueue;
+
+    let runtime = MessageOrigin::Pallet(b"p0".to_vec());
+    let worker0 = MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([0u8; 32]));
+
+    let queue = MessageSendQueue::new();
+    let handle0 = queue.channel(worker0.clone(), TestSigner(b"key0".to_vec()));
+    let handle1 = queue.channel(runtime.clone(), TestSigner(b"key1".to_vec()));
+    for payload in [&b"ok"[..], b"poison", b"after"] {
+        handle0.push_data(payload.to_vec(), b"phala.network/test0".to_vec());
+    }
+    handle1.push_data(b"other".to_vec(), b"phala.network/test1".to_vec());
+
+    let pending: Vec<_> = queue
+        .messages(&worker0)
+        .into_iter()
+        .map(|msg| (msg.sequence, msg.message.payload))
+        .collect();
+    assert_eq!(
+        pending,
+        vec![
+            (0, b"ok".to_vec()),
+            (1, b"poison".to_vec()),
+            (2, b"after".to_vec())
+        ]
+    );
+
+    // No message at the sequence, nothing dropped.
+    assert_eq!(queue.drop_messages_from(&worker0, 5), 0);
+    assert_eq!(queue.count_messages(), 4);
+
+    // Only the messages of the worker itself can be dropped.
+    assert_eq!(queue.drop_messages_from(&runtime, 0), 0);
+    assert_eq!(queue.drop_messages_from(&MessageOrigin::Gatekeeper, 0), 0);
+    assert_eq!(queue.count_messages(), 4);
+
+    // The poison message and the one after it are dropped.
+    assert_eq!(queue.drop_messages_from(&worker0, 1), 2);
+    let messages = queue.messages(&worker0);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].sequence, 0);
+    assert_eq!(queue.messages(&runtime).len(), 1);
+
+    // The next message takes over the dropped sequence, leaving no gap.
+    handle0.push_data(b"retry".to_vec(), b"phala.network/test0".to_vec());
+    let messages = queue.messages(&worker0);
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].sequence, 1);
+    assert_eq!(messages[1].message.payload, b"retry");
+}
+
+#[cfg(feature = "queue")]
+#[test]
+fn test_simulated_messages_are_capped() {
+    use phala_mq::{MessageSendQueue, MAX_SIMULATED_MESSAGES};
+
+    let runtime = MessageOrigin::Pallet(b"p0".to_vec());
+    let queue = MessageSendQueue::new();
+    queue.set_simulation(true);
+    let handle = queue.channel(runtime, TestSigner(b"key0".to_vec()));
+    for _ in 0..MAX_SIMULATED_MESSAGES + 10 {
+        handle.push_data(b"payload".to_vec(), b"phala.network/test0".to_vec());
+    }
+    assert_eq!(queue.count_messages(), 0);
+
+    // Only the latest messages are kept.
+    let simulated = queue.simulated_messages();
+    assert_eq!(simulated.len(), MAX_SIMULATED_MESSAGES);
+    assert_eq!(simulated[0].sequence, 10);
+    assert_eq!(
+        simulated.last().unwrap().sequence,
+        (MAX_SIMULATED_MESSAGES + 9) as u64
+    );
+
+    queue.set_simulation(false);
+    assert!(queue.simulated_messages().is_empty());
+}
+
+#[cfg(feature = "queue")]
+#[test]
+fn test_simulated_messages_take_no_sequence() {
+    use phala_mq::MessageSendQueue;
+
+    let runtime = MessageOrigin::Pallet(b"p0".to_vec());
+    let queue = MessageSendQueue::new();
+    let handle = queue.channel(runtime.clone(), TestSigner(b"key0".to_vec()));
+    handle.push_data(b"real".to_vec(), b"phala.network/test0".to_vec());
+
+    queue.set_simulation(true);
+    handle.push_data(b"dry0".to_vec(), b"phala.network/test0".to_vec());
+    handle.push_data(b"dry1".to_vec(), b"phala.network/test0".to_vec());
+    let sequences: Vec<_> = queue
+        .simulated_messages()
+        .iter()
+        .map(|msg| msg.sequence)
+        .collect();
+    assert_eq!(sequences, vec![1, 2]);
+    assert_eq!(queue.next_sequence(&runtime), 1);
+
+    // The real messages carry on without a gap.
+    queue.set_simulation(false);
+    handle.push_data(b"real".to_vec(), b"phala.network/test0".to_vec());
+    let sequences: Vec<_> = queue
+        .messages(&runtime)
+        .iter()
+        .map(|msg| msg.sequence)
+        .collect();
+    assert_eq!(sequences, vec![0, 1]);
+}
+
 #[cfg(feature = "dispatcher")]
 #[test]
 fn test_dispatcher() {
@@ -174,3 +366,38 @@ fn test_select_order() {
     }
     assert_eq!(payloads, [0, 1, 2, 3, 4]);
 }
+
+#[cfg(all(feature = "queue", feature = "signers"))]
+#[test]
+fn test_signed_message_verify() {
+    use phala_mq::{Message, SigningMessage};
+    use sp_core::{sr25519, Pair};
+
+    let key = sr25519::Pair::from_seed(&[1; 32]);
+    let other_key = sr25519::Pair::from_seed(&[2; 32]);
+    let message = Message::new(
+        MessageOrigin::Worker(key.public()),
+        *b"phala.network/test",
+        b"payload".to_vec(),
+    );
+    let signed = SigningMessage {
+        message,
+        signer: phala_mq::Sr25519Signer::from(key.clone()),
+    }
+    .sign(5);
+
+    assert!(signed.verify(&key.public()));
+    assert!(!signed.verify(&other_key.public()));
+
+    let mut tampered = signed.clone();
+    tampered.message.payload = b"tampered".to_vec();
+    assert!(!tampered.verify(&key.public()));
+
+    let mut tampered = signed.clone();
+    tampered.sequence += 1;
+    assert!(!tampered.verify(&key.public()));
+
+    let mut truncated = signed;
+    truncated.signature.pop();
+    assert!(!truncated.verify(&key.public()));
+}
@@ -0,0 +1,65 @@
+//! Encode a JSON array element by element.
+
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// Writes the elements of a JSON array to `W` one at a time, so that the whole array never needs
+/// to be built in memory before encoding.
+pub(crate) struct JsonArrayWriter<W> {
+    writer: W,
+    empty: bool,
+}
+
+impl<W: Write> JsonArrayWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(b"[")?;
+        Ok(Self {
+            writer,
+            empty: true,
+        })
+    }
+
+    pub fn push<T: Serialize>(&mut self, element: &T) -> serde_json::Result<()> {
+        if !self.empty {
+            self.writer.write_all(b",").map_err(serde_json::Error::io)?;
+        }
+        self.empty = false;
+        serde_json::to_writer(&mut self.writer, element)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(b"]")?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GetStorageChangesResponse, StorageChanges, StorageKey};
+
+    fn stream(response: &GetStorageChangesResponse) -> Vec<u8> {
+        let mut writer = JsonArrayWriter::new(vec![]).unwrap();
+        for changes in response {
+            writer.push(changes).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn streamed_output_equals_batch_output() {
+        let key = |k: &[u8]| StorageKey(k.to_vec());
+        let response: GetStorageChangesResponse = vec![
+            StorageChanges {
+                main_storage_changes: vec![(key(b"a"), Some(key(b"1"))), (key(b"b"), None)],
+                child_storage_changes: vec![],
+            },
+            StorageChanges {
+                main_storage_changes: vec![],
+                child_storage_changes: vec![(key(b"child"), vec![(key(b"c"), Some(key(b"3")))])],
+            },
+        ];
+        assert_eq!(stream(&response), serde_json::to_vec(&response).unwrap());
+        assert_eq!(stream(&vec![]), b"[]");
+    }
+}
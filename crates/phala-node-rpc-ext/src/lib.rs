@@ -1,47 +1,111 @@
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use codec::Encode;
+use futures::{channel::oneshot, FutureExt as _, StreamExt as _};
 use jsonrpsee::{
     core::{async_trait, Error as JsonRpseeError, RpcResult},
     proc_macros::rpc,
     types::error::{CallError, ErrorObject},
-    RpcModule
+    PendingSubscription, RpcModule
 };
 use pallet_mq_runtime_api::MqApi;
 use sc_client_api::blockchain::{HeaderBackend, HeaderMetadata};
-use sc_client_api::{backend, Backend, BlockBackend, StorageProvider};
+use sc_client_api::{backend, Backend, BlockBackend, BlockchainEvents, StorageProvider};
 use sc_transaction_pool_api::{InPoolTransaction, TransactionPool};
+use serde_json::value::RawValue;
 use sp_api::{ApiExt, Core, ProvideRuntimeApi, StateBackend};
+use sp_core::{traits::SpawnNamed, H256};
 use sp_runtime::traits::Header;
 use sp_runtime::{generic::BlockId, traits::Block as BlockT};
 use std::fmt::Display;
-use storage_changes::Error as StorageChangesError;
+use storage_changes::{ChangesFilter, Error as StorageChangesError};
 
-pub use storage_changes::{GetStorageChangesResponse, MakeInto, StorageChanges};
+pub use storage_changes::{
+    BlockStorageChanges, DedupMode, GetStorageChangesPerBlockResponse, GetStorageChangesResponse,
+    MakeInto, StorageChanges, StorageKey,
+};
 
+mod json_stream;
 mod mq_seq;
 mod storage_changes;
 
 /// Base code for all errors.
 const CUSTOM_RPC_ERROR: i32 = 10000;
 
+/// Max number of block replaying requests being processed at the same time.
+const MAX_CONCURRENT_STORAGE_CHANGES_REQUESTS: usize = 4;
+
+/// Max number of storage changes subscriptions alive at the same time.
+const MAX_STORAGE_CHANGES_SUBSCRIPTIONS: usize = 16;
+
 #[rpc(server)]
 pub trait NodeRpcExtApi<BlockHash> {
     /// Return the storage changes made by each block one by one from `from` to `to`(both inclusive).
     /// To get better performance, the client should limit the amount of requested block properly.
     /// 100 blocks for each call should be OK. REQUESTS FOR TOO LARGE NUMBER OF BLOCKS WILL BE REJECTED.
+    /// If `prefixes` is given, only the changes under any of them are returned. Child storage
+    /// changes are filtered by their child storage key.
+    /// A block failing to execute fails the whole call, unless `per_block_results` is set, in which
+    /// case it is reported as an error in its place.
+    /// Child tries touched without any change left are omitted if `omit_empty_child_tries` is set.
+    /// Keys changed more than once in a block are rejected or compacted according to `dedup`.
+    /// If `verify` is set, the changes of each block are applied on its parent state and the block
+    /// is reported as an error if that doesn't lead to its state root.
+    /// The response is a JSON encoded `GetStorageChangesResponse`, or
+    /// `GetStorageChangesPerBlockResponse` if `per_block_results` is set. It is encoded block by
+    /// block, but still fully buffered in the node before being sent.
     #[method(name = "pha_getStorageChanges")]
-    fn get_storage_changes(
+    async fn get_storage_changes(
         &self,
         from: BlockHash,
         to: BlockHash,
-    ) -> RpcResult<GetStorageChangesResponse>;
+        prefixes: Option<Vec<StorageKey>>,
+        per_block_results: Option<bool>,
+        omit_empty_child_tries: Option<bool>,
+        dedup: Option<DedupMode>,
+        verify: Option<bool>,
+    ) -> RpcResult<Box<RawValue>>;
+
+    /// Return the storage changes made by each of the given blocks against its own parent, in the
+    /// requested order. The blocks don't need to be contiguous and a block failing to execute is
+    /// reported as an error in its place. The same block limit as `pha_getStorageChanges` applies.
+    /// The response is a JSON encoded `GetStorageChangesPerBlockResponse`.
+    #[method(name = "pha_getStorageChangesForBlocks")]
+    async fn get_storage_changes_for_blocks(
+        &self,
+        blocks: Vec<BlockHash>,
+    ) -> RpcResult<Box<RawValue>>;
+
+    /// Return the blake2-256 hash of the SCALE-encoded `StorageChanges` made by each block from
+    /// `from` to `to`(both inclusive), which is cheaper to compare with a previous response than
+    /// the full changes. The same block limit as `pha_getStorageChanges` applies.
+    #[method(name = "pha_getStorageChangesHash")]
+    async fn get_storage_changes_hash(
+        &self,
+        from: BlockHash,
+        to: BlockHash,
+    ) -> RpcResult<Vec<H256>>;
 
     /// Get storage changes made by given block.
     /// Returns `hex_encode(scale_encode(StorageChanges))`
     #[method(name = "pha_getStorageChangesAt")]
-    fn get_storage_changes_at(&self, block: BlockHash) -> RpcResult<String>;
+    async fn get_storage_changes_at(&self, block: BlockHash) -> RpcResult<String>;
+
+    /// Return the storage changes made by the given block alone.
+    #[method(name = "pha_getBlockStorageChanges")]
+    async fn get_block_storage_changes(&self, at: BlockHash) -> RpcResult<StorageChanges>;
+
+    /// Subscribe to the storage changes made by each newly finalized block, keeping only the
+    /// changes under any of the `prefixes` if given. The number of live subscriptions is limited
+    /// separately from the concurrent storage changes requests.
+    #[subscription(
+        name = "pha_subscribeStorageChanges" => "pha_storageChanges",
+        unsubscribe = "pha_unsubscribeStorageChanges",
+        item = BlockStorageChanges
+    )]
+    fn subscribe_storage_changes(&self, prefixes: Option<Vec<StorageKey>>);
 
     /// Return the next mq sequence number for given sender which take the ready transactions in count.
     #[method(name = "pha_getMqNextSequence")]
@@ -54,21 +118,116 @@ struct NodeRpcExt<BE, Block: BlockT, Client, P> {
     backend: Arc<BE>,
     is_archive_mode: bool,
     pool: Arc<P>,
+    spawner: Box<dyn SpawnNamed>,
+    storage_changes_requests: Arc<AtomicUsize>,
+    storage_changes_subscriptions: Arc<AtomicUsize>,
+    /// Max number of blocks allowed in one `pha_getStorageChanges` call.
+    max_blocks: u64,
     _phantom: PhantomData<Block>,
 }
 
 impl<BE, Block: BlockT, Client, P> NodeRpcExt<BE, Block, Client, P> {
-    fn new(client: Arc<Client>, backend: Arc<BE>, is_archive_mode: bool, pool: Arc<P>) -> Self {
+    fn new(
+        client: Arc<Client>,
+        backend: Arc<BE>,
+        is_archive_mode: bool,
+        pool: Arc<P>,
+        spawner: Box<dyn SpawnNamed>,
+        max_blocks: u64,
+    ) -> Self {
         Self {
             client,
             backend,
             is_archive_mode,
             pool,
+            spawner,
+            storage_changes_requests: Default::default(),
+            storage_changes_subscriptions: Default::default(),
+            max_blocks,
             _phantom: Default::default(),
         }
     }
 }
 
+impl<BE, Block: BlockT, Client, P> NodeRpcExt<BE, Block, Client, P>
+where
+    BE: Send + Sync + 'static,
+    Client: Send + Sync + 'static,
+{
+    /// Run `replay` on a blocking thread, as replaying blocks is heavy and would otherwise stall
+    /// the async executor serving other RPCs.
+    async fn replay_blocks<R: Send + 'static>(
+        &self,
+        replay: impl FnOnce(&Client, &BE) -> Result<R, StorageChangesError> + Send + 'static,
+    ) -> RpcResult<R> {
+        if !self.is_archive_mode {
+            return Err(JsonRpseeError::from(archive_mode_required()));
+        }
+        let slot = RequestSlot::acquire(
+            &self.storage_changes_requests,
+            MAX_CONCURRENT_STORAGE_CHANGES_REQUESTS,
+        )
+        .ok_or_else(|| {
+            StorageChangesError::ResourceLimited("Too many concurrent requests".into())
+        })?;
+
+        let client = self.client.clone();
+        let backend = self.backend.clone();
+        let result = run_blocking(&*self.spawner, "pha-replay-blocks", move || {
+            let _slot = slot;
+            replay(client.as_ref(), backend.as_ref())
+        })
+        .await;
+
+        Ok(result?)
+    }
+}
+
+fn archive_mode_required() -> StorageChangesError {
+    StorageChangesError::Unavailable(
+        r#"Add "--pruning=archive" to the command line to enable this RPC"#.into(),
+    )
+}
+
+/// Run `f` on a blocking thread and wait for its result.
+async fn run_blocking<R: Send + 'static>(
+    spawner: &dyn SpawnNamed,
+    name: &'static str,
+    f: impl FnOnce() -> Result<R, StorageChangesError> + Send + 'static,
+) -> Result<R, StorageChangesError> {
+    let (tx, rx) = oneshot::channel();
+    spawner.spawn_blocking(
+        name,
+        Some("rpc"),
+        Box::pin(async move {
+            let _ = tx.send(f());
+        }),
+    );
+    rx.await.map_err(|_| {
+        StorageChangesError::Unavailable("The request was dropped by the node".into())
+    })?
+}
+
+/// Occupies one of the limited slots of concurrent requests until dropped.
+struct RequestSlot(Arc<AtomicUsize>);
+
+impl RequestSlot {
+    fn acquire(in_flight: &Arc<AtomicUsize>, limit: usize) -> Option<Self> {
+        in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < limit).then(|| n + 1)
+            })
+            .ok()?;
+        Some(Self(in_flight.clone()))
+    }
+}
+
+impl Drop for RequestSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 #[async_trait]
 impl<BE: 'static, Block: BlockT, Client: 'static, P> NodeRpcExtApiServer<Block::Hash>
     for NodeRpcExt<BE, Block, Client, P>
@@ -78,7 +237,8 @@ where
         + HeaderBackend<Block>
         + BlockBackend<Block>
         + HeaderMetadata<Block, Error = sp_blockchain::Error>
-        + ProvideRuntimeApi<Block>,
+        + ProvideRuntimeApi<Block>
+        + BlockchainEvents<Block>,
     Client::Api:
         sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
     Client::Api: MqApi<Block>,
@@ -86,34 +246,165 @@ where
     <<Block as BlockT>::Header as Header>::Number: Into<u64>,
     P: TransactionPool + 'static,
 {
-    fn get_storage_changes(
+    async fn get_storage_changes(
         &self,
         from: Block::Hash,
         to: Block::Hash,
-    ) -> RpcResult<GetStorageChangesResponse> {
-        if !self.is_archive_mode {
-            Err(JsonRpseeError::from(StorageChangesError::Unavailable(
-                r#"Add "--pruning=archive" to the command line to enable this RPC"#.into(),
-            )))
-        } else {
-            let result = storage_changes::get_storage_changes(
-                self.client.as_ref(),
-                self.backend.as_ref(),
+        prefixes: Option<Vec<StorageKey>>,
+        per_block_results: Option<bool>,
+        omit_empty_child_tries: Option<bool>,
+        dedup: Option<DedupMode>,
+        verify: Option<bool>,
+    ) -> RpcResult<Box<RawValue>> {
+        let max_blocks = self.max_blocks;
+        self.replay_blocks(move |client, backend| {
+            let filter = ChangesFilter {
+                prefixes: prefixes.as_deref(),
+                omit_empty_child_tries: omit_empty_child_tries.unwrap_or(false),
+                dedup,
+                verify: verify.unwrap_or(false),
+            };
+            storage_changes::get_storage_changes_json(
+                client,
+                backend,
                 from,
                 to,
-            );
+                filter,
+                per_block_results.unwrap_or(false),
+                max_blocks,
+            )
+        })
+        .await
+    }
 
-            Ok(result?)
-        }
+    async fn get_storage_changes_for_blocks(
+        &self,
+        blocks: Vec<Block::Hash>,
+    ) -> RpcResult<Box<RawValue>> {
+        let max_blocks = self.max_blocks;
+        self.replay_blocks(move |client, backend| {
+            storage_changes::get_storage_changes_for_blocks_json(
+                client, backend, blocks, max_blocks,
+            )
+        })
+        .await
     }
 
-    fn get_storage_changes_at(&self, block: Block::Hash) -> RpcResult<String> {
-        let changes = self.get_storage_changes(block, block)?;
-        // get_storage_changes never returns empty vec without error.
-        let encoded = changes[0].encode();
+    async fn get_storage_changes_hash(
+        &self,
+        from: Block::Hash,
+        to: Block::Hash,
+    ) -> RpcResult<Vec<H256>> {
+        let max_blocks = self.max_blocks;
+        self.replay_blocks(move |client, backend| {
+            storage_changes::get_storage_changes_hash(client, backend, from, to, max_blocks)
+        })
+        .await
+    }
+
+    async fn get_storage_changes_at(&self, block: Block::Hash) -> RpcResult<String> {
+        let changes = self
+            .replay_blocks(move |client, backend| {
+                storage_changes::get_storage_changes(
+                    client,
+                    backend,
+                    block,
+                    block,
+                    ChangesFilter::default(),
+                    true,
+                    // A single block is always within the limit
+                    0,
+                )
+            })
+            .await?;
+        // get_storage_changes never returns empty vec or failed blocks without error in fail-fast
+        // mode.
+        let encoded = match &changes[0] {
+            Ok(changes) => changes.encode(),
+            Err(err) => return Err(StorageChangesError::InvalidBlock(err.clone()).into()),
+        };
         Ok(impl_serde::serialize::to_hex(&encoded, false))
     }
 
+    async fn get_block_storage_changes(&self, at: Block::Hash) -> RpcResult<StorageChanges> {
+        self.replay_blocks(move |client, backend| {
+            storage_changes::changes_for_block(client, backend, at, ChangesFilter::default())
+        })
+        .await
+    }
+
+    fn subscribe_storage_changes(
+        &self,
+        pending: PendingSubscription,
+        prefixes: Option<Vec<StorageKey>>,
+    ) {
+        let reject = |pending: PendingSubscription, err: StorageChangesError| {
+            let _ = pending.reject(ErrorObject::owned(
+                CUSTOM_RPC_ERROR,
+                err.to_string(),
+                Option::<()>::None,
+            ));
+        };
+        if !self.is_archive_mode {
+            return reject(pending, archive_mode_required());
+        }
+        // Each subscription keeps replaying blocks, so it holds a subscription slot while alive.
+        // They have their own slots to not starve the one-shot requests.
+        let slot = match RequestSlot::acquire(
+            &self.storage_changes_subscriptions,
+            MAX_STORAGE_CHANGES_SUBSCRIPTIONS,
+        ) {
+            Some(slot) => slot,
+            None => {
+                return reject(
+                    pending,
+                    StorageChangesError::ResourceLimited("Too many subscriptions".into()),
+                )
+            }
+        };
+        let mut sink = match pending.accept() {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        let client = self.client.clone();
+        let backend = self.backend.clone();
+        let spawner = self.spawner.clone();
+        let changes = self
+            .client
+            .finality_notification_stream()
+            .flat_map(|notification| {
+                // Blocks finalized along with the notified one are listed in the tree route.
+                let mut hashes = notification.tree_route.to_vec();
+                hashes.push(notification.hash);
+                futures::stream::iter(hashes)
+            })
+            .then(move |hash| {
+                let client = client.clone();
+                let backend = backend.clone();
+                let prefixes = prefixes.clone();
+                let spawner = spawner.clone();
+                async move {
+                    run_blocking(&*spawner, "pha-storage-changes-sub", move || {
+                        let filter = ChangesFilter {
+                            prefixes: prefixes.as_deref(),
+                            ..Default::default()
+                        };
+                        storage_changes::changes_for_block(&*client, &*backend, hash, filter)
+                    })
+                    .await
+                    .map_err(|err| err.to_string())
+                }
+            });
+        // Ends when the subscriber unsubscribes or disconnects.
+        let fut = async move {
+            let _slot = slot;
+            let _ = sink.pipe_from_stream(changes.boxed()).await;
+        };
+        self.spawner
+            .spawn("pha-storage-changes-sub", Some("rpc"), fut.boxed());
+    }
+
     fn get_mq_seq(&self, sender_hex: String) -> RpcResult<u64> {
         let result = mq_seq::get_mq_seq(&*self.client, &self.pool, sender_hex);
 
@@ -127,6 +418,8 @@ pub fn extend_rpc<Client, BE, Block, P>(
     backend: Arc<BE>,
     is_archive_mode: bool,
     pool: Arc<P>,
+    spawner: impl SpawnNamed + 'static,
+    max_blocks: u64,
 ) where
     BE: Backend<Block> + 'static,
     Client: StorageProvider<Block, BE>
@@ -134,6 +427,7 @@ pub fn extend_rpc<Client, BE, Block, P>(
         + BlockBackend<Block>
         + HeaderMetadata<Block, Error = sp_blockchain::Error>
         + ProvideRuntimeApi<Block>
+        + BlockchainEvents<Block>
         + 'static,
     Block: BlockT + 'static,
     Client::Api:
@@ -148,6 +442,8 @@ pub fn extend_rpc<Client, BE, Block, P>(
             backend,
             is_archive_mode,
             pool,
+            Box::new(spawner),
+            max_blocks,
         ).into_rpc(),
     ).expect("Initialize Phala node RPC ext failed.");
 }
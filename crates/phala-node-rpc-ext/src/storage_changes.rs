@@ -1,6 +1,14 @@
 use super::*;
+use crate::json_stream::JsonArrayWriter;
 pub use ext_types::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sp_core::{
+    storage::{ChildInfo, StateVersion},
+    Hasher, H256,
+};
+use std::collections::HashSet;
 
 /// State RPC errors.
 #[derive(Debug, thiserror::Error)]
@@ -25,6 +33,10 @@ pub enum Error {
     /// The RPC is unavailable.
     #[error("This RPC is unavailable. {0}")]
     Unavailable(String),
+
+    /// Failed to encode the response.
+    #[error("Failed to encode the response: {0}")]
+    Encode(String),
 }
 
 impl Error {
@@ -47,12 +59,159 @@ impl From<Error> for JsonRpseeError {
     }
 }
 
+fn header<Client: HeaderBackend<Block>, Block: BlockT>(
+    client: &Client,
+    id: BlockId<Block>,
+) -> Result<Block::Header, Error> {
+    client
+        .header(id)
+        .map_err(|e| Error::invalid_block(id, e))?
+        .ok_or_else(|| Error::invalid_block(id, "header not found"))
+}
+
 pub(super) fn get_storage_changes<Client, BE, Block>(
     client: &Client,
     backend: &BE,
     from: Block::Hash,
     to: Block::Hash,
-) -> Result<GetStorageChangesResponse, Error>
+    filter: ChangesFilter<'_>,
+    fail_fast: bool,
+    max_blocks: u64,
+) -> Result<GetStorageChangesPerBlockResponse, Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let mut response = vec![];
+    let blocks = resolve_range(client, from, to, max_blocks)?;
+    for_each_storage_changes(
+        client,
+        backend,
+        blocks,
+        filter,
+        fail_fast,
+        into_storage_changes,
+        |changes| {
+            response.push(changes);
+            Ok(())
+        },
+    )?;
+    Ok(response)
+}
+
+/// Return the hash of the storage changes made by each block from `from` to `to`.
+///
+/// Any block failing to execute fails the whole range, as there is no room to report it in place.
+pub(super) fn get_storage_changes_hash<Client, BE, Block>(
+    client: &Client,
+    backend: &BE,
+    from: Block::Hash,
+    to: Block::Hash,
+    max_blocks: u64,
+) -> Result<Vec<H256>, Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let mut hashes = vec![];
+    let blocks = resolve_range(client, from, to, max_blocks)?;
+    for_each_storage_changes(
+        client,
+        backend,
+        blocks,
+        ChangesFilter::default(),
+        true,
+        hash_changes,
+        |hash| {
+            // Failures never reach here in fail-fast mode.
+            hashes.extend(hash.ok());
+            Ok(())
+        },
+    )?;
+    Ok(hashes)
+}
+
+/// Same as `get_storage_changes` but encode the response as JSON while the blocks are processed,
+/// so that the decoded changes of the whole range are never held in memory at once.
+///
+/// This only re-encodes the response block by block, it doesn't stream it to the client: the
+/// whole JSON is still buffered here, and copied once more by jsonrpsee when sending it.
+pub(super) fn get_storage_changes_json<Client, BE, Block>(
+    client: &Client,
+    backend: &BE,
+    from: Block::Hash,
+    to: Block::Hash,
+    filter: ChangesFilter<'_>,
+    per_block_results: bool,
+    max_blocks: u64,
+) -> Result<Box<RawValue>, Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let blocks = resolve_range(client, from, to, max_blocks)?;
+    storage_changes_json(client, backend, blocks, filter, per_block_results)
+}
+
+/// Same as `get_storage_changes_json` but for the given `blocks` in the given order, each against
+/// its own parent. The results are always reported per block.
+pub(super) fn get_storage_changes_for_blocks_json<Client, BE, Block>(
+    client: &Client,
+    backend: &BE,
+    blocks: Vec<Block::Hash>,
+    max_blocks: u64,
+) -> Result<Box<RawValue>, Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let blocks = resolve_blocks(client, blocks, max_blocks)?;
+    storage_changes_json(client, backend, blocks, ChangesFilter::default(), true)
+}
+
+/// Encode the storage changes made by each of the `blocks` as a JSON array.
+///
+/// The array holds a `BlockStorageChanges` per block if `per_block_results` is set. Otherwise it
+/// holds the plain `StorageChanges`, and a block failing to execute aborts the whole range.
+fn storage_changes_json<Client, BE, Block>(
+    client: &Client,
+    backend: &BE,
+    blocks: Vec<(Block::Hash, bool)>,
+    filter: ChangesFilter<'_>,
+    per_block_results: bool,
+) -> Result<Box<RawValue>, Error>
 where
     BE: Backend<Block>,
     Client: StorageProvider<Block, BE>
@@ -65,16 +224,49 @@ where
         sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
     <<Block as BlockT>::Header as Header>::Number: Into<u64>,
 {
-    fn header<Client: HeaderBackend<Block>, Block: BlockT>(
-        client: &Client,
-        id: BlockId<Block>,
-    ) -> Result<Block::Header, Error> {
-        client
-            .header(id)
-            .map_err(|e| Error::invalid_block(id, e))?
-            .ok_or_else(|| Error::invalid_block(id, "header not found"))
+    fn encode_error(err: impl Display) -> Error {
+        Error::Encode(err.to_string())
     }
 
+    let mut writer = JsonArrayWriter::new(vec![]).map_err(encode_error)?;
+    for_each_storage_changes(
+        client,
+        backend,
+        blocks,
+        filter,
+        !per_block_results,
+        into_storage_changes,
+        |changes| push_json(&mut writer, changes, per_block_results).map_err(encode_error),
+    )?;
+    let json = writer.finish().map_err(encode_error)?;
+    let json = String::from_utf8(json).map_err(encode_error)?;
+    RawValue::from_string(json).map_err(encode_error)
+}
+
+fn push_json(
+    writer: &mut JsonArrayWriter<Vec<u8>>,
+    changes: BlockStorageChanges,
+    per_block_results: bool,
+) -> serde_json::Result<()> {
+    match changes {
+        // Failures never reach here unless reported per block.
+        Ok(changes) if !per_block_results => writer.push(&changes),
+        changes => writer.push(&changes),
+    }
+}
+
+/// List the blocks from `from` to `to` in order, along with whether each is the genesis block.
+fn resolve_range<Client, Block>(
+    client: &Client,
+    from: Block::Hash,
+    to: Block::Hash,
+    max_blocks: u64,
+) -> Result<Vec<(Block::Hash, bool)>, Error>
+where
+    Client: HeaderBackend<Block>,
+    Block: BlockT + 'static,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
     let n_from: u64 = (*header(client, BlockId::Hash(from))?.number()).into();
     let n_to: u64 = (*header(client, BlockId::Hash(to))?.number()).into();
 
@@ -85,73 +277,594 @@ where
         });
     }
 
-    // TODO: Set max_number_of_blocks properly.
-    let max_number_of_blocks = 10000u64;
-    if n_to - n_from > max_number_of_blocks {
-        return Err(Error::ResourceLimited("Too large number of blocks".into()));
+    if n_to - n_from > max_blocks {
+        return Err(Error::ResourceLimited(format!(
+            "Too large number of blocks, the limit is {max_blocks}"
+        )));
     }
 
-    let mut headers = std::collections::VecDeque::new();
+    let mut blocks = std::collections::VecDeque::new();
 
     let mut this_block = to;
 
     loop {
-        let id = BlockId::Hash(this_block);
-        let header = header(client, id)?;
-        let parent = *header.parent_hash();
-        headers.push_front((id, header));
+        let header = header(client, BlockId::Hash(this_block))?;
+        let is_genesis = (*header.number()).into() == 0u64;
+        blocks.push_front((this_block, is_genesis));
         if this_block == from {
             break;
         }
-        this_block = parent;
-    }
-
-    headers
-        .into_par_iter()
-        .map(|(id, mut header)| -> Result<_, Error> {
-            let api = client.runtime_api();
-            let hash = client.expect_block_hash_from_id(&id).expect("Should get the block hash");
-            if (*header.number()).into() == 0u64 {
-                let state = backend
-                    .state_at(hash)
-                    .map_err(|e| Error::invalid_block(id, e))?;
-                return Ok(StorageChanges {
-                    main_storage_changes: state
-                        .pairs()
-                        .into_iter()
-                        .map(|(k, v)| (StorageKey(k), Some(StorageKey(v))))
-                        .collect(),
-                    child_storage_changes: vec![],
-                });
-            }
+        this_block = *header.parent_hash();
+    }
 
-            let extrinsics = client
-                .block_body(hash)
-                .map_err(|e| Error::invalid_block(id, e))?
-                .ok_or_else(|| Error::invalid_block(id, "block body not found"))?;
-            let parent_hash = *header.parent_hash();
-            let parent_id = BlockId::Hash(parent_hash);
+    Ok(Vec::from(blocks))
+}
 
-            // Remove all `Seal`s as they are added by the consensus engines after building the block.
-            // On import they are normally removed by the consensus engine.
-            header.digest_mut().logs.retain(|d| d.as_seal().is_none());
+/// Look up each of the `blocks`, keeping their order, along with whether it is the genesis block.
+fn resolve_blocks<Client, Block>(
+    client: &Client,
+    blocks: Vec<Block::Hash>,
+    max_blocks: u64,
+) -> Result<Vec<(Block::Hash, bool)>, Error>
+where
+    Client: HeaderBackend<Block>,
+    Block: BlockT + 'static,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    if blocks.len() as u64 > max_blocks {
+        return Err(Error::ResourceLimited(format!(
+            "Too large number of blocks, the limit is {max_blocks}"
+        )));
+    }
+    blocks
+        .into_iter()
+        .map(|hash| {
+            let header = header(client, BlockId::Hash(hash))?;
+            Ok((hash, (*header.number()).into() == 0u64))
+        })
+        .collect()
+}
 
-            let block = Block::new(header, extrinsics);
-            api.execute_block(&parent_id, block)
-                .map_err(|e| Error::invalid_block(id, e))?;
+/// Feed the storage changes made by each of the `blocks`, processed by `convert`, to `sink` in
+/// block order.
+///
+/// The blocks are executed in parallel chunk by chunk, so only the changes of one chunk are held
+/// in memory at a time. A block failing to execute is reported in place, unless `fail_fast` is
+/// set, in which case it aborts the whole range.
+fn for_each_storage_changes<Client, BE, Block, T: Send>(
+    client: &Client,
+    backend: &BE,
+    blocks: Vec<(Block::Hash, bool)>,
+    filter: ChangesFilter<'_>,
+    fail_fast: bool,
+    convert: impl Fn(RawStorageChanges) -> T + Sync,
+    mut sink: impl FnMut(Result<T, String>) -> Result<(), Error>,
+) -> Result<(), Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    for chunk in blocks.chunks(rayon::current_num_threads().max(1)) {
+        let changes: Vec<_> = chunk
+            .par_iter()
+            .map(|&(hash, is_genesis)| -> Result<_, Error> {
+                if is_genesis {
+                    let state = backend
+                        .state_at(hash)
+                        .map_err(|e| Error::invalid_block(BlockId::<Block>::Hash(hash), e))?;
+                    let mut pairs = state.pairs();
+                    filter.apply_main(&mut pairs);
+                    let main = pairs.into_iter().map(|(k, v)| (k, Some(v))).collect();
+                    return Ok(convert((main, vec![])));
+                }
+                raw_changes_for_block(client, backend, hash, filter).map(&convert)
+            })
+            .collect();
+        feed_results(changes, fail_fast, &mut sink)?;
+    }
+    Ok(())
+}
 
-            let state = backend
-                .state_at(hash)
-                .map_err(|e| Error::invalid_block(parent_id, e))?;
+fn feed_results<T>(
+    results: Vec<Result<T, Error>>,
+    fail_fast: bool,
+    sink: &mut impl FnMut(Result<T, String>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    for result in results {
+        let changes = match result {
+            Ok(changes) => Ok(changes),
+            Err(err) if fail_fast => return Err(err),
+            Err(err) => Err(err.to_string()),
+        };
+        sink(changes)?;
+    }
+    Ok(())
+}
 
-            let storage_changes = api
-                .into_storage_changes(&state, parent_hash)
-                .map_err(|e| Error::invalid_block(parent_id, e))?;
+/// The main and child storage changes made by a block, as returned by the runtime.
+type RawStorageChanges = (
+    StorageCollection<Vec<u8>, Vec<u8>>,
+    ChildStorageCollection<Vec<u8>, Vec<u8>>,
+);
 
-            Ok(StorageChanges {
-                main_storage_changes: storage_changes.main_storage_changes.into_(),
-                child_storage_changes: storage_changes.child_storage_changes.into_(),
+fn into_storage_changes((main, child): RawStorageChanges) -> StorageChanges {
+    StorageChanges {
+        main_storage_changes: main.into_(),
+        child_storage_changes: child.into_(),
+    }
+}
+
+/// Hash the changes without converting them to `StorageChanges` first.
+///
+/// `StorageKey` encodes the same as the bytes it wraps, so this equals the blake2-256 hash of the
+/// SCALE-encoded `StorageChanges`.
+fn hash_changes(changes: RawStorageChanges) -> H256 {
+    H256(sp_core::blake2_256(&changes.encode()))
+}
+
+/// Execute the block `hash` on top of its parent state and return the storage changes it made.
+///
+/// Only the changes passing the `filter` are returned. The genesis block has no parent to execute
+/// on, so it is rejected as an invalid block.
+pub(super) fn changes_for_block<Client, BE, Block>(
+    client: &Client,
+    backend: &BE,
+    hash: Block::Hash,
+    filter: ChangesFilter<'_>,
+) -> Result<StorageChanges, Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    raw_changes_for_block(client, backend, hash, filter).map(into_storage_changes)
+}
+
+fn raw_changes_for_block<Client, BE, Block>(
+    client: &Client,
+    backend: &BE,
+    hash: Block::Hash,
+    filter: ChangesFilter<'_>,
+) -> Result<RawStorageChanges, Error>
+where
+    BE: Backend<Block>,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProvideRuntimeApi<Block>,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let id = BlockId::Hash(hash);
+    let mut header = header(client, id)?;
+    if (*header.number()).into() == 0u64 {
+        return Err(Error::invalid_block(id, "the genesis block has no parent"));
+    }
+
+    let api = client.runtime_api();
+    let extrinsics = client
+        .block_body(hash)
+        .map_err(|e| Error::invalid_block(id, e))?
+        .ok_or_else(|| Error::invalid_block(id, "block body not found"))?;
+    let parent_hash = *header.parent_hash();
+    let parent_id = BlockId::Hash(parent_hash);
+    let state_root = *header.state_root();
+
+    // Remove all `Seal`s as they are added by the consensus engines after building the block.
+    // On import they are normally removed by the consensus engine.
+    header.digest_mut().logs.retain(|d| d.as_seal().is_none());
+
+    let block = Block::new(header, extrinsics);
+    api.execute_block(&parent_id, block)
+        .map_err(|e| Error::invalid_block(id, e))?;
+
+    let state = backend
+        .state_at(hash)
+        .map_err(|e| Error::invalid_block(parent_id, e))?;
+
+    let mut storage_changes = api
+        .into_storage_changes(&state, parent_hash)
+        .map_err(|e| Error::invalid_block(parent_id, e))?;
+    if filter.verify {
+        let parent_state = backend
+            .state_at(parent_hash)
+            .map_err(|e| Error::invalid_block(parent_id, e))?;
+        let state_version = client
+            .runtime_api()
+            .version(&parent_id)
+            .map_err(|e| Error::invalid_block(parent_id, e))?
+            .state_version();
+        let root = storage_root_after(
+            &parent_state,
+            &storage_changes.main_storage_changes,
+            &storage_changes.child_storage_changes,
+            state_version,
+        );
+        if root != state_root {
+            return Err(Error::invalid_block(
+                id,
+                format!("the changes lead to state root {root}, expected {state_root}"),
+            ));
+        }
+    }
+    // Filter before the conversion so that the dropped entries are never serialized.
+    if let Some(mode) = filter.dedup {
+        dedup(&mut storage_changes.main_storage_changes, mode)
+            .and_then(|()| {
+                storage_changes
+                    .child_storage_changes
+                    .iter_mut()
+                    .try_for_each(|(_, changes)| dedup(changes, mode))
             })
-        })
-        .collect()
+            .map_err(|key| {
+                let key = impl_serde::serialize::to_hex(&key, false);
+                Error::invalid_block(id, format!("duplicate key {key}"))
+            })?;
+    }
+    filter.apply_main(&mut storage_changes.main_storage_changes);
+    filter.apply_child(&mut storage_changes.child_storage_changes);
+
+    Ok((
+        storage_changes.main_storage_changes,
+        storage_changes.child_storage_changes,
+    ))
+}
+
+/// Compute the state root after applying the changes on top of `state`.
+#[allow(clippy::ptr_arg)]
+fn storage_root_after<H, S>(
+    state: &S,
+    main: &StorageCollection<Vec<u8>, Vec<u8>>,
+    child: &ChildStorageCollection<Vec<u8>, Vec<u8>>,
+    state_version: StateVersion,
+) -> H::Out
+where
+    H: Hasher,
+    H::Out: Ord + codec::Codec,
+    S: StateBackend<H>,
+{
+    let child: Vec<_> = child
+        .iter()
+        .map(|(key, changes)| (ChildInfo::new_default(key), changes))
+        .collect();
+    let (root, _) = state.full_storage_root(
+        main.iter().map(|(k, v)| (&k[..], v.as_deref())),
+        child
+            .iter()
+            .map(|(info, changes)| (info, changes.iter().map(|(k, v)| (&k[..], v.as_deref())))),
+        state_version,
+    );
+    root
+}
+
+/// Selects, checks and post-processes the storage changes to return.
+#[derive(Default, Clone, Copy)]
+pub(super) struct ChangesFilter<'a> {
+    /// Only keep the changes whose key starts with any of the prefixes if given. Child storage
+    /// changes are selected by their child storage key.
+    pub prefixes: Option<&'a [StorageKey]>,
+    /// Omit the child tries which are touched but left without any change.
+    pub omit_empty_child_tries: bool,
+    /// How to handle the keys changed more than once in a block, left as is if not given.
+    pub dedup: Option<DedupMode>,
+    /// Check that applying the changes on the parent state leads to the state root of the block,
+    /// rejecting the block as invalid otherwise. The genesis block has nothing to check against.
+    pub verify: bool,
+}
+
+/// How to handle the keys changed more than once in a block.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DedupMode {
+    /// Reject the block as invalid.
+    Strict,
+    /// Keep the last change of each key only.
+    Compact,
+}
+
+/// Handle the keys changed more than once according to `mode`, returning the first duplicate key
+/// found in strict mode.
+fn dedup<V>(changes: &mut Vec<(Vec<u8>, V)>, mode: DedupMode) -> Result<(), Vec<u8>> {
+    match mode {
+        DedupMode::Strict => {
+            let mut seen = HashSet::new();
+            match changes.iter().find(|(key, _)| !seen.insert(key)) {
+                Some((key, _)) => Err(key.clone()),
+                None => Ok(()),
+            }
+        }
+        DedupMode::Compact => {
+            let keep: Vec<bool> = {
+                let mut seen = HashSet::new();
+                changes
+                    .iter()
+                    .rev()
+                    .map(|(key, _)| seen.insert(key))
+                    .collect()
+            };
+            let mut keep = keep.into_iter().rev();
+            changes.retain(|_| keep.next().unwrap_or(true));
+            Ok(())
+        }
+    }
+}
+
+impl ChangesFilter<'_> {
+    fn apply_main<V>(&self, changes: &mut Vec<(Vec<u8>, V)>) {
+        self.retain_prefixed(changes);
+    }
+
+    fn apply_child<K, V>(&self, changes: &mut Vec<(Vec<u8>, Vec<(K, V)>)>) {
+        self.retain_prefixed(changes);
+        if self.omit_empty_child_tries {
+            changes.retain(|(_, child_changes)| !child_changes.is_empty());
+        }
+    }
+
+    fn retain_prefixed<V>(&self, collection: &mut Vec<(Vec<u8>, V)>) {
+        if let Some(prefixes) = self.prefixes {
+            collection.retain(|(key, _)| prefixes.iter().any(|prefix| key.starts_with(&prefix.0)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(key: &[u8]) -> StorageChanges {
+        StorageChanges {
+            main_storage_changes: vec![(StorageKey(key.to_vec()), None)],
+            child_storage_changes: vec![],
+        }
+    }
+
+    fn results() -> Vec<Result<StorageChanges, Error>> {
+        vec![
+            Ok(changes(b"a")),
+            Err(Error::InvalidBlock("0x02: block body not found".into())),
+            Ok(changes(b"c")),
+        ]
+    }
+
+    fn feed(fail_fast: bool) -> Result<GetStorageChangesPerBlockResponse, Error> {
+        let mut response = vec![];
+        feed_results(results(), fail_fast, &mut |changes| {
+            response.push(changes);
+            Ok(())
+        })?;
+        Ok(response)
+    }
+
+    #[test]
+    fn unchanged_child_tries_are_omitted_on_demand() {
+        let child_changes = || -> Vec<(Vec<u8>, Vec<(Vec<u8>, Option<Vec<u8>>)>)> {
+            vec![
+                (b"touched".to_vec(), vec![]),
+                (
+                    b"changed".to_vec(),
+                    vec![(b"k".to_vec(), Some(b"v".to_vec()))],
+                ),
+            ]
+        };
+
+        let mut changes = child_changes();
+        ChangesFilter::default().apply_child(&mut changes);
+        assert_eq!(changes, child_changes());
+
+        let mut changes = child_changes();
+        let filter = ChangesFilter {
+            omit_empty_child_tries: true,
+            ..Default::default()
+        };
+        filter.apply_child(&mut changes);
+        assert_eq!(changes, child_changes()[1..]);
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected_or_compacted() {
+        let changes = || -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+            vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"b".to_vec(), Some(b"2".to_vec())),
+                (b"a".to_vec(), None),
+                (b"c".to_vec(), Some(b"3".to_vec())),
+                (b"b".to_vec(), Some(b"4".to_vec())),
+            ]
+        };
+
+        let mut strict = changes();
+        assert_eq!(dedup(&mut strict, DedupMode::Strict), Err(b"a".to_vec()));
+        assert_eq!(strict, changes());
+
+        let mut compact = changes();
+        assert_eq!(dedup(&mut compact, DedupMode::Compact), Ok(()));
+        assert_eq!(
+            compact,
+            vec![
+                (b"a".to_vec(), None),
+                (b"c".to_vec(), Some(b"3".to_vec())),
+                (b"b".to_vec(), Some(b"4".to_vec())),
+            ]
+        );
+
+        let mut unique = changes()[..2].to_vec();
+        assert_eq!(dedup(&mut unique, DedupMode::Strict), Ok(()));
+        assert_eq!(dedup(&mut unique, DedupMode::Compact), Ok(()));
+        assert_eq!(unique, changes()[..2]);
+    }
+
+    #[test]
+    fn applied_changes_lead_to_the_block_state_root() {
+        type InMemoryBackend = sp_state_machine::InMemoryBackend<sp_runtime::traits::BlakeTwo256>;
+
+        let trie = |main: &[(&str, &str)], child: &[(&str, &str)]| -> InMemoryBackend {
+            let collection = |pairs: &[(&str, &str)]| -> Vec<_> {
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.as_bytes().to_vec(), Some(v.as_bytes().to_vec())))
+                    .collect()
+            };
+            InMemoryBackend::from((
+                vec![
+                    (None, collection(main)),
+                    (Some(ChildInfo::new_default(b"child")), collection(child)),
+                ],
+                StateVersion::V1,
+            ))
+        };
+        let parent = trie(&[("a", "1"), ("b", "2")], &[("c", "3")]);
+        let block = trie(&[("a", "1"), ("d", "4")], &[("c", "5")]);
+
+        let main = vec![(b"b".to_vec(), None), (b"d".to_vec(), Some(b"4".to_vec()))];
+        let child = vec![(
+            b"child".to_vec(),
+            vec![(b"c".to_vec(), Some(b"5".to_vec()))],
+        )];
+        let root = storage_root_after(&parent, &main, &child, StateVersion::V1);
+        assert_eq!(&root, block.root());
+
+        // A change missing from the diff is caught
+        let root = storage_root_after(&parent, &main[..1].to_vec(), &child, StateVersion::V1);
+        assert_ne!(&root, block.root());
+    }
+
+    #[test]
+    fn changes_hash_matches_the_encoded_storage_changes() {
+        let raw: RawStorageChanges = (
+            vec![(b"a".to_vec(), Some(b"1".to_vec())), (b"b".to_vec(), None)],
+            vec![(
+                b"child".to_vec(),
+                vec![(b"c".to_vec(), Some(b"2".to_vec()))],
+            )],
+        );
+        let expected = sp_core::blake2_256(&into_storage_changes(raw.clone()).encode());
+        assert_eq!(hash_changes(raw), H256(expected));
+    }
+
+    #[test]
+    fn scattered_blocks_are_resolved_in_the_requested_order() {
+        use sp_blockchain::{BlockStatus, Info};
+        use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper, Header as TestHeader};
+
+        type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+        struct Chain(Vec<TestHeader>);
+
+        impl HeaderBackend<Block> for Chain {
+            fn header(&self, id: BlockId<Block>) -> sp_blockchain::Result<Option<TestHeader>> {
+                Ok(match id {
+                    BlockId::Hash(hash) => self.0.iter().find(|h| h.hash() == hash).cloned(),
+                    BlockId::Number(number) => self.0.get(number as usize).cloned(),
+                })
+            }
+            fn info(&self) -> Info<Block> {
+                let genesis = &self.0[0];
+                let best = self.0.last().unwrap_or(genesis);
+                Info {
+                    best_hash: best.hash(),
+                    best_number: best.number,
+                    genesis_hash: genesis.hash(),
+                    finalized_hash: best.hash(),
+                    finalized_number: best.number,
+                    finalized_state: None,
+                    number_leaves: 1,
+                    block_gap: None,
+                }
+            }
+            fn status(&self, id: BlockId<Block>) -> sp_blockchain::Result<BlockStatus> {
+                Ok(match self.header(id)? {
+                    Some(_) => BlockStatus::InChain,
+                    None => BlockStatus::Unknown,
+                })
+            }
+            fn number(&self, hash: H256) -> sp_blockchain::Result<Option<u64>> {
+                Ok(self.0.iter().find(|h| h.hash() == hash).map(|h| h.number))
+            }
+            fn hash(&self, number: u64) -> sp_blockchain::Result<Option<H256>> {
+                Ok(self.0.get(number as usize).map(|h| h.hash()))
+            }
+        }
+
+        let mut headers = vec![TestHeader::new_from_number(0)];
+        for number in 1..10 {
+            let mut header = TestHeader::new_from_number(number);
+            header.parent_hash = headers.last().unwrap().hash();
+            headers.push(header);
+        }
+        let hashes: Vec<_> = headers.iter().map(|h| h.hash()).collect();
+        let chain = Chain(headers);
+
+        let requested = vec![hashes[7], hashes[2], hashes[0], hashes[5], hashes[2]];
+        assert_eq!(
+            resolve_blocks(&chain, requested.clone(), 10).unwrap(),
+            vec![
+                (hashes[7], false),
+                (hashes[2], false),
+                (hashes[0], true),
+                (hashes[5], false),
+                (hashes[2], false),
+            ]
+        );
+        assert!(matches!(
+            resolve_blocks(&chain, requested, 4),
+            Err(Error::ResourceLimited(_))
+        ));
+        assert!(matches!(
+            resolve_blocks(&chain, vec![hashes[3], H256::repeat_byte(1)], 10),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn failed_block_is_reported_in_place() {
+        let response = feed(false).unwrap();
+        assert_eq!(
+            response,
+            vec![
+                Ok(changes(b"a")),
+                Err("Error occurred while processing the block 0x02: block body not found.".into()),
+                Ok(changes(b"c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_block_aborts_the_range_in_fail_fast_mode() {
+        assert!(matches!(feed(true), Err(Error::InvalidBlock(_))));
+    }
+
+    #[test]
+    fn json_keeps_the_legacy_shape_unless_per_block_results() {
+        let encode = |per_block_results| {
+            let mut writer = JsonArrayWriter::new(vec![]).unwrap();
+            for changes in [changes(b"a"), changes(b"c")] {
+                push_json(&mut writer, Ok(changes), per_block_results).unwrap();
+            }
+            writer.finish().unwrap()
+        };
+        let legacy: GetStorageChangesResponse = vec![changes(b"a"), changes(b"c")];
+        assert_eq!(encode(false), serde_json::to_vec(&legacy).unwrap());
+        let per_block: GetStorageChangesPerBlockResponse =
+            vec![Ok(changes(b"a")), Ok(changes(b"c"))];
+        assert_eq!(encode(true), serde_json::to_vec(&per_block).unwrap());
+    }
 }
@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use phala_node_rpc_ext_types::{MakeInto, StorageCollection, StorageKey, StorageValue};
+
+fn large_diff(n: u32) -> StorageCollection<Vec<u8>, Vec<u8>> {
+    (0..n)
+        .map(|i| {
+            let key = [&i.to_le_bytes()[..], &[0xaa; 60]].concat();
+            let value = (i % 8 != 0).then(|| vec![i as u8; 128]);
+            (key, value)
+        })
+        .collect()
+}
+
+fn bench_make_into(c: &mut Criterion) {
+    let diff = large_diff(100_000);
+    let mut group = c.benchmark_group("convert 100k changes");
+    group.bench_function("copy", |b| {
+        b.iter_batched(
+            || diff.clone(),
+            |diff| {
+                diff.iter()
+                    .map(|(k, v)| (StorageKey(k.clone()), v.clone().map(StorageKey)))
+                    .collect::<StorageCollection<StorageKey, StorageValue>>()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("make_into", |b| {
+        b.iter_batched(
+            || diff.clone(),
+            MakeInto::<StorageCollection<StorageKey, StorageValue>>::into_,
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_make_into);
+criterion_main!(benches);
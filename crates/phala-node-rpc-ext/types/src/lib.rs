@@ -3,7 +3,11 @@ use scale_info::TypeInfo;
 use serde::{Deserialize, Serialize};
 
 /// Storage key.
-#[derive(Serialize, Deserialize, Clone, Debug, Encode, Decode, TypeInfo)]
+///
+/// Being transparent over `Vec<u8>` lets the conversions below collect into the source buffers
+/// in place instead of allocating new ones.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[repr(transparent)]
 pub struct StorageKey(#[serde(with = "impl_serde::serialize")] pub Vec<u8>);
 
 /// Storage value.
@@ -15,7 +19,7 @@ pub type StorageCollection<K, V> = Vec<(K, Option<V>)>;
 /// In memory arrays of storage values for multiple child tries.
 pub type ChildStorageCollection<K, V> = Vec<(K, StorageCollection<K, V>)>;
 
-#[derive(Serialize, Deserialize, Clone, Debug, Encode, Decode, TypeInfo)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
 #[serde(rename_all = "camelCase")]
 pub struct StorageChanges {
     /// A value of `None` means that it was deleted.
@@ -24,9 +28,16 @@ pub struct StorageChanges {
     pub child_storage_changes: ChildStorageCollection<StorageKey, StorageValue>,
 }
 
+/// The storage changes made by a block, or why the block failed to execute.
+pub type BlockStorageChanges = Result<StorageChanges, String>;
+
 /// Response for the `pha_getStorageChanges` RPC.
 pub type GetStorageChangesResponse = Vec<StorageChanges>;
 
+/// Response for the `pha_getStorageChanges` RPC with `per_block_results` set, and for the
+/// `pha_getStorageChangesForBlocks` RPC.
+pub type GetStorageChangesPerBlockResponse = Vec<BlockStorageChanges>;
+
 // Stuffs to convert ChildStorageCollection and StorageCollection types,
 // in order to dump the keys values into hex strings instead of list of dec numbers.
 pub trait MakeInto<T>: Sized {
@@ -63,6 +74,92 @@ where
 
 impl<F: MakeInto<T>, T> MakeInto<Vec<T>> for Vec<F> {
     fn into_(self) -> Vec<T> {
+        // `Vec<u8>` and `StorageKey` have the same layout, so this collects in place.
         self.into_iter().map(|v| v.into_()).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The addresses of all the key and value buffers in the collections, in order.
+    fn buffers<K: AsRef<[u8]>>(
+        main: &StorageCollection<K, K>,
+        child: &ChildStorageCollection<K, K>,
+    ) -> Vec<*const u8> {
+        fn collection<K: AsRef<[u8]>>(items: &StorageCollection<K, K>, out: &mut Vec<*const u8>) {
+            for (key, value) in items {
+                out.push(key.as_ref().as_ptr());
+                if let Some(value) = value {
+                    out.push(value.as_ref().as_ptr());
+                }
+            }
+        }
+        let mut out = vec![];
+        collection(main, &mut out);
+        for (child_key, items) in child {
+            out.push(child_key.as_ref().as_ptr());
+            collection(items, &mut out);
+        }
+        out
+    }
+
+    impl AsRef<[u8]> for StorageKey {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn make_into_moves_the_buffers() {
+        let main: StorageCollection<Vec<u8>, Vec<u8>> = vec![
+            (b"key1".to_vec(), Some(b"value1".to_vec())),
+            (b"key2".to_vec(), None),
+        ];
+        let child: ChildStorageCollection<Vec<u8>, Vec<u8>> = vec![(
+            b"child".to_vec(),
+            vec![
+                (b"key3".to_vec(), Some(b"value3".to_vec())),
+                (b"key4".to_vec(), None),
+            ],
+        )];
+        let original = buffers(&main, &child);
+        // 2 main keys, 1 main value, 1 child key, 2 child storage keys and 1 child storage value
+        assert_eq!(original.len(), 7);
+
+        let main: StorageCollection<StorageKey, StorageValue> = main.into_();
+        let child: ChildStorageCollection<StorageKey, StorageValue> = child.into_();
+
+        assert_eq!(
+            main,
+            vec![
+                (
+                    StorageKey(b"key1".to_vec()),
+                    Some(StorageKey(b"value1".to_vec()))
+                ),
+                (StorageKey(b"key2".to_vec()), None),
+            ]
+        );
+        assert_eq!(
+            child,
+            vec![(
+                StorageKey(b"child".to_vec()),
+                vec![
+                    (
+                        StorageKey(b"key3".to_vec()),
+                        Some(StorageKey(b"value3".to_vec()))
+                    ),
+                    (StorageKey(b"key4".to_vec()), None),
+                ]
+            )]
+        );
+        // Every key and value is moved into its `StorageKey` rather than copied
+        assert_eq!(buffers(&main, &child), original);
+
+        // And back
+        let main: StorageCollection<Vec<u8>, Vec<u8>> = main.into_();
+        let child: ChildStorageCollection<Vec<u8>, Vec<u8>> = child.into_();
+        assert_eq!(buffers(&main, &child), original);
+    }
+}
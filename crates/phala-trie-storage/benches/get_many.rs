@@ -0,0 +1,31 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use phala_trie_storage::TrieStorage;
+use sp_core::Blake2Hasher;
+
+fn keys_and_trie(n: u32) -> (Vec<Vec<u8>>, TrieStorage<Blake2Hasher>) {
+    let pairs: Vec<_> = (0..n)
+        .map(|i| {
+            (
+                sp_core::hashing::blake2_256(&i.to_le_bytes()).to_vec(),
+                i.to_le_bytes().to_vec(),
+            )
+        })
+        .collect();
+    let mut trie = TrieStorage::default();
+    trie.load(pairs.iter().map(|(k, v)| (k, v)));
+    let keys = pairs.into_iter().step_by(100).map(|(k, _)| k).collect();
+    (keys, trie)
+}
+
+fn bench_get_many(c: &mut Criterion) {
+    let (keys, trie) = keys_and_trie(10_000);
+    let mut group = c.benchmark_group("read 100 keys");
+    group.bench_function("get", |b| {
+        b.iter(|| keys.iter().map(|key| trie.get(key)).collect::<Vec<_>>())
+    });
+    group.bench_function("get_many", |b| b.iter(|| trie.get_many(&keys)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_get_many);
+criterion_main!(benches);
@@ -16,7 +16,10 @@ use parity_scale_codec::Codec;
 use sp_core::storage::ChildInfo;
 use sp_core::Hasher;
 use sp_state_machine::{Backend, TrieBackend, TrieBackendBuilder};
-use sp_trie::{trie_types::TrieDBMutBuilderV0 as TrieDBMutBuilder, TrieMut};
+use sp_trie::{
+    trie_types::{TrieDBBuilder, TrieDBMutBuilderV0 as TrieDBMutBuilder},
+    Trie, TrieMut,
+};
 
 pub use memdb::GenericMemoryDB as MemoryDB;
 
@@ -63,18 +66,36 @@ where
     TrieBackendBuilder::new(mdb, root).build()
 }
 
+/// Key-values serialized as a map in the order of the keys.
+///
+/// The iteration order of the hash map in MemoryDB varies between instances, serializing it
+/// directly would make the same trie serialize to different bytes.
+#[cfg(feature = "serde")]
+struct SortedKvs<K, V>(Vec<(K, V)>);
+
+#[cfg(feature = "serde")]
+impl<K: Serialize, V: Serialize> Serialize for SortedKvs<K, V> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+    }
+}
+
 #[cfg(feature = "serde")]
 pub fn serialize_trie_backend<H: Hasher, S>(
     trie: &TrieBackend<MemoryDB<H>, H>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
-    H::Out: Codec + Serialize,
+    H::Out: Codec + Serialize + Ord,
     S: Serializer,
 {
     let root = trie.root();
-    let kvs: im::HashMap<_, _> = trie.backend_storage().clone().drain();
-    (root, kvs).serialize(serializer)
+    let mut kvs: Vec<_> = trie.backend_storage().clone().drain().into_iter().collect();
+    kvs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    (root, SortedKvs(kvs)).serialize(serializer)
 }
 
 #[cfg(feature = "serde")]
@@ -161,6 +182,19 @@ where
         self.0.storage(key.as_ref()).ok().flatten()
     }
 
+    /// Given storage keys return the storage values in the same order
+    ///
+    /// All the keys are looked up through a single trie instance instead of building one per key.
+    pub fn get_many<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Vec<Option<Vec<u8>>> {
+        let trie = TrieDBBuilder::<H>::new(self.0.backend_storage(), self.0.root()).build();
+        keys.into_iter()
+            .map(|key| trie.get(key.as_ref()).ok().flatten())
+            .collect()
+    }
+
     /// Return storage pairs which start with given storage key prefix
     pub fn pairs(&self, prefix: impl AsRef<[u8]>) -> Vec<(Vec<u8>, Vec<u8>)> {
         self.pairs_into(prefix)
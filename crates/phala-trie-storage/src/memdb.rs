@@ -288,6 +288,25 @@ where
     }
 }
 
+impl<H, KF, T, M> MemoryDB<H, KF, T, M>
+where
+    H: KeyHasher,
+    T: AsRef<[u8]>,
+    KF: KeyFunction<H>,
+    M: MemTracker<T>,
+{
+    /// Total length in bytes of the values referenced at least once.
+    ///
+    /// Unlike the malloc based size, this only depends on the content of the database.
+    pub fn value_size(&self) -> usize {
+        self.data
+            .values()
+            .filter(|(_, rc)| *rc > 0)
+            .map(|(value, _)| value.as_ref().len())
+            .sum()
+    }
+}
+
 impl<H, KF, T, M> MallocSizeOf for MemoryDB<H, KF, T, M>
 where
     H: KeyHasher,
@@ -717,6 +736,26 @@ mod tests {
         assert!(db.contains(&root, EMPTY_PREFIX));
     }
 
+    #[test]
+    fn value_size_counts_referenced_values() {
+        let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+        assert_eq!(db.value_size(), 0);
+
+        let hello = db.insert(EMPTY_PREFIX, b"hello");
+        db.insert(EMPTY_PREFIX, b"hello");
+        db.insert(EMPTY_PREFIX, b"world!");
+        assert_eq!(db.value_size(), 11);
+
+        db.remove(&hello, EMPTY_PREFIX);
+        assert_eq!(db.value_size(), 11);
+        db.remove(&hello, EMPTY_PREFIX);
+        assert_eq!(db.value_size(), 6);
+
+        // Pending removals don't count.
+        db.remove(&KeccakHasher::hash(b"absent"), EMPTY_PREFIX);
+        assert_eq!(db.value_size(), 6);
+    }
+
     #[test]
     fn malloc_size_of() {
         let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
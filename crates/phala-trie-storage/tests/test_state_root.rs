@@ -125,3 +125,33 @@ fn test_apply_main_changes() {
         assert_eq!(format!("{:?}", trie.root()), roots[number + 1]);
     }
 }
+
+#[test]
+fn test_serialization_is_deterministic() {
+    // Each trie is backed by a hash map with its own random hasher state.
+    let trie0 = load_genesis_trie();
+    let trie1 = load_genesis_trie();
+    let bytes0 = serde_json::to_vec(&trie0).unwrap();
+    let bytes1 = serde_json::to_vec(&trie1).unwrap();
+    assert_eq!(bytes0, bytes1);
+
+    let restored: TrieStorage<NativeBlakeTwo256> = serde_json::from_slice(&bytes0).unwrap();
+    assert_eq!(restored.root(), trie0.root());
+    assert_eq!(serde_json::to_vec(&restored).unwrap(), bytes0);
+}
+
+#[test]
+fn test_get_many_matches_get() {
+    let trie = load_genesis_trie();
+    let mut keys: Vec<_> = trie.pairs(b"").into_iter().map(|(k, _)| k).collect();
+    keys.push(b"missing".to_vec());
+    keys.insert(0, keys[1].clone());
+
+    let values = trie.get_many(&keys);
+    assert_eq!(values.len(), keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value, trie.get(key));
+    }
+    assert_eq!(trie.get_many(&keys[keys.len() - 1..]), vec![None]);
+    assert!(trie.get_many(Vec::<Vec<u8>>::new()).is_empty());
+}
@@ -58,7 +58,7 @@ pub mod messaging {
     use crate::messaging::EncryptedKey;
     use crate::{ClusterPublicKey, WorkerIdentity, WorkerPublicKey};
     use phala_mq::bind_topic;
-    use sp_core::crypto::AccountId32;
+    use sp_core::{crypto::AccountId32, H256};
 
     bind_topic!(ClusterEvent, b"phala/cluster/event");
     #[derive(Encode, Decode, Debug)]
@@ -121,6 +121,13 @@ pub mod messaging {
         ClusterDeploymentFailed {
             id: ContractClusterId,
         },
+        ClusterDeployedV2 {
+            id: ContractClusterId,
+            pubkey: ClusterPublicKey,
+            /// A commitment to the full cluster key material. Workers deployed with the same
+            /// cluster key report the same fingerprint.
+            fingerprint: H256,
+        },
     }
 
     #[derive(Encode, Decode, TypeInfo, Clone, PartialEq, Eq, Debug)]
@@ -291,6 +298,8 @@ pub enum ContractQueryError {
     DecodeError,
     /// Other errors reported during the contract query execution.
     OtherError(String),
+    /// The cluster of the contract is missing.
+    ClusterNotFound,
 }
 
 impl From<ContractQueryError> for prpc::server::Error {
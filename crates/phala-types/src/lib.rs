@@ -344,6 +344,16 @@ pub mod messaging {
         pub encrypted_master_key_history: Vec<(u64, BlockNumber, EncryptedKey)>,
     }
 
+    // Messages: Request from a gatekeeper which lost its sealed master key
+    bind_topic!(MasterKeyReshareRequest, b"phala/gatekeeper/reshare");
+    /// Sent by a registered gatekeeper, identified by the message origin, asking the other
+    /// gatekeepers to share the master key history to it again.
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct MasterKeyReshareRequest {
+        /// The ECDH public key to encrypt the shared keys to, must match the registered one
+        pub ecdh_pubkey: EcdhPublicKey,
+    }
+
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
     pub struct BatchRotateMasterKeyEvent {
         pub rotation_id: u64,
@@ -628,6 +638,7 @@ pub enum SignedContentType {
     EndpointInfo = 2,
     MasterKeyRotation = 3,
     MasterKeyStore = 4,
+    EcdhKeyAttestation = 5,
 }
 
 pub fn wrap_content_to_sign(data: &[u8], sigtype: SignedContentType) -> Cow<[u8]> {
@@ -253,19 +253,25 @@ fn now() -> u64 {
     REF_TIME.elapsed().as_secs()
 }
 
-pub fn apply_cache_op(contract: &AccountId32, op: CacheOp) {
-    with_global_cache(|cache| {
-        let contract: &[u8] = contract.as_ref();
-        match op {
-            CacheOp::Set { key, value } => {
-                let _ = cache.set(contract.into(), key.into(), value.into());
-            }
-            CacheOp::SetExpiration { key, expiration } => {
-                cache.set_expire(contract.into(), key.into(), expiration)
-            }
-            CacheOp::Remove { key } => {
-                let _ = cache.remove(contract, &key);
-            }
+/// The id of the cache storage of `contract` deployed in `cluster`.
+///
+/// Storages are namespaced by the cluster id so that contracts in different clusters never share a
+/// cache, even if they have the same address.
+pub fn cache_id(cluster: &[u8], contract: &[u8]) -> Vec<u8> {
+    [cluster, contract].concat()
+}
+
+pub fn apply_cache_op(cluster: &[u8], contract: &AccountId32, op: CacheOp) {
+    let id = cache_id(cluster, contract.as_ref());
+    with_global_cache(|cache| match op {
+        CacheOp::Set { key, value } => {
+            let _ = cache.set(id.into(), key.into(), value.into());
+        }
+        CacheOp::SetExpiration { key, expiration } => {
+            cache.set_expire(id.into(), key.into(), expiration)
+        }
+        CacheOp::Remove { key } => {
+            let _ = cache.remove(&id, &key);
         }
     })
 }
@@ -379,6 +385,32 @@ mod test {
         assert_eq!(get_size(&cache, b"id"), 0);
     }
 
+    #[test]
+    fn caches_are_isolated_between_clusters() {
+        enable_test_mode();
+        let contract = AccountId32::new([1u8; 32]);
+        let (cluster_a, cluster_b) = ([0xa; 32], [0xb; 32]);
+        let id_a = cache_id(&cluster_a, contract.as_ref());
+        let id_b = cache_id(&cluster_b, contract.as_ref());
+        apply_quotas([(&id_a[..], 1000), (&id_b[..], 1000)]);
+
+        let set = |value: &[u8]| CacheOp::Set {
+            key: b"foo".to_vec(),
+            value: value.to_vec(),
+        };
+        apply_cache_op(&cluster_a, &contract, set(b"a"));
+        apply_cache_op(&cluster_b, &contract, set(b"b"));
+        assert_eq!(get(&id_a, b"foo"), Some(b"a".to_vec()));
+        assert_eq!(get(&id_b, b"foo"), Some(b"b".to_vec()));
+
+        let remove = CacheOp::Remove {
+            key: b"foo".to_vec(),
+        };
+        apply_cache_op(&cluster_a, &contract, remove);
+        assert_eq!(get(&id_a, b"foo"), None);
+        assert_eq!(get(&id_b, b"foo"), Some(b"b".to_vec()));
+    }
+
     #[test]
     fn fit_size_works() {
         let mut store = Storage::new(20);
@@ -32,6 +32,10 @@ pub struct ExecSideEffects {
 }
 
 impl ExecSideEffects {
+    pub fn is_empty(&self) -> bool {
+        self.pink_events.is_empty() && self.ink_events.is_empty() && self.instantiated.is_empty()
+    }
+
     pub fn into_query_only_effects(mut self) -> Self {
         self.pink_events
             .retain(|(_, event)| event.allowed_in_query());
@@ -169,6 +173,10 @@ impl CallInQuery {
         }
         Ok(())
     }
+
+    fn cache_id(&self) -> Vec<u8> {
+        local_cache::cache_id(&crate::runtime::Pink::cluster_id(), self.address.as_ref())
+    }
 }
 
 impl PinkExtBackend for CallInQuery {
@@ -218,20 +226,20 @@ impl PinkExtBackend for CallInQuery {
         key: Cow<[u8]>,
         value: Cow<[u8]>,
     ) -> Result<Result<(), StorageQuotaExceeded>, Self::Error> {
-        Ok(local_cache::set(self.address.as_ref(), &key, &value))
+        Ok(local_cache::set(&self.cache_id(), &key, &value))
     }
 
     fn cache_set_expiration(&self, key: Cow<[u8]>, expire: u64) -> Result<(), Self::Error> {
-        local_cache::set_expiration(self.address.as_ref(), &key, expire);
+        local_cache::set_expiration(&self.cache_id(), &key, expire);
         Ok(())
     }
 
     fn cache_get(&self, key: Cow<'_, [u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
-        Ok(local_cache::get(self.address.as_ref(), &key))
+        Ok(local_cache::get(&self.cache_id(), &key))
     }
 
     fn cache_remove(&self, key: Cow<'_, [u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
-        Ok(local_cache::remove(self.address.as_ref(), &key))
+        Ok(local_cache::remove(&self.cache_id(), &key))
     }
 
     fn log(&self, level: u8, message: Cow<str>) -> Result<(), Self::Error> {
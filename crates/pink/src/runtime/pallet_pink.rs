@@ -33,6 +33,7 @@ pub mod pallet {
     }
 
     #[pallet::storage]
+    #[pallet::getter(fn cluster_id)]
     pub(crate) type ClusterId<T: Config> = StorageValue<_, Vec<u8>, ValueQuery>;
 
     #[pallet::storage]
@@ -230,6 +230,37 @@ where
             .0
     }
 
+    /// Approximate size in bytes of a contract's storage, the sum of the lengths of the keys and
+    /// values in its child trie.
+    ///
+    /// Returns `None` if there is no contract at the given address.
+    pub fn contract_storage_size(&self, address: &AccountId) -> Option<u64> {
+        use frame_support::{storage::storage_prefix, StorageHasher, Twox64Concat};
+        use scale::{Decode, Encode};
+        use sp_core::storage::ChildInfo;
+
+        let mut key = storage_prefix(b"Contracts", b"ContractInfoOf").to_vec();
+        key.extend(Twox64Concat::hash(&address.encode()));
+        let info = self.get(&key)?;
+        // The trie id is the first field of the pallet-contracts `ContractInfo`.
+        let trie_id = Vec::<u8>::decode(&mut &info[..]).ok()?;
+        let child_info = ChildInfo::new_default(&trie_id);
+        let mut size = 0_u64;
+        self.backend
+            .apply_to_key_values_while(
+                Some(&child_info),
+                None,
+                None,
+                |key, value| {
+                    size += (key.len() + value.len()) as u64;
+                    true
+                },
+                true,
+            )
+            .ok()?;
+        Some(size)
+    }
+
     pub fn code_hash(&self, account: &AccountId) -> Option<Hash> {
         self.execute_with(true, None, || Contracts::code_hash(account))
             .0
@@ -244,6 +275,13 @@ where
     }
 }
 
+impl Storage<InMemoryBackend> {
+    /// The number of bytes held by the underlying trie database.
+    pub fn size(&self) -> usize {
+        self.backend.backend_storage().value_size()
+    }
+}
+
 impl Serialize for Storage<InMemoryBackend> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -0,0 +1,26 @@
+(module
+	(import "seal0" "seal_input" (func $seal_input (param i32 i32)))
+	(import "seal0" "seal_set_storage" (func $seal_set_storage (param i32 i32 i32)))
+	(import "env" "memory" (memory 1 1))
+
+	;; [0, 4) size of the input buffer
+	(data (i32.const 0) "\00\01")
+
+	;; [4, 36) storage key
+
+	;; [36, 292) input buffer
+
+	(func (export "call")
+		(call $seal_input (i32.const 36) (i32.const 0))
+
+		;; store the call input under the key given by its first byte.
+		(i32.store8 (i32.const 4) (i32.load8_u (i32.const 36)))
+		(call $seal_set_storage
+			(i32.const 4) ;; key_ptr
+			(i32.const 36) ;; value_ptr
+			(i32.load (i32.const 0)) ;; value_len
+		)
+	)
+
+	(func (export "deploy"))
+)
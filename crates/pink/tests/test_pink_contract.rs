@@ -238,6 +238,38 @@ fn test_use_cache() {
     );
 }
 
+#[test]
+fn test_contract_storage_size() {
+    let mut storage = Storage::default();
+    storage.deposit(&ALICE, ENOUGH);
+
+    let wasm = wat::parse_bytes(include_bytes!("./fixtures/storage_size.wat"))
+        .unwrap()
+        .into_owned();
+    let code_hash = storage.upload_code(&ALICE, wasm, true).unwrap();
+    let contract = Contract::new(code_hash, vec![], vec![], false, tx_args(&mut storage))
+        .unwrap()
+        .0;
+    assert_eq!(storage.contract_storage_size(&contract.address), Some(0));
+    assert_eq!(
+        storage.contract_storage_size(&AccountId32::new([0xff; 32])),
+        None
+    );
+
+    let mut write = |key: u8, len: usize| {
+        let mut input = vec![0; len];
+        input[0] = key;
+        let result = contract.bare_call(input, false, tx_args(&mut storage)).0;
+        assert!(!result.result.unwrap().did_revert());
+        storage.contract_storage_size(&contract.address).unwrap()
+    };
+    let size = write(1, 64);
+    assert!(size >= 64);
+    let size_grown = write(2, 128);
+    assert!(size_grown >= size + 128);
+    // Overwriting an entry with a shorter value shrinks the storage.
+    assert!(write(2, 8) < size_grown);
+}
 
 #[test]
 #[ignore = "for dev"]
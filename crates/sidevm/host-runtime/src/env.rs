@@ -149,7 +149,7 @@ pub trait CacheOps {
     fn remove(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>>;
 }
 
-pub type DynCacheOps = &'static (dyn CacheOps + Send + Sync);
+pub type DynCacheOps = Arc<dyn CacheOps + Send + Sync>;
 
 struct VmMemory(Option<Memory>);
 
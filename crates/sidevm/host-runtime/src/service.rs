@@ -70,6 +70,11 @@ pub struct Spawner {
 }
 
 pub fn service(worker_threads: usize) -> (ServiceRun, Spawner) {
+    try_service(worker_threads).expect("Failed to create the sidevm service")
+}
+
+/// Like `service`, but returns an error rather than panicking if the runtime can not be created.
+pub fn try_service(worker_threads: usize) -> Result<(ServiceRun, Spawner)> {
     let worker_threads = worker_threads.max(1);
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .max_blocking_threads(16)
@@ -79,7 +84,7 @@ pub fn service(worker_threads: usize) -> (ServiceRun, Spawner) {
         .worker_threads(worker_threads + 2)
         .enable_all()
         .build()
-        .unwrap();
+        .context("Failed to build the sidevm tokio runtime")?;
     let runtime_handle = runtime.handle().clone();
     let (report_tx, report_rx) = channel(100);
     let run = ServiceRun { runtime, report_rx };
@@ -88,7 +93,7 @@ pub fn service(worker_threads: usize) -> (ServiceRun, Spawner) {
         report_tx,
         scheduler: TaskScheduler::new(worker_threads as _),
     };
-    (run, spawner)
+    Ok((run, spawner))
 }
 
 impl ServiceRun {
@@ -3,7 +3,7 @@ use sidevm_host_runtime::{CacheOps, DynCacheOps, OcallError};
 use clap::Parser;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 mod web_api;
 
@@ -51,7 +51,7 @@ fn simple_cache() -> DynCacheOps {
             Ok(value)
         }
     }
-    &Ops
+    Arc::new(Ops)
 }
 
 #[tokio::main]
@@ -63,6 +63,8 @@ pub mod pallet {
 			pubkey: ContractPublicKey,
 			deployer: ContractId,
 		},
+		/// The events emitted by a cluster in one block. Batches can not be nested.
+		Batch(Vec<ContractRegistryEvent>),
 	}
 
 	#[pallet::config]
@@ -154,6 +156,19 @@ pub mod pallet {
 			account: H256,
 			amount: BalanceOf<T>,
 		},
+		ClusterDeployedV2 {
+			cluster: ContractClusterId,
+			pubkey: ClusterPublicKey,
+			worker: WorkerPublicKey,
+			fingerprint: H256,
+		},
+		/// An event of a `ContractRegistryEvent::Batch` was rejected. The rest of the batch is
+		/// still applied.
+		RegistryEventRejected {
+			cluster: ContractClusterId,
+			index: u32,
+			error: DispatchError,
+		},
 	}
 
 	#[pallet::error]
@@ -170,6 +185,7 @@ pub mod pallet {
 		PayloadTooLarge,
 		NoPinkSystemCode,
 		ContractNotFound,
+		NestedBatch,
 	}
 
 	type CodeHash<T> = <T as frame_system::Config>::Hash;
@@ -467,7 +483,14 @@ pub mod pallet {
 				MessageOrigin::Cluster(cluster) => cluster,
 				_ => return Err(Error::<T>::InvalidSender.into()),
 			};
-			match message.payload {
+			Self::apply_contract_registry_event(cluster, message.payload)
+		}
+
+		fn apply_contract_registry_event(
+			cluster: ContractClusterId,
+			event: ContractRegistryEvent,
+		) -> DispatchResult {
+			match event {
 				ContractRegistryEvent::PubkeyAvailable {
 					contract,
 					pubkey,
@@ -495,6 +518,21 @@ pub mod pallet {
 						deployer,
 					});
 				}
+				ContractRegistryEvent::Batch(events) => {
+					for (index, event) in events.into_iter().enumerate() {
+						let result = match event {
+							ContractRegistryEvent::Batch(_) => Err(Error::<T>::NestedBatch.into()),
+							event => Self::apply_contract_registry_event(cluster, event),
+						};
+						if let Err(error) = result {
+							Self::deposit_event(Event::RegistryEventRejected {
+								cluster,
+								index: index as u32,
+								error,
+							});
+						}
+					}
+				}
 			}
 			Ok(())
 		}
@@ -522,6 +560,19 @@ pub mod pallet {
 						worker: worker_pubkey,
 					});
 				}
+				WorkerClusterReport::ClusterDeployedV2 {
+					id,
+					pubkey,
+					fingerprint,
+				} => {
+					ClusterWorkers::<T>::append(id, worker_pubkey);
+					Self::deposit_event(Event::ClusterDeployedV2 {
+						cluster: id,
+						pubkey,
+						worker: worker_pubkey,
+						fingerprint,
+					});
+				}
 			}
 			Ok(())
 		}
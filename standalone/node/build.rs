@@ -21,6 +21,13 @@ fn main() {
 	cli::main();
 }
 
+/// Stands in for the node's `service` module, which `src/cli.rs` refers to for its defaults.
+#[cfg(feature = "cli")]
+mod service {
+	// The default values don't make it into the completion scripts.
+	pub const DEFAULT_STORAGE_CHANGES_MAX_BLOCKS: u64 = 0;
+}
+
 #[cfg(feature = "cli")]
 mod cli {
 	include!("src/cli.rs");
@@ -505,7 +505,7 @@ pub fn testnet_genesis(
 #[cfg(test)]
 pub(crate) mod tests {
 	use super::*;
-	use crate::service::{new_full_base, NewFullBase};
+	use crate::service::{new_full_base, NewFullBase, DEFAULT_STORAGE_CHANGES_MAX_BLOCKS};
 	use sc_service_test;
 	use sp_runtime::BuildStorage;
 
@@ -565,7 +565,7 @@ pub(crate) mod tests {
 
 		sc_service_test::connectivity(integration_test_config_with_two_authorities(), |config| {
 			let NewFullBase { task_manager, client, network, transaction_pool, .. } =
-				new_full_base(config, false, |_, _| ())?;
+				new_full_base(config, false, DEFAULT_STORAGE_CHANGES_MAX_BLOCKS, |_, _| ())?;
 			Ok(sc_service_test::TestNetComponents::new(
 				task_manager,
 				client,
@@ -40,6 +40,10 @@ pub struct Cli {
 	/// Custom block duration in milliseconds (only useful with --dev)
 	#[arg(long)]
 	pub block_millisecs: Option<u64>,
+
+	/// Max number of blocks allowed in one `pha_getStorageChanges` call.
+	#[arg(long, default_value_t = crate::service::DEFAULT_STORAGE_CHANGES_MAX_BLOCKS)]
+	pub storage_changes_max_blocks: u64,
 }
 
 /// Possible subcommands of the main binary.
@@ -123,10 +123,14 @@ pub fn create_extrinsic(
 	)
 }
 
+/// Default max number of blocks allowed in one `pha_getStorageChanges` call.
+pub const DEFAULT_STORAGE_CHANGES_MAX_BLOCKS: u64 = 10000;
+
 /// Creates a new partial node.
 #[allow(clippy::type_complexity)]
 pub fn new_partial(
 	config: &Configuration,
+	storage_changes_max_blocks: u64,
 ) -> Result<
 	sc_service::PartialComponents<
 		FullClient,
@@ -256,6 +260,7 @@ pub fn new_partial(
 		let chain_spec = config.chain_spec.cloned_box();
 
 		let rpc_backend = backend.clone();
+		let rpc_spawner = task_manager.spawn_handle();
 		let is_archive_mode = match &config.state_pruning {
 			Some(m) => {
 				match m {
@@ -287,7 +292,7 @@ pub fn new_partial(
 			};
 
 			let mut io = node_rpc::create_full(deps, rpc_backend.clone())?;
-			phala_node_rpc_ext::extend_rpc(&mut io, client.clone(), rpc_backend.clone(), is_archive_mode, pool.clone());
+			phala_node_rpc_ext::extend_rpc(&mut io, client.clone(), rpc_backend.clone(), is_archive_mode, pool.clone(), rpc_spawner.clone(), storage_changes_max_blocks);
 			Ok(io)
 		};
 
@@ -324,6 +329,7 @@ pub struct NewFullBase {
 pub fn new_full_base(
 	mut config: Configuration,
 	disable_hardware_benchmarks: bool,
+	storage_changes_max_blocks: u64,
 	with_startup_data: impl FnOnce(
 		&sc_consensus_babe::BabeBlockImport<Block, FullClient, FullGrandpaBlockImport>,
 		&sc_consensus_babe::BabeLink<Block>,
@@ -347,7 +353,7 @@ pub fn new_full_base(
 		select_chain,
 		transaction_pool,
 		other: (rpc_builder, import_setup, rpc_setup, mut telemetry),
-	} = new_partial(&config)?;
+	} = new_partial(&config, storage_changes_max_blocks)?;
 
 	let shared_voter_state = rpc_setup;
 	let auth_disc_publish_non_global_ips = config.network.allow_non_globals_in_dht;
@@ -567,14 +573,15 @@ pub fn new_full_base(
 pub fn new_full(
 	config: Configuration,
 	disable_hardware_benchmarks: bool,
+	storage_changes_max_blocks: u64,
 ) -> Result<TaskManager, ServiceError> {
-	new_full_base(config, disable_hardware_benchmarks, |_, _| ())
+	new_full_base(config, disable_hardware_benchmarks, storage_changes_max_blocks, |_, _| ())
 		.map(|NewFullBase { task_manager, .. }| task_manager)
 }
 
 #[cfg(test)]
 mod tests {
-	use crate::service::{new_full_base, NewFullBase};
+	use crate::service::{new_full_base, NewFullBase, DEFAULT_STORAGE_CHANGES_MAX_BLOCKS};
 	use codec::Encode;
 	use node_runtime::{
 		constants::{currency::CENTS, time::SLOT_DURATION},
@@ -637,6 +644,7 @@ mod tests {
 					new_full_base(
 						config,
 						false,
+						DEFAULT_STORAGE_CHANGES_MAX_BLOCKS,
 						|block_import: &sc_consensus_babe::BabeBlockImport<Block, _, _>,
 						 babe_link: &sc_consensus_babe::BabeLink<Block>| {
 							setup_handles = Some((block_import.clone(), babe_link.clone()));
@@ -815,7 +823,7 @@ mod tests {
 			crate::chain_spec::tests::integration_test_config_with_two_authorities(),
 			|config| {
 				let NewFullBase { task_manager, client, network, transaction_pool, .. } =
-					new_full_base(config, false, |_, _| ())?;
+					new_full_base(config, false, DEFAULT_STORAGE_CHANGES_MAX_BLOCKS, |_, _| ())?;
 				Ok(sc_service_test::TestNetComponents::new(
 					task_manager,
 					client,
@@ -33,6 +33,7 @@ use phactory_api::blocks::{
     self, AuthoritySet, AuthoritySetChange, BlockHeader, BlockHeaderWithChanges, HeaderToSync,
     StorageProof,
 };
+use phactory_api::handshake::{self, ProtocolVersion};
 use phactory_api::prpc::{self, InitRuntimeResponse, PhactoryInfo};
 use phactory_api::pruntime_client;
 
@@ -1004,6 +1005,40 @@ async fn wait_until_synced<T: subxt::Config>(client: &phaxt::Client<T>) -> Resul
     }
 }
 
+/// Make sure the pRuntime at `endpoint` speaks a protocol version this pherry can work with.
+async fn negotiate_protocol_version(endpoint: &str) -> Result<()> {
+    #[derive(serde::Deserialize)]
+    struct Response {
+        status: String,
+        payload: String,
+    }
+
+    let url = format!("{endpoint}/get_protocol_version");
+    let response = reqwest::get(url).await?;
+    let pruntime = if response.status() == reqwest::StatusCode::NOT_FOUND {
+        warn!("The pRuntime does not serve its protocol version, assuming the legacy one");
+        ProtocolVersion::legacy_pruntime()
+    } else {
+        let response = response
+            .error_for_status()
+            .context("Failed to query the pRuntime protocol version")?
+            .text()
+            .await?;
+        let response: Response = serde_json::from_str(&response)?;
+        if response.status != "ok" {
+            return Err(anyhow!(
+                "Failed to query the pRuntime protocol version: {}",
+                response.payload
+            ));
+        }
+        serde_json::from_str(&response.payload)?
+    };
+    handshake::check_compatible(&pruntime, &ProtocolVersion::pherry())
+        .map_err(|err| anyhow!("Incompatible pRuntime: {err}"))?;
+    info!("pRuntime protocol version: v{}", pruntime.version);
+    Ok(())
+}
+
 async fn bridge(
     args: &Args,
     flags: &mut RunningFlags,
@@ -1041,6 +1076,7 @@ async fn bridge(
 
     // Other initialization
     let pr = pruntime_client::new_pruntime_client(args.pruntime_endpoint.clone());
+    negotiate_protocol_version(&args.pruntime_endpoint).await?;
     let pair = <sr25519::Pair as Pair>::from_string(&args.mnemonic, None)
         .expect("Bad privkey derive path");
     let mut signer = SrSigner::new(pair);
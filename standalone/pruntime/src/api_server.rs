@@ -146,6 +146,13 @@ fn getinfo() -> String {
     runtime::ecall_getinfo()
 }
 
+/// The benchmark iterations per second, sampled over `window_ms` (1s by default, 10s at most).
+#[get("/benchmark_rate?<window_ms>")]
+async fn benchmark_rate(window_ms: Option<u64>) -> String {
+    let window_ms = window_ms.unwrap_or(1000).clamp(100, 10_000);
+    runtime::ecall_benchmark_rate(std::time::Duration::from_millis(window_ms)).await
+}
+
 #[get("/contract_info?<id>")]
 fn get_contract_info(id: Option<String>) -> String {
     runtime::ecall_get_contract_info(&id.unwrap_or_default())
@@ -156,6 +163,11 @@ fn get_cluster_info() -> String {
     runtime::ecall_get_cluster_info()
 }
 
+#[get("/metrics")]
+fn metrics() -> String {
+    runtime::ecall_prometheus_metrics()
+}
+
 enum RpcType {
     Public,
     Private,
@@ -320,6 +332,163 @@ pub(super) fn rocket(args: &super::Args) -> rocket::Rocket<impl Phase> {
             proxy_routes![
                 (get, "/get_info", get_info, actions::ACTION_GET_INFO),
                 (post, "/get_info", get_info_post, actions::ACTION_GET_INFO),
+                (
+                    get,
+                    "/get_ecdh_key_attestation",
+                    get_ecdh_key_attestation,
+                    actions::ACTION_GET_ECDH_KEY_ATTESTATION
+                ),
+                (
+                    post,
+                    "/verify_ecdh_key_attestation",
+                    verify_ecdh_key_attestation,
+                    actions::ACTION_VERIFY_ECDH_KEY_ATTESTATION
+                ),
+                (
+                    post,
+                    "/relocate_storage",
+                    relocate_storage,
+                    actions::ACTION_RELOCATE_STORAGE
+                ),
+                (
+                    post,
+                    "/get_cluster_of_contract",
+                    get_cluster_of_contract,
+                    actions::ACTION_GET_CLUSTER_OF_CONTRACT
+                ),
+                (
+                    post,
+                    "/get_contract_metadata",
+                    get_contract_metadata,
+                    actions::ACTION_GET_CONTRACT_METADATA
+                ),
+                (
+                    get,
+                    "/get_key_operations",
+                    get_key_operations,
+                    actions::ACTION_GET_KEY_OPERATIONS
+                ),
+                (
+                    get,
+                    "/get_cluster_storage_usage",
+                    get_cluster_storage_usage,
+                    actions::ACTION_GET_CLUSTER_STORAGE_USAGE
+                ),
+                (
+                    post,
+                    "/get_contract_storage_size",
+                    get_contract_storage_size,
+                    actions::ACTION_GET_CONTRACT_STORAGE_SIZE
+                ),
+                (
+                    post,
+                    "/set_cluster_query_cache",
+                    set_cluster_query_cache,
+                    actions::ACTION_SET_CLUSTER_QUERY_CACHE
+                ),
+                (
+                    get,
+                    "/get_role_tags",
+                    get_role_tags,
+                    actions::ACTION_GET_ROLE_TAGS
+                ),
+                (
+                    post,
+                    "/set_role_tags",
+                    set_role_tags,
+                    actions::ACTION_SET_ROLE_TAGS
+                ),
+                (post, "/set_paused", set_paused, actions::ACTION_SET_PAUSED),
+                (
+                    get,
+                    "/list_egress_messages",
+                    list_egress_messages,
+                    actions::ACTION_LIST_EGRESS_MESSAGES
+                ),
+                (
+                    post,
+                    "/drop_egress_message",
+                    drop_egress_message,
+                    actions::ACTION_DROP_EGRESS_MESSAGE
+                ),
+                (
+                    get,
+                    "/simulated_egress",
+                    simulated_egress,
+                    actions::ACTION_GET_SIMULATED_EGRESS
+                ),
+                (
+                    get,
+                    "/export_pending_egress",
+                    export_pending_egress,
+                    actions::ACTION_EXPORT_PENDING_EGRESS
+                ),
+                (
+                    post,
+                    "/import_pending_egress",
+                    import_pending_egress,
+                    actions::ACTION_IMPORT_PENDING_EGRESS
+                ),
+                (
+                    post,
+                    "/resync_gk_workers",
+                    resync_gk_workers,
+                    actions::ACTION_RESYNC_GK_WORKERS
+                ),
+                (
+                    get,
+                    "/get_protocol_version",
+                    get_protocol_version,
+                    actions::ACTION_GET_PROTOCOL_VERSION
+                ),
+                (
+                    get,
+                    "/get_processing_times",
+                    get_processing_times,
+                    actions::ACTION_GET_PROCESSING_TIMES
+                ),
+                (
+                    get,
+                    "/get_slow_contracts",
+                    get_slow_contracts,
+                    actions::ACTION_GET_SLOW_CONTRACTS
+                ),
+                (
+                    get,
+                    "/get_checkpoint_stats",
+                    get_checkpoint_stats,
+                    actions::ACTION_GET_CHECKPOINT_STATS
+                ),
+                (
+                    post,
+                    "/start_benchmark",
+                    start_benchmark,
+                    actions::ACTION_START_BENCHMARK
+                ),
+                (
+                    get,
+                    "/get_gk_worker_tokenomics",
+                    get_gk_worker_tokenomics,
+                    actions::ACTION_GET_GK_WORKER_TOKENOMICS
+                ),
+                (
+                    get,
+                    "/get_gk_heartbeat_anomalies",
+                    get_gk_heartbeat_anomalies,
+                    actions::ACTION_GET_GK_HEARTBEAT_ANOMALIES
+                ),
+                (
+                    post,
+                    "/simulate_heartbeat_challenge",
+                    simulate_heartbeat_challenge,
+                    actions::ACTION_SIMULATE_HEARTBEAT_CHALLENGE
+                ),
+                (
+                    get,
+                    "/get_worker_state",
+                    get_worker_state,
+                    actions::ACTION_GET_WORKER_STATE
+                ),
             ],
         )
         .mount(
@@ -343,7 +512,16 @@ pub(super) fn rocket(args: &super::Args) -> rocket::Rocket<impl Phase> {
                 ),
             ],
         )
-        .mount("/", routes![getinfo, get_contract_info, get_cluster_info]);
+        .mount(
+            "/",
+            routes![
+                getinfo,
+                get_contract_info,
+                get_cluster_info,
+                metrics,
+                benchmark_rate
+            ],
+        );
 
     if args.enable_kick_api {
         info!("ENABLE `kick` API");
@@ -53,6 +53,7 @@ fn target_allowed(target: &str) -> bool {
         ("sidevm", Prefix),
         ("prpc_measuring", Eq),
         ("gk_computing", Eq),
+        ("master_key_audit", Eq),
         ("phala_mq", Eq),
         ("pruntime", Prefix),
     ];
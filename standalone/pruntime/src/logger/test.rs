@@ -10,6 +10,7 @@ fn whitelist_works() {
         allowed,
         [
             "gk_computing",
+            "master_key_audit",
             "phactory",
             "phactory::benchmark",
             "phactory::bin_api_service",
@@ -18,6 +19,7 @@ fn whitelist_works() {
             "phactory::contracts::support::keeper",
             "phactory::light_validation",
             "phactory::light_validation::justification::communication",
+            "phactory::poisoned_state_audit",
             "phactory::prpc_service",
             "phactory::storage::storage_ext",
             "phactory::system",
@@ -9,7 +9,7 @@ use clap::Parser;
 use log::{error, info};
 
 use phactory::BlockNumber;
-use phactory_api::ecall_args::{git_revision, InitArgs};
+use phactory_api::ecall_args::{git_revision, InitArgs, PoisonedStatePolicy};
 
 mod logger;
 
@@ -70,6 +70,47 @@ struct Args {
     #[arg(long)]
     #[arg(default_value_t = 100)]
     gc_interval: BlockNumber,
+
+    /// Tag the intended role of the worker (e.g. gatekeeper, mining, query), can be repeated.
+    /// Overrides the tags saved in the checkpoint if given.
+    #[arg(long = "role-tag")]
+    role_tags: Vec<String>,
+
+    /// Start in safe mode. The state is loaded but no block is dispatched until the worker is
+    /// resumed via the /set_paused API.
+    #[arg(long)]
+    start_paused: bool,
+
+    /// Dry run: record the egress messages instead of sending them, e.g. to replay the chain
+    /// with a shadow gatekeeper. The recorded messages are listed by the /simulated_egress API.
+    #[arg(long)]
+    simulation: bool,
+
+    /// Drop the offending event with an audit log instead of aborting when the system state is
+    /// found poisoned. The worker keeps going in a possibly inconsistent state.
+    #[arg(long)]
+    continue_on_poisoned_state: bool,
+
+    /// Log the contracts taking longer than the given milliseconds to process a block, 0 to disable
+    #[arg(long)]
+    #[arg(default_value_t = 1000)]
+    slow_contract_threshold_ms: u64,
+
+    /// Defer the checkpoints while the memory in use is above the given MiB, 0 to disable
+    #[arg(long)]
+    #[arg(default_value_t = 0)]
+    checkpoint_max_memory_mb: u64,
+
+    /// Duration in blocks of the benchmarks started by the start_benchmark API without a duration,
+    /// 0 for the default
+    #[arg(long)]
+    #[arg(default_value_t = 0)]
+    bench_duration: u32,
+
+    /// Clamp the benchmark reports to this many iterations, 0 for no limit
+    #[arg(long)]
+    #[arg(default_value_t = 0)]
+    max_bench_iterations: u64,
 }
 
 #[rocket::main]
@@ -133,6 +174,18 @@ async fn main() -> Result<(), rocket::Error> {
             gc_interval: args.gc_interval,
             cores,
             public_port: args.public_port,
+            role_tags: args.role_tags,
+            start_paused: args.start_paused,
+            simulation: args.simulation,
+            poisoned_state_policy: if args.continue_on_poisoned_state {
+                PoisonedStatePolicy::LogAndContinue
+            } else {
+                PoisonedStatePolicy::Abort
+            },
+            slow_contract_threshold_ms: args.slow_contract_threshold_ms,
+            checkpoint_max_memory_mb: args.checkpoint_max_memory_mb,
+            bench_duration: args.bench_duration,
+            max_bench_iterations: args.max_bench_iterations,
         }
     };
     info!("init_args: {:#?}", init_args);
@@ -4,6 +4,7 @@ use anyhow::Result;
 use core::sync::atomic::{AtomicU32, Ordering};
 use log::info;
 use phactory::{benchmark, Phactory, RpcService};
+use std::time::Duration;
 
 lazy_static::lazy_static! {
     static ref APPLICATION: RpcService<GraminePlatform> = RpcService::new(GraminePlatform);
@@ -45,6 +46,10 @@ pub fn ecall_get_cluster_info() -> String {
     serialize_result(result.map(|it| it.clusters))
 }
 
+pub fn ecall_prometheus_metrics() -> String {
+    APPLICATION.lock_phactory().prometheus_metrics()
+}
+
 pub fn ecall_sign_http_response(data: &[u8]) -> Option<String> {
     APPLICATION.lock_phactory().sign_http_response(data)
 }
@@ -93,6 +98,21 @@ pub fn ecall_bench_run(index: u32) {
     }
 }
 
+/// Sample the benchmark iteration counter over `window`.
+///
+/// The phactory is not locked, so the block syncing is not held up while sampling.
+pub async fn ecall_benchmark_rate(window: Duration) -> String {
+    let start = benchmark::Sample::now();
+    rocket::tokio::time::sleep(window).await;
+    let rate = start.iteration_rate(&benchmark::Sample::now());
+    serde_json::to_string_pretty(&serde_json::json!({
+        "iterations_per_sec": rate,
+        "window_ms": window.as_millis() as u64,
+        "paused": benchmark::paused(),
+    }))
+    .unwrap_or_default()
+}
+
 pub async fn ecall_prpc_request(path: String, data: &[u8]) -> (u16, Vec<u8>) {
     let (code, data) = APPLICATION.dispatch_request(path, data).await;
     info!("pRPC status code: {}, data len: {}", code, data.len());